@@ -66,6 +66,33 @@
 //! # fn main() {}
 //! ```
 //!
+//! <h5><b>Writing wrapper macros</b></h5>
+//!
+//! You can build your own wrappers on top of `#[crabtime::eval_function]`, like `crabtime::eval!`
+//! does. To let a wrapper add its own options, put the `lenient` marker among the options.
+//! Unknown options are then collected instead of being rejected, and the macro body can read the
+//! tokens of their values with `crabtime::passthrough_option(name)`, or list all of them with the
+//! `crabtime::PASSTHROUGH_OPTIONS` constant. Values are the option tokens as written, so a string
+//! literal keeps its quotes. Without the marker, unknown options are errors.
+//!
+//! ```
+//! macro_rules! flavored_eval {
+//!     (flavor = $flavor:ident; $($ts:tt)*) => {{
+//!         #[crabtime::eval_function(cache=true, content_base_name=true, lenient, flavor=$flavor)]
+//!         fn run() -> _ {
+//!             $($ts)*
+//!         }
+//!     }};
+//! }
+//!
+//! const FLAVOR: &str = flavored_eval! { flavor = fancy;
+//!     format!("{:?}", crabtime::passthrough_option("flavor").unwrap_or("plain"))
+//! };
+//! # fn main() {
+//! #     assert_eq!(FLAVOR, "fancy");
+//! # }
+//! ```
+//!
 //! <br/>
 //! <br/>
 //!
@@ -106,6 +133,21 @@
 //! # fn main() {}
 //! ```
 //!
+//! Attributes placed on the function, like `#[macro_export]` or doc comments, are applied to the
+//! generated macro definition, so you can also export the macro conditionally with
+//! `#[cfg_attr(feature = "macros", macro_export)]`. The same applies to `macro_export` and doc
+//! attributes used as inner attributes in the function body, also when wrapped in `cfg_attr`.
+//!
+//! An exported macro is evaluated in the crate invoking it, so that crate needs the `crabtime`
+//! dependency, and the nested build uses its configuration, not yours. On nightly, the
+//! build-dependencies used by the macro are discovered in the invoking crate's workspace, and on
+//! stable, they are not discovered at all, so prefer `#![dependency(...)]` attributes in exported
+//! macros. To make this visible to your users, the docs of every exported macro end with a
+//! "Downstream Requirements" section listing the required setup, including the inline
+//! dependencies of the macro and the build-dependencies of your crate it uses. Set the
+//! `CRABTIME_CHECK_EXPORT=1` environment variable to also get the list as a warning when
+//! building your crate.
+//!
 //! <br/>
 //!
 //! <div class="warning">
@@ -227,21 +269,22 @@
 //! <h5><b>Generating output by using <code>crabtime::quote!</code></b></h5>
 //!
 //! The `crabtime::quote!` macro is just like `crabtime::output!`, but instead of outputting the
-//! code immediately, it returns it (as a `String`), so you can store it in a variable and re-use
-//! it across different subsequent calls to `crabtime::quote!` or `crabtime::output!`.
+//! code immediately, it returns it (as a `crabtime::Snippet`), so you can store it in a variable
+//! and re-use it across different subsequent calls to `crabtime::quote!` or `crabtime::output!`.
+//! Snippets can be concatenated with `+` and collected from an iterator, which joins them with new
+//! lines. A snippet dereferences to `str` and converts to `String` with `.into()`.
 //!
 //! ```
 //! #[crabtime::function]
-//! fn gen_positions3(components: Vec<String>) -> String {
-//!     let structs = (1 ..= components.len()).map(|dim| {
+//! fn gen_positions3(components: Vec<String>) -> crabtime::Snippet {
+//!     (1 ..= components.len()).map(|dim| {
 //!         let cons = components[0..dim].join(",");
 //!         crabtime::quote! {
 //!             enum Position{{dim}} {
 //!                 {{cons}}
 //!             }
 //!         }
-//!     }).collect::<Vec<String>>();
-//!     structs.join("\n")
+//!     }).collect()
 //! }
 //! gen_positions3!(["X", "Y", "Z", "W"]);
 //! # fn main() {}
@@ -249,6 +292,211 @@
 //!
 //! <br/>
 //!
+//! <h5><b>Generating <code>macro_rules!</code> by using <code>crabtime::output_macro_rules!</code></b></h5>
+//!
+//! Generating a `macro_rules!` definition with `crabtime::output!` is error-prone, as the braces
+//! of the generated rules are easily confused with interpolation braces. The
+//! `crabtime::output_macro_rules!` macro accepts the macro name followed by its rules and outputs
+//! the `macro_rules!` definition. Interpolation is supported in the macro name (and in the
+//! attributes put before it), while all braces inside the rules are printed literally. The
+//! generated macro can be used right after the Crabtime macro call.
+//!
+//! ```
+//! #[crabtime::function]
+//! fn gen_adder(name: String, value: usize) {
+//!     crabtime::output_macro_rules! {
+//!         #[allow(unused_macros)]
+//!         {{name}}_{{value}} {
+//!             ($x:expr) => {{ let base = $x; base + 1 }};
+//!         }
+//!     }
+//!     crabtime::output! {
+//!         const ADDED: usize = {{name}}_{{value}}!(1);
+//!     }
+//! }
+//! gen_adder!(add, 1);
+//! # fn main() {}
+//! ```
+//!
+//! <br/>
+//!
+//! <h5><b>Generating feature-gated items by using <code>crabtime::output_cfg!</code></b></h5>
+//!
+//! Templating a `#[cfg(...)]` attribute with `crabtime::output!` is brittle, so the
+//! `crabtime::output_cfg!` macro accepts a `cfg` predicate followed by `=>` and a block of items,
+//! and outputs each of the items gated by the predicate. The predicate can be given as tokens or
+//! as a string literal and supports `all`, `any`, and `not`. It is printed as-is, while
+//! interpolation is supported in the items.
+//!
+//! ```
+//! pub struct Config;
+//!
+//! #[crabtime::function]
+//! fn gen_config_impls(value: usize) {
+//!     crabtime::output_cfg! {
+//!         feature = "serde" => {
+//!             impl Config { pub const SERIALIZABLE: bool = true; }
+//!         }
+//!     }
+//!     crabtime::output_cfg! {
+//!         "all(not(feature = \"serde\"), not(test))" => {
+//!             impl Config { pub const SERIALIZABLE: bool = false; }
+//!             const VALUE: usize = {{value}};
+//!         }
+//!     }
+//! }
+//! gen_config_impls!(1);
+//! # fn main() {
+//! #     assert!(!Config::SERIALIZABLE);
+//! #     assert_eq!(VALUE, 1);
+//! # }
+//! ```
+//!
+//! <br/>
+//!
+//! <h5><b>Building identifiers and paths</b></h5>
+//!
+//! Identifiers assembled by string concatenation are easy to get wrong. The `crabtime::ident`
+//! function joins its parts into a valid identifier: invalid characters are replaced with `_`,
+//! leading digits are prefixed with `_`, and keywords become raw identifiers. The `crabtime::path`
+//! function joins path segments with `::`, keeping path keywords like `crate` or `super` intact. If
+//! the result cannot be made valid (e.g. an empty identifier), an error is reported.
+//!
+//! ```
+//! #[crabtime::function]
+//! fn gen_getters(names: Vec<String>) {
+//!     for name in names {
+//!         let getter = crabtime::ident(["get_", &name]);
+//!         let value = format!("{name:?}");
+//!         crabtime::output! {
+//!             pub fn {{getter}}() -> &'static str { {{value}} }
+//!         }
+//!     }
+//! }
+//! gen_getters!(["first-name", "type"]);
+//! # fn main() {
+//! #     assert_eq!(get_first_name(), "first-name");
+//! #     assert_eq!(get_type(), "type");
+//! # }
+//! ```
+//!
+//! <br/>
+//!
+//! <h5><b>Converting identifier cases</b></h5>
+//!
+//! The prelude provides `crabtime::to_snake_case`, `to_shouty_snake_case`, `to_kebab_case`,
+//! `to_camel_case`, `to_pascal_case`, and `to_title_case`, so you don't need to depend on the
+//! `heck` crate. Words are split the same way `heck` does it, so `XMLHttpRequest` becomes
+//! `xml_http_request` in snake case.
+//!
+//! ```
+//! #[crabtime::function]
+//! fn gen_enum(name: String, variants: Vec<String>) {
+//!     let name = crabtime::to_pascal_case(&name);
+//!     let variants = variants.iter().map(|v| crabtime::to_pascal_case(v)).collect::<Vec<_>>();
+//!     let variants = variants.join(",");
+//!     crabtime::output! {
+//!         enum {{name}} { {{variants}} }
+//!     }
+//! }
+//! gen_enum!("http_method", ["get", "POST", "put-all"]);
+//! # fn main() {
+//! #     let _ = [HttpMethod::Get, HttpMethod::Post, HttpMethod::PutAll];
+//! # }
+//! ```
+//!
+//! <br/>
+//!
+//! <h5><b>Emitting string constants</b></h5>
+//!
+//! To emit a constant with a value computed by the macro, use `crabtime::const_str(name, value)`,
+//! which returns a `pub const` item, or `crabtime::static_bytes(name, bytes)` for byte slices. If
+//! you need just the literal, use `crabtime::escape_str` or `crabtime::escape_bytes`. Formatting
+//! the value with `{:?}` mostly works, but it follows the `Debug` rules, which escape some unicode
+//! characters differently than the Rust literal syntax.
+//!
+//! ```
+//! #[crabtime::function]
+//! fn gen_banner() -> String {
+//!     let banner = "Say \"hi\"\n\tto the 🦀";
+//!     let consts = [
+//!         crabtime::const_str("BANNER", banner),
+//!         crabtime::static_bytes("BANNER_BYTES", banner.as_bytes()),
+//!     ];
+//!     consts.join("\n")
+//! }
+//! gen_banner!();
+//! # fn main() {
+//! #     assert_eq!(BANNER, "Say \"hi\"\n\tto the 🦀");
+//! #     assert_eq!(BANNER_BYTES, BANNER.as_bytes());
+//! # }
+//! ```
+//!
+//! <br/>
+//!
+//! <h5><b>Building structured code</b></h5>
+//!
+//! If you build the output programmatically but don't want to depend on `quote` or `syn`, you can
+//! use `crabtime::Code`, a string builder which keeps track of indentation. Use `item` to add code
+//! at the current indentation, `block` to add a `header { ... }` block, and `indent` and `dedent`
+//! to change the indentation manually. A `Code` value can be returned from the macro or
+//! interpolated in `crabtime::output!`. The indentation makes the output readable when you debug
+//! it, while the compiler does not care about it.
+//!
+//! ```
+//! #[crabtime::function]
+//! fn gen_vectors(components: Vec<String>) -> crabtime::Code {
+//!     let mut code = crabtime::Code::new();
+//!     code.item("#[derive(Clone, Copy, Debug, Default)]");
+//!     code.block("pub struct Vector", |b| {
+//!         for c in &components {
+//!             b.item(format_args!("pub {c}: f32,"));
+//!         }
+//!     });
+//!     code.block("impl Vector", |b| {
+//!         b.block("pub fn dot(&self, other: &Self) -> f32", |b| {
+//!             let terms = components.iter().map(|c| format!("self.{c} * other.{c}"));
+//!             b.item(terms.collect::<Vec<_>>().join(" + "));
+//!         });
+//!     });
+//!     code
+//! }
+//! gen_vectors!(["x", "y", "z"]);
+//! # fn main() {
+//! #     let v = Vector { x: 1.0, y: 2.0, z: 3.0 };
+//! #     assert_eq!(v.dot(&v), 14.0);
+//! # }
+//! ```
+//!
+//! <br/>
+//!
+//! <h5><b>Build ids and randomness</b></h5>
+//!
+//! Macros are expected to be deterministic: the same input should always generate the same code.
+//! If you need to salt generated identifiers, use `crabtime::build_id()`, which is the same for
+//! all macros expanded within one build and different across builds, or `crabtime::random_u64()`.
+//! Calling `random_u64` marks the expansion output as non-reusable (shown as
+//! `Reusable Output: false` in the compilation stats), so a random-using macro is always evaluated
+//! again and never gets a stale output of a previous expansion. Builds are detected by a pause
+//! between expansions, like for the build summary, so builds started in quick succession may share
+//! the same id.
+//!
+//! ```
+//! #[crabtime::function]
+//! fn gen_salted() {
+//!     let salt = crabtime::random_u64() % 1000;
+//!     crabtime::output! {
+//!         const SALTED_NAME: &str = concat!("item_", {{salt}});
+//!     }
+//! }
+//! gen_salted!();
+//! # fn main() {
+//! #     assert!(SALTED_NAME.starts_with("item_"));
+//! # }
+//! ```
+//!
+//! <br/>
+//!
 //! <h5><b>Generating output by returning a string or number</b></h5>
 //!
 //! You can simply return a string or number from the function. It will be used as the generated
@@ -325,6 +573,39 @@
 //! # fn main() {}
 //! ```
 //!
+//! Returning a `TokenStream` requires `proc-macro2` 1.0 or newer. Renamed dependencies, like
+//! `pm2 = { package = "proc-macro2", version = "1" }`, are supported too, in which case the macro
+//! should return `pm2::TokenStream`.
+//!
+//! <br/>
+//!
+//! <h5><b>Generating output from JSON</b></h5>
+//!
+//! If the macro depends on `serde_json`, it can return a `serde_json::Value`, which is converted
+//! to a Rust expression: numbers, strings, and booleans become literals, arrays become array
+//! expressions, and `null` becomes `None`. Objects are not supported. If the macro depends on
+//! `serde` too, `crabtime::from_json_str` and `crabtime::to_json_string` are available for
+//! converting your own types.
+//!
+//! ```
+//! #[crabtime::expression]
+//! fn gen_ports() -> serde_json::Value {
+//!     // Inline dependencies used for brevity.
+//!     // You should use [build-dependencies] section in your Cargo.toml instead.
+//!     #![dependency(serde = { version = "1", features = ["derive"] })]
+//!     #![dependency(serde_json = "1")]
+//!     #[derive(serde::Deserialize)]
+//!     struct Service { port: u16 }
+//!     let services: Vec<Service> =
+//!         crabtime::from_json_str(r#"[{"port": 8080}, {"port": 8443}]"#);
+//!     serde_json::json!(services.iter().map(|s| s.port).collect::<Vec<_>>())
+//! }
+//!
+//! fn main() {
+//!     assert_eq!(gen_ports!(), [8080, 8443]);
+//! }
+//! ```
+//!
 //! <br/>
 //! <br/>
 //!
@@ -340,8 +621,12 @@
 //!
 //! Currently, you can use any combination of the following types as arguments to your macro and
 //! they will be automatically translated to patterns: `Vec<...>`, `&str`, `String`, and numbers.
-//! If the expected argument is a string, you can pass either a string literal or an identifier,
-//! which will automatically be converted to a string.
+//! If the expected argument is a string, you can pass either a string literal or any other
+//! expression, like an identifier, a path, or a type, which will automatically be converted to a
+//! string. String literals are passed without their quotes, and other expressions are printed as
+//! they were written, so `std::collections::HashMap::<K, V>` is not spaced out the way
+//! `stringify!` would do it. Generic types have to use the turbofish syntax, as they are parsed as
+//! expressions.
 //!
 //! ```
 //! #[crabtime::function]
@@ -360,6 +645,82 @@
 //! # fn main() {}
 //! ```
 //!
+//! `Option<...>` arguments are passed as plain values and wrapped in `Some`. If every argument is
+//! a `Vec<...>`, an `Option<...>`, or is marked with `#[default]`, the macro can also be invoked
+//! without arguments, in which case they default to an empty vector, `None`, and
+//! `Default::default()`, respectively.
+//!
+//! ```
+//! #[crabtime::function]
+//! fn gen_consts(names: Vec<String>, value: Option<usize>) {
+//!     let value = value.unwrap_or_default();
+//!     for name in names {
+//!         crabtime::output! {
+//!             pub const {{name}}: usize = {{value}};
+//!         }
+//!     }
+//! }
+//! gen_consts!(["A", "B"], 1);
+//! gen_consts!();
+//! # fn main() {}
+//! ```
+//!
+//! Integer ranges, typed `std::ops::Range<...>` or `std::ops::RangeInclusive<...>`, are passed as
+//! `start..end` and `start..=end`, where both bounds are literals. A range whose start is greater
+//! than its end is reported as an error. As argument types are used in the generated project, the
+//! range types have to be written with their full paths.
+//!
+//! ```
+//! #[crabtime::function]
+//! fn gen_tuple_sizes(sizes: std::ops::RangeInclusive<usize>) {
+//!     for size in sizes {
+//!         let params = (0..size).map(|i| format!("T{i}")).collect::<Vec<_>>().join(",");
+//!         crabtime::output! {
+//!             impl<{{params}}> TupleSize for ({{params}},) {
+//!                 const SIZE: usize = {{size}};
+//!             }
+//!         }
+//!     }
+//! }
+//!
+//! trait TupleSize {
+//!     const SIZE: usize;
+//! }
+//!
+//! gen_tuple_sizes!(1..=12);
+//! # fn main() {
+//! #     assert_eq!(<(u8, u16, u32) as TupleSize>::SIZE, 3);
+//! # }
+//! ```
+//!
+//! Call sites can customize parts of the generated code by passing a closure to an argument typed
+//! `crabtime::CodeFn`, an alias of `fn(&str) -> String`, which can also be spelled out. The closure
+//! is pasted into the generated program and bound to the argument name, so it runs in the context
+//! of the generated crate, not the crate invoking the macro. It cannot use items of the invoking
+//! crate nor capture other arguments, and a closure not matching the type is reported as a compile
+//! error of the generated project.
+//!
+//! ```
+//! #[crabtime::function]
+//! fn gen_getters(name: String, fields: Vec<String>, getter: crabtime::CodeFn) {
+//!     let getters = fields.iter().map(|field| {
+//!         let signature = getter(field);
+//!         format!("{signature} -> usize {{ self.{field} }}")
+//!     }).collect::<Vec<_>>().join("\n");
+//!     crabtime::output! {
+//!         impl {{name}} {
+//!             {{getters}}
+//!         }
+//!     }
+//! }
+//!
+//! struct User { age: usize, score: usize }
+//! gen_getters!(User, ["age", "score"], |field| format!("pub fn get_{field}(&self)"));
+//! # fn main() {
+//! #     assert_eq!(User { age: 1, score: 2 }.get_score(), 2);
+//! # }
+//! ```
+//!
 //! <br/>
 //!
 //! <h5><b>Input by using patterns</b></h5>
@@ -421,6 +782,27 @@
 //! # fn main() {}
 //! ```
 //!
+//! In both the `TokenStream` and the pattern modes, the `crabtime::ARG_COUNT` and
+//! `crabtime::ARG_STRS` constants provide the top-level comma-separated arguments of the macro
+//! call. Commas nested in parentheses, brackets, or braces do not split the arguments, so
+//! `my_macro!((1, 2), [3, 4])` has two arguments, `"(1, 2)"` and `"[3, 4]"`. The arguments are
+//! printed by the compiler, so their whitespace does not follow the whitespace of the call:
+//!
+//! ```
+//! #[crabtime::function]
+//! fn gen_arg_count(_input: TokenStream) {
+//!     #![dependency(proc-macro2 = "1")]
+//!     let count = crabtime::ARG_COUNT;
+//!     crabtime::output! {
+//!         const ARG_COUNT: usize = {{count}};
+//!     }
+//! }
+//! gen_arg_count!((1, 2), [3, 4], {5, 6});
+//! # fn main() {
+//! #     assert_eq!(ARG_COUNT, 3);
+//! # }
+//! ```
+//!
 //! <br/>
 //! <br/>
 //!
@@ -437,7 +819,7 @@
 //! |                                            | <div style="width:200px">Proc Macro</div> | <div style="width:200px">Crabtime</div>               | <div style="width:200px"><code>macro_rules!</code></div> |
 //! | :---                                       | :---                                      | :---                                                  | :---                                               |
 //! | First evaluation (incl. compilation)       | ⚠️ Relatively slow                        | ⚠️ Relatively slow                                    | ✅ Fast                                            |
-//! | Next evaluation (on call-site change)      | ✅ Fast                                   | ✅ Fast on nightly <br/> ⚠️ Relatively slow on stable |  ❌ Slow for complex transformations               |
+//! | Next evaluation (on call-site change)      | ✅ Fast                                   | ✅ Fast on nightly <br/> ✅ Fast on stable for unchanged input, ⚠️ relatively slow otherwise |  ❌ Slow for complex transformations               |
 //! | Cost after changing module code without changing macro-call site code | ✅ Zero        | ✅ Zero                                               | ✅ Zero                                            |
 //!
 //! <br/>
@@ -448,29 +830,282 @@
 //! interprets the results as the generated Rust code. When you call the macro again (for example,
 //! after changing the macro’s parameters or calling the same macro in a different place), Crabtime
 //! can reuse the previously generated project. This feature is called “caching.” It is enabled by
-//! default on the nightly channel and can be enabled on the stable channel by providing a `module`
-//! attribute, for example:
+//! default on both channels. On the stable channel, where the call site is unknown, projects are
+//! identified by the hash of their code and arguments, in `project_<hash>` directories, so an
+//! expansion with unchanged input reuses the compiled dependencies, while any change builds a new
+//! project. To reuse the project of a macro across changes on the stable channel, provide a
+//! `module` attribute, for example:
 //!
-//! ```ignore
-//! #[crabtime::function(cache_key=my_key)]
+//! ```
+//! #[crabtime::function]
 //! #[module(my_crate::my_module)]
 //! fn my_macro() {
-//!     // ...
+//!     crabtime::output! {
+//!         const MY_VALUE: usize = 1;
+//!     }
 //! }
+//! my_macro!();
+//! # fn main() { let _ = MY_VALUE; }
 //! ```
 //!
+//! The module can also be passed as the `module = my_crate::my_module` option. It replaces the
+//! call-site path in the cache path below, on both channels. Macros of the same module are kept in
+//! separate subdirectories named after them, and every set of code and arguments is built in its
+//! own `args_<hash>` subdirectory.
+//!
 //! The cache is always written to
-//! `<project_dir>/target/debug/build/crabtime/<module>/<macro_name>`. The defaults are presented
-//! below:
+//! `<project_dir>/target/debug/build/crabtime/crates/<crate>/<module>/<macro_name>`, where
+//! `<crate>` is the crate invoking the macro, so same-named macros called from files with the same
+//! paths in different crates of a workspace do not share a project. The file path is taken
+//! relative to the workspace, whether the compiler reports it as a relative or an absolute path, so
+//! Rust Analyzer and `cargo test` share the project of a macro called in `#[cfg(test)]` code. Rust
+//! Analyzer enables `cfg(test)`, so such a macro is expanded in the IDE even if only `cargo build`
+//! was run, but it uses the same project as the next `cargo test`. Because macro arguments are
+//! a part of the generated code, every set of call-site arguments is built in its own
+//! `args_<hash>` subdirectory. Thus, editing the arguments of one call site does not invalidate the
+//! builds of other call sites of the same macro. These subprojects have their own manifests and
+//! code, but share the `target` directory next to them, so the dependencies of the macro are
+//! compiled once. The subproject which served the expansion is shown in the `Subproject` line of
+//! the compilation stats. Macros called in doctests are kept in the `doctests` subdirectory of
+//! the documented file, like `src/lib/doctests/<macro_name>`, instead of directories named after
+//! their line numbers, so moving the docs around reuses their builds. If two expansions with
+//! different code still end up in one directory in a build, the expansion fails instead of overwriting the other project.
+//! Cached projects are kept in the `cached` subdirectory, while macros with `cache = false` are
+//! built in `oneshot/<unique_name>` directories removed after usage. Toggling the option removes
+//! the projects left by the other mode. Cached projects are generated from scratch after Crabtime
+//! or the compiler are updated, so they never reuse the files of the previous version.
+//! One-shot projects left by killed compiler processes, like after Ctrl-C or a canceled IDE check,
+//! are removed by the next expansion of the macro, and so are the projects of older inputs or
+//! arguments unused for 14 days. Set the `CRABTIME_MAX_PROJECT_AGE` environment variable to
+//! change the number of days. The `crabtime` build directory is also capped at 10 GB. When it
+//! grows larger, the projects of the least recently expanded macros are removed, except for the
+//! ones in use. The size is checked at most every 10 minutes. Set the `CRABTIME_CACHE_MAX_MB`
+//! environment variable to change the limit in megabytes. Parallel expansions of a cached project, like the ones of your IDE
+//! and of a terminal build, wait for each other. If the project is used for more than two minutes,
+//! the waiting expansion builds the macro in a one-shot project instead. On Windows, files of a
+//! finished build can stay open for a moment, for example, by antivirus scanners, so removing a
+//! project is retried a few times. Projects which still cannot be removed are renamed with the
+//! `.trash` suffix and removed by a later expansion.
+//! If the target directory is outside your workspace, like when it is shared between workspaces
+//! with `CARGO_TARGET_DIR` or `build.target-dir`, the projects are still kept in it, while your
+//! workspace is discovered from the manifest of the crate using the macro. The target directory
+//! can have any name, and every project is built in its own `target` subdirectory, so the nested
+//! builds never wait for the lock of the build expanding the macro.
+//! In sandboxes where the build directory is read-only or has an unknown layout, like under Nix or
+//! Bazel, set the `CRABTIME_OUT_DIR` environment variable to a writable directory. It is used
+//! verbatim instead of the `crabtime` build directory, and relative paths are resolved against the
+//! working directory. The `out_dir = "<path>"` option sets the directory for the projects of a
+//! single macro, relative to its crate, and takes precedence over the environment variable. If the
+//! macro crate was not built by cargo and its build directory does not exist at expansion time, the
+//! projects are created in the `crabtime` directory of the system temporary directory, and a
+//! warning is emitted once. Build wrappers, like cross-rs, can place the build directory of the
+//! macro crate in other layouts, so if it is not found, the projects are created in the nearest
+//! directory named `build`, then in the profile directory of the nearest directory named `target`,
+//! and finally in the temporary directory. The chosen directory is shown as `Output Root` in the
+//! compilation stats.
+//! The defaults are presented below:
 //!
 //! |                      | Rust Unstable           | Rust Stable                               |
 //! | :---                 | :---                    | :---                                      |
-//! | Cache enabled        | ✅                      | ❌ by default, ✅ when `module` used.    |
+//! | Cache enabled        | ✅                      | ✅ per input by default, per macro when `module` used. |
 //! | `module` default     | path to def-site module | __none__                                 |
 //!
-//! Please note that caching will be automatically enabled on the stable channel as soon as the
-//! [proc_macro_span][proc_macro_span] feature is stabilized. That feature allows Crabtime to read
-//! the path of the file where the macro was used, so it can build a unique cache key.
+//! Please note that caching per macro will be automatically enabled on the stable channel as soon
+//! as the [proc_macro_span][proc_macro_span] feature is stabilized. That feature allows Crabtime to
+//! read the path of the file where the macro was used, so it can build a unique cache key.
+//!
+//! You can also provide the cache key yourself with the `cache_key` option, given as an identifier
+//! or a string literal of ASCII letters, digits, `_`, and `-`. It replaces the directory name
+//! derived from the call site, so macros with the same key share one project directory in a crate,
+//! while macros with different keys never do. Every set of code and arguments is still built in
+//! its own `args_<hash>` subdirectory. The key takes precedence over the module. Like the module,
+//! it enables caching on the stable channel, while `cache = false` still wins and makes the project
+//! one-shot.
+//!
+//! ```
+//! #[crabtime::function(cache_key = shared_gen)]
+//! fn gen_shared_struct(name: String) {
+//!     crabtime::output! {
+//!         pub struct {{name}};
+//!     }
+//! }
+//! gen_shared_struct!("SharedA");
+//! gen_shared_struct!("SharedB");
+//! # fn main() { let _ = (SharedA, SharedB); }
+//! ```
+//!
+//! Even a cached project is run by every expansion, which costs a few hundred milliseconds. If
+//! your macro is a pure function of its code and arguments, the `memoize = true` option stores its
+//! output in the cached project and returns it without running the project while the code, the
+//! manifest, Crabtime, and the compiler stay the same. Such expansions are shown as
+//! `Memoization: on, reused` in the compilation stats. Leave the option off for macros reading
+//! files or environment variables, as their changes are not detected. Outputs of macros using
+//! random numbers are never memoized, and neither are the ones of one-shot projects.
+//!
+//! ```
+//! #[crabtime::function(memoize = true)]
+//! fn gen_memoized_const(value: usize) {
+//!     let square = value * value;
+//!     crabtime::output! {
+//!         const MEMOIZED_SQUARE: usize = {{square}};
+//!     }
+//! }
+//! gen_memoized_const!(3);
+//! # fn main() { assert_eq!(MEMOIZED_SQUARE, 9); }
+//! ```
+//!
+//! Macros without dependencies and Rust lints are compiled with `rustc` directly instead of
+//! `cargo build`, which saves hundreds of milliseconds per expansion, especially on stable, where
+//! projects are built from scratch. The binary is kept in the project's `target/rustc` directory
+//! and reused while the code, edition, and compiler stay the same. If `rustc` fails, the project
+//! is built with cargo, which reports the errors. Cached projects built with cargo skip it too, if
+//! the manifest, the code, the lockfile, the compiler, and `RUSTFLAGS` did not change, and none of
+//! the source files the binary was built from, like the ones of path dependencies, is newer than
+//! the binary. The binary is then run directly, and if that fails, it is built with cargo again.
+//! Changes of the settings in `.cargo/config.toml` files are not detected, so after changing
+//! them, run `cargo clean`.
+//!
+//! For tiny macros, the fixed cost of discovering your Cargo.toml, inheriting its configuration,
+//! and querying `rustc` dominates the expansion time. The `minimal = true` option skips all of
+//! them and builds the project with a bare-bones manifest, using the compiler and target the macro
+//! was built with. Minimal macros cannot have dependencies, and `CRATE_CONFIG_PATH` points to the
+//! workspace manifest. The time spent before the project is built is shown in the `Setup` line of
+//! the compilation stats.
+//!
+//! ```
+//! #[crabtime::expression(minimal = true)]
+//! fn gen_square(value: usize) -> String {
+//!     format!("{}", value * value)
+//! }
+//!
+//! # fn main() {
+//! assert_eq!(gen_square!(7), 49);
+//! # }
+//! ```
+//!
+//! ```compile_fail
+//! #[crabtime::function(minimal = true)]
+//! fn gen_with_dependency() {
+//!     #![dependency(anyhow = "1")]
+//! }
+//! gen_with_dependency!();
+//! # fn main() {}
+//! ```
+//!
+//! To check that cached projects produce the same output as fresh ones, set the
+//! `CRABTIME_VERIFY_CACHE=1` environment variable, for example, on CI. Every expansion reusing a
+//! cached project is then built again from scratch in a temporary directory, and a warning with a
+//! diff of both outputs is printed if they differ. It usually means that the macro is
+//! nondeterministic or that its cache key does not cover all of its inputs. Non-reusable outputs,
+//! like ones using `crabtime::random_u64`, are not verified. The verification doubles the expansion
+//! time, and its result is shown in the `Cache Verification` line of the compilation stats.
+//!
+//! <br/>
+//!
+//! <h5><b>Offline snapshots</b></h5>
+//!
+//! Builds without network access, like the ones of docs.rs, can't fetch the dependencies of the
+//! generated projects. To expand your macros there, build your crate with the
+//! `CRABTIME_SNAPSHOT_DIR=crabtime-snapshots` environment variable first. The output of every
+//! expansion is then written to the directory, relative to your crate, in a file named after the
+//! macro and the hash of its input, which you commit with your crate. Builds with
+//! `CRABTIME_USE_SNAPSHOTS=1` read the outputs from the snapshots instead of building the
+//! projects. Offline builds, with `CARGO_NET_OFFLINE=true` or on docs.rs, read them too, but build
+//! the macros without snapshots as usual. If the input of a macro changed since its snapshots were
+//! written, the expansion fails, asking you to regenerate them. The `crabtime-snapshots` directory
+//! is used if `CRABTIME_SNAPSHOT_DIR` is not set, and the `Snapshot` line of the compilation stats
+//! shows whether the snapshot was read or written.
+//!
+//! <br/>
+//!
+//! <h5><b>Caching expensive phases</b></h5>
+//!
+//! The project cache does not help if the macro code changes, as every change requires evaluating
+//! the macro again. If your macro has an expensive phase, like parsing a large schema, followed by
+//! a cheap one which you change often, you can cache the result of the expensive phase with
+//! `crabtime::phase_cache!(key, [input_files], || phase())`. The list of input files is optional.
+//! The result is serialized to JSON, so the macro has to depend on both `serde` and `serde_json`,
+//! and the result type has to implement `Serialize` and `Deserialize`.
+//!
+//! ```
+//! #[crabtime::function]
+//! fn gen_tables() {
+//!     // Inline dependencies used for brevity.
+//!     // You should use [build-dependencies] section in your Cargo.toml instead.
+//!     #![dependency(serde = "1")]
+//!     #![dependency(serde_json = "1")]
+//!     let schema = format!("{}/Cargo.toml", crabtime::WORKSPACE_PATH);
+//!     let tables: Vec<String> = crabtime::phase_cache!("tables-v1", [schema], || {
+//!         // Slow parsing of the schema.
+//!         vec!["users".to_string(), "posts".to_string()]
+//!     });
+//!     for table in tables {
+//!         let name = crabtime::to_pascal_case(&table);
+//!         crabtime::output! {
+//!             pub struct {{name}};
+//!         }
+//!     }
+//! }
+//! gen_tables!();
+//! # fn main() {}
+//! ```
+//!
+//! Results are stored in `target/<profile>/build/crabtime/phase_cache`, one file per key, and are
+//! shared by all macros. A stored result is used only if all of the following hold:
+//!
+//! - The key is the same. **Changing the code of the phase does not invalidate its result**, so
+//!   include a version in the key, like `"schema-v2"`, and bump it when you change the phase.
+//! - The content of every input file is the same. Missing files are allowed and are considered
+//!   changed when they appear.
+//! - The result type is the same.
+//!
+//! Otherwise, and after `cargo clean`, the phase is evaluated again and its result is stored for
+//! subsequent expansions.
+//!
+//! <br/>
+//!
+//! <h5><b>Deduplicating expansions</b></h5>
+//!
+//! If a macro is invoked with the same arguments at many call sites, for example, in several
+//! modules or crates of a workspace, every call site defines the same items again, which costs
+//! compile time and can cause conflicting implementations when the items meet. With the
+//! `dedup = true` option, only the first call site of the build emits the generated code. Later
+//! call sites generating exactly the same code expand to an empty marker constant documenting the
+//! first call site. If you provide the `dedup_path` option, they also re-export the named items
+//! from the given module with `pub use`:
+//!
+//! ```
+//! #[crabtime::function(dedup = true, dedup_path = "crate::first")]
+//! fn gen_shared_items() {
+//!     crabtime::output! {
+//!         pub struct Shared;
+//!         impl Shared {
+//!             pub const ID: usize = 7;
+//!         }
+//!     }
+//! }
+//! pub mod first { gen_shared_items!(); }
+//! pub mod second { gen_shared_items!(); }
+//! # fn main() {
+//! #     assert_eq!(second::Shared::ID, 7);
+//! # }
+//! ```
+//!
+//! The default behavior is unchanged, and deduplication has limits you should be aware of:
+//!
+//! - Only the first expansion emits the code, and the order of expansions is the order in which
+//!   the compiler expands them. Within a crate, it is the source order. Across crates, it depends
+//!   on the build order, so use `dedup_path` pointing to a crate all other crates depend on.
+//! - Re-exports cover named items only. Implementations, macros, and `use` items are not
+//!   re-exported, and items must be visible at the re-exporting call site.
+//! - Only outputs which are lists of items are deduplicated. Expansions are tracked per build
+//!   (expansions separated by less than 30 seconds), and call sites are identified by the crate
+//!   and the order of expansion, so reordering call sites within 30 seconds of the last build may
+//!   move the emitted code to another call site until the next build.
+//! - Rust Analyzer expands call sites in any order, so it shows the full output at every call
+//!   site.
+//!
+//! The result is shown in the `Dedup` line of the compilation stats.
 //!
 //! <br/>
 //!
@@ -485,16 +1120,118 @@
 //! # Compilation Stats
 //! Start: 13:17:09 (825)
 //! Duration: 0.35 s
+//! Setup: 0.01 s
 //! Cached: true
-//! Output Dir: /Users/crabtime_user/my_project/target/debug/build/crabtime/macro_path
+//! Project: cached, shared per macro, argument-specific build
+//! Subproject: args_4ed63e19bcdc95bb861c2f7a2fe11dff, sharing target '/Users/crabtime_user/my_project/target/debug/build/crabtime/crates/my_project/src/lib/my_macro/target'
+//! Output Size: 1.27 KB
+//! Reusable Output: true
+//! Jobs: default
+//! Profile: debug
+//! Memoization: off
+//! Cache Verification: off
+//! Dedup: off
+//! Snapshot: off
+//! Inputs: 1 files, 4 env vars
+//! Output Dir: /Users/crabtime_user/my_project/target/debug/build/crabtime/crates/my_project/src/lib/my_macro/args_4ed63e19bcdc95bb861c2f7a2fe11dff/cached
+//! Output Root: /Users/crabtime_user/my_project/target/debug/build/crabtime (cargo build directory)
 //! Macro Options: MacroOptions {
 //!     cache: true,
 //!     content_base_name: false,
+//!     cache_key: None,
+//!     module: None,
+//!     memoize: false,
+//!     out_dir: None,
+//!     max_output: None,
+//!     max_output_warn: None,
+//!     provenance_doc: true,
+//!     provenance_const: false,
+//!     jobs: None,
+//!     retries: 1,
+//!     timeout_secs: None,
+//!     forward_stderr: true,
+//!     ide_compat: Auto,
+//!     dedup: false,
+//!     dedup_path: None,
+//!     passthrough: [],
+//!     lockfile: true,
+//!     profile: Debug,
+//!     minimal: false,
 //! }
 //! ```
 //!
 //! Please note that you can be presented with the `Cached: true` result even after the first
-//! macro evaluation if your IDE or build system evaluated it earlier in the background.
+//! macro evaluation if your IDE or build system evaluated it earlier in the background. If the
+//! manifest and the code of the cached project did not change, its files are not written at all,
+//! so cargo does not need to check them again, and the result is `Cached: true (unchanged)`.
+//!
+//! Build systems wrapping cargo can find the external inputs of every expansion in
+//! `target/<profile>/build/crabtime/metadata/<macro>-<hash>.json`, replaced atomically after each
+//! expansion. It lists the files read, like your Cargo.toml, with hashes of their contents, the
+//! environment variables read, with their values (`null` if not set), and the fingerprint of the
+//! generated project. The expansion needs to be repeated if any of them changes:
+//!
+//! ```json
+//! {
+//!   "macro": "my_macro",
+//!   "inputs": {
+//!     "files": [
+//!       { "path": "/my_project/Cargo.toml", "hash": "4b2d9e6c1a0f3e57" }
+//!     ],
+//!     "env": [
+//!       { "name": "CRABTIME_JOBS", "value": null }
+//!     ],
+//!     "config_fingerprint": "9c0e1f7a3b5d2c84"
+//!   }
+//! }
+//! ```
+//!
+//! Moreover, every top-level item generated by a Crabtime macro gets a doc attribute naming the
+//! macro and its call site location, so IDE hovers tell you where an item comes from. You can
+//! disable it with the `provenance_doc = false` option.
+//!
+//! Items which cannot be documented, like macro invocations, are not marked this way. With the
+//! `provenance_const = true` option, Crabtime also prepends an unused
+//! `__CRABTIME_PROVENANCE_<hash>` constant with the macro name and call site location to each
+//! expansion generating items, so the source macro is visible in `cargo expand` and IDE previews
+//! of the whole expansion. Expansions in expression position are left unchanged.
+//!
+//! Stats of separate expansions do not tell you how much your whole build spent in Crabtime, so
+//! Crabtime also reports a build summary, like:
+//!
+//! ```text
+//! note: crabtime: 25 expansions, 3 rebuilt, total 4.20 s, slowest: gen_router 1.90 s
+//! ```
+//!
+//! Proc macros are not notified when the build ends, so every expansion records its stats in the
+//! `target/<profile>/build/crabtime/build_stats` file, and the first expansion of the next build
+//! reports the summary of the previous one. Expansions more than 30 seconds apart are treated as
+//! separate builds, so the summary is approximate when builds overlap or pause for a long time.
+//!
+//! <br/>
+//!
+//! <h5><b>Output Size Limits</b></h5>
+//!
+//! A bug in a macro can easily make it generate enormous amounts of code, which `rustc` would then
+//! spend minutes processing. To fail fast, Crabtime tracks the size of the generated code. It
+//! prints a warning when the output exceeds 10 MB (configurable with the `max_output_warn`
+//! option), and reports an error naming the macro when the output exceeds the `max_output` limit.
+//! There is no hard limit by default, but you can set one for all macros with the
+//! `CRABTIME_MAX_OUTPUT` environment variable. Both options are expressed in bytes, and the
+//! `max_output` option takes precedence over the environment variable.
+//!
+//! ```compile_fail
+//! #[crabtime::function(max_output = 1024)]
+//! fn gen_too_much() {
+//!     for ix in 0 .. 10_000 {
+//!         crabtime::output! {
+//!             pub const C{{ix}}: usize = {{ix}};
+//!         }
+//!     }
+//! }
+//! gen_too_much!();
+//! # fn main() {}
+//! ```
 //!
 //! <br/>
 //! <br/>
@@ -506,23 +1243,61 @@
 //! currently a nightly-only feature, Crabtime prints nicer warnings and errors if you are using
 //! nightly Rust channel. They look just like warnings and errors from the Rust compiler.
 //! Otherwise, your warnings and errors will be printed to the console with a `[WARNING]` or
-//! `[ERROR]` prefix.
+//! `[ERROR]` prefix. Warnings reported by Crabtime itself also include the source location they
+//! refer to, like `[WARNING] src/main.rs:3:19: ...`.
 //!
 //! | Method               | Behavior on stable | Behavior on nightly |
 //! | :---                 | :---               | :---                |
 //! | `println!`           | Debug log in console | Debug log in console |
 //! | `crabtime::warning!` | Debug log in console | Warning in console   |
 //! | `crabtime::error!`   | Debug log in console | Error in console     |
+//! | `crabtime::notice!`  | Debug log in console, once per build | Warning in console, once per build |
 //!
-//! <br/>
-//!
-//! <h5><b>Stdout Protocol</b></h5>
+//! Use `crabtime::warning!` for problems of a particular call site, as it is reported on every
+//! expansion. Use `crabtime::notice!` for messages meant for the user of your macro as a whole,
+//! like deprecations of generation options. A notice is reported once per build for every macro
+//! and message, no matter how many call sites or crates expand the macro. Marking the generated
+//! items as deprecated is done in the output itself:
 //!
-//! Please note that Crabtime uses stdout for all communication between the code generation process
-//! and the host process. Depending on the prefix of each stdout line, it is interpreted according
-//! to the following table. In particular, instead of using the methods shown above, you can
-//! generate code from your macros by printing it to stdout (like
-//! `println!("[OUTPUT] struct T {}")`), but it's highly discouraged.
+//! ```
+//! #[crabtime::function]
+//! fn gen_getter(name: String, version: usize) {
+//!     let deprecated = if version < 2 {
+//!         crabtime::notice!("Version 1 of 'gen_getter!' is deprecated.");
+//!         "#[deprecated(note = \"Generated with the deprecated version 1.\")]"
+//!     } else {
+//!         ""
+//!     };
+//!     crabtime::output! {
+//!         {{deprecated}}
+//!         pub fn {{name}}() -> usize { 0 }
+//!     }
+//! }
+//! gen_getter!("get_a", 2);
+//! # fn main() {}
+//! ```
+//!
+//! The generated project is built with `cargo build`, and its binary is run separately, so the
+//! output of cargo never mixes with the output of the macro. The warnings and errors of the
+//! compiler building the project are reported as warnings and errors of the macro, with their
+//! usual colored rendering pointing to the generated code.
+//!
+//! If the generated project fails to compile or panics, the error ends with a line like
+//! `Reproduce: cd <dir> && env -u RUSTC_WRAPPER TMPDIR=<dir> cargo build --target <triple> ...`.
+//! It is the exact command Crabtime ran, including the environment variables it set or removed,
+//! so you can paste it into a terminal to debug the generated code. If the binary fails, the line
+//! runs the binary instead. The line uses POSIX shell quoting, so on Windows, run it in a POSIX
+//! shell, like Git Bash.
+//!
+//! <br/>
+//!
+//! <h5><b>Stdout Protocol</b></h5>
+//!
+//! Please note that Crabtime uses stdout for all communication between the code generation process
+//! and the host process. Depending on the prefix of each stdout line, it is interpreted according
+//! to the following table. In particular, instead of using the methods shown above, you can
+//! generate code from your macros by printing it to stdout (like
+//! `println!("[OUTPUT] struct T {}")`), but it's highly discouraged.
 //!
 //! | Prefix      | Meaning |
 //! | :---        | :---    |
@@ -530,6 +1305,18 @@
 //! | `[OUTPUT]`  | A line of generated Rust code to be included in the final macro output. |
 //! | `[WARNING]` | A compilation warning. |
 //! | `[ERROR]`   | A compilation error. |
+//! | `[NOTICE]`  | A compilation warning, reported once per build for every macro and message. |
+//!
+//! Debug log messages are printed to the compiler console as soon as the macro prints them, so
+//! progress messages of long-running macros are visible while they run. The other lines are
+//! interpreted after the macro finishes, in the order they were printed.
+//!
+//! The stderr of the macro, like `eprintln!` messages or logs of `env_logger`, is printed to the
+//! compiler console after a successful run too, with every line prefixed with the macro name, like
+//! `[crabtime:gen_positions] reading positions.csv`. The status lines and compiler messages of the
+//! nested cargo are skipped. You can silence the stderr of noisy macros with the `forward_stderr`
+//! option, like `#[crabtime::function(forward_stderr = false)]`. If the run fails, the stderr is
+//! always shown.
 //!
 //! <br/>
 //!
@@ -561,6 +1348,11 @@
 //!         // Adds `[ERROR]` to each line of the input string.
 //!         # panic!()
 //!     }
+//!
+//!     fn prefix_lines_with_notice(input: &str) -> String {
+//!         // Adds `[NOTICE]` to each line of the input string.
+//!         # panic!()
+//!     }
 //! }
 //! ```
 //!
@@ -584,6 +1376,11 @@
 //!         // On the stable channel prints a log prefixed with `[ERROR]`.
 //!         # () => {};
 //!     }
+//!
+//!     macro_rules! notice {
+//!         // Prints a line prefixed with `[NOTICE]`, reported like a warning once per build.
+//!         # () => {};
+//!     }
 //! }
 //! ```
 //!
@@ -594,12 +1391,19 @@
 //!
 //! <div style="background-color:#397be440; padding: 8px; border-radius: 8px; margin-bottom: 8px;">
 //! 💡 On the Rust unstable channel, all configuration is automatically gathered from your
-//! Cargo.toml. It includes build-dependencies and code lints, including those defined in your
-//! workspace.
+//! Cargo.toml. It includes build-dependencies, `[patch]` sections, and code lints, including
+//! those defined in your workspace.
 //! </div>
 //!
 //! Every Crabtime macro is a separate Cargo project with its own configuration and dependencies.
-//! If you use nightly, Crabtime automatically uses your Cargo.toml configuration. On stable, due
+//! If you use nightly, Crabtime automatically uses your Cargo.toml configuration. It is the nearest
+//! Cargo.toml above the macro call site with a `[package]` table, so virtual manifests of nested
+//! directories, like the ones keeping examples out of a workspace, are skipped. Like cargo,
+//! Crabtime inherits the configuration of the nearest workspace listing your crate in its
+//! `members`, so workspaces nested in larger ones with `exclude` are supported. The same
+//! configuration is used for macros called from integration tests, examples, and benches of your
+//! crate, and for macros called from its build script, with `crabtime` in the
+//! `[build-dependencies]`. On stable, due
 //! to lack of [proc_macro_span][proc_macro_span] stabilization, Crabtime cannot discover your
 //! Cargo.toml automatically. You must provide cargo configuration in your macro blocks, for
 //! example:
@@ -626,11 +1430,225 @@
 //!
 //! <h5><b>Supported Cargo Configuration Attributes</b></h5>
 //!
-//! | Attribute             | Default |
-//! | :---                  | :---    |
-//! | `#![edition(...)]`    | 2024    |
-//! | `#![resolver(...)]`   | 3       |
-//! | `#![dependency(...)]` | []      |
+//! | Attribute                 | Default |
+//! | :---                      | :---    |
+//! | `#![package_name(...)]`   | macro name |
+//! | `#![edition(...)]`        | 2024    |
+//! | `#![output_edition(...)]` | none    |
+//! | `#![resolver(...)]`       | 3       |
+//! | `#![dependency(...)]`     | []      |
+//! | `#![clippy_lints(...)]`   | true    |
+//! | `#![inherit_lints(...)]`  | true    |
+//! | `#![lints(...)]`          | none    |
+//! | `#![profile(...)]`        | debug   |
+//! | `#![profile_settings(...)]` | none  |
+//!
+//! The edition must be one of 2015, 2018, 2021, and 2024. If the resolver is not set, the default
+//! one of the edition is used, like cargo does: 3 for edition 2024, 2 for editions 2021 and 2018,
+//! and 1 for edition 2015. In edition 2015, the dependencies are declared with `extern crate` in
+//! the generated project, so you can use them like in later editions.
+//!
+//! The generated package is named after the macro, so the build output and panic messages of
+//! different macros can be told apart. Use `#![package_name("gen_positions")]` to name it
+//! differently. Characters which cannot be used in identifiers are replaced with underscores.
+//!
+//! Other inner attributes of the macro body, like `#![feature(iter_intersperse)]` or
+//! `#![recursion_limit = "256"]`, are placed at the top of the generated crate, so crate-level
+//! attributes work as in your own crates. Unstable features require the nightly channel.
+//!
+//! Dependencies can be specified with the inline table syntax of Cargo.toml, including
+//! `features`, `default-features`, and `optional`, for example,
+//! `#![dependency(serde = { version = "1", features = ["derive"], default-features = false })]`.
+//! The specification is validated as TOML, and errors are reported at the attribute.
+//!
+//! Git dependencies, like
+//! `#![dependency(my_parser = { git = "https://github.com/me/my-parser", branch = "main" })]`,
+//! are supported with the `branch`, `tag`, and `rev` keys, both inline and in your
+//! `[build-dependencies]`. If cargo fails to fetch one, its error is reported at the attribute.
+//!
+//! Dependencies from alternative registries, like
+//! `#![dependency(corp-utils = { version = "1", registry = "internal" })]`, use the
+//! `[registries.internal]` section of the `.cargo/config.toml` files of your crate and its parent
+//! directories, or of your Cargo home, the way cargo would when building your crate. The config
+//! file defining the registry is passed to the macro project, as it may not be in its parent
+//! directories, for example, if it belongs to a workspace member or `CARGO_TARGET_DIR` is set.
+//! Credentials are read by cargo from your Cargo home. A registry which is not configured, nor set
+//! with the `CARGO_REGISTRIES_<NAME>_INDEX` environment variable, is reported at the attribute.
+//!
+//! Build-dependencies declared with `workspace = true` are taken from the
+//! `[workspace.dependencies]` section of your workspace manifest, including ones declared as
+//! tables, like `tokio = { version = "1", features = ["rt"] }`. As in cargo, the `features` listed
+//! in your crate extend the workspace ones, and `optional` is taken from your crate.
+//!
+//! Target-specific build-dependencies, like `[target.'cfg(windows)'.build-dependencies]`, are
+//! included if they apply to the host, which macro projects are built for. Target triples and the
+//! `windows`, `unix`, and `target_family` cfg predicates, combined with `not`, `all`, and `any`,
+//! are supported. Tables with other predicates are skipped with a warning. A dependency declared
+//! in `[build-dependencies]` too is taken from there.
+//!
+//! The `[patch]` sections of your crate and workspace manifests are copied to the macro project,
+//! with the crate ones taking precedence, so macros compile against the same patched crates as
+//! the rest of your workspace. Their relative paths are resolved like the ones of dependencies.
+//!
+//! The `Cargo.lock` of your workspace is copied to the macro project, so its dependencies resolve
+//! to the versions pinned in your workspace instead of the newest compatible ones. Cargo removes
+//! the entries the macro does not use. Use the `lockfile = false` option, for example,
+//! `#[crabtime::function(lockfile = false)]`, to resolve the newest versions instead.
+//!
+//! Relative `path` dependencies, like `helpers = { path = "../helpers" }`, are resolved against
+//! the directory of your crate's Cargo.toml, as the macro project is generated in the `target`
+//! directory. On stable, where your Cargo.toml is not discovered, they are resolved against the
+//! workspace directory (see `WORKSPACE_PATH`).
+//!
+//! Dependency versions can be written without quotes, like `#![dependency(anyhow = 1)]`. Version
+//! requirements are validated before the project is generated, so a typo like
+//! `#![dependency(anyhow = "1.0.x.y")]` is reported at the attribute:
+//!
+//! ```compile_fail
+//! #[crabtime::function]
+//! fn my_macro() {
+//!     #![dependency(anyhow = "1.0.x.y")]
+//! }
+//! my_macro!();
+//! # fn main() {}
+//! ```
+//!
+//! The `edition` attribute applies to the code of your macro only. If the crate using the macro
+//! has an older or newer edition, set it with `#![output_edition(2018)]`. It does not change the
+//! generated project, but Crabtime then warns about identifiers of the generated code which are
+//! keywords in that edition, like `gen` in edition 2024 or `try` in edition 2018, and suggests
+//! using raw identifiers instead. Only obvious cases are detected, and the full validation
+//! happens when your crate compiles the generated code.
+//!
+//! ```
+//! #[crabtime::function]
+//! fn gen_generators() {
+//!     #![output_edition(2024)]
+//!     crabtime::output! {
+//!         pub fn r#gen() -> usize { 0 }
+//!     }
+//! }
+//! gen_generators!();
+//! # fn main() {}
+//! ```
+//!
+//! On nightly, the `[lints.clippy]` table of your Cargo.toml is copied to the generated projects.
+//! Lints inherited with `[lints] workspace = true` come from your `[workspace.lints]` section, and
+//! their levels and priorities, like `all = { level = "warn", priority = -1 }`, are preserved.
+//! Clippy is not run on them, as Crabtime removes the `RUSTC_WRAPPER` and `RUSTC_WORKSPACE_WRAPPER`
+//! environment variables set by `cargo clippy` from the nested build. If you run Clippy on the
+//! generated projects yourself, you can opt out of your lints with `#![clippy_lints(false)]`, as
+//! scratch code rarely meets the standards of your codebase.
+//!
+//! Strict Rust lints, like `missing_docs = "deny"`, apply to the generated projects too. Use
+//! `#![inherit_lints(false)]` to drop all the inherited lints of a macro, or override some of them
+//! with `#![lints(...)]`, which takes the lint names, with the `clippy::` prefix for Clippy lints,
+//! and the levels in the Cargo.toml format. The overrides take precedence over the inherited
+//! lints, regardless of the order of the attributes.
+//!
+//! ```
+//! #[crabtime::function]
+//! fn gen_scratch() {
+//!     #![lints(missing_docs = "allow", clippy::pedantic = { level = "allow", priority = -1 })]
+//!     crabtime::output! {
+//!         pub struct Scratch;
+//!     }
+//! }
+//! gen_scratch!();
+//! # fn main() {}
+//! ```
+//!
+//! Macros are built in debug mode, which compiles fast. Macros doing heavy computations, like
+//! precomputing lookup tables, can run many times faster when built in release mode. Use the
+//! `profile = "release"` option, like `#[crabtime::function(profile = "release")]`, or the
+//! `#![profile(release)]` attribute, which takes precedence. The artifacts of both profiles are
+//! kept separately, so toggling the option does not invalidate the cached build of the other one.
+//! The profile used is shown in the `Profile` line of the compilation stats.
+//!
+//! To tune the profile instead of switching it, use the `#![profile_settings(...)]` attribute,
+//! which takes the settings of the Cargo.toml `[profile]` section, with underscores in place of
+//! dashes, like `#![profile_settings(opt_level = 3, debug = false, lto = "thin")]`. The settings
+//! are applied to the profile the macro is built with, and changing them rebuilds the project.
+//!
+//! ```
+//! #[crabtime::function]
+//! fn gen_squares() {
+//!     #![profile_settings(opt_level = 3, debug_assertions = false)]
+//!     let squares = (0..16_u64).map(|t| t * t).collect::<Vec<_>>();
+//!     let squares = squares.iter().map(|t| t.to_string()).collect::<Vec<_>>().join(", ");
+//!     crabtime::output! {
+//!         const SQUARES: [u64; 16] = [{{squares}}];
+//!     }
+//! }
+//! gen_squares!();
+//! # fn main() {}
+//! ```
+//!
+//! <br/>
+//!
+//! <h5><b>Nested Cargo Flags</b></h5>
+//!
+//! The generated projects are built by a nested `cargo` invocation, which inherits the environment
+//! of your build. Cargo configuration provided through environment variables, like
+//! `CARGO_NET_OFFLINE`, is thus applied to the nested build automatically. Command-line flags, like
+//! `--locked`, are not visible to Crabtime, so you need to provide them with the
+//! `CRABTIME_CARGO_FLAGS` environment variable, for example
+//! `CRABTIME_CARGO_FLAGS="--locked" cargo build --locked`. Only the following flags are allowed, as
+//! other flags could change where or what the nested cargo builds:
+//!
+//! | Flag                       | Meaning |
+//! | :---                       | :---    |
+//! | `--locked`                 | Require the generated project's `Cargo.lock` to be up-to-date. |
+//! | `--frozen`                 | Equivalent to `--locked` and `--offline`. |
+//! | `--offline`                | Run without accessing the network. |
+//! | `--quiet`, `-q`            | Do not print cargo log messages. |
+//! | `--verbose`, `-v`, `-vv`   | Use verbose cargo output. |
+//!
+//! The nested build uses the cargo and rustc binaries set in the `CARGO` and `RUSTC` environment
+//! variables, which cargo sets so that nested invocations use the same toolchain as your build.
+//! This matters under rustup proxies, custom toolchains, and build systems providing their own
+//! toolchains, where the binaries in `PATH` may be different ones or missing. If the variables are
+//! not set, `cargo` and `rustc` are looked up in `PATH`. Errors about running them name the binary
+//! which was invoked.
+//!
+//! The nested cargo builds in parallel by default, competing for CPU with your build. You can
+//! limit its number of parallel jobs with the `jobs` option, like `#[crabtime::function(jobs = 2)]`,
+//! or for all macros with the `CRABTIME_JOBS` environment variable. If neither is provided, the
+//! `CARGO_BUILD_JOBS` environment variable is used. The number of jobs is shown in the
+//! compilation stats.
+//!
+//! Network hiccups and registry index lock contention occasionally fail the nested build even
+//! though nothing is wrong with the macro. Such failures are recognized by their error messages,
+//! like `failed to download` or `Timeout was reached`, and the nested build is retried once, after
+//! a warning and a one-second delay. You can change the number of retries with the `retries`
+//! option, like `#[crabtime::function(retries = 3)]`, where every next retry waits twice as long,
+//! or disable them with `retries = 0`. Compilation errors and panics of the macro are never
+//! retried.
+//!
+//! A macro stuck in an infinite loop would otherwise stall your build forever. You can limit the
+//! time of compiling and running the macro with the `timeout_secs` option, like
+//! `#[crabtime::function(timeout_secs = 60)]`, or for all macros with the `CRABTIME_TIMEOUT_SECS`
+//! environment variable. When the limit is exceeded, the nested build is killed, the debug output
+//! the macro printed so far is shown, and the expansion fails with an error naming the macro, the
+//! elapsed time, and the project directory, where you can reproduce the run with `cargo run`. The
+//! option takes precedence over the environment variable, and `timeout_secs = 0` disables the
+//! limit. There is no limit by default.
+//!
+//! If the expansion is interrupted, for example, when you press Ctrl-C during `cargo build`, the
+//! nested build is killed too, so it does not hold the build directory lock and stall your next
+//! build. On Unix, the nested cargo and the compiler processes it spawns run in their own process
+//! group, which is killed within a second after the expansion process exits. On other platforms,
+//! only the nested cargo is killed, and only if the expansion unwinds, not if its process is
+//! killed.
+//!
+//! <br/>
+//!
+//! <h5><b>Working Directory</b></h5>
+//!
+//! The macro body runs with its working directory set to the `run` subdirectory of the generated
+//! project and with `TMPDIR` pointing to its `tmp` subdirectory. Both are emptied before every
+//! run, so files created by the macro, or by tools it spawns, never end up in the project itself.
+//! If the macro creates files in the project directory anyway, a warning is reported.
 //!
 //! <br/>
 //! <br/>
@@ -698,6 +1716,9 @@
 //! # fn main() {}
 //! ```
 //!
+//! Helpers your code does not mention, like `sum_combinations` or the case converters, are left
+//! out of the `crabtime` module to keep the generated project small.
+//!
 //! The `output!` macro is essentially a shortcut for writing to output buffer using `format!`, so
 //! this:
 //!
@@ -777,10 +1798,10 @@
 //!
 //! # ⚠️ Corner Cases
 //! There are a few things you should be aware of when using Crabtime:
-//! - Caching is associated with the current file path. It means that if in a single file you have
-//!   multiple Crabtime macros of the same name (e.g. by putting them in different modules within a
-//!   single file), they will use the same Rust project under the hood, which effectively breaks
-//!   the whole purpose of caching.
+//! - Caching is associated with the current file path. If in a single file you have multiple
+//!   Crabtime macros of the same name (e.g. by putting them in different modules within a single
+//!   file), they share the macro directory, but every macro body is built in its own
+//!   `args_<hash>` subproject, so they are cached independently and do not overwrite each other.
 //! - You can't use Crabtime functions to generate consts. Instead, use `Crabtime::eval!` as shown
 //!   above. This is because when expanding constants, macros need to produce an additional pair of
 //!   `{` and `}` around the expanded tokens. If anyone knows how to improve this, please contact
@@ -803,6 +1824,12 @@
 //! `crabtime::output_str!` syntax described above. If you encounter this, please
 //! [open an issue](https://github.com/wdanilo/eval-macro/issues) to let us know!
 //!
+//! `RustRover` provides incorrect token spans, so Crabtime pads keywords in `crabtime::output!`
+//! and `crabtime::quote!` with spaces when the spans look incorrect. The padding changes spacing
+//! of the generated code, like `{{name}}for` becoming `{name} for`, so you can control it with
+//! the `ide_compat` option: `"auto"` (the default) pads only when needed, `"intellij"` always pads,
+//! and `"none"` never does, for example, `#[crabtime::function(ide_compat = "none")]`.
+//!
 //! [zigs_comptime]: https://zig.guide/language-basics/comptime
 //! [token_stream]: https://doc.rust-lang.org/proc_macro/struct.TokenStream.html
 //! [macro_fragments]: https://doc.rust-lang.org/reference/macros-by-example.html#metavariables
@@ -854,7 +1881,29 @@ macro_rules! output {
 /// AVAILABLE ONLY WITHIN THE CRABTIME MACRO.
 #[macro_export]
 macro_rules! quote {
-    ($($ts:tt)*) => { String::new() };
+    ($($ts:tt)*) => { $crate::Snippet::default() };
+}
+
+/// AVAILABLE ONLY WITHIN THE CRABTIME MACRO.
+#[macro_export]
+macro_rules! output_macro_rules {
+    ($($ts:tt)*) => {};
+}
+
+/// AVAILABLE ONLY WITHIN THE CRABTIME MACRO.
+#[macro_export]
+macro_rules! output_cfg {
+    ($($ts:tt)*) => {};
+}
+
+/// AVAILABLE ONLY WITHIN THE CRABTIME MACRO.
+///
+/// Caches the result of an expensive phase of the macro across expansions, even if the macro code
+/// changes. Available if the macro depends on both `serde` and `serde_json`.
+#[macro_export]
+macro_rules! phase_cache {
+    ($key:expr, [$($input:expr),* $(,)?], $f:expr $(,)?) => { ($f)() };
+    ($key:expr, $f:expr $(,)?) => { ($f)() };
 }
 
 /// AVAILABLE ONLY WITHIN THE CRABTIME MACRO.
@@ -883,61 +1932,1246 @@ pub fn sum_combinations(_n: usize) -> Vec<Vec<usize>> {
     panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
 }
 
-pub const WORKSPACE_PATH: &str = "AVAILABLE ONLY WITHIN THE CRABTIME MACRO.";
-pub const CRATE_CONFIG_PATH: &str = "AVAILABLE ONLY WITHIN THE CRABTIME MACRO.";
-pub const CALL_SITE_FILE_PATH: &str = "AVAILABLE ONLY WITHIN THE CRABTIME MACRO.";
+/// AVAILABLE ONLY WITHIN THE CRABTIME MACRO.
+///
+/// Joins the parts into a valid identifier. Invalid characters are replaced with `_`, leading
+/// digits are prefixed with `_`, and keywords are turned into raw identifiers, including the ones
+/// reserved by the edition of the crate, like `gen` in edition 2024. For example, `ident(["fn"])`
+/// returns `r#fn` and `ident(["1st", "-item"])` returns `_1st_item`.
+#[cfg(feature = "std")]
+#[allow(clippy::panic)]
+pub fn ident<T: AsRef<str>>(_parts: impl IntoIterator<Item = T>) -> String {
+    panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
+}
 
-// =============
-// === Tests ===
-// =============
+/// AVAILABLE ONLY WITHIN THE CRABTIME MACRO.
+///
+/// Joins the segments with `::`, turning every segment into a valid identifier with [`ident`].
+/// Path keywords like `crate`, `self`, and `super` are kept as they are. For example,
+/// `path(["crate", "util::fn"])` returns `crate::util::r#fn`.
+#[cfg(feature = "std")]
+#[allow(clippy::panic)]
+pub fn path<T: AsRef<str>>(_segments: impl IntoIterator<Item = T>) -> String {
+    panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
+}
 
-/// Most of the tests are included in the documentation above. These tests cover corner cases to
-/// ensure that the macro works as expected.
-#[cfg(all(test, feature = "std"))]
-mod tests {
-    #[test]
-    fn empty_def_compilation() {
-        #[crabtime::function]
-        fn empty_def_compilation() {}
-        empty_def_compilation!();
+/// AVAILABLE ONLY WITHIN THE CRABTIME MACRO.
+///
+/// Converts the string to `snake_case`, splitting words like the `heck` crate does. For example,
+/// `to_snake_case("XMLHttpRequest")` returns `xml_http_request`.
+#[cfg(feature = "std")]
+#[allow(clippy::panic)]
+pub fn to_snake_case(_s: &str) -> String {
+    panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
+}
+
+/// AVAILABLE ONLY WITHIN THE CRABTIME MACRO.
+///
+/// Converts the string to `SHOUTY_SNAKE_CASE`. For example, `to_shouty_snake_case("maxSize")`
+/// returns `MAX_SIZE`.
+#[cfg(feature = "std")]
+#[allow(clippy::panic)]
+pub fn to_shouty_snake_case(_s: &str) -> String {
+    panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
+}
+
+/// AVAILABLE ONLY WITHIN THE CRABTIME MACRO.
+///
+/// Converts the string to `kebab-case`. For example, `to_kebab_case("MaxSize")` returns
+/// `max-size`.
+#[cfg(feature = "std")]
+#[allow(clippy::panic)]
+pub fn to_kebab_case(_s: &str) -> String {
+    panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
+}
+
+/// AVAILABLE ONLY WITHIN THE CRABTIME MACRO.
+///
+/// Converts the string to `camelCase`. For example, `to_camel_case("max_size")` returns
+/// `maxSize`.
+#[cfg(feature = "std")]
+#[allow(clippy::panic)]
+pub fn to_camel_case(_s: &str) -> String {
+    panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
+}
+
+/// AVAILABLE ONLY WITHIN THE CRABTIME MACRO.
+///
+/// Converts the string to `PascalCase`. For example, `to_pascal_case("max_size")` returns
+/// `MaxSize`.
+#[cfg(feature = "std")]
+#[allow(clippy::panic)]
+pub fn to_pascal_case(_s: &str) -> String {
+    panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
+}
+
+/// AVAILABLE ONLY WITHIN THE CRABTIME MACRO.
+///
+/// Converts the string to `Title Case`. For example, `to_title_case("max_size")` returns
+/// `Max Size`.
+#[cfg(feature = "std")]
+#[allow(clippy::panic)]
+pub fn to_title_case(_s: &str) -> String {
+    panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
+}
+
+/// AVAILABLE ONLY WITHIN THE CRABTIME MACRO.
+///
+/// Returns the string as a Rust string literal, including the quotes. Unlike `{:?}`, it keeps all
+/// characters a literal can contain, escaping only quotes, backslashes, control characters, and
+/// bidirectional formatting characters. For example, `escape_str("say \"hi\"")` returns
+/// `"say \"hi\""`.
+#[cfg(feature = "std")]
+#[allow(clippy::panic)]
+pub fn escape_str(_value: &str) -> String {
+    panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
+}
+
+/// AVAILABLE ONLY WITHIN THE CRABTIME MACRO.
+///
+/// Returns the bytes as a Rust byte string literal, like `b"GIF\x00"`.
+#[cfg(feature = "std")]
+#[allow(clippy::panic)]
+pub fn escape_bytes(_value: &[u8]) -> String {
+    panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
+}
+
+/// AVAILABLE ONLY WITHIN THE CRABTIME MACRO.
+///
+/// Returns a `pub const` string item, like `pub const NAME: &str = "value";`, escaping the value
+/// with [`escape_str`].
+#[cfg(feature = "std")]
+#[allow(clippy::panic)]
+pub fn const_str(_name: &str, _value: &str) -> String {
+    panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
+}
+
+/// AVAILABLE ONLY WITHIN THE CRABTIME MACRO.
+///
+/// Returns a `pub static` byte slice item, like `pub static NAME: &[u8] = b"value";`, escaping the
+/// value with [`escape_bytes`].
+#[cfg(feature = "std")]
+#[allow(clippy::panic)]
+pub fn static_bytes(_name: &str, _value: &[u8]) -> String {
+    panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
+}
+
+/// AVAILABLE ONLY WITHIN THE CRABTIME MACRO.
+///
+/// Generated code built line by line, indented according to its structure. It can be returned
+/// from the macro or interpolated in `crabtime::output!`.
+#[cfg(feature = "std")]
+#[derive(Clone, Debug, Default)]
+pub struct Code;
+
+#[cfg(feature = "std")]
+#[allow(clippy::panic)]
+impl Code {
+    /// AVAILABLE ONLY WITHIN THE CRABTIME MACRO.
+    pub fn new() -> Self {
+        panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
     }
 
-    // ===
+    /// AVAILABLE ONLY WITHIN THE CRABTIME MACRO.
+    ///
+    /// Adds the code at the current indentation. Multi-line code is indented line by line.
+    pub fn item(&mut self, _code: impl core::fmt::Display) -> &mut Self {
+        panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
+    }
 
-    mod mod_a {
-        #[crabtime::function]
-        fn inter_module_macro() -> &str {
-            "pub struct Generated;"
-        }
-        #[allow(clippy::single_component_path_imports)]
-        pub(super) use inter_module_macro;
+    /// AVAILABLE ONLY WITHIN THE CRABTIME MACRO.
+    ///
+    /// Adds `header {`, the code added by `f` indented one level deeper, and `}`.
+    pub fn block(&mut self, _header: impl core::fmt::Display, _f: impl FnOnce(&mut Self)) -> &mut Self {
+        panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
     }
 
-    mod mod_b {
-        super::mod_a::inter_module_macro!();
+    /// AVAILABLE ONLY WITHIN THE CRABTIME MACRO.
+    ///
+    /// Indents the code added later one level deeper.
+    pub fn indent(&mut self) -> &mut Self {
+        panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
     }
 
-    #[test]
-    fn inter_module_macro() {
-        let _p = mod_b::Generated;
+    /// AVAILABLE ONLY WITHIN THE CRABTIME MACRO.
+    ///
+    /// Reverts one [`Code::indent`] call.
+    pub fn dedent(&mut self) -> &mut Self {
+        panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
     }
 
-    #[test] fn interpolation_before_brace() {
-        #[crabtime::function]
-        fn interpolation_before_brace() {
-            let is_a_branches = "A => true, B => false";
-            crabtime::output! {
-                enum E { A, B }
-                impl E {
-                    fn is_a(&self) -> bool {
-                        match self {
-                            {{is_a_branches}}
-                        }
-                    }
-                }
-            }
-        }
-        interpolation_before_brace!();
+    /// AVAILABLE ONLY WITHIN THE CRABTIME MACRO.
+    ///
+    /// Adds the lines of the other code at the current indentation.
+    pub fn append(&mut self, _other: &Code) -> &mut Self {
+        panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
+    }
+
+    /// AVAILABLE ONLY WITHIN THE CRABTIME MACRO.
+    pub fn is_empty(&self) -> bool {
+        panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
+    }
+}
+
+#[cfg(feature = "std")]
+#[allow(clippy::panic)]
+impl core::fmt::Display for Code {
+    fn fmt(&self, _f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
+    }
+}
+
+/// AVAILABLE ONLY WITHIN THE CRABTIME MACRO.
+///
+/// Generated code returned by `crabtime::quote!`. Snippets can be concatenated with `+`, collected
+/// from an iterator, and interpolated in `crabtime::output!` and `crabtime::quote!`.
+#[cfg(feature = "std")]
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Snippet;
+
+#[cfg(feature = "std")]
+#[allow(clippy::panic)]
+impl Snippet {
+    /// AVAILABLE ONLY WITHIN THE CRABTIME MACRO.
+    pub fn new() -> Self {
+        panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
+    }
+
+    /// AVAILABLE ONLY WITHIN THE CRABTIME MACRO.
+    pub fn as_str(&self) -> &str {
+        panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
+    }
+}
+
+#[cfg(feature = "std")]
+#[allow(clippy::panic)]
+impl core::ops::Deref for Snippet {
+    type Target = str;
+    fn deref(&self) -> &str {
+        panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
+    }
+}
+
+#[cfg(feature = "std")]
+#[allow(clippy::panic)]
+impl core::fmt::Display for Snippet {
+    fn fmt(&self, _f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
+    }
+}
+
+#[cfg(feature = "std")]
+#[allow(clippy::panic)]
+impl From<String> for Snippet {
+    fn from(_code: String) -> Self {
+        panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
+    }
+}
+
+#[cfg(feature = "std")]
+#[allow(clippy::panic)]
+impl From<&str> for Snippet {
+    fn from(_code: &str) -> Self {
+        panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
+    }
+}
+
+#[cfg(feature = "std")]
+#[allow(clippy::panic)]
+impl From<Snippet> for String {
+    fn from(_snippet: Snippet) -> Self {
+        panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
+    }
+}
+
+#[cfg(feature = "std")]
+#[allow(clippy::panic)]
+impl<T: AsRef<str>> core::ops::Add<T> for Snippet {
+    type Output = Snippet;
+    fn add(self, _rhs: T) -> Snippet {
+        panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
+    }
+}
+
+#[cfg(feature = "std")]
+#[allow(clippy::panic)]
+impl<T: AsRef<str>> core::ops::AddAssign<T> for Snippet {
+    fn add_assign(&mut self, _rhs: T) {
+        panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
+    }
+}
+
+#[cfg(feature = "std")]
+#[allow(clippy::panic)]
+impl AsRef<str> for Snippet {
+    fn as_ref(&self) -> &str {
+        panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
+    }
+}
+
+#[cfg(feature = "std")]
+#[allow(clippy::panic)]
+impl FromIterator<Snippet> for Snippet {
+    fn from_iter<I: IntoIterator<Item = Snippet>>(_iter: I) -> Self {
+        panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
+    }
+}
+
+/// Type of code callback arguments, passed at the call site as closures, like
+/// `|field| format!("get_{field}")`. The closures are run by the generated program, not by the
+/// crate invoking the macro.
+#[cfg(feature = "std")]
+pub type CodeFn = fn(&str) -> String;
+
+/// AVAILABLE ONLY WITHIN THE CRABTIME MACRO.
+///
+/// Id of the current build, the same for all macros expanded within one build and different
+/// across builds. Builds are detected by a pause between expansions, so overlapping builds may
+/// share the id.
+#[cfg(feature = "std")]
+#[allow(clippy::panic)]
+pub fn build_id() -> u64 {
+    panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
+}
+
+/// AVAILABLE ONLY WITHIN THE CRABTIME MACRO.
+///
+/// Returns a random number. Calling it marks the output of the expansion as non-reusable, so the
+/// macro is always evaluated again instead of reusing its previous output.
+#[cfg(feature = "std")]
+#[allow(clippy::panic)]
+pub fn random_u64() -> u64 {
+    panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
+}
+
+/// AVAILABLE ONLY WITHIN THE CRABTIME MACRO.
+///
+/// Deserializes a value from a JSON string, panicking if it is invalid. Available if the macro
+/// depends on both `serde` and `serde_json`.
+#[cfg(feature = "std")]
+#[allow(clippy::panic)]
+pub fn from_json_str<T>(_json: &str) -> T {
+    panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
+}
+
+/// AVAILABLE ONLY WITHIN THE CRABTIME MACRO.
+///
+/// Serializes a value to a JSON string. Available if the macro depends on both `serde` and
+/// `serde_json`.
+#[cfg(feature = "std")]
+#[allow(clippy::panic)]
+pub fn to_json_string<T: ?Sized>(_value: &T) -> String {
+    panic!("AVAILABLE ONLY WITHIN THE CRABTIME MACRO.")
+}
+
+pub const WORKSPACE_PATH: &str = "AVAILABLE ONLY WITHIN THE CRABTIME MACRO.";
+pub const ARG_COUNT: usize = 0;
+pub const ARG_STRS: &[&str] = &[];
+pub const CRATE_CONFIG_PATH: &str = "AVAILABLE ONLY WITHIN THE CRABTIME MACRO.";
+pub const CALL_SITE_FILE_PATH: &str = "AVAILABLE ONLY WITHIN THE CRABTIME MACRO.";
+
+// =============
+// === Tests ===
+// =============
+
+/// Most of the tests are included in the documentation above. These tests cover corner cases to
+/// ensure that the macro works as expected.
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    #[test]
+    fn empty_def_compilation() {
+        #[crabtime::function]
+        fn empty_def_compilation() {}
+        empty_def_compilation!();
+    }
+
+    // ===
+
+    mod mod_a {
+        #[crabtime::function]
+        fn inter_module_macro() -> &str {
+            "pub struct Generated;"
+        }
+        #[allow(clippy::single_component_path_imports)]
+        pub(super) use inter_module_macro;
+    }
+
+    mod mod_b {
+        super::mod_a::inter_module_macro!();
+    }
+
+    #[test]
+    fn inter_module_macro() {
+        let _p = mod_b::Generated;
+    }
+
+    #[test] fn interpolation_before_brace() {
+        #[crabtime::function]
+        fn interpolation_before_brace() {
+            let is_a_branches = "A => true, B => false";
+            crabtime::output! {
+                enum E { A, B }
+                impl E {
+                    fn is_a(&self) -> bool {
+                        match self {
+                            {{is_a_branches}}
+                        }
+                    }
+                }
+            }
+        }
+        interpolation_before_brace!();
+    }
+
+    // ===
+
+    #[test]
+    fn max_output_within_limit() {
+        #[crabtime::function(max_output = 1024, max_output_warn = 512)]
+        fn max_output_within_limit() {
+            for ix in 0 .. 4 {
+                crabtime::output! {
+                    const _: usize = {{ix}};
+                }
+            }
+        }
+        max_output_within_limit!();
+    }
+
+    // ===
+
+    mod random {
+        #[crabtime::function]
+        fn gen_random() {
+            let random = crabtime::random_u64();
+            let build_id = crabtime::build_id();
+            crabtime::output! {
+                pub const RANDOM: u64 = {{random}};
+                pub const BUILD_ID: u64 = {{build_id}};
+            }
+        }
+
+        // Both calls use the same project, as their arguments are the same.
+        pub mod first { gen_random!(); }
+        pub mod second { gen_random!(); }
+    }
+
+    #[test]
+    fn random_output_not_reused() {
+        assert_ne!(random::first::RANDOM, random::second::RANDOM);
+        assert_eq!(random::first::BUILD_ID, random::second::BUILD_ID);
+        assert_ne!(random::first::BUILD_ID, 0);
+    }
+
+    // ===
+
+    mod run_dir {
+        #[crabtime::function]
+        fn gen_stray_file() {
+            let cwd = std::env::current_dir().unwrap();
+            let project_dir = cwd.parent().unwrap();
+            let in_run_dir = cwd.ends_with("run") && project_dir.join("Cargo.toml").exists();
+            let tmp_in_project = std::env::temp_dir().starts_with(project_dir);
+            let leftover = std::path::Path::new("stray.txt").exists();
+            std::fs::write("stray.txt", "").unwrap();
+            let created = cwd.join("stray.txt").exists();
+            crabtime::output! {
+                pub const STATE: [bool; 4] =
+                    [{{in_run_dir}}, {{tmp_in_project}}, {{created}}, {{leftover}}];
+            }
+        }
+
+        // Both calls use the same project, as their arguments are the same.
+        pub mod first { gen_stray_file!(); }
+        pub mod second { gen_stray_file!(); }
+    }
+
+    #[test]
+    fn run_dir() {
+        // The file lands in the run dir, which is cleaned before the next run.
+        assert_eq!(run_dir::first::STATE, [true, true, true, false]);
+        assert_eq!(run_dir::second::STATE, [true, true, true, false]);
+    }
+
+    // ===
+
+    #[test]
+    fn output_macro_rules() {
+        #[crabtime::function]
+        fn gen_macro_rules(name: String) {
+            crabtime::output_macro_rules! {
+                {{name}} {
+                    ($x:expr) => {{ let base = $x; base + 1 }};
+                    ($x:expr, $($xs:expr),*) => { $x $(+ $xs)* + 1 };
+                }
+            }
+        }
+        gen_macro_rules!(add_one);
+        assert_eq!(add_one!(1), 2);
+        assert_eq!(add_one!(1, 2, 3), 7);
+    }
+
+    // ===
+
+    mod output_cfg {
+        #[crabtime::function]
+        fn gen_cfg(name: String) {
+            for value in [1, 2] {
+                crabtime::output_cfg! {
+                    test => { pub const {{name}}_{{value}}: usize = {{value}}; }
+                }
+                crabtime::output_cfg! {
+                    "not(test)" => { pub const {{name}}_{{value}}: usize = 0; }
+                }
+            }
+            crabtime::output_cfg! {
+                "all(test, not(test))" => { compile_error!("Disabled items must be removed."); }
+            }
+            crabtime::output_cfg! {
+                any(test, doc) => {
+                    pub struct Gated;
+                    impl Gated { pub const VALUE: usize = 3; }
+                }
+            }
+        }
+        gen_cfg!(VALUE);
+    }
+
+    #[test]
+    fn output_cfg() {
+        assert_eq!(output_cfg::VALUE_1 + output_cfg::VALUE_2, 3);
+        assert_eq!(output_cfg::Gated::VALUE, 3);
+    }
+
+    // ===
+
+    #[test]
+    fn ident_and_path_constructors() {
+        #[crabtime::function]
+        fn gen_idents() {
+            let keyword = crabtime::ident(["fn"]);
+            let leading_digit = crabtime::ident(["1st", "-item"]);
+            let unicode = crabtime::ident(["zażółć"]);
+            let path = crabtime::path(["std", "string::String"]);
+            crabtime::output! {
+                fn {{keyword}}() -> usize { 1 }
+                fn {{leading_digit}}() -> usize { 2 }
+                fn {{unicode}}() -> usize { 3 }
+                type Str = {{path}};
+            }
+        }
+        gen_idents!();
+        assert_eq!(r#fn() + _1st_item() + zażółć(), 6);
+        assert_eq!(Str::new(), "");
+    }
+
+    #[test]
+    fn ident_escapes_all_keywords() {
+        #[crabtime::function]
+        fn gen_keyword_idents() {
+            let strict = format!("{:?}", crabtime::ident(["static"]));
+            let edition_2024 = format!("{:?}", crabtime::ident(["gen"]));
+            crabtime::output! {
+                const STRICT: &str = {{strict}};
+                const EDITION_2024: &str = {{edition_2024}};
+            }
+        }
+        gen_keyword_idents!();
+        assert_eq!(STRICT, "r#static");
+        assert_eq!(EDITION_2024, "r#gen");
+    }
+
+    // ===
+
+    #[test]
+    fn case_conversions() {
+        #[crabtime::function]
+        fn gen_case_table() {
+            let inputs = ["CamelCase", "snake_case", "XMLHttpRequest", "kebab-case word", "field2Name"];
+            let rows = inputs.iter().map(|input| {
+                let row = [
+                    input.to_string(),
+                    crabtime::to_snake_case(input),
+                    crabtime::to_shouty_snake_case(input),
+                    crabtime::to_kebab_case(input),
+                    crabtime::to_camel_case(input),
+                    crabtime::to_pascal_case(input),
+                    crabtime::to_title_case(input),
+                ];
+                format!("{row:?}")
+            }).collect::<Vec<_>>().join(",");
+            crabtime::output! {
+                const CASE_TABLE: &[[&str; 7]] = &[{{rows}}];
+            }
+        }
+        gen_case_table!();
+        // Input, snake, shouty snake, kebab, camel, Pascal, Title. Expectations match `heck`.
+        assert_eq!(CASE_TABLE, &[
+            ["CamelCase", "camel_case", "CAMEL_CASE", "camel-case", "camelCase", "CamelCase", "Camel Case"],
+            ["snake_case", "snake_case", "SNAKE_CASE", "snake-case", "snakeCase", "SnakeCase", "Snake Case"],
+            ["XMLHttpRequest", "xml_http_request", "XML_HTTP_REQUEST", "xml-http-request", "xmlHttpRequest", "XmlHttpRequest", "Xml Http Request"],
+            ["kebab-case word", "kebab_case_word", "KEBAB_CASE_WORD", "kebab-case-word", "kebabCaseWord", "KebabCaseWord", "Kebab Case Word"],
+            ["field2Name", "field2_name", "FIELD2_NAME", "field2-name", "field2Name", "Field2Name", "Field2 Name"],
+        ]);
+    }
+
+    // ===
+
+    mod escaped_literals {
+        // Every constant is emitted with its code points, so the compiled constant can be compared
+        // with the original value without escaping it.
+        #[crabtime::function]
+        fn gen_escaped_literals() -> String {
+            let mut values = [
+                "", "\"", "\\", "\\\"", "\"#", "\r", "\r\n", "\n\r", "\0", "\t\u{b}\u{c}",
+                "{}", "{{x}}", "\\u{41}", "\\x41", "\u{7f}\u{80}\u{9f}\u{a0}", "\u{ad}",
+                "e\u{301}", "\u{200b}\u{200d}\u{feff}", "\u{202e}abc\u{202c}", "\u{2066}\u{2069}",
+                "\u{200e}\u{200f}", "\u{2028}\u{2029}", "👨\u{200d}👩\u{200d}👧", "\u{10ffff}",
+                "\u{e000}", "\u{fffd}", "*/ /*", "// x", "zażółć gęślą jaźń",
+            ].map(String::from).to_vec();
+            values.push((0..=0x2100).filter_map(char::from_u32).collect());
+            let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+            for _ in 0..64 {
+                let value = (0..16).filter_map(|_| {
+                    seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                    char::from_u32((seed >> 33) as u32 % 0x11_0000)
+                }).collect();
+                values.push(value);
+            }
+            let items = values.iter().enumerate().map(|(i, value)| {
+                let chars = value.chars().map(|c| c as u32).collect::<Vec<_>>();
+                let bytes = (0..=255).map(|b| (b + i) as u8).collect::<Vec<_>>();
+                let name = format!("VALUE_{i}");
+                let literal = crabtime::escape_str(value);
+                format!(
+                    "{}\n{}\npub const LITERAL_{i}: &str = {literal};\n\
+                    pub static CHARS_{i}: &[u32] = &{chars:?};\n\
+                    pub static BYTES_{i}_EXPECTED: &[u8] = &{bytes:?};",
+                    crabtime::const_str(&name, value),
+                    crabtime::static_bytes(&format!("BYTES_{i}"), &bytes),
+                )
+            }).collect::<Vec<_>>();
+            let cases = (0..values.len()).map(|i| {
+                format!("(VALUE_{i}, LITERAL_{i}, CHARS_{i}, BYTES_{i}, BYTES_{i}_EXPECTED)")
+            }).collect::<Vec<_>>().join(",");
+            format!(
+                "{}\npub static CASES: &[(&str, &str, &[u32], &[u8], &[u8])] = &[{cases}];",
+                items.join("\n")
+            )
+        }
+        gen_escaped_literals!();
+    }
+
+    #[test]
+    fn escaped_literals() {
+        assert_eq!(escaped_literals::CASES.len(), 94);
+        for (value, literal, chars, bytes, expected_bytes) in escaped_literals::CASES {
+            let value_chars = value.chars().map(|c| c as u32).collect::<Vec<_>>();
+            assert_eq!(&value_chars, chars);
+            assert_eq!(value, literal);
+            assert_eq!(bytes, expected_bytes);
+        }
+    }
+
+    // ===
+
+    mod provenance_doc {
+        #[crabtime::function]
+        fn gen_documented() -> &str {
+            "/// Documented.
+            pub struct Documented;
+            impl Documented {}"
+        }
+        gen_documented!();
+
+        #[crabtime::function(provenance_doc = false)]
+        fn gen_undocumented() -> &str {
+            "pub struct Undocumented;"
+        }
+        gen_undocumented!();
+    }
+
+    mod provenance_const {
+        #[crabtime::function(provenance_const = true)]
+        fn gen_marked() {
+            crabtime::output! {
+                pub struct Marked;
+            }
+        }
+        gen_marked!();
+    }
+
+    #[test]
+    fn provenance_const() {
+        // In expression position, a constant would make the expansion invalid.
+        #[crabtime::expression(provenance_const = true)]
+        fn gen_marked_expr() -> usize {
+            1
+        }
+        let _marked = provenance_const::Marked;
+        assert_eq!(gen_marked_expr!() + crabtime::eval! { 1_usize }, 2);
+    }
+
+    #[test]
+    fn provenance_doc() {
+        let _documented = provenance_doc::Documented;
+        let _undocumented = provenance_doc::Undocumented;
+    }
+
+    // ===
+
+    macro_rules! flavored_eval {
+        (flavor = $flavor:ident; $($ts:tt)*) => {{
+            #[crabtime::eval_function(cache=true, content_base_name=true, lenient, flavor=$flavor)]
+            fn run() -> _ {
+                $($ts)*
+            }
+        }};
+    }
+
+    #[test]
+    fn wrapper_options_passed_through() {
+        let flavor: &str = flavored_eval! { flavor = fancy;
+            format!("{:?}", crabtime::passthrough_option("flavor").unwrap_or("plain"))
+        };
+        let count: usize = flavored_eval! { flavor = plain;
+            crabtime::PASSTHROUGH_OPTIONS.len()
+        };
+        assert_eq!((flavor, count), ("fancy", 1));
+    }
+
+    // ===
+
+    mod call_arguments {
+        #[crabtime::function]
+        fn gen_token_stream_args(_input: TokenStream) {
+            #![dependency(proc-macro2 = "1")]
+            let count = crabtime::ARG_COUNT;
+            let strs = crabtime::ARG_STRS.iter().map(|t| format!("{t:?}")).collect::<Vec<_>>();
+            let strs = strs.join(", ");
+            crabtime::output! {
+                pub const TOKEN_STREAM_ARGS: (usize, &[&str]) = ({{count}}, &[{{strs}}]);
+            }
+        }
+        gen_token_stream_args!((1, (2, 3)), [[4, 5], [6]], foo, {a: 1, b: 2},);
+
+        #[crabtime::function]
+        fn gen_pattern_args(pattern!($name:ident $(, $xs:expr)* $(,)?): _) {
+            let name = stringify!($name);
+            let count = crabtime::ARG_COUNT;
+            let strs = crabtime::ARG_STRS.iter().map(|t| format!("{t:?}")).collect::<Vec<_>>();
+            let strs = strs.join(", ");
+            crabtime::output! {
+                pub const {{name}}: (usize, &[&str]) = ({{count}}, &[{{strs}}]);
+            }
+        }
+        gen_pattern_args!(PATTERN_ARGS, (1, 2), [3, [4, 5]], 6,);
+        gen_pattern_args!(NO_PATTERN_ARGS);
+
+        #[test]
+        fn test() {
+            assert_eq!(TOKEN_STREAM_ARGS.0, 4);
+            let args = TOKEN_STREAM_ARGS.1.iter().map(|t| t.replace(' ', "")).collect::<Vec<_>>();
+            assert_eq!(args, ["(1,(2,3))", "[[4,5],[6]]", "foo", "{a:1,b:2}"]);
+            assert_eq!(PATTERN_ARGS.0, 4);
+            assert_eq!(PATTERN_ARGS.1, ["PATTERN_ARGS", "(1, 2)", "[3, [4, 5]]", "6"]);
+            assert_eq!(NO_PATTERN_ARGS, (1, &["NO_PATTERN_ARGS"][..]));
+        }
+    }
+
+    // ===
+
+    mod default_arguments {
+        #[crabtime::function]
+        fn gen_joined(components: Vec<String>, count: Option<usize>, #[default] prefix: String) {
+            let joined = format!("\"{prefix}{}\"", components.join(","));
+            let count = count.unwrap_or(components.len());
+            crabtime::output! {
+                pub const JOINED: (&str, usize) = ({{joined}}, {{count}});
+            }
+        }
+
+        pub mod populated { gen_joined!(["X", "Y"], 7, "p:"); }
+        pub mod empty_populated { gen_joined!([], 0, ""); }
+        pub mod empty { gen_joined!(); }
+    }
+
+    #[test]
+    fn default_arguments() {
+        assert_eq!(default_arguments::populated::JOINED, ("p:X,Y", 7));
+        assert_eq!(default_arguments::empty_populated::JOINED, ("", 0));
+        assert_eq!(default_arguments::empty::JOINED, ("", 0));
+    }
+
+    // ===
+
+    mod code_builder {
+        pub struct Fields { pub a: usize, pub b: usize }
+
+        #[crabtime::function]
+        fn gen_getters(fields: Vec<String>) {
+            let count = fields.len();
+            let mut code = crabtime::Code::new();
+            code.block("impl Fields", |b| {
+                for field in &fields {
+                    b.block(format_args!("pub fn get_{field}(&self) -> usize"), |b| {
+                        b.item(format_args!("self.{field}"));
+                    });
+                }
+            });
+            crabtime::output! {
+                pub const GETTERS: usize = {{count}};
+                {{code}}
+            }
+        }
+        gen_getters!(["a", "b"]);
+    }
+
+    #[test]
+    fn code_builder() {
+        let fields = code_builder::Fields { a: 1, b: 2 };
+        assert_eq!((fields.get_a(), fields.get_b()), (1, 2));
+        assert_eq!(code_builder::GETTERS, 2);
+    }
+
+    // ===
+
+    mod notices {
+        #[crabtime::function]
+        fn gen_versioned(name: String, version: usize) {
+            let deprecated = if version < 2 {
+                crabtime::notice!("Version 1 of 'gen_versioned!' is deprecated.");
+                "#[deprecated(note = \"Use the current version.\")]"
+            } else {
+                ""
+            };
+            crabtime::output! {
+                {{deprecated}}
+                pub const {{name}}: usize = 1;
+            }
+        }
+        // The notice is reported once, although both expansions emit it.
+        gen_versioned!("A", 1);
+        gen_versioned!("B", 1);
+        gen_versioned!("C", 2);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn notices() {
+        assert_eq!((notices::A, notices::B, notices::C), (1, 1, 1));
+    }
+
+    // ===
+
+    mod snippets {
+        #[crabtime::function]
+        fn gen_snippets(names: Vec<String>) {
+            let consts = names.iter().enumerate().map(|(index, name)| crabtime::quote! {
+                pub const {{name}}: usize = {{index}};
+            }).collect::<crabtime::Snippet>();
+            let count = names.len();
+            let mut sum = crabtime::quote! { pub const SUM: usize = 0 }
+                + crabtime::quote! { + {{count}} };
+            sum += ";";
+            crabtime::output! {
+                {{consts}}
+                {{sum}}
+            }
+        }
+        gen_snippets!(["A", "B"]);
+
+        #[crabtime::function]
+        fn gen_string() -> String {
+            let code: String = crabtime::quote! { pub const LEN: usize = 3; }.into();
+            code
+        }
+        gen_string!();
+    }
+
+    #[test]
+    fn snippets() {
+        assert_eq!((snippets::A, snippets::B), (0, 1));
+        assert_eq!(snippets::SUM, 2);
+        assert_eq!(snippets::LEN, 3);
+    }
+
+    // ===
+
+    mod range_arguments {
+        pub trait Arity {
+            const ARITY: usize;
+        }
+
+        #[crabtime::function]
+        fn gen_arity(arities: std::ops::RangeInclusive<usize>) {
+            for arity in arities {
+                let params = (0..arity).map(|i| format!("T{i}")).collect::<Vec<_>>().join(",");
+                crabtime::output! {
+                    impl<{{params}}> Arity for ({{params}},) {
+                        const ARITY: usize = {{arity}};
+                    }
+                }
+            }
+        }
+        gen_arity!(1..=4);
+
+        #[crabtime::function]
+        fn gen_sum(name: String, items: std::ops::Range<u32>, scale: u32) {
+            let sum = items.map(|i| i * scale).sum::<u32>();
+            crabtime::output! {
+                pub const {{name}}: u32 = {{sum}};
+            }
+        }
+        gen_sum!(SUM, 0..4, 10);
+        gen_sum!(EMPTY, 3..3, 10);
+    }
+
+    #[test]
+    fn range_arguments() {
+        use range_arguments::Arity;
+        assert_eq!(<(u8,) as Arity>::ARITY, 1);
+        assert_eq!(<(u8, u8, u8, u8) as Arity>::ARITY, 4);
+        assert_eq!(range_arguments::SUM, 60);
+        assert_eq!(range_arguments::EMPTY, 0);
+    }
+
+    // ===
+
+    mod code_fn_arguments {
+        pub struct User {
+            pub name: &'static str,
+        }
+
+        #[crabtime::function]
+        fn gen_accessor(name: String, field: String, accessor: crabtime::CodeFn) {
+            let method = format!("{} -> &'static str {{ self.{field} }}", accessor(&field));
+            crabtime::output! {
+                impl {{name}} {
+                    {{method}}
+                }
+            }
+        }
+        gen_accessor!(User, name, |field| format!("pub fn get_{field}(&self)"));
+
+        #[crabtime::function]
+        fn gen_const(name: String, value: fn(&str) -> String) {
+            let value = format!("{:?}", value(&name));
+            crabtime::output! {
+                pub const {{name}}: &str = {{value}};
+            }
+        }
+        gen_const!(LOWER, |name| name.to_lowercase());
+        gen_const!(REPEATED, |name| name.repeat(2));
+    }
+
+    #[test]
+    fn code_fn_arguments() {
+        let user = code_fn_arguments::User { name: "Ann" };
+        assert_eq!(user.get_name(), "Ann");
+        assert_eq!(code_fn_arguments::LOWER, "lower");
+        assert_eq!(code_fn_arguments::REPEATED, "REPEATEDREPEATED");
+    }
+
+    // ===
+
+    mod dedup {
+        #[crabtime::function(dedup = true, dedup_path = "crate::tests::dedup::first")]
+        fn gen_dedup_items(name: String) {
+            crabtime::output! {
+                pub struct {{name}};
+                impl {{name}} {
+                    pub const ID: usize = 7;
+                }
+            }
+        }
+
+        #[crabtime::function]
+        fn gen_plain_items(name: String) {
+            crabtime::output! {
+                pub struct {{name}};
+            }
+        }
+
+        pub mod first {
+            gen_dedup_items!(Deduped);
+            gen_plain_items!(Plain);
+        }
+
+        pub mod second {
+            gen_dedup_items!(Deduped);
+            gen_plain_items!(Plain);
+        }
+    }
+
+    #[test]
+    fn dedup_across_modules() {
+        use std::any::TypeId;
+        use dedup::first;
+        use dedup::second;
+        assert_eq!(TypeId::of::<first::Deduped>(), TypeId::of::<second::Deduped>());
+        assert_eq!(second::Deduped::ID, 7);
+        // Without the option, every call site defines its own items.
+        assert_ne!(TypeId::of::<first::Plain>(), TypeId::of::<second::Plain>());
+    }
+
+    // ===
+
+    #[test]
+    fn resolver_and_edition_attributes() {
+        // The macro body runs in the `run` subdirectory of the generated project.
+        #[crabtime::expression]
+        fn gen_manifest_config() -> String {
+            #![edition(2021)]
+            #![resolver(2)]
+            let manifest = std::fs::read_to_string("../Cargo.toml").unwrap_or_default();
+            let value = |key: &str| manifest.lines()
+                .filter_map(|line| line.trim().strip_prefix(key)?.trim().strip_prefix('='))
+                .map(|value| value.trim().to_string())
+                .next()
+                .unwrap_or_else(|| "\"\"".to_string());
+            format!("({}, {})", value("edition"), value("resolver"))
+        }
+        assert_eq!(gen_manifest_config!(), ("2021", "2"));
+    }
+
+    #[test]
+    fn old_edition() {
+        // Edition 2018 gets resolver 2 by default, as resolver 3 requires edition 2024.
+        #[crabtime::expression]
+        fn gen_from_async_fn() -> String {
+            #![edition(2018)]
+            use std::future::Future;
+            async fn answer(value: usize) -> usize {
+                async move { value * 2 }.await
+            }
+            let mut future = Box::pin(answer(21));
+            let mut context = std::task::Context::from_waker(std::task::Waker::noop());
+            match future.as_mut().poll(&mut context) {
+                std::task::Poll::Ready(value) => value.to_string(),
+                std::task::Poll::Pending => "0".to_string(),
+            }
+        }
+        assert_eq!(gen_from_async_fn!(), 42);
+
+        // In edition 2015, dependencies are declared with `extern crate`, as `use` paths are
+        // resolved from the crate root, and prelude macros are still available by their paths.
+        #[crabtime::expression]
+        fn gen_from_2015_edition() -> String {
+            #![edition(2015)]
+            #![dependency(semver = "1")]
+            use semver::Version;
+            let version = Version::parse("1.2.3").map(|v| v.minor).unwrap_or_default();
+            crabtime::output_str!("{}", version);
+            String::new()
+        }
+        assert_eq!(gen_from_2015_edition!(), 2);
+    }
+
+    // ===
+
+    #[test]
+    fn release_profile() {
+        #[crabtime::expression(profile = "release")]
+        fn gen_release_option() -> String {
+            format!("{}", cfg!(debug_assertions))
+        }
+        #[crabtime::expression]
+        fn gen_release_attribute() -> String {
+            #![profile(release)]
+            format!("{}", cfg!(debug_assertions))
+        }
+        #[crabtime::expression]
+        fn gen_debug() -> String {
+            format!("{}", cfg!(debug_assertions))
+        }
+        let debug_assertions = (gen_release_option!(), gen_release_attribute!(), gen_debug!());
+        assert_eq!(debug_assertions, (false, false, true));
+    }
+
+    #[test]
+    fn profile_settings() {
+        #[crabtime::expression]
+        fn gen_optimized() -> String {
+            #![profile_settings(opt_level = 3, debug_assertions = false)]
+            format!("{}", cfg!(debug_assertions))
+        }
+        #[crabtime::expression(minimal = true)]
+        fn gen_minimal_optimized() -> String {
+            #![profile_settings(debug_assertions = false)]
+            format!("{}", cfg!(debug_assertions))
+        }
+        assert_eq!((gen_optimized!(), gen_minimal_optimized!()), (false, false));
+    }
+
+    // ===
+
+    mod minimal {
+        #[crabtime::function(minimal = true)]
+        fn gen_minimal_consts(names: Vec<String>) {
+            for (index, name) in names.iter().enumerate() {
+                crabtime::output! {
+                    pub const {{name}}: usize = {{index}};
+                }
+            }
+        }
+        gen_minimal_consts!(["A", "B", "C"]);
+    }
+
+    #[test]
+    fn minimal() {
+        #[crabtime::expression(minimal = true, profile = "release")]
+        fn gen_minimal_release() -> String {
+            format!("{}", cfg!(debug_assertions))
+        }
+        assert_eq!((minimal::A, minimal::C, gen_minimal_release!()), (0, 2, false));
+    }
+
+    // ===
+
+    #[test]
+    fn workspace_lockfile_pins_versions() {
+        // The macro body runs in the `run` subdirectory of the generated project.
+        #[crabtime::expression]
+        fn gen_locked_semver() -> String {
+            #![dependency(semver = "1")]
+            let lockfile = std::fs::read_to_string("../Cargo.lock").unwrap_or_default();
+            let version = lockfile.split("[[package]]")
+                .find(|t| t.contains("name = \"semver\""))
+                .and_then(|t| t.lines().find_map(|line| line.strip_prefix("version = ")))
+                .unwrap_or("\"\"");
+            version.to_string()
+        }
+        let workspace_version = include_str!("../../Cargo.lock").split("[[package]]")
+            .find(|t| t.contains("name = \"semver\""))
+            .and_then(|t| t.lines().find_map(|line| line.strip_prefix("version = ")))
+            .map(|t| t.trim_matches('"'));
+        assert_eq!(Some(gen_locked_semver!()), workspace_version);
+    }
+
+    // ===
+
+    #[test]
+    fn string_arguments() {
+        #[crabtime::expression]
+        fn gen_quoted(name: &str) -> String {
+            format!("{name:?}")
+        }
+
+        assert_eq!(gen_quoted!(std::collections::HashMap), "std::collections::HashMap");
+        assert_eq!(gen_quoted!("my name"), "my name");
+        assert_eq!(gen_quoted!(Name), "Name");
+        assert_eq!(gen_quoted!(HashMap::<String, Vec<u8>>), "HashMap::<String, Vec<u8>>");
+        assert_eq!(gen_quoted!(a   +   b), "a + b");
+    }
+
+    // ===
+
+    #[test]
+    fn json_conversions() {
+        #[crabtime::expression]
+        fn gen_config_strings() -> serde_json::Value {
+            #![dependency(serde = { version = "1", features = ["derive"] })]
+            #![dependency(serde_json = "1")]
+            #[derive(serde::Deserialize, serde::Serialize)]
+            struct Config {
+                name: String,
+                sizes: Vec<usize>,
+            }
+            let config: Config = crabtime::from_json_str(r#"{"name": "gen", "sizes": [1, 2]}"#);
+            serde_json::json!([config.name, crabtime::to_json_string(&config.sizes)])
+        }
+
+        #[crabtime::expression]
+        fn gen_matrix() -> serde_json::Value {
+            #![dependency(serde_json = "1")]
+            serde_json::json!([[1.5, -2.0], [3.0, 4e10]])
+        }
+
+        assert_eq!(gen_config_strings!(), ["gen", "[1,2]"]);
+        assert_eq!(gen_matrix!(), [[1.5, -2.0], [3.0, 4e10]]);
+    }
+
+    // ===
+
+    #[test]
+    fn renamed_proc_macro2_dependency() {
+        #[crabtime::expression]
+        fn gen_renamed_token_stream() -> pm2::TokenStream {
+            #![dependency(pm2 = { package = "proc-macro2", version = "1" })]
+            use pm2::TokenStream;
+            let value = pm2::Literal::usize_unsuffixed(7);
+            TokenStream::from(pm2::TokenTree::Literal(value))
+        }
+
+        assert_eq!(gen_renamed_token_stream!(), 7);
+    }
+
+    // ===
+
+    #[test]
+    fn single_job_build() {
+        #[crabtime::function(jobs = 1)]
+        fn gen_single_job() {
+            crabtime::output! {
+                const SINGLE_JOB: usize = 1;
+            }
+        }
+        gen_single_job!();
+        assert_eq!(SINGLE_JOB, 1);
+    }
+
+    // ===
+
+    mod phase_cache {
+        #[crabtime::function]
+        fn gen_phase_runs(name: String) {
+            #![dependency(serde = "1")]
+            #![dependency(serde_json = "1")]
+            // The build id makes the cache key unique per build, so the test is independent of
+            // previous builds.
+            let build_id = crabtime::build_id();
+            let counter = std::path::Path::new(crabtime::WORKSPACE_PATH)
+                .join("target")
+                .join(format!("crabtime_phase_cache_test_{build_id}"));
+            let schema: Vec<String> = crabtime::phase_cache!(&format!("schema-{build_id}"), || {
+                let runs = std::fs::read_to_string(&counter).unwrap_or_default().len();
+                std::fs::write(&counter, "x".repeat(runs + 1)).unwrap();
+                vec!["a".to_string(), "b".to_string()]
+            });
+            let runs = std::fs::read_to_string(&counter).unwrap_or_default().len();
+            let fields = schema.len();
+            crabtime::output! {
+                pub const {{name}}: [usize; 2] = [{{runs}}, {{fields}}];
+            }
+        }
+        gen_phase_runs!(FIRST);
+        gen_phase_runs!(SECOND);
+    }
+
+    #[test]
+    fn phase_cache() {
+        assert_eq!(phase_cache::FIRST, [1, 2]);
+        assert_eq!(phase_cache::SECOND, [1, 2]);
+    }
+
+    // ===
+
+    #[test]
+    fn wrappers_not_inherited() {
+        // Run `cargo clippy` to test it with a wrapper set by the outer build.
+        #[crabtime::function]
+        fn gen_wrapper_state() {
+            let wrappers = ["RUSTC_WRAPPER", "RUSTC_WORKSPACE_WRAPPER"]
+                .iter()
+                .filter(|name| std::env::var_os(name).is_some())
+                .count();
+            crabtime::output! {
+                const WRAPPERS: usize = {{wrappers}};
+            }
+        }
+        gen_wrapper_state!();
+        assert_eq!(WRAPPERS, 0);
     }
 
     // ===
@@ -948,6 +3182,7 @@ mod tests {
         fn fn_in_impl() -> &str {
             "pub fn test(&self) {}"
         }
+        #[allow(dead_code)]
         struct Test;
         impl Test {
             fn_in_impl!();
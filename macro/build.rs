@@ -5,5 +5,10 @@ fn main() {
     if rustc_version::version_meta().unwrap().channel == rustc_version::Channel::Nightly {
         println!("cargo:rustc-cfg=nightly");
     }
+    // Proc macros are compiled for the host, so its target is the one of the macro projects.
+    println!("cargo:rustc-env=CRABTIME_HOST_TARGET={}", std::env::var("TARGET").unwrap());
+    // The compiler running the macro, identifying the builds of minimal macro projects.
+    let version = rustc_version::version_meta().unwrap().short_version_string;
+    println!("cargo:rustc-env=CRABTIME_RUSTC_VERSION={version}");
     println!("cargo:rerun-if-changed=build.rs");
 }
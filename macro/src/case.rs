@@ -0,0 +1,89 @@
+// Identifier case conversions available in the generated prelude. This file is embedded verbatim
+// in the prelude of every generated project, so it must not depend on anything but `std` and
+// must not use inner attributes or inner doc comments. The word splitting follows the `heck`
+// crate: words are separated by non-alphanumeric characters, by lowercase-to-uppercase
+// transitions, and before the last uppercase letter of an uppercase run followed by a lowercase
+// letter, so `XMLHttpRequest` is split into `XML`, `Http`, and `Request`.
+
+fn case_words(s: &str) -> Vec<&str> {
+    #[derive(Clone, Copy, PartialEq)]
+    enum Mode {
+        Boundary,
+        Lowercase,
+        Uppercase,
+    }
+
+    let mut words = Vec::new();
+    for word in s.split(|c: char| !c.is_alphanumeric()) {
+        let mut chars = word.char_indices().peekable();
+        let mut start = 0;
+        let mut mode = Mode::Boundary;
+        while let Some((i, c)) = chars.next() {
+            let Some(&(next_i, next)) = chars.peek() else {
+                words.push(&word[start..]);
+                break;
+            };
+            let next_mode = if c.is_lowercase() {
+                Mode::Lowercase
+            } else if c.is_uppercase() {
+                Mode::Uppercase
+            } else {
+                mode
+            };
+            if next_mode == Mode::Lowercase && next.is_uppercase() {
+                words.push(&word[start..next_i]);
+                start = next_i;
+                mode = Mode::Boundary;
+            } else if mode == Mode::Uppercase && c.is_uppercase() && next.is_lowercase() {
+                words.push(&word[start..i]);
+                start = i;
+                mode = Mode::Boundary;
+            } else {
+                mode = next_mode;
+            }
+        }
+    }
+    words
+}
+
+fn capitalize_word(word: &str) -> String {
+    let mut chars = word.chars();
+    chars.next().map_or_else(String::new, |first| {
+        first.to_uppercase().chain(chars.flat_map(char::to_lowercase)).collect()
+    })
+}
+
+fn join_case_words(s: &str, separator: &str, f: impl Fn(usize, &str) -> String) -> String {
+    case_words(s).into_iter().enumerate().map(|(i, word)| f(i, word)).collect::<Vec<_>>()
+        .join(separator)
+}
+
+/// Converts the string to `snake_case`.
+pub fn to_snake_case(s: &str) -> String {
+    join_case_words(s, "_", |_, word| word.to_lowercase())
+}
+
+/// Converts the string to `SHOUTY_SNAKE_CASE`.
+pub fn to_shouty_snake_case(s: &str) -> String {
+    join_case_words(s, "_", |_, word| word.to_uppercase())
+}
+
+/// Converts the string to `kebab-case`.
+pub fn to_kebab_case(s: &str) -> String {
+    join_case_words(s, "-", |_, word| word.to_lowercase())
+}
+
+/// Converts the string to `camelCase`.
+pub fn to_camel_case(s: &str) -> String {
+    join_case_words(s, "", |i, word| if i == 0 { word.to_lowercase() } else { capitalize_word(word) })
+}
+
+/// Converts the string to `PascalCase`.
+pub fn to_pascal_case(s: &str) -> String {
+    join_case_words(s, "", |_, word| capitalize_word(word))
+}
+
+/// Converts the string to `Title Case`.
+pub fn to_title_case(s: &str) -> String {
+    join_case_words(s, " ", |_, word| capitalize_word(word))
+}
@@ -0,0 +1,67 @@
+// A string builder of generated code available in the generated prelude. This file is embedded
+// verbatim in the prelude of every generated project, so it must not depend on anything but `std`
+// and must not use inner attributes or inner doc comments. Its `CodeFromOutput` implementation is
+// defined in the prelude, as the trait is not available when this file is tested.
+
+/// Generated code built line by line, indented according to its structure.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Code {
+    lines: Vec<String>,
+    indent: usize,
+}
+
+impl Code {
+    const INDENT: &'static str = "    ";
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the code at the current indentation. Multi-line code is indented line by line, so it
+    /// keeps its own relative indentation. Empty code adds an empty line.
+    pub fn item(&mut self, code: impl std::fmt::Display) -> &mut Self {
+        let prefix = Self::INDENT.repeat(self.indent);
+        let code = code.to_string();
+        for line in code.lines().chain(code.is_empty().then_some("")) {
+            let line = line.trim_end();
+            self.lines.push(if line.is_empty() { String::new() } else { format!("{prefix}{line}") });
+        }
+        self
+    }
+
+    /// Adds `header {`, the code added by `f` indented one level deeper, and `}`.
+    pub fn block(&mut self, header: impl std::fmt::Display, f: impl FnOnce(&mut Self)) -> &mut Self {
+        let header = header.to_string();
+        let open = if header.is_empty() { "{".to_string() } else { format!("{header} {{") };
+        self.item(open).indent();
+        f(self);
+        self.dedent().item("}")
+    }
+
+    /// Indents the code added later one level deeper.
+    pub fn indent(&mut self) -> &mut Self {
+        self.indent += 1;
+        self
+    }
+
+    /// Reverts one [`Code::indent`] call.
+    pub fn dedent(&mut self) -> &mut Self {
+        self.indent = self.indent.saturating_sub(1);
+        self
+    }
+
+    /// Adds the lines of the other code at the current indentation.
+    pub fn append(&mut self, other: &Code) -> &mut Self {
+        self.item(other)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+}
+
+impl std::fmt::Display for Code {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.lines.join("\n"))
+    }
+}
@@ -0,0 +1,124 @@
+use crate::error::*;
+use crate::shared_file;
+use crate::shared_file::LOCK_TIMEOUT;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+// =============
+// === Entry ===
+// =============
+
+/// A line of the dedup file, recording the call site which first emitted an expansion in a build.
+#[derive(Clone, Debug, PartialEq)]
+struct Entry {
+    build_id: u128,
+    /// Hash of the macro name, its input, and its output.
+    key: u64,
+    call_site: String,
+}
+
+impl Entry {
+    fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.splitn(3, '\t');
+        let build_id = fields.next()?.parse().ok()?;
+        let key = u64::from_str_radix(fields.next()?, 16).ok()?;
+        let call_site = fields.next()?.to_string();
+        Some(Self { build_id, key, call_site })
+    }
+
+    fn to_line(&self) -> String {
+        format!("{}\t{:016x}\t{}\n", self.build_id, self.key, self.call_site)
+    }
+}
+
+// =============
+// === Claim ===
+// =============
+
+/// Number of expansions of each key in the current compilation, as the macro library stays loaded
+/// for the whole compilation of a crate.
+static OCCURRENCES: Mutex<Option<HashMap<u64, usize>>> = Mutex::new(None);
+
+/// Counts the expansion with the given key in the current compilation and returns its 1-based
+/// occurrence number.
+pub(crate) fn occurrence(key: u64) -> usize {
+    let mut occurrences = OCCURRENCES.lock().unwrap_or_else(|t| t.into_inner());
+    let count = occurrences.get_or_insert_with(HashMap::new).entry(key).or_default();
+    *count += 1;
+    *count
+}
+
+const WHAT: &str = "dedup file";
+
+/// Claims the expansion with the given key for the call site. Returns `None` if the call site is
+/// the first one emitting the expansion in the build, or the first call site otherwise. Entries of
+/// other builds are dropped. The file is locked for the whole update, as macros are expanded by
+/// many compiler processes in parallel. If the lock is not acquired in time, the expansion is
+/// emitted and recorded in a side file, so it is claimed once the side file is merged.
+pub(crate) fn claim(
+    path: &Path,
+    build_id: u128,
+    key: u64,
+    call_site: &str,
+) -> Result<Option<String>> {
+    let entry = Entry { build_id, key, call_site: call_site.to_string() };
+    let Some(mut file) = shared_file::lock(path, WHAT, LOCK_TIMEOUT)? else {
+        shared_file::append_to_side_file(path, WHAT, &entry.to_line())?;
+        return Ok(None)
+    };
+    let content = shared_file::read(&mut file, path, WHAT)?;
+    let entries = content.lines().filter_map(Entry::parse).collect::<Vec<_>>();
+    let current = entries.iter().filter(|t| t.build_id == build_id).collect::<Vec<_>>();
+    if let Some(first) = current.iter().find(|t| t.key == key) {
+        let other = (first.call_site != call_site).then(|| first.call_site.clone());
+        return Ok(other)
+    }
+    if current.len() != entries.len() {
+        let kept = current.iter().map(|t| t.to_line()).collect::<String>();
+        shared_file::replace(&mut file, path, WHAT, &kept)?;
+    }
+    shared_file::append(&mut file, path, WHAT, &entry.to_line())
+        .map(|_| None)
+}
+
+// =============
+// === Tests ===
+// =============
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_round_trip() {
+        let entry = Entry { build_id: 12, key: 0xabc, call_site: "src/a.rs:3\tx".to_string() };
+        assert_eq!(Entry::parse(entry.to_line().trim_end()), Some(entry));
+        assert_eq!(Entry::parse("12\tnot-hex\tsrc/a.rs:3"), None);
+    }
+
+    #[test]
+    fn occurrences_are_counted_per_key() {
+        assert_eq!((occurrence(u64::MAX), occurrence(u64::MAX)), (1, 2));
+        assert_eq!(occurrence(u64::MAX - 1), 1);
+    }
+
+    #[test]
+    fn first_call_site_wins() {
+        let path = std::env::temp_dir()
+            .join(format!("crabtime_dedup_test_{}", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let claim = |build_id, key, call_site| claim(&path, build_id, key, call_site).ok();
+        assert_eq!(claim(1, 7, "a.rs:1"), Some(None));
+        // Re-expanding the first call site, e.g. in the test build of the crate, emits it again.
+        assert_eq!(claim(1, 7, "a.rs:1"), Some(None));
+        assert_eq!(claim(1, 7, "b.rs:2"), Some(Some("a.rs:1".to_string())));
+        assert_eq!(claim(1, 8, "b.rs:2"), Some(None));
+        // A new build forgets the expansions of the previous one.
+        assert_eq!(claim(2, 7, "b.rs:2"), Some(None));
+        assert_eq!(claim(2, 7, "a.rs:1"), Some(Some("b.rs:2".to_string())));
+        let lines = std::fs::read_to_string(&path).map(|t| t.lines().count()).ok();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(lines, Some(1));
+    }
+}
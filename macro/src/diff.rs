@@ -0,0 +1,102 @@
+// ============
+// === Diff ===
+// ============
+
+/// Number of unchanged lines shown around the changed ones.
+const CONTEXT_LINES: usize = 3;
+
+/// Unified diff of two texts, or `None` if they are equal. The diff consists of a single hunk
+/// spanning from the first to the last changed line, which is enough to spot the difference
+/// without computing a minimal diff of potentially large outputs. At most `max_lines` changed
+/// lines are shown.
+pub(crate) fn unified_diff(old: &str, new: &str, max_lines: usize) -> Option<String> {
+    if old == new {
+        return None
+    }
+    let old_lines = old.lines().collect::<Vec<_>>();
+    let new_lines = new.lines().collect::<Vec<_>>();
+    let prefix = old_lines.iter().zip(&new_lines).take_while(|(a, b)| a == b).count();
+    let suffix = old_lines[prefix..].iter().rev().zip(new_lines[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let start = prefix.saturating_sub(CONTEXT_LINES);
+    let old_end = (old_lines.len() - suffix + CONTEXT_LINES).min(old_lines.len());
+    let new_end = (new_lines.len() - suffix + CONTEXT_LINES).min(new_lines.len());
+
+    let mut changes = Vec::new();
+    changes.extend(old_lines[prefix..old_lines.len() - suffix].iter().map(|t| format!("-{t}")));
+    changes.extend(new_lines[prefix..new_lines.len() - suffix].iter().map(|t| format!("+{t}")));
+    let hidden = changes.len().saturating_sub(max_lines);
+    changes.truncate(max_lines);
+    if hidden > 0 {
+        changes.push(format!("... ({hidden} more changed lines)"));
+    }
+
+    let mut out = vec![
+        "--- cached".to_string(),
+        "+++ fresh".to_string(),
+        format!("@@ -{} +{} @@", range(start, old_end), range(start, new_end)),
+    ];
+    out.extend(old_lines[start..prefix].iter().map(|t| format!(" {t}")));
+    out.extend(changes);
+    out.extend(old_lines[old_lines.len() - suffix..old_end].iter().map(|t| format!(" {t}")));
+    Some(out.join("\n"))
+}
+
+/// Hunk range in the unified diff format, with 1-based line numbers.
+fn range(start: usize, end: usize) -> String {
+    match end - start {
+        0 => format!("{start},0"),
+        1 => format!("{}", start + 1),
+        len => format!("{},{len}", start + 1),
+    }
+}
+
+// =============
+// === Tests ===
+// =============
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn equal_texts_have_no_diff() {
+        assert_eq!(unified_diff("a\nb", "a\nb", 10), None);
+    }
+
+    #[test]
+    fn changed_line_with_context() {
+        let old = "1\n2\n3\n4\n5\n6\n7\n8\n9";
+        let new = "1\n2\n3\n4\nfive\n6\n7\n8\n9";
+        assert_eq!(unified_diff(old, new, 10).as_deref(), Some(
+            "--- cached\n+++ fresh\n@@ -2,7 +2,7 @@\n 2\n 3\n 4\n-5\n+five\n 6\n 7\n 8"
+        ));
+    }
+
+    #[test]
+    fn added_and_removed_lines() {
+        assert_eq!(unified_diff("a", "a\nb", 10).as_deref(), Some(
+            "--- cached\n+++ fresh\n@@ -1 +1,2 @@\n a\n+b"
+        ));
+        assert_eq!(unified_diff("a\nb", "", 10).as_deref(), Some(
+            "--- cached\n+++ fresh\n@@ -1,2 +0,0 @@\n-a\n-b"
+        ));
+    }
+
+    #[test]
+    fn long_diffs_are_truncated() {
+        let old = (0..10).map(|i| format!("const A{i}: usize = {i};")).collect::<Vec<_>>();
+        let new = (0..10).map(|i| format!("const A{i}: usize = {};", i + 1)).collect::<Vec<_>>();
+        let diff = unified_diff(&old.join("\n"), &new.join("\n"), 3).unwrap_or_default();
+        assert_eq!(diff.lines().collect::<Vec<_>>(), [
+            "--- cached",
+            "+++ fresh",
+            "@@ -1,10 +1,10 @@",
+            "-const A0: usize = 0;",
+            "-const A1: usize = 1;",
+            "-const A2: usize = 2;",
+            "... (17 more changed lines)",
+        ]);
+    }
+}
@@ -1,4 +1,6 @@
 use std::fmt::Debug;
+use std::io::ErrorKind;
+use std::path::Path;
 use proc_macro2::Span;
 use proc_macro2::TokenStream;
 
@@ -6,19 +8,21 @@ use proc_macro2::TokenStream;
 // === Level ===
 // =============
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub(crate) enum Level {
+    Note,
     Warning,
     Error,
 }
 
 impl Level {
+    pub const NOTE_PREFIX: &'static str = "[NOTE]";
     pub const WARNING_PREFIX: &'static str = "[WARNING]";
     pub const ERROR_PREFIX: &'static str = "[ERROR]";
 
-    #[cfg(not(nightly))]
     fn prefix(&self) -> &str {
         match self {
+            Level::Note => Self::NOTE_PREFIX,
             Level::Warning => Self::WARNING_PREFIX,
             Level::Error => Self::ERROR_PREFIX,
         }
@@ -29,26 +33,50 @@ impl Level {
 impl From<Level> for proc_macro::Level {
     fn from(level: Level) -> Self {
         match level {
+            Level::Note => proc_macro::Level::Note,
             Level::Warning => proc_macro::Level::Warning,
             Level::Error => proc_macro::Level::Error,
         }
     }
 }
 
-pub(crate) fn print(level: Level, message: &str) {
-    #[cfg(nightly)] {
-        let span = proc_macro::Span::call_site();
+/// Prints the message pointing to the span, or to the macro call site if no span is provided.
+pub(crate) fn print(level: Level, span: Option<Span>, message: &str) {
+    #[cfg(nightly)]
+    if proc_macro::is_available() {
+        // SAFETY: This unwrap is safe in proc macros.
+        let span = span.map_or_else(proc_macro::Span::call_site, |t| t.unwrap());
         proc_macro::Diagnostic::spanned(span, level.into(), message).emit();
+        return;
     }
-    #[cfg(not(nightly))] {
-        println!("{} {message}", level.prefix());
-    }
+    println!("{}", format_message(level, span, message));
+}
+
+/// Diagnostics can't be emitted on stable or outside of a proc macro (e.g. in unit tests), so the
+/// message is printed with the span location.
+fn format_message(level: Level, span: Option<Span>, message: &str) -> String {
+    let location = span.and_then(location).map(|t| format!("{t}: ")).unwrap_or_default();
+    format!("{} {location}{message}", level.prefix())
+}
+
+/// The `file:line:column` location of the span start, or `None` if it is unknown.
+pub(crate) fn location(span: Span) -> Option<String> {
+    let start = span.start();
+    (start.line > 0).then(|| format!("{}:{}:{}", span.file(), start.line, start.column + 1))
+}
+
+macro_rules! print_issue {
+    ($l:expr,          $s:literal $($t:tt)*) => { print($l, None,     &format!($s $($t)*)) };
+    ($l:expr, $e:expr, $s:literal $($t:tt)*) => { print($l, Some($e), &format!($s $($t)*)) };
 }
 
 macro_rules! debug         { ($($ts:tt)*) => { if DEBUG { println!( $($ts)* )}  }; }
-macro_rules! print_warning { ($($ts:tt)*) => { print (Level::Warning, &format!( $($ts)* )); }; }
-macro_rules! print_error   { ($($ts:tt)*) => { print (Level::Error,   &format!( $($ts)* )); }; }
+macro_rules! print_note    { ($($ts:tt)*) => { print_issue!(Level::Note,    $($ts)*) }; }
+macro_rules! print_warning { ($($ts:tt)*) => { print_issue!(Level::Warning, $($ts)*) }; }
+macro_rules! print_error   { ($($ts:tt)*) => { print_issue!(Level::Error,   $($ts)*) }; }
+pub(crate) use print_issue;
 pub(crate) use debug;
+pub(crate) use print_note;
 pub(crate) use print_warning;
 pub(crate) use print_error;
 
@@ -59,15 +87,14 @@ pub(crate) use print_error;
 pub(crate) type Result<T=(), E=Issue> = std::result::Result<T, E>;
 
 pub(crate) struct Issue {
-    pub level: Level,
     pub span: Option<Span>,
     pub message: String,
     pub context: Option<Box<Issue>>,
 }
 
 impl Issue {
-    pub fn msg(level: Level, span: Option<Span>, message: String) -> Self {
-        Self { level, span, message, context: None }
+    pub fn msg(span: Option<Span>, message: String) -> Self {
+        Self { span, message, context: None }
     }
 
     pub fn context(mut self, f: impl FnOnce() -> Issue) -> Self {
@@ -83,15 +110,6 @@ impl Issue {
         }
     }
 
-    #[cfg(nightly)]
-    pub fn emit(&self) {
-        // SAFETY: This unwrap is safe in proc macros.
-        let span = self.span.unwrap_or_else(Span::call_site).unwrap();
-        let level = self.level.into();
-        let message = self.message_with_cause();
-        proc_macro::Diagnostic::spanned(span, level, message).emit();
-    }
-
     // This is a hack to make compile errors with spans on stable.
     // Source: https://stackoverflow.com/questions/54392702/how-to-report-errors-in-a-procedural-macro-using-the-quote-macro
     pub fn compile_error(&self) -> TokenStream {
@@ -103,27 +121,23 @@ impl Issue {
 
 impl<E: Debug> From<E> for Issue {
     fn from(e: E) -> Self {
-        Self::msg(Level::Error, None, format!("{e:?}"))
+        Self::msg(None, format!("{e:?}"))
     }
 }
 
-macro_rules! issue   {
-    ($l:expr,          $s:literal           ) => { Issue::msg($l, None,     format!($s)) };
-    ($l:expr,          $s:expr              ) => { Issue::msg($l, None,     format!("{}", $s)) };
-    ($l:expr,          $s:literal, $($t:tt)*) => { Issue::msg($l, None,     format!($s, $($t)*)) };
-    ($l:expr, $e:expr, $s:literal           ) => { Issue::msg($l, Some($e), format!($s)) };
-    ($l:expr, $e:expr, $s:expr              ) => { Issue::msg($l, Some($e), format!("{}", $s)) };
-    ($l:expr, $e:expr, $s:literal, $($t:tt)*) => { Issue::msg($l, Some($e), format!($s, $($t)*)) };
-    ($l:expr, $e:expr,             $($t:tt)*) => { Issue::msg($l, Some($e), format!($($t)*)) };
-    ($l:expr,                      $($t:tt)*) => { Issue::msg($l, None,     format!($($t)*)) };
+macro_rules! error   {
+    (         $s:literal           ) => { Issue::msg(None,     format!($s)) };
+    (         $s:expr              ) => { Issue::msg(None,     format!("{}", $s)) };
+    (         $s:literal, $($t:tt)*) => { Issue::msg(None,     format!($s, $($t)*)) };
+    ($e:expr, $s:literal           ) => { Issue::msg(Some($e), format!($s)) };
+    ($e:expr, $s:expr              ) => { Issue::msg(Some($e), format!("{}", $s)) };
+    ($e:expr, $s:literal, $($t:tt)*) => { Issue::msg(Some($e), format!($s, $($t)*)) };
+    ($e:expr,             $($t:tt)*) => { Issue::msg(Some($e), format!($($t)*)) };
+    (                     $($t:tt)*) => { Issue::msg(None,     format!($($t)*)) };
 }
 
-macro_rules! error   { ($($ts:tt)*) => { issue! { Level::Error,   $($ts)* }}; }
-macro_rules! warning { ($($ts:tt)*) => { issue! { Level::Warning, $($ts)* }}; }
 macro_rules! err     { ($($ts:tt)*) => { Err(error!($($ts)*)) }; }
-pub(crate) use issue;
 pub(crate) use error;
-pub(crate) use warning;
 pub(crate) use err;
 
 // ===============
@@ -173,6 +187,43 @@ impl<T> Context<T, &'static str> for Option<T> {
     }
 }
 
+// =================
+// === IoContext ===
+// =================
+
+pub(crate) trait IoContext<T> {
+    /// Reports the failed filesystem operation, like `"write"`, together with the path, the OS
+    /// error, and a hint for the errors users can fix themselves.
+    fn with_path(self, operation: &str, path: &Path) -> Result<T>;
+}
+
+impl<T> IoContext<T> for std::io::Result<T> {
+    fn with_path(self, operation: &str, path: &Path) -> Result<T> {
+        self.map_err(|e| error!("{}", io_error_message(operation, path, &e)))
+    }
+}
+
+pub(crate) fn io_error_message(operation: &str, path: &Path, error: &std::io::Error) -> String {
+    let message = format!("Failed to {operation} '{}': {error}.", path.display());
+    let hint = match error.kind() {
+        ErrorKind::StorageFull | ErrorKind::QuotaExceeded => Some(
+            "The disk is full. Crabtime projects are stored in the 'build/crabtime' directory of \
+            the Cargo target directory. Free up space there, or point 'CARGO_TARGET_DIR' to a \
+            'target' directory on a larger disk."
+        ),
+        ErrorKind::PermissionDenied | ErrorKind::ReadOnlyFilesystem => Some(
+            "Crabtime needs write access to the 'build/crabtime' directory of the Cargo target \
+            directory. Check the permissions of the directory, or point 'CARGO_TARGET_DIR' to a \
+            writable 'target' directory."
+        ),
+        _ => None,
+    };
+    match hint {
+        Some(hint) => format!("{message}\n{hint}"),
+        None => message,
+    }
+}
+
 // ==============
 // === Unwrap ===
 // ==============
@@ -186,3 +237,46 @@ impl Unwrap for Result<TokenStream, Issue> {
         self.unwrap_or_else(|e| e.compile_error())
     }
 }
+
+// =============
+// === Tests ===
+// =============
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn second_token_span() -> Option<Span> {
+        let tokens = "serde\n  = \"1\"".parse::<TokenStream>().ok()?;
+        tokens.into_iter().nth(1).map(|t| t.span())
+    }
+
+    #[test]
+    fn span_location() {
+        let location = second_token_span().and_then(location);
+        assert!(location.is_some_and(|t| t.ends_with(":2:3")));
+    }
+
+    #[test]
+    fn io_errors_contain_path_and_hint() {
+        let path = Path::new("target/debug/build/crabtime/project/Cargo.toml");
+        let full = io_error_message("write", path, &ErrorKind::StorageFull.into());
+        assert!(full.starts_with("Failed to write 'target/debug/build/crabtime/project/Cargo.toml'"));
+        assert!(full.contains("CARGO_TARGET_DIR"));
+        let denied = io_error_message("create directory", path, &ErrorKind::PermissionDenied.into());
+        assert!(denied.contains("write access"));
+        let other = io_error_message("read", path, &ErrorKind::NotFound.into());
+        assert_eq!(other.lines().count(), 1);
+    }
+
+    #[test]
+    fn stable_messages_contain_location() {
+        let span = second_token_span();
+        let location = span.and_then(location).unwrap_or_default();
+        assert_eq!(
+            format_message(Level::Warning, span, "Message."),
+            format!("[WARNING] {location}: Message.")
+        );
+        assert_eq!(format_message(Level::Error, None, "Message."), "[ERROR] Message.");
+    }
+}
@@ -0,0 +1,218 @@
+use crate::error::*;
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::path::Path;
+
+// ==============
+// === Inputs ===
+// ==============
+
+/// External inputs which influenced an expansion, for build systems wrapping cargo which need to
+/// know when to expand the macro again.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Inputs {
+    /// Files read by the expansion, with the hashes of their contents.
+    pub files: Vec<(String, u64)>,
+    /// Environment variables read by the expansion, with their values, `None` if not set.
+    pub env: Vec<(String, Option<String>)>,
+    /// Hash of the generated project configuration and code.
+    pub config_fingerprint: u64,
+}
+
+impl Inputs {
+    /// The metadata of the expansion, in the format:
+    ///
+    /// ```json
+    /// {
+    ///   "macro": "my_macro",
+    ///   "inputs": {
+    ///     "files": [{ "path": "/my_crate/Cargo.toml", "hash": "0123456789abcdef" }],
+    ///     "env": [{ "name": "CRABTIME_JOBS", "value": null }],
+    ///     "config_fingerprint": "0123456789abcdef"
+    ///   }
+    /// }
+    /// ```
+    pub fn to_json(&self, name: &str) -> String {
+        let files = self.files.iter()
+            .map(|(path, hash)| {
+                format!("{{ \"path\": {}, \"hash\": \"{hash:016x}\" }}", quote(path))
+            })
+            .collect::<Vec<_>>();
+        let env = self.env.iter()
+            .map(|(name, value)| {
+                let value = value.as_deref().map_or_else(|| "null".to_string(), quote);
+                format!("{{ \"name\": {}, \"value\": {value} }}", quote(name))
+            })
+            .collect::<Vec<_>>();
+        let list = |items: Vec<String>| if items.is_empty() {
+            "[]".to_string()
+        } else {
+            format!("[\n      {}\n    ]", items.join(",\n      "))
+        };
+        format!(
+            "{{\n  \"macro\": {},\n  \"inputs\": {{\n    \"files\": {},\n    \"env\": {},\n    \
+            \"config_fingerprint\": \"{:016x}\"\n  }}\n}}\n",
+            quote(name), list(files), list(env), self.config_fingerprint
+        )
+    }
+
+    /// Writes the metadata to the file, replacing it atomically, so external tools never read a
+    /// partially written file.
+    pub fn write(&self, name: &str, path: &Path) -> Result {
+        let dir = path.parent().context(|| error!("Path '{}' has no parent.", path.display()))?;
+        std::fs::create_dir_all(dir).with_path("create the metadata dir", dir)?;
+        let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+        std::fs::write(&tmp, self.to_json(name)).with_path("write the metadata file", &tmp)?;
+        std::fs::rename(&tmp, path).with_path("replace the metadata file", path)
+    }
+}
+
+/// Quotes the string as a JSON string.
+fn quote(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+pub(crate) fn hash(value: impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Offset basis of the 128-bit FNV-1a hash.
+pub(crate) const FNV_OFFSET_BASIS: u128 = 0x6c62_272e_07bb_0142_62b8_2175_6295_c58d;
+/// Prime of the 128-bit FNV-1a hash.
+pub(crate) const FNV_PRIME: u128 = 0x0000_0000_0100_0000_0000_0000_0000_013b;
+
+/// The 128-bit FNV-1a hash of the bytes. Unlike [`hash`], whose algorithm can change between Rust
+/// releases, it is stable, so it is used for the names of directories kept across builds, which
+/// would be orphaned by toolchain updates otherwise.
+pub(crate) fn stable_hash(bytes: &[u8]) -> u128 {
+    bytes.iter()
+        .fold(FNV_OFFSET_BASIS, |hash, byte| (hash ^ u128::from(*byte)).wrapping_mul(FNV_PRIME))
+}
+
+// =================
+// === Recording ===
+// =================
+
+thread_local! {
+    /// Inputs read by the current expansion. Expansions of a thread run one after another.
+    static RECORDED: RefCell<Inputs> = RefCell::default();
+}
+
+/// Starts recording the inputs of a new expansion.
+pub(crate) fn begin() {
+    RECORDED.with_borrow_mut(|t| *t = Inputs::default());
+}
+
+/// Returns the inputs recorded since [`begin`].
+pub(crate) fn finish(config_fingerprint: u64) -> Inputs {
+    let recorded = RECORDED.with_borrow_mut(std::mem::take);
+    Inputs { config_fingerprint, ..recorded }
+}
+
+/// Reads the environment variable, recording it as an input of the expansion.
+pub(crate) fn var(name: &str) -> Option<String> {
+    let value = std::env::var(name).ok();
+    RECORDED.with_borrow_mut(|t| if !t.env.iter().any(|(n, _)| n == name) {
+        t.env.push((name.to_string(), value.clone()));
+    });
+    value
+}
+
+/// Reads the file, recording it as an input of the expansion.
+pub(crate) fn read_file(path: &Path) -> std::io::Result<String> {
+    let content = std::fs::read_to_string(path)?;
+    let path = path.display().to_string();
+    let content_hash = hash(&content);
+    RECORDED.with_borrow_mut(|t| match t.files.iter_mut().find(|(p, _)| *p == path) {
+        Some(file) => file.1 = content_hash,
+        None => t.files.push((path, content_hash)),
+    });
+    Ok(content)
+}
+
+// =============
+// === Tests ===
+// =============
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recorded_inputs_match_reads() {
+        let path = std::env::temp_dir()
+            .join(format!("crabtime_inputs_test_{}", std::process::id()));
+        let written = std::fs::write(&path, "[package]").ok();
+        begin();
+        let content = read_file(&path).ok();
+        read_file(&path).ok();
+        var("CRABTIME_INPUTS_TEST_UNSET");
+        var("CRABTIME_INPUTS_TEST_UNSET");
+        let path_var = var("PATH");
+        let inputs = finish(7);
+        let json_path = path.with_extension("json");
+        let json_written = inputs.write("my_macro", &json_path).ok();
+        let json = std::fs::read_to_string(&json_path).ok();
+        std::fs::remove_file(&path).ok();
+        std::fs::remove_file(&json_path).ok();
+        assert_eq!(written, Some(()));
+        assert_eq!(content.as_deref(), Some("[package]"));
+        assert_eq!(inputs.files, [(path.display().to_string(), hash("[package]"))]);
+        assert_eq!(inputs.env, [
+            ("CRABTIME_INPUTS_TEST_UNSET".to_string(), None),
+            ("PATH".to_string(), path_var),
+        ]);
+        assert_eq!(finish(0), Inputs::default());
+        assert_eq!(json_written, Some(()));
+        assert_eq!(json, Some(inputs.to_json("my_macro")));
+    }
+
+    #[test]
+    fn stable_hash_values() {
+        assert_eq!(stable_hash(b""), 0x6c62_272e_07bb_0142_62b8_2175_6295_c58d);
+        assert_eq!(stable_hash(b"a"), 0xd228_cb69_6f1a_8caf_7891_2b70_4e4a_8964);
+        assert_eq!(stable_hash(b"fn main() {}"), 0x4ed6_3e19_bcdc_95bb_861c_2f7a_2fe1_1dff);
+    }
+
+    #[test]
+    fn json_format() {
+        let inputs = Inputs {
+            files: vec![("C:\\my \"crate\"\\Cargo.toml".to_string(), 0xab)],
+            env: vec![("A".to_string(), Some("1\n".to_string())), ("B".to_string(), None)],
+            config_fingerprint: 0xcd,
+        };
+        assert_eq!(inputs.to_json("gen"), r#"{
+  "macro": "gen",
+  "inputs": {
+    "files": [
+      { "path": "C:\\my \"crate\"\\Cargo.toml", "hash": "00000000000000ab" }
+    ],
+    "env": [
+      { "name": "A", "value": "1\n" },
+      { "name": "B", "value": null }
+    ],
+    "config_fingerprint": "00000000000000cd"
+  }
+}
+"#);
+        assert!(Inputs::default().to_json("gen").contains("\"files\": [],\n    \"env\": []"));
+    }
+}
@@ -0,0 +1,198 @@
+use std::iter::Peekable;
+use std::str::Chars;
+
+// ============
+// === JSON ===
+// ============
+
+/// A parsed JSON value. Only reading is supported, as needed for the messages cargo prints with
+/// `--message-format=json`. Numbers are kept as written.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Value {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// The field of the object, or `None` if the value is not an object or has no such field.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Self::Object(fields) => fields.iter().find(|(name, _)| name == key).map(|(_, t)| t),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+/// Parses the JSON document, returning `None` if it is malformed.
+pub(crate) fn parse(input: &str) -> Option<Value> {
+    let mut chars = input.chars().peekable();
+    let value = parse_value(&mut chars)?;
+    skip_whitespace(&mut chars);
+    chars.peek().is_none().then_some(value)
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.next_if(|c| c.is_ascii_whitespace()).is_some() {}
+}
+
+fn parse_value(chars: &mut Peekable<Chars>) -> Option<Value> {
+    skip_whitespace(chars);
+    match chars.peek()? {
+        '{' => parse_object(chars),
+        '[' => parse_array(chars),
+        '"' => parse_string(chars).map(Value::String),
+        't' => parse_keyword(chars, "true", Value::Bool(true)),
+        'f' => parse_keyword(chars, "false", Value::Bool(false)),
+        'n' => parse_keyword(chars, "null", Value::Null),
+        _ => parse_number(chars),
+    }
+}
+
+fn parse_keyword(chars: &mut Peekable<Chars>, keyword: &str, value: Value) -> Option<Value> {
+    keyword.chars().all(|c| chars.next() == Some(c)).then_some(value)
+}
+
+fn parse_number(chars: &mut Peekable<Chars>) -> Option<Value> {
+    let mut number = String::new();
+    while let Some(c) = chars.next_if(|c| c.is_ascii_digit() || "+-.eE".contains(*c)) {
+        number.push(c);
+    }
+    (!number.is_empty()).then_some(Value::Number(number))
+}
+
+fn parse_string(chars: &mut Peekable<Chars>) -> Option<String> {
+    if chars.next()? != '"' {
+        return None
+    }
+    let mut value = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(value),
+            '\\' => match chars.next()? {
+                'n' => value.push('\n'),
+                't' => value.push('\t'),
+                'r' => value.push('\r'),
+                'b' => value.push('\u{8}'),
+                'f' => value.push('\u{c}'),
+                'u' => value.push(parse_unicode_escape(chars)?),
+                c => value.push(c),
+            },
+            c => value.push(c),
+        }
+    }
+}
+
+/// The character of a `\uXXXX` escape, whose `\u` was already consumed. Characters outside of the
+/// Basic Multilingual Plane are escaped as surrogate pairs, like `🦀`.
+fn parse_unicode_escape(chars: &mut Peekable<Chars>) -> Option<char> {
+    let code = parse_hex_code(chars)?;
+    if !(0xD800..0xDC00).contains(&code) {
+        return char::from_u32(code)
+    }
+    if chars.next()? != '\\' || chars.next()? != 'u' {
+        return None
+    }
+    let low = parse_hex_code(chars)?.checked_sub(0xDC00).filter(|t| *t < 0x400)?;
+    char::from_u32(0x10000 + ((code - 0xD800) << 10) + low)
+}
+
+fn parse_hex_code(chars: &mut Peekable<Chars>) -> Option<u32> {
+    let hex = (0..4).map(|_| chars.next()).collect::<Option<String>>()?;
+    u32::from_str_radix(&hex, 16).ok()
+}
+
+fn parse_array(chars: &mut Peekable<Chars>) -> Option<Value> {
+    chars.next();
+    let mut items = Vec::new();
+    skip_whitespace(chars);
+    if chars.next_if_eq(&']').is_some() {
+        return Some(Value::Array(items))
+    }
+    loop {
+        items.push(parse_value(chars)?);
+        skip_whitespace(chars);
+        match chars.next()? {
+            ',' => {}
+            ']' => return Some(Value::Array(items)),
+            _ => return None,
+        }
+    }
+}
+
+fn parse_object(chars: &mut Peekable<Chars>) -> Option<Value> {
+    chars.next();
+    let mut fields = Vec::new();
+    skip_whitespace(chars);
+    if chars.next_if_eq(&'}').is_some() {
+        return Some(Value::Object(fields))
+    }
+    loop {
+        skip_whitespace(chars);
+        let key = parse_string(chars)?;
+        skip_whitespace(chars);
+        if chars.next()? != ':' {
+            return None
+        }
+        fields.push((key, parse_value(chars)?));
+        skip_whitespace(chars);
+        match chars.next()? {
+            ',' => {}
+            '}' => return Some(Value::Object(fields)),
+            _ => return None,
+        }
+    }
+}
+
+// =============
+// === Tests ===
+// =============
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cargo_message_parsed() {
+        let line = r#"{"reason":"compiler-message","message":{"level":"warning","spans":[],
+            "rendered":"\u001b[33mwarning\u001b[0m: unused \"x\"\n","code":null,"n":-1.5e3}}"#;
+        let value = parse(line);
+        let message = value.as_ref().and_then(|t| t.get("message"));
+        assert_eq!(value.as_ref().and_then(|t| t.get("reason")).and_then(Value::as_str),
+            Some("compiler-message"));
+        assert_eq!(message.and_then(|t| t.get("rendered")).and_then(Value::as_str),
+            Some("\u{1b}[33mwarning\u{1b}[0m: unused \"x\"\n"));
+        assert_eq!(message.and_then(|t| t.get("spans")), Some(&Value::Array(vec![])));
+        assert_eq!(message.and_then(|t| t.get("code")), Some(&Value::Null));
+        assert_eq!(message.and_then(|t| t.get("n")), Some(&Value::Number("-1.5e3".to_string())));
+    }
+
+    #[test]
+    fn escapes_and_nesting() {
+        assert_eq!(parse(r#""🦀 \\ \/""#), Some(Value::String("🦀 \\ /".to_string())));
+        assert_eq!(parse(r#""\ud83e\udd80 \u00e9""#), Some(Value::String("🦀 é".to_string())));
+        assert_eq!(parse("[true, [null], {}]"), Some(Value::Array(vec![
+            Value::Bool(true), Value::Array(vec![Value::Null]), Value::Object(vec![])
+        ])));
+    }
+
+    #[test]
+    fn malformed_documents_rejected() {
+        assert_eq!(parse("Compiling gen v1.0.0"), None);
+        assert_eq!(parse(r#"{"a": 1"#), None);
+        assert_eq!(parse(r#"{"a" 1}"#), None);
+        assert_eq!(parse("[1,]"), None);
+        assert_eq!(parse("{} {}"), None);
+        assert_eq!(parse(""), None);
+    }
+}
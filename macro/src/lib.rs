@@ -5,8 +5,29 @@
 #![cfg_attr(not(nightly), allow(unused_macros))]
 #![cfg_attr(not(nightly), allow(unused_imports))]
 
+mod dedup;
+mod diff;
 mod error;
+mod inputs;
+mod json;
 mod path;
+mod process;
+mod registry;
+mod shared_file;
+mod snapshot;
+mod summary;
+#[cfg(test)]
+mod temp_dir;
+
+// Embedded in the generated prelude as source, compiled here only to be tested.
+#[cfg(test)]
+mod case;
+#[cfg(test)]
+mod code;
+#[cfg(test)]
+mod literal;
+#[cfg(test)]
+mod snippet;
 
 use error::*;
 
@@ -20,14 +41,11 @@ use quote::ToTokens;
 use quote::quote;
 use std::fs::File;
 use std::fs;
-use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
 use std::process::Command;
 use std::default::Default;
-use std::collections::hash_map::DefaultHasher;
-use std::hash::Hash;
-use std::hash::Hasher;
+use std::collections::HashSet;
 
 // =================
 // === Constants ===
@@ -40,25 +58,163 @@ const CRATE: &str = "crabtime";
 /// Module with utils functions in the generated project.
 const GEN_MOD: &str = CRATE;
 const DEFAULT_EDITION: &str = "2024";
-const DEFAULT_RESOLVER: &str = "3";
+const EDITIONS: &[&str] = &["2015", "2018", "2021", "2024"];
+/// Name of the generated package if the macro name cannot be used.
+const DEFAULT_PACKAGE_NAME: &str = "eval_project";
+/// Cargo profile settings which can be set with the `profile_settings` attribute.
+const PROFILE_SETTINGS: &[&str] = &[
+    "opt-level", "debug", "split-debuginfo", "strip", "debug-assertions", "overflow-checks", "lto",
+    "panic", "incremental", "codegen-units", "rpath",
+];
+/// Names cargo rejects or warns about, as they collide with the built-in crates or the directories
+/// of the build.
+const RESERVED_PACKAGE_NAMES: &[&str] = &[
+    "test", "std", "core", "alloc", "proc_macro", "deps", "examples", "build", "incremental",
+];
+/// Keywords reserved in later editions, with the edition reserving them.
+const EDITION_KEYWORDS: &[(&str, &str)] = &[
+    ("async", "2018"), ("await", "2018"), ("dyn", "2018"), ("try", "2018"), ("gen", "2024"),
+];
+const RESOLVERS: &[&str] = &["1", "2", "3"];
 const OUTPUT_PREFIX: &str = "[OUTPUT]";
+/// Line printed by the macro body if its output must not be reused, e.g. because it is random.
+const NON_REUSABLE_MARKER: &str = "[NON_REUSABLE]";
+/// Prefix of the lines of notices, reported at most once per build.
+const NOTICE_PREFIX: &str = "[NOTICE]";
+/// Environment variable passing the id of the current build to the macro body.
+const BUILD_ID_ENV: &str = "CRABTIME_BUILD_ID";
+/// Environment variable passing the directory of the `phase_cache!` results to the macro body.
+const PHASE_CACHE_DIR_ENV: &str = "CRABTIME_PHASE_CACHE_DIR";
 const OUT_DIR: &str = env!("OUT_DIR");
+/// Environment variable providing the default for the `max_output` macro option.
+const MAX_OUTPUT_ENV: &str = "CRABTIME_MAX_OUTPUT";
+/// Output size (in bytes) above which a warning is emitted, unless `max_output_warn` is provided.
+const DEFAULT_MAX_OUTPUT_WARN: usize = 10 * 1024 * 1024;
+/// Environment variable cargo sets to the path of its binary, so that nested builds can use it.
+const CARGO_ENV: &str = "CARGO";
+/// Environment variable with the path of the compiler, set by custom toolchains and build systems.
+const RUSTC_ENV: &str = "RUSTC";
+/// Environment variable with extra flags appended to the nested cargo invocation.
+const CARGO_FLAGS_ENV: &str = "CRABTIME_CARGO_FLAGS";
+/// Environment variables of the outer build which are not passed to the nested cargo invocation.
+const WRAPPER_ENVS: &[&str] = &["RUSTC_WRAPPER", "RUSTC_WORKSPACE_WRAPPER"];
+/// Environment variable limiting the number of parallel jobs of the nested cargo invocation.
+const JOBS_ENV: &str = "CRABTIME_JOBS";
+/// Cargo's environment variable limiting the number of parallel jobs, used if `CRABTIME_JOBS` is
+/// not set.
+const CARGO_JOBS_ENV: &str = "CARGO_BUILD_JOBS";
+/// Environment variable enabling the verification of outputs of cached projects against fresh
+/// builds. It doubles the expansion time, so it is meant for CI and debugging.
+const VERIFY_CACHE_ENV: &str = "CRABTIME_VERIFY_CACHE";
+/// Maximum number of changed lines shown when the cache verification fails.
+const VERIFY_CACHE_DIFF_LINES: usize = 40;
+/// Default number of retries of a nested build which failed with a transient error.
+const DEFAULT_RETRIES: usize = 1;
+/// Delay before the first retry of a failed nested build, doubled before every next retry.
+const RETRY_BACKOFF: std::time::Duration = std::time::Duration::from_secs(1);
+/// Environment variable providing the default for the `timeout_secs` macro option.
+const TIMEOUT_ENV: &str = "CRABTIME_TIMEOUT_SECS";
+/// Environment variable enabling warnings listing the setup required by crates invoking exported
+/// macros.
+const CHECK_EXPORT_ENV: &str = "CRABTIME_CHECK_EXPORT";
+/// Environment variable setting the number of days after which unused projects are removed.
+const MAX_PROJECT_AGE_ENV: &str = "CRABTIME_MAX_PROJECT_AGE";
+/// Number of days after which unused projects are removed, unless `CRABTIME_MAX_PROJECT_AGE` is set.
+const DEFAULT_MAX_PROJECT_AGE_DAYS: u64 = 14;
+/// Time an expansion waits for a cached project used by another expansion, before building the
+/// macro in a one-shot project instead.
+const PROJECT_LOCK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(120);
+/// Interval of checking whether a cached project used by another expansion was released.
+const PROJECT_LOCK_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(50);
+/// Number of retries of removing a project directory which could not be removed.
+const REMOVE_RETRIES: usize = 3;
+/// Delay before the first retry of removing a project directory, doubled before every next retry.
+const REMOVE_RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(10);
+/// Suffix of the project directories which could not be removed and are removed by later
+/// expansions.
+const TRASH_SUFFIX: &str = ".trash";
+/// File of a macro directory, whose modification time is the time of its last expansion.
+const LAST_USED_FILE: &str = ".crabtime.used";
+/// Prefix of the crate names cargo gives to build scripts, like `build_script_build`.
+const BUILD_SCRIPT_CRATE_PREFIX: &str = "build_script_";
+/// Environment variable overriding the directory of the generated projects, for sandboxes where the
+/// build directory is read-only or has an unknown layout.
+const OUT_DIR_ENV: &str = "CRABTIME_OUT_DIR";
+/// Environment variable setting the maximum size of the `crabtime` build directory in megabytes.
+const CACHE_MAX_MB_ENV: &str = "CRABTIME_CACHE_MAX_MB";
+/// Maximum size of the `crabtime` build directory in megabytes, unless `CRABTIME_CACHE_MAX_MB` is
+/// set.
+const DEFAULT_CACHE_MAX_MB: u64 = 10 * 1024;
+/// File of the `crabtime` build directory, whose modification time is the time of the last check
+/// of its size.
+const CACHE_SIZE_CHECKED_FILE: &str = ".crabtime.size-checked";
+/// Minimum time between checks of the size of the `crabtime` build directory, as it requires
+/// walking the files of all projects.
+const CACHE_SIZE_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+/// Inner attribute passing the input of the macro call to `eval_function`, to introspect the
+/// arguments.
+const CALL_ARGS_ATTR: &str = "__crabtime_args";
+/// Outer attribute of the macro function, equivalent to the `module` option.
+const MODULE_ATTR: &str = "module";
+/// Target directory shared by the argument-specific subprojects, relative to their parent.
+const SHARED_TARGET_DIR: &str = "target";
+/// Working directory of the generated program, relative to the project directory.
+const RUN_DIR: &str = "run";
+/// Temporary directory of the generated program, relative to the project directory.
+const RUN_TMP_DIR: &str = "tmp";
+/// Subdirectory of the project's `target` directory with the binary built with `rustc` directly.
+const RUSTC_BIN_DIR: &str = "rustc";
+/// Lock file held in the project directory while it is in use, so it is not removed by a
+/// parallel expansion.
+const LOCK_FILE: &str = ".crabtime.lock";
+/// Directory of the project of a macro with caching enabled, relative to the macro directory.
+const CACHED_PROJECT_DIR: &str = "cached";
+/// Directory of the macros of the doctests of a file, relative to the directory of the file.
+const DOCTESTS_DIR: &str = "doctests";
+/// Directory of the one-shot projects of a macro with caching disabled, relative to the macro
+/// directory.
+const ONE_SHOT_PROJECTS_DIR: &str = "oneshot";
+/// Flags allowed in `CRABTIME_CARGO_FLAGS`. Other flags could change where or what the nested cargo
+/// builds, so they are rejected.
+const ALLOWED_CARGO_FLAGS: &[&str] = &[
+    "--locked", "--frozen", "--offline", "--quiet", "-q", "--verbose", "-v", "-vv",
+];
 
-/// Rust keywords for special handling. This is not needed for this macro to work, it is only used
-/// to make `IntelliJ` / `RustRover` work correctly, as their `TokenStream` spans are incorrect.
+/// Strict and reserved keywords of every edition, which are not valid identifiers. Keywords
+/// reserved only in later editions are listed in [`EDITION_KEYWORDS`].
 const KEYWORDS: &[&str] = &[
-    "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum",
-    "extern", "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move",
-    "mut", "pub", "ref", "return", "self", "Self", "struct", "super", "trait", "true",
-    "type", "unsafe", "use", "where", "while", "abstract", "become", "box", "do", "final", "macro",
-    "override", "priv", "typeof", "unsized", "virtual", "yield", "try",
+    "as", "break", "const", "continue", "crate", "else", "enum", "extern", "false", "fn", "for",
+    "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref", "return",
+    "self", "Self", "static", "struct", "super", "trait", "true", "type", "unsafe", "use", "where",
+    "while", "abstract", "become", "box", "do", "final", "macro", "override", "priv", "typeof",
+    "unsized", "virtual", "yield",
 ];
 
+/// Keywords which can be used as path segments but can't be used as raw identifiers.
+const PATH_KEYWORDS: &[&str] = &["crate", "self", "super", "Self"];
+
+/// Keywords which are not valid identifiers in the edition.
+fn ident_keywords(edition: &str) -> Vec<&'static str> {
+    let edition_keywords = EDITION_KEYWORDS.iter()
+        .filter(|(_, since)| *since <= edition)
+        .map(|(keyword, _)| *keyword);
+    KEYWORDS.iter().copied().chain(edition_keywords).collect()
+}
+
+/// Keywords of any edition padded with spaces. This is not needed for this macro to work, it is
+/// only used to make `IntelliJ` / `RustRover` work correctly, as their `TokenStream` spans are
+/// incorrect. See [`IdeCompat`]. `static` is not padded, as it would split the `'static` lifetime.
+fn is_padded_keyword(ident: &str) -> bool {
+    let is_keyword = KEYWORDS.contains(&ident)
+        || EDITION_KEYWORDS.iter().any(|(keyword, _)| *keyword == ident);
+    is_keyword && ident != "static"
+}
+
 // ==================
 // === TokenRange ===
 // ==================
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 struct TokenRange {
     start: TokenTree,
     end: TokenTree,
@@ -69,7 +225,14 @@ impl TokenRange {
         Self { start, end }
     }
 
-    #[cfg(nightly)]
+    /// Range from the first to the last token of the stream, or `None` if the stream is empty.
+    fn from_tokens(tokens: TokenStream) -> Option<Self> {
+        let mut iter = tokens.into_iter();
+        let start = iter.next()?;
+        let end = iter.last().unwrap_or_else(|| start.clone());
+        Some(Self::new(start, end))
+    }
+
     fn span(&self) -> Span {
         let first_span = self.start.span();
         let last_span = self.end.span();
@@ -77,17 +240,100 @@ impl TokenRange {
     }
 }
 
+// ===============
+// === SpanMap ===
+// ===============
+
+/// Token ranges of the parts of a macro definition, recorded while parsing it, so that
+/// diagnostics can point at the exact tokens instead of the whole macro.
+#[derive(Debug, Default)]
+struct SpanMap {
+    /// Function arguments, by name. Arguments which are not plain identifiers, like
+    /// `pattern!(...)`, are named by their printed pattern.
+    args: Vec<(String, TokenRange)>,
+    /// Values of inline attributes, like `#![edition(...)]`, by attribute name.
+    attributes: Vec<(String, TokenRange)>,
+    /// Values of macro options, like `jobs = 2`, by option name.
+    options: Vec<(String, TokenRange)>,
+}
+
+impl SpanMap {
+    fn find<'t>(entries: &'t [(String, TokenRange)], name: &str) -> Option<&'t TokenRange> {
+        entries.iter().rev().find(|(key, _)| key == name).map(|(_, range)| range)
+    }
+
+    /// The value of the last attribute with the given name, as the last one takes effect.
+    fn attribute(&self, name: &str) -> Option<&TokenRange> {
+        Self::find(&self.attributes, name)
+    }
+
+    fn option(&self, name: &str) -> Option<&TokenRange> {
+        Self::find(&self.options, name)
+    }
+
+    /// Span of all the arguments, or the call site if there are none.
+    fn args_span(&self) -> Span {
+        let first = self.args.first().map(|(_, range)| range.start.clone());
+        let last = self.args.last().map(|(_, range)| range.end.clone());
+        let range = first.zip(last).map(|(first, last)| TokenRange::new(first, last));
+        Self::span(range.as_ref())
+    }
+
+    /// Span of the range, or the call site if the range was not recorded.
+    fn span(range: Option<&TokenRange>) -> Span {
+        range.map_or_else(Span::call_site, TokenRange::span)
+    }
+}
+
 // ==============================
 // === Generated Code Prelude ===
 // ==============================
 
-fn gen_prelude(include_token_stream_impl: bool, paths: &Paths) -> String {
+/// Crates of the macro dependencies the prelude provides extra conveniences for.
+#[derive(Debug, Default, PartialEq)]
+struct PreludeCrates {
+    /// The `proc-macro2` crate, whose `TokenStream` can be returned from the macro.
+    token_stream: Option<String>,
+    serde_json: Option<String>,
+    serde: Option<String>,
+    /// Crates declared with `extern crate`, as edition 2015 requires it to use dependencies.
+    extern_crates: Vec<String>,
+    /// Keywords `ident` turns into raw identifiers, which depend on the edition of the output.
+    ident_keywords: Vec<&'static str>,
+}
+
+fn gen_prelude(
+    crates: &PreludeCrates,
+    paths: &Paths,
+    passthrough: &[(String, TokenStream)],
+    call_args: &[String],
+    body: &str,
+) -> String {
     let warning_prefix = Level::WARNING_PREFIX;
     let error_prefix = Level::ERROR_PREFIX;
-    let prelude_tok_stream = if include_token_stream_impl { PRELUDE_FOR_TOKEN_STREAM } else { "" };
+    let prelude_tok_stream =
+        crates.token_stream.as_deref().map(gen_prelude_for_token_stream).unwrap_or_default();
+    let prelude_json = crates.serde_json.as_deref()
+        .map(|serde_json| gen_prelude_for_json(serde_json, crates.serde.as_deref()))
+        .unwrap_or_default();
+    let prelude_helpers = gen_prelude_helpers(body);
+    let keywords =
+        crates.ident_keywords.iter().map(|k| format!("\"{k}\"")).collect::<Vec<_>>().join(", ");
+    let path_keywords =
+        PATH_KEYWORDS.iter().map(|k| format!("\"{k}\"")).collect::<Vec<_>>().join(", ");
+    let extern_crates = crates.extern_crates.iter()
+        .map(|name| format!("extern crate {name};"))
+        .collect::<Vec<_>>()
+        .join("\n");
 
     let workspace_path =
         format!("pub const WORKSPACE_PATH: &str = r#\"{}\"#;", paths.workspace.display());
+    let passthrough_options = passthrough.iter()
+        .map(|(name, value)| format!("({name:?}, {:?})", value.to_string()))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let arg_count = call_args.len();
+    let arg_strs = call_args.iter().map(|t| format!("{t:?}")).collect::<Vec<_>>().join(", ");
 
     #[cfg(nightly)]
     let crate_config_path =
@@ -102,6 +348,8 @@ fn gen_prelude(include_token_stream_impl: bool, paths: &Paths) -> String {
     let call_site_file_path = "";
 
     format!("
+        {extern_crates}
+
         #[allow(unused_macros)]
         #[allow(unused_imports)]
         #[allow(clippy::all)]
@@ -112,44 +360,211 @@ fn gen_prelude(include_token_stream_impl: bool, paths: &Paths) -> String {
             {call_site_file_path}
 
             pub const OUTPUT_PREFIX: &str = \"{OUTPUT_PREFIX}\";
+            pub const NON_REUSABLE_MARKER: &str = \"{NON_REUSABLE_MARKER}\";
+            pub const NOTICE_PREFIX: &str = \"{NOTICE_PREFIX}\";
+            pub const BUILD_ID_ENV: &str = \"{BUILD_ID_ENV}\";
+            pub const PHASE_CACHE_DIR_ENV: &str = \"{PHASE_CACHE_DIR_ENV}\";
             pub const WARNING_PREFIX: &str = \"{warning_prefix}\";
             pub const ERROR_PREFIX: &str = \"{error_prefix}\";
+            pub const KEYWORDS: &[&str] = &[{keywords}];
+            pub const PATH_KEYWORDS: &[&str] = &[{path_keywords}];
+            /// Options collected with the `lenient` marker, as names and value tokens.
+            pub const PASSTHROUGH_OPTIONS: &[(&str, &str)] = &[{passthrough_options}];
+            /// Number of the top-level comma-separated arguments of the macro call.
+            pub const ARG_COUNT: usize = {arg_count};
+            /// The top-level comma-separated arguments of the macro call, as printed by the
+            /// compiler.
+            pub const ARG_STRS: &[&str] = &[{arg_strs}];
+
+            /// The value tokens of the option collected with the `lenient` marker.
+            pub fn passthrough_option(name: &str) -> Option<&'static str> {{
+                PASSTHROUGH_OPTIONS.iter().find(|(n, _)| *n == name).map(|(_, value)| *value)
+            }}
+
+            /// Code callback passed at the call site, like `|field| format!(\"get_{{field}}\")`.
+            pub type CodeFn = fn(&str) -> String;
+
+            #[macro_export]
+            #[doc(hidden)]
+            macro_rules! __crabtime_prelude_output_str {{
+                ($($ts:tt)*) => {{
+                    println!(\"{{}}\", {GEN_MOD}::prefix_lines_with_output(&format!($($ts)*)))
+                }};
+            }}
+            pub(super) use crate::__crabtime_prelude_output_str as output_str;
 
-            macro_rules! output_str {{
+            #[macro_export]
+            #[doc(hidden)]
+            macro_rules! __crabtime_prelude_warning {{
                 ($($ts:tt)*) => {{
-                    println!(\"{{}}\", {GEN_MOD}::prefix_lines_with_output(&format!($($ts)*)));
+                    println!(\"{{}}\", {GEN_MOD}::prefix_lines_with_warning(&format!($($ts)*)))
                 }};
             }}
-            pub(super) use output_str;
+            pub(super) use crate::__crabtime_prelude_warning as warning;
 
-            macro_rules! warning {{
+            #[macro_export]
+            #[doc(hidden)]
+            macro_rules! __crabtime_prelude_error {{
                 ($($ts:tt)*) => {{
-                    println!(\"{{}}\", {GEN_MOD}::prefix_lines_with_warning(&format!($($ts)*)));
+                    println!(\"{{}}\", {GEN_MOD}::prefix_lines_with_error(&format!($($ts)*)))
                 }};
             }}
-            pub(super) use warning;
+            pub(super) use crate::__crabtime_prelude_error as error;
 
-            macro_rules! error {{
+            #[macro_export]
+            #[doc(hidden)]
+            macro_rules! __crabtime_prelude_notice {{
                 ($($ts:tt)*) => {{
-                    println!(\"{{}}\", {GEN_MOD}::prefix_lines_with_error(&format!($($ts)*)));
+                    println!(\"{{}}\", {GEN_MOD}::prefix_lines_with_notice(&format!($($ts)*)))
                 }};
             }}
-            pub(super) use error;
+            pub(super) use crate::__crabtime_prelude_notice as notice;
 
             {PRELUDE_STATIC}
             {prelude_tok_stream}
-            {PRELUDE_ADDONS}
+            {prelude_json}
+            {prelude_helpers}
+            {PRELUDE_CODE}
+            {PRELUDE_SNIPPET}
+
+            impl CodeFromOutput for Code {{
+                fn code_from_output(output: Self) -> String {{
+                    output.to_string()
+                }}
+            }}
+
+            impl CodeFromOutput for Snippet {{
+                fn code_from_output(output: Self) -> String {{
+                    output.into()
+                }}
+            }}
         }}
     ")
 }
 
-const PRELUDE_FOR_TOKEN_STREAM: &str = "
-    impl CodeFromOutput for proc_macro2::TokenStream {
-        fn code_from_output(output: Self) -> String {
-            output.to_string()
-        }
-    }
-";
+fn gen_prelude_for_token_stream(proc_macro2_crate: &str) -> String {
+    format!("
+        impl CodeFromOutput for {proc_macro2_crate}::TokenStream {{
+            fn code_from_output(output: Self) -> String {{
+                output.to_string()
+            }}
+        }}
+    ")
+}
+
+/// JSON conversions, available if `serde_json` is a dependency. Conversions of user types are
+/// available only if `serde` is a dependency too, as their bounds are defined there.
+fn gen_prelude_for_json(serde_json_crate: &str, serde_crate: Option<&str>) -> String {
+    let fnv_offset_basis = inputs::FNV_OFFSET_BASIS;
+    let fnv_prime = inputs::FNV_PRIME;
+    let serde_conversions = serde_crate.map(|serde_crate| format!("
+        pub fn from_json_str<T: {serde_crate}::de::DeserializeOwned>(json: &str) -> T {{
+            {serde_json_crate}::from_str(json)
+                .unwrap_or_else(|e| panic!(\"Failed to deserialize JSON: {{e}}\"))
+        }}
+
+        pub fn to_json_string<T: {serde_crate}::Serialize + ?Sized>(value: &T) -> String {{
+            {serde_json_crate}::to_string(value)
+                .unwrap_or_else(|e| panic!(\"Failed to serialize JSON: {{e}}\"))
+        }}
+
+        #[macro_export]
+        #[doc(hidden)]
+        macro_rules! __crabtime_prelude_phase_cache {{
+            ($key:expr, [$($input:expr),* $(,)?], $f:expr $(,)?) => {{
+                {GEN_MOD}::phase_cache_with_inputs(
+                    $key,
+                    &[$(AsRef::<std::path::Path>::as_ref(&$input)),*],
+                    $f
+                )
+            }};
+            ($key:expr, $f:expr $(,)?) => {{
+                {GEN_MOD}::phase_cache_with_inputs($key, &[], $f)
+            }};
+        }}
+        pub(super) use crate::__crabtime_prelude_phase_cache as phase_cache;
+
+        pub fn phase_cache_with_inputs<T, F>(key: &str, inputs: &[&std::path::Path], f: F) -> T
+        where
+            T: {serde_crate}::Serialize + {serde_crate}::de::DeserializeOwned,
+            F: FnOnce() -> T {{
+            // The hashes are stored on disk, so they use the 128-bit FNV-1a hash, which, unlike
+            // `DefaultHasher`, does not change between Rust releases. Every part is prefixed with
+            // its length, so that the parts cannot be confused with each other.
+            let stable_hash = |parts: &[&[u8]]| parts.iter()
+                .flat_map(|part| (part.len() as u64).to_le_bytes().into_iter().chain(part.to_vec()))
+                .fold({fnv_offset_basis:#x}, |hash: u128, byte| {{
+                    (hash ^ u128::from(byte)).wrapping_mul({fnv_prime:#x})
+                }});
+            let Ok(dir) = std::env::var(PHASE_CACHE_DIR_ENV) else {{ return f() }};
+            let path = std::path::Path::new(&dir)
+                .join(format!(\"{{:032x}}.json\", stable_hash(&[key.as_bytes()])));
+            let mut contents = Vec::with_capacity(inputs.len());
+            for input in inputs {{
+                contents.push(std::fs::read(input).ok());
+            }}
+            let mut parts = vec![key.as_bytes(), std::any::type_name::<T>().as_bytes()];
+            for (input, content) in inputs.iter().zip(&contents) {{
+                parts.push(input.as_os_str().as_encoded_bytes());
+                parts.push(if content.is_some() {{ &[1] }} else {{ &[0] }});
+                parts.push(content.as_deref().unwrap_or_default());
+            }}
+            let fingerprint = format!(\"{{:032x}}\", stable_hash(&parts));
+            let cached = std::fs::read_to_string(&path).ok()
+                .and_then(|t| {serde_json_crate}::from_str::<{serde_json_crate}::Value>(&t).ok())
+                .filter(|t| t[\"fingerprint\"] == fingerprint.as_str())
+                .and_then(|mut t| {serde_json_crate}::from_value(t[\"value\"].take()).ok());
+            if let Some(value) = cached {{
+                return value
+            }}
+            let value = f();
+            if let Ok(json) = {serde_json_crate}::to_value(&value) {{
+                let entry = {serde_json_crate}::json!({{ \"fingerprint\": fingerprint, \"value\": json }});
+                // Parallel expansions may compute the same phase, so the file is replaced
+                // atomically.
+                let tmp_path = path.with_extension(format!(\"{{}}.tmp\", std::process::id()));
+                let written = std::fs::create_dir_all(&dir)
+                    .and_then(|_| std::fs::write(&tmp_path, entry.to_string()))
+                    .and_then(|_| std::fs::rename(&tmp_path, &path));
+                if let Err(e) = written {{
+                    println!(\"{{}}\", prefix_lines_with_warning(
+                        &format!(\"Failed to store the phase cache '{{key}}': {{e}}\")
+                    ));
+                }}
+            }}
+            value
+        }}
+    ")).unwrap_or_default();
+    format!("
+        impl CodeFromOutput for {serde_json_crate}::Value {{
+            fn code_from_output(output: Self) -> String {{
+                json_code(&output)
+            }}
+        }}
+
+        fn json_code(value: &{serde_json_crate}::Value) -> String {{
+            use {serde_json_crate}::Value;
+            match value {{
+                Value::Null => \"None\".to_string(),
+                Value::Bool(value) => value.to_string(),
+                Value::Number(value) => value.to_string(),
+                Value::String(value) => format!(\"{{value:?}}\"),
+                Value::Array(items) => {{
+                    let items = items.iter().map(json_code).collect::<Vec<_>>();
+                    format!(\"[{{}}]\", items.join(\", \"))
+                }}
+                Value::Object(_) => {{
+                    println!(\"{{}}\", prefix_lines_with_error(
+                        \"JSON objects cannot be converted to Rust code.\"
+                    ));
+                    String::new()
+                }}
+            }}
+        }}
+
+        {serde_conversions}
+    ")
+}
 
 const PRELUDE_STATIC: &str = "
     pub(super) trait CodeFromOutput {
@@ -246,33 +661,63 @@ const PRELUDE_STATIC: &str = "
         prefix_lines_with(ERROR_PREFIX, input)
     }
 
-    macro_rules! write_ln {
+    pub(super) fn prefix_lines_with_notice(input: &str) -> String {
+        prefix_lines_with(NOTICE_PREFIX, input)
+    }
+
+    #[macro_export]
+    #[doc(hidden)]
+    macro_rules! __crabtime_prelude_write_ln {
         ($target:expr, $($ts:tt)*) => {
             $target.push_str(&format!( $($ts)* ));
             $target.push_str(\"\n\");
         };
     }
-    pub(super) use write_ln;
+    pub(super) use crate::__crabtime_prelude_write_ln as write_ln;
 
-    macro_rules! stringify_if_needed {
+    #[macro_export]
+    #[doc(hidden)]
+    macro_rules! __crabtime_prelude_stringify_if_needed {
         ($t:literal) => { $t };
         ($t:expr) => { stringify!($t) };
     }
-    pub(super) use stringify_if_needed;
+    pub(super) use crate::__crabtime_prelude_stringify_if_needed as stringify_if_needed;
 
     // This is defined only to prevent compilation errors. The real expansion is done by the
     // `function` attribute macro.
-    macro_rules! output {
+    #[macro_export]
+    #[doc(hidden)]
+    macro_rules! __crabtime_prelude_output {
         ($($ts:tt)*) => {};
     }
-    pub(super) use output;
+    pub(super) use crate::__crabtime_prelude_output as output;
 
     // This is defined only to prevent compilation errors. The real expansion is done by the
     // `function` attribute macro.
-    macro_rules! quote {
+    #[macro_export]
+    #[doc(hidden)]
+    macro_rules! __crabtime_prelude_quote {
         ($($ts:tt)*) => { String::new() };
     }
-    pub(super) use quote;
+    pub(super) use crate::__crabtime_prelude_quote as quote;
+
+    // This is defined only to prevent compilation errors. The real expansion is done by the
+    // `function` attribute macro.
+    #[macro_export]
+    #[doc(hidden)]
+    macro_rules! __crabtime_prelude_output_macro_rules {
+        ($($ts:tt)*) => {};
+    }
+    pub(super) use crate::__crabtime_prelude_output_macro_rules as output_macro_rules;
+
+    // This is defined only to prevent compilation errors. The real expansion is done by the
+    // `function` attribute macro.
+    #[macro_export]
+    #[doc(hidden)]
+    macro_rules! __crabtime_prelude_output_cfg {
+        ($($ts:tt)*) => {};
+    }
+    pub(super) use crate::__crabtime_prelude_output_cfg as output_cfg;
 ";
 
 const PRELUDE_ADDONS: &str = "
@@ -298,8 +743,91 @@ const PRELUDE_ADDONS: &str = "
         generate(n, vec![], &mut result);
         result
     }
+
+    pub fn ident<T: AsRef<str>>(parts: impl IntoIterator<Item = T>) -> String {
+        let joined = parts.into_iter().map(|part| part.as_ref().to_string()).collect::<String>();
+        let mut out = joined.chars()
+            .map(|c| if c == '_' || c.is_alphanumeric() { c } else { '_' })
+            .collect::<String>();
+        if out.starts_with(|c: char| c.is_numeric()) {
+            out.insert(0, '_');
+        }
+        if out.is_empty() || out == \"_\" {
+            println!(\"{}\", prefix_lines_with_error(
+                &format!(\"Cannot create an identifier from '{out}'.\")
+            ));
+        } else if PATH_KEYWORDS.contains(&out.as_str()) {
+            println!(\"{}\", prefix_lines_with_error(
+                &format!(\"Keyword '{out}' cannot be used as an identifier.\")
+            ));
+        } else if KEYWORDS.contains(&out.as_str()) {
+            out.insert_str(0, \"r#\");
+        }
+        out
+    }
+
+    pub fn path<T: AsRef<str>>(segments: impl IntoIterator<Item = T>) -> String {
+        segments.into_iter()
+            .flat_map(|segment| {
+                segment.as_ref().split(\"::\").map(|t| t.trim().to_string()).collect::<Vec<_>>()
+            })
+            .enumerate()
+            .map(|(i, segment)| {
+                let is_global_prefix = i == 0 && segment.is_empty();
+                if is_global_prefix || PATH_KEYWORDS.contains(&segment.as_str()) {
+                    segment
+                } else {
+                    ident([segment])
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(\"::\")
+    }
+
+    pub fn build_id() -> u64 {
+        std::env::var(BUILD_ID_ENV).ok().and_then(|t| t.parse().ok()).unwrap_or_default()
+    }
+
+    pub fn random_u64() -> u64 {
+        use std::hash::BuildHasher;
+        use std::hash::Hasher;
+        static NON_REUSABLE: std::sync::Once = std::sync::Once::new();
+        static COUNTER: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+        NON_REUSABLE.call_once(|| println!(\"{NON_REUSABLE_MARKER}\"));
+        let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+        hasher.write_u64(COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed));
+        hasher.finish()
+    }
 ";
 
+const PRELUDE_CASE: &str = include_str!("case.rs");
+
+const PRELUDE_LITERAL: &str = include_str!("literal.rs");
+
+const PRELUDE_CODE: &str = include_str!("code.rs");
+
+const PRELUDE_SNIPPET: &str = include_str!("snippet.rs");
+
+/// Prelude sections with helper functions the output machinery does not depend on.
+const PRELUDE_HELPERS: &[&str] = &[PRELUDE_ADDONS, PRELUDE_CASE, PRELUDE_LITERAL];
+
+/// The helper sections of the prelude which the body may use. The body is scanned for the names of
+/// the public functions of each section, which over-approximates their usage, as the names can be
+/// used for something else. Code included with `include!` cannot be scanned, so all sections are
+/// included then.
+fn gen_prelude_helpers(body: &str) -> String {
+    let body_idents = body.split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .collect::<HashSet<_>>();
+    let uses_all = body_idents.contains("include");
+    let section_fns = |section: &'static str| section.split("pub fn ").skip(1)
+        .filter_map(|t| t.split(|c: char| !(c.is_alphanumeric() || c == '_')).next());
+    PRELUDE_HELPERS.iter()
+        .filter(|section| uses_all || section_fns(section).any(|t| body_idents.contains(t)))
+        .copied()
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
 // =============
 // === Paths ===
 // =============
@@ -307,6 +835,11 @@ const PRELUDE_ADDONS: &str = "
 #[derive(Debug)]
 struct Paths {
     workspace: PathBuf,
+    /// The `crabtime` directory containing the projects of all macros, whose size is capped.
+    output_root: PathBuf,
+    output_root_source: OutputRootSource,
+    /// Directory containing the `cached` and `oneshot` project directories of the macro.
+    macro_dir: PathBuf,
     output_dir: PathBuf,
     #[cfg(nightly)]
     call_site_file: PathBuf,
@@ -314,1089 +847,6964 @@ struct Paths {
     crate_config: PathBuf,
     // Whether we should remove `output_dir` after usage.
     one_shot_output_dir: bool,
+    /// Whether `output_dir` is a subdirectory of the macro project specific to the call-site
+    /// arguments.
+    args_specific_output_dir: bool,
     /// None if we are on stable.
     cargo_toml_path: Option<CargoConfigPaths>,
 }
 
 impl Paths {
     #[cfg(nightly)]
-    fn new(options: MacroOptions, macro_name: &str, input_str: &str) -> Result<Self> {
+    fn new(options: &MacroOptions, macro_name: &str, input_str: &str) -> Result<Self> {
         let name = if options.content_base_name {
             Self::project_name_from_input(input_str)
         } else {
             macro_name.to_string()
         };
-        let call_site_path = Self::get_call_site_rel();
-        let output_dir = Self::get_output_root()?.join(&call_site_path).join(&name);
-        let target = path::find_parent(&output_dir, "target")?;
-        let workspace = path::parent(target)?.to_path_buf();
-        let call_site_file = workspace.join(&call_site_path);
-        let cargo_toml_path = find_cargo_configs(&call_site_file)?;
-        let crate_config = cargo_toml_path.crate_config.clone();
-        let cargo_toml_path = Some(cargo_toml_path);
+        let manifest_dir = inputs::var("CARGO_MANIFEST_DIR").map(PathBuf::from);
+        let (mut call_site_file, is_doctest) = Self::get_call_site_file(manifest_dir.as_deref());
+        let mut call_site_path = Self::relative_call_site(&call_site_file, manifest_dir.as_deref());
+        // The doctests of a file share a directory, where the arguments directory, hashing the
+        // input, separates their macros instead of their line numbers, which change with any edit.
+        if is_doctest {
+            call_site_path.push(DOCTESTS_DIR);
+        }
+        call_site_file.set_extension("");
+        let crate_name = Self::crate_name();
+        let (output_root, output_root_source) = Self::project_output_root(options);
+        let crate_output_root = Self::crate_output_root(&output_root, crate_name);
+        let mut macro_dir = match (&options.cache_key, &options.module) {
+            (Some(key), _) => crate_output_root.join(Self::cache_key_dir_name(key)),
+            (None, Some(module)) => Self::module_dir(&crate_output_root, module).join(&name),
+            (None, None) => crate_output_root.join(&call_site_path).join(&name),
+        };
+        // Arguments are baked into the generated code, so call sites with different arguments get
+        // separate builds. Otherwise, they would invalidate each other's build on every expansion.
+        let args_specific_output_dir = !options.content_base_name || options.cache_key.is_some();
+        if args_specific_output_dir {
+            macro_dir = macro_dir.join(Self::args_dir_name(input_str));
+        }
+        let workspace = Self::find_workspace(&macro_dir)?;
+        // Minimal macros do not use the manifests, so the workspace one is assumed.
+        // If the call site file can't be located, the manifests are searched from the crate dir.
+        // Build scripts can be anywhere in the package, like in a `build` directory with its own
+        // manifest, but they are always configured by the package manifest.
+        let build_script_manifest = manifest_dir.as_deref().filter(|_| Self::is_build_script());
+        let cargo_toml_path = (!options.minimal)
+            .then(|| match build_script_manifest {
+                Some(manifest_dir) => find_cargo_configs(manifest_dir),
+                None => find_cargo_configs(&call_site_file).or_else(|err| {
+                    manifest_dir.as_deref().map_or(Err(err), find_cargo_configs)
+                }),
+            })
+            .transpose()?;
+        let crate_config = cargo_toml_path.as_ref()
+            .map_or_else(|| workspace.join("Cargo.toml"), |t| t.crate_config.clone());
         let one_shot_output_dir = false;
         let out = Self {
             workspace,
-            output_dir,
+            output_root,
+            output_root_source,
+            output_dir: macro_dir.clone(),
+            macro_dir,
             crate_config,
             call_site_file,
             cargo_toml_path,
-            one_shot_output_dir
+            one_shot_output_dir,
+            args_specific_output_dir,
         }.init(options);
         Ok(out)
     }
 
     #[cfg(not(nightly))]
-    fn new(options: MacroOptions, _macro_name: &str, input_str: &str) -> Result<Self> {
-        let name = Self::project_name_from_input(input_str);
-        let output_dir = Self::get_output_root()?.join(&name);
-        let target = path::find_parent(&output_dir, "target")?;
-        let workspace = path::parent(target)?.to_path_buf();
+    fn new(options: &MacroOptions, macro_name: &str, input_str: &str) -> Result<Self> {
+        let (output_root, output_root_source) = Self::project_output_root(options);
+        let crate_name = Self::crate_name();
+        let crate_output_root = Self::crate_output_root(&output_root, crate_name);
+        // The cache key or the module identifies the macro, like the call site does on nightly.
+        let macro_dir = match (&options.cache_key, &options.module) {
+            (Some(key), _) => Some(crate_output_root.join(Self::cache_key_dir_name(key))),
+            (None, Some(module)) =>
+                Some(Self::module_dir(&crate_output_root, module).join(macro_name)),
+            (None, None) => None,
+        };
+        let args_specific_output_dir = macro_dir.is_some();
+        // Without a cache key or a module, only identical expansions share a project, so the macro
+        // name and the options, which affect the project too, are a part of the hashed content.
+        let macro_dir = match macro_dir {
+            Some(macro_dir) => macro_dir.join(Self::args_dir_name(input_str)),
+            None => output_root.join(Self::project_name_from_input(
+                &format!("{macro_name}\n{options:?}\n{input_str}")
+            )),
+        };
+        let workspace = Self::find_workspace(&macro_dir)?;
         let cargo_toml_path = None;
         let one_shot_output_dir = false;
-        Ok(Self { workspace, output_dir, cargo_toml_path, one_shot_output_dir }.init(options))
+        Ok(Self {
+            workspace,
+            output_root,
+            output_root_source,
+            output_dir: macro_dir.clone(),
+            macro_dir,
+            cargo_toml_path,
+            one_shot_output_dir,
+            args_specific_output_dir,
+        }.init(options))
+    }
+
+    /// The workspace of the crate being compiled. It is the parent of the `target` directory,
+    /// unless the target directory is outside the workspace, like when it is shared between
+    /// workspaces with `CARGO_TARGET_DIR`.
+    fn find_workspace(output_dir: &Path) -> Result<PathBuf> {
+        // Cargo resolves a relative target directory against the working directory.
+        let target_dir = inputs::var("CARGO_TARGET_DIR")
+            .map(|dir| std::path::absolute(&dir).unwrap_or_else(|_| PathBuf::from(dir)));
+        let manifest_dir = inputs::var("CARGO_MANIFEST_DIR").map(PathBuf::from);
+        Self::workspace_dir(output_dir, target_dir.as_deref(), manifest_dir.as_deref())
+    }
+
+    /// If the output directory is in the `CARGO_TARGET_DIR` directory, or the parent of its
+    /// `target` directory does not contain the crate, the target directory is external, and the
+    /// workspace is the directory of the topmost workspace manifest of the crate instead.
+    fn workspace_dir(
+        output_dir: &Path,
+        target_dir: Option<&Path>,
+        manifest_dir: Option<&Path>,
+    ) -> Result<PathBuf> {
+        let target_parent = path::find_parent(output_dir, "target").ok().and_then(Path::parent);
+        let in_target_dir = target_dir.is_some_and(|dir| output_dir.starts_with(dir));
+        let is_external = in_target_dir || target_parent.is_none_or(|workspace| {
+            manifest_dir.is_some_and(|dir| !dir.starts_with(workspace))
+        });
+        match (target_parent, manifest_dir) {
+            (Some(workspace), _) if !is_external => Ok(workspace.to_path_buf()),
+            (_, Some(manifest_dir)) => {
+                let configs = find_cargo_configs(manifest_dir)?;
+                let root_config = configs.workspace_config.unwrap_or(configs.crate_config);
+                Ok(path::parent(&root_config)?.to_path_buf())
+            }
+            (Some(workspace), None) => Ok(workspace.to_path_buf()),
+            (None, None) => err!(
+                "Path '{}' does not have parent 'target' directory, and 'CARGO_MANIFEST_DIR' is \
+                not set.", output_dir.display()
+            ),
+        }
+    }
+
+    /// The lockfile of the workspace, next to its root manifest, if it exists. On stable, where the
+    /// manifests are not discovered, it is looked up in the workspace directory.
+    fn lockfile(&self) -> Option<PathBuf> {
+        let root_dir = self.cargo_toml_path.as_ref()
+            .and_then(|t| t.workspace_config.as_ref().unwrap_or(&t.crate_config).parent())
+            .unwrap_or(&self.workspace);
+        Some(root_dir.join("Cargo.lock")).filter(|t| t.is_file())
+    }
+
+    /// Directory relative dependency paths are resolved against. It is the crate directory on
+    /// nightly, and the workspace directory on stable, where the crate is not discovered.
+    fn manifest_dir(&self) -> &Path {
+        #[cfg(nightly)]
+        let dir = path::parent(&self.crate_config).unwrap_or(&self.workspace);
+        #[cfg(not(nightly))]
+        let dir = &self.workspace;
+        dir
     }
 
-    fn init(mut self, options: MacroOptions) -> Self {
-        // We cache projects on nightly by default. On stable, the project name is based on the
-        // input code.
-        self.one_shot_output_dir = cfg!(not(nightly)) || !options.cache;
+    fn init(mut self, options: &MacroOptions) -> Self {
+        // We cache projects by default. On stable, the project name is based on the input code,
+        // unless a cache key or a module is provided, so only identical expansions share it.
+        self.one_shot_output_dir = !options.cache;
         // If we are removing projects after usage, it is possible that multiple processes try to
         // expand the same macro in parallel – e.g. user's watch script and IDE checker. In such a
         // case, one of the processes might end while another is still running. This can cause
         // the other process to fail if it still needs project access on disk.
-        if self.one_shot_output_dir {
-            let pid = std::process::id();
-            self.output_dir = self.output_dir.join(format!("pid_{pid}"));
-        }
+        // Pids are reused, and a single process can expand the same macro from many threads, e.g.
+        // in rust-analyzer, so a random per-process nonce and a per-expansion counter are added.
+        self.output_dir = Self::project_dir(&self.macro_dir, self.one_shot_output_dir);
         self
     }
 
+    /// Project directory of the given caching mode. Cached and one-shot projects are kept in
+    /// separate subdirectories, so switching the mode never picks up the other mode's project.
+    fn project_dir(macro_dir: &Path, one_shot: bool) -> PathBuf {
+        if one_shot {
+            macro_dir.join(ONE_SHOT_PROJECTS_DIR).join(Self::unique_dir_name())
+        } else {
+            macro_dir.join(CACHED_PROJECT_DIR)
+        }
+    }
+
+    /// Directory name unique across processes and expansions.
+    fn unique_dir_name() -> String {
+        static NONCE: std::sync::OnceLock<u64> = std::sync::OnceLock::new();
+        static COUNTER: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+        let pid = std::process::id();
+        let nonce = NONCE.get_or_init(|| {
+            use std::hash::BuildHasher;
+            std::collections::hash_map::RandomState::new().hash_one(pid)
+        });
+        let count = COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        format!("pid_{pid}_{nonce:016x}_{count}")
+    }
+
+    /// The file of the macro call site, and whether it is in a doctest. Cargo reports the files of
+    /// workspace members relative to the workspace root, which is the working directory of rustc,
+    /// and the files of other crates, like path dependencies outside the workspace, as absolute
+    /// paths. Doctests have synthetic names, which are not local files.
     #[cfg(nightly)]
-    fn get_call_site_rel() -> PathBuf {
-        // Sometimes `proc_macro::Span::call_site()` returns a relative path, sometimes an absolute
-        // one. In the latter case, we need to discover the relative part from the project root.
-        let mut call_site_path = proc_macro::Span::call_site()
-            .local_file()
-            .unwrap_or_default();
+    fn get_call_site_file(manifest_dir: Option<&Path>) -> (PathBuf, bool) {
+        let span = proc_macro::Span::call_site();
+        let call_site = span.local_file().unwrap_or_else(|| PathBuf::from(span.file()));
+        let doctest_file = Self::doctest_source_file(&call_site.to_string_lossy());
+        let is_doctest = doctest_file.is_some();
+        let call_site = doctest_file.unwrap_or(call_site);
+        (Self::resolve_call_site(&call_site, manifest_dir), is_doctest)
+    }
+
+    /// The file containing the doctest, if the call site has the synthetic name rustdoc gives
+    /// doctests, like `src/lib.rs - my_mod::Item (line 42)`, or `src/lib.rs - (line 3)` for the
+    /// docs of the crate.
+    #[cfg_attr(not(nightly), allow(dead_code))]
+    fn doctest_source_file(call_site: &str) -> Option<PathBuf> {
+        let (name, line) = call_site.strip_suffix(')')?.rsplit_once("(line ")?;
+        if line.is_empty() || !line.chars().all(|t| t.is_ascii_digit()) {
+            return None
+        }
+        let name = name.trim_end();
+        let file = name.rsplit_once(" - ").map(|t| t.0).or_else(|| name.strip_suffix(" -"))?;
+        Some(PathBuf::from(file))
+    }
+
+    /// Absolute path of the call site file. Relative paths are resolved against the working
+    /// directory, which is not the workspace root for every target kind and driver, like for
+    /// doctests run in the crate directory, or for integration tests, examples, and benches built
+    /// by other tools. If the file does not exist there, it is looked up in the crate directory
+    /// and its ancestors, one of which is the workspace root.
+    #[cfg_attr(not(nightly), allow(dead_code))]
+    fn resolve_call_site(call_site: &Path, manifest_dir: Option<&Path>) -> PathBuf {
+        let from_working_dir = std::path::absolute(call_site)
+            .unwrap_or_else(|_| call_site.to_path_buf());
+        if call_site.is_absolute() || from_working_dir.is_file() {
+            return from_working_dir
+        }
+        manifest_dir.into_iter()
+            .flat_map(Path::ancestors)
+            .map(|dir| dir.join(call_site))
+            .find(|path| path.is_file())
+            .unwrap_or(from_working_dir)
+    }
+
+    /// The call site path relative to the workspace of its crate, without the extension. Project
+    /// dirs are keyed by it, so it must not depend on how the compiler was invoked. Cargo reports
+    /// paths relative to the workspace, while Rust Analyzer and some test runners report absolute
+    /// ones, which would otherwise give one call site, like a macro used only in `#[cfg(test)]`
+    /// code, separate project dirs for the IDE and for `cargo test`. The workspace is the one of
+    /// the manifests above the call site, so crates outside of the built workspace, like path
+    /// dependencies, resolve to their own workspaces, regardless of symlinks in the path. If no
+    /// manifest is found, the path is taken relative to the crate directory, `manifest_dir`.
+    #[cfg_attr(not(nightly), allow(dead_code))]
+    fn relative_call_site(call_site: &Path, manifest_dir: Option<&Path>) -> PathBuf {
+        let mut call_site_path = call_site.to_path_buf();
         call_site_path.set_extension("");
         if call_site_path.is_relative() {
-            return call_site_path.to_path_buf();
-        }
-
-        // We strip the common prefix of `proc_macro::Span::call_site()` and `OUT_DIR`.
-        let mut common_prefix_len = 0;
-        let mut common_prefix = PathBuf::new();
-        let mut components1 = call_site_path.components();
-        let mut components2 = Path::new(OUT_DIR).components();
-        while let (Some(part1), Some(part2)) = (components1.next(), components2.next()) {
-            if part1 == part2 {
-                common_prefix_len += 1;
-                common_prefix.push(part1);
-            } else {
-                break;
-            }
+            return call_site_path;
         }
 
-        // We don't want to strip small prefix, like `/` or `C:\\`. E.g. when running tests, cargo
-        // generates projects in `var/folders/wm/...`
-        if common_prefix_len <= 3 {
-            return call_site_path.to_path_buf();
+        // The workspace root is the directory of the top-level manifest.
+        let workspace_root = find_cargo_configs(call_site).ok().and_then(|configs| {
+            let root_config = configs.workspace_config.unwrap_or(configs.crate_config);
+            root_config.parent().map(Path::to_path_buf)
+        });
+        let roots = [workspace_root.as_deref(), manifest_dir];
+        if let Some(relative_path) = roots.into_iter().flatten()
+            .find_map(|root| call_site_path.strip_prefix(root).ok()) {
+            return relative_path.to_path_buf();
         }
 
-        match call_site_path.strip_prefix(&common_prefix) {
-            Ok(relative_path) => relative_path.to_path_buf(),
-            Err(_) => call_site_path.to_path_buf(), // Should not happen
-        }
+        // Otherwise, the path without its root, so that the project dir stays in the output root.
+        call_site_path.components()
+            .filter(|t| matches!(t, std::path::Component::Normal(_)))
+            .collect()
     }
 
-    fn project_name_from_input(input_str: &str) -> String {
-        let mut hasher = DefaultHasher::new();
-        input_str.hash(&mut hasher);
-        format!("project_{:016x}", hasher.finish())
+    /// Name of the crate being compiled. Cargo names the build scripts of all packages
+    /// `build_script_build`, so their names are prefixed with the package name, giving them
+    /// separate directories, like `my_crate_build_script_build`.
+    fn crate_name() -> Option<String> {
+        Self::qualified_crate_name(inputs::var("CARGO_CRATE_NAME"), inputs::var("CARGO_PKG_NAME"))
     }
 
-    fn get_output_root() -> Result<PathBuf> {
-        let crate_out_str = OUT_DIR;
-        let crate_out = Path::new(&crate_out_str);
-        let build_dir = path::find_parent(crate_out, "build")?;
-        Ok(build_dir.join(CRATE))
+    fn qualified_crate_name(crate_name: Option<String>, package: Option<String>) -> Option<String> {
+        match (crate_name, package) {
+            (Some(crate_name), Some(package)) if crate_name.starts_with(BUILD_SCRIPT_CRATE_PREFIX) =>
+                Some(format!("{}_{crate_name}", package.replace('-', "_"))),
+            (crate_name, _) => crate_name,
+        }
     }
 
-    fn with_output_dir<T>(&self, f: impl FnOnce(&PathBuf) -> Result<T>) -> Result<T> {
-        if !self.output_dir.exists() {
-            fs::create_dir_all(&self.output_dir).context("Failed to create project directory.")?;
-        }
-        let out = f(&self.output_dir);
-        if self.one_shot_output_dir {
-            fs::remove_dir_all(&self.output_dir).ok();
-        }
-        out
+    /// Whether the macro is expanded in the build script of a package.
+    fn is_build_script() -> bool {
+        inputs::var("CARGO_CRATE_NAME").is_some_and(|t| t.starts_with(BUILD_SCRIPT_CRATE_PREFIX))
     }
-}
 
-// ========================
-// === CargoConfigPaths ===
-// ========================
+    /// Directory of the projects of the crate being compiled. Crates of a workspace share the
+    /// output root, and their call sites can have the same relative paths, like `src/lib`.
+    #[cfg_attr(not(nightly), allow(dead_code))]
+    fn crate_output_root(output_root: &Path, crate_name: Option<String>) -> PathBuf {
+        match crate_name {
+            Some(crate_name) => output_root.join("crates").join(crate_name),
+            None => output_root.to_path_buf(),
+        }
+    }
 
-#[derive(Debug)]
-struct CargoConfigPaths {
-    crate_config: PathBuf,
-    workspace_config: Option<PathBuf>,
-}
+    /// Stable hash of the input, so the names of the project dirs survive toolchain updates and
+    /// can be shared between machines. Dirs named with other hashes are not used anymore.
+    fn input_hash(input_str: &str) -> String {
+        format!("{:032x}", inputs::stable_hash(input_str.as_bytes()))
+    }
 
-fn find_cargo_configs(path: &Path) -> Result<CargoConfigPaths> {
-    let mut current_path = path.to_path_buf();
-    let mut candidates = Vec::new();
-    loop {
-        let candidate = current_path.join("Cargo.toml");
-        if candidate.is_file() { candidates.push(candidate) }
-        if !current_path.pop() { break }
+    fn project_name_from_input(input_str: &str) -> String {
+        format!("project_{}", Self::input_hash(input_str))
     }
-    let Some((crate_config, other_candidates)) = candidates.split_first() else {
-        return err!("No 'Cargo.toml' files found in parent directories of '{}'.", path.display())
-    };
 
-    // Cargo uses the top-level workspace only.
-    let mut workspace_config = None;
-    for candidate in other_candidates.iter().rev() {
-        if CargoConfig::is_workspace(candidate)? {
-            workspace_config = Some(candidate.clone());
-        }
+    fn cache_key_dir_name(key: &str) -> String {
+        format!("key_{key}")
     }
-    let crate_config = crate_config.clone();
-    Ok(CargoConfigPaths { crate_config, workspace_config })
-}
 
-// ===================
-// === CargoConfig ===
-// ===================
+    /// Directory of the macros of the module, with a subdirectory per module path segment.
+    fn module_dir(crate_output_root: &Path, module: &str) -> PathBuf {
+        module.split("::").fold(crate_output_root.to_path_buf(), |dir, segment| dir.join(segment))
+    }
 
-#[derive(Debug)]
-struct Dependency {
-    label: String,
-    tokens_str: String,
-    token_range: Option<TokenRange>,
-}
+    fn args_dir_name(input_str: &str) -> String {
+        format!("args_{}", Self::input_hash(input_str))
+    }
 
-impl Dependency {
-    fn new(label: String, tokens_str: String, token_range: Option<TokenRange>) -> Self {
-        Self { label, tokens_str, token_range }
+    /// Name of the argument-specific subproject of a cached macro project, like `args_<hash>`.
+    fn subproject(&self) -> Option<String> {
+        let is_subproject = self.args_specific_output_dir && !self.one_shot_output_dir;
+        is_subproject.then(|| self.macro_dir.file_name()).flatten()
+            .map(|t| t.to_string_lossy().to_string())
     }
 
-    fn to_config_string(&self) -> String {
-        format!("{} = {}", self.label, self.tokens_str)
+    /// Target directory shared by the subprojects of the macro, so their dependencies are compiled
+    /// once, while every subproject keeps its own manifest and code, and thus its fingerprints.
+    fn shared_target_dir(&self) -> Option<PathBuf> {
+        self.subproject()?;
+        self.macro_dir.parent().map(|t| t.join(SHARED_TARGET_DIR))
     }
 
-    #[cfg(nightly)]
-    fn span(&self) -> Span {
-        self.token_range.as_ref().map_or(Span::call_site(), |t| t.span())
+    /// Description of the project layout used in the compilation stats.
+    fn project_kind(&self) -> &'static str {
+        match (self.one_shot_output_dir, self.args_specific_output_dir) {
+            (true, _) => "one-shot, removed after usage",
+            (false, true) => "cached, shared per macro, argument-specific build",
+            (false, false) => "cached, shared per input content",
+        }
     }
-}
 
-#[derive(Debug, Default)]
-struct CargoConfig {
-    edition: Option<String>,
-    resolver: Option<String>,
-    dependencies: Vec<Dependency>,
-    lints: LintsConfig,
-}
+    /// Description of the output root used in the compilation stats.
+    fn output_root_stats(&self) -> String {
+        format!("{} ({})", self.output_root.display(), self.output_root_source.description())
+    }
 
-#[derive(Debug, Default)]
-struct LintsConfig {
-    clippy: String,
-    rust: String,
-}
+    /// Directory of the `phase_cache!` results, shared by all expansions, as the results must
+    /// survive changes of the macro code.
+    fn phase_cache_dir() -> PathBuf {
+        Self::get_output_root().join("phase_cache")
+    }
 
-impl CargoConfig {
-    fn contains_dependency(&self, name: &str) -> bool {
-        self.dependencies.iter().any(|d| d.label == name)
+    /// One-shot directory of a fresh build used to verify the output of a cached project.
+    fn verify_cache_dir() -> PathBuf {
+        Self::get_output_root().join("verify_cache").join(Self::unique_dir_name())
     }
 
-    fn print(&self) -> String {
-        let edition = self.edition.as_ref().map_or(DEFAULT_EDITION, |t| t.as_str());
-        let resolver = self.resolver.as_ref().map_or(DEFAULT_RESOLVER, |t| t.as_str());
-        let lints_rust = &self.lints.rust;
-        let lints_clippy = &self.lints.clippy;
-        let dependencies = self.dependencies.iter()
-            .map(|t| t.to_config_string())
-            .collect::<Vec<_>>()
-            .join("\n");
-        let out = format!("
-            [workspace]
-            [package]
-            name     = \"eval_project\"
-            version  = \"1.0.0\"
-            edition  = \"{edition}\"
-            resolver = \"{resolver}\"
+    /// The stats file shared by all expansions, used to report the build summary.
+    fn build_stats_file() -> PathBuf {
+        Self::get_output_root().join("build_stats")
+    }
 
-            [dependencies]
-            {dependencies}
+    /// The file shared by all expansions, recording the first call sites of deduplicated ones.
+    fn dedup_file() -> PathBuf {
+        Self::get_output_root().join("dedup")
+    }
 
-            [lints.rust]
-            {lints_rust}
+    /// The file describing the inputs of the expansion of the macro with the given input, read by
+    /// external build systems. Named with the stable input hash, like the project dirs, so that the
+    /// build systems find it after toolchain updates.
+    fn metadata_file(macro_name: &str, input_str: &str) -> PathBuf {
+        let file_name = format!("{macro_name}-{}.json", Self::input_hash(input_str));
+        Self::get_output_root().join("metadata").join(file_name)
+    }
 
-            [lints.clippy]
-            {lints_clippy}
-        ");
-        out
+    fn get_output_root() -> PathBuf {
+        Self::resolve_output_root().0
     }
 
-    fn is_workspace(path: &Path) -> Result<bool> {
-        let cargo_toml_content = fs::read_to_string(path)?;
-        let parsed: toml::Value = toml::from_str(&cargo_toml_content)?;
-        Ok(parsed.get("workspace").is_some())
+    /// The output root and how it was chosen. Falling back to the temporary directory is reported
+    /// once per compilation, as the projects are not kept there persistently.
+    fn resolve_output_root() -> (PathBuf, OutputRootSource) {
+        static FALLBACK_WARNING: std::sync::Once = std::sync::Once::new();
+        let (output_root, source) =
+            Self::output_root_with_override(inputs::var(OUT_DIR_ENV), Path::new(OUT_DIR));
+        let reason = match source {
+            OutputRootSource::MissingOutDir => "does not exist, as the macro crate was not built by \
+                cargo",
+            OutputRootSource::TempDir => "is not in a cargo target directory",
+            _ => return (output_root, source),
+        };
+        FALLBACK_WARNING.call_once(|| print_warning!(
+            "The build directory of Crabtime '{OUT_DIR}' {reason}, so the projects are created in \
+            '{}' instead, which the system may clear at any time. Set the '{OUT_DIR_ENV}' \
+            environment variable to choose a persistent directory.",
+            output_root.display()
+        ));
+        (output_root, source)
     }
 
-    fn is_workspace_table(value: &toml::Value) -> bool {
-        if let toml::Value::Table(table) = value {
-            if let Some(toml::Value::Boolean(true)) = table.get("workspace") {
-                return true;
+    /// The `CRABTIME_OUT_DIR` directory used verbatim, if set, or the one derived from `OUT_DIR`.
+    /// Relative paths are resolved against the working directory, like `CARGO_TARGET_DIR`.
+    /// `OUT_DIR` is embedded when the macro crate is built, so if it is missing at expansion time,
+    /// for example, when the crate was built by a non-cargo driver or prebuilt elsewhere, the
+    /// projects are created in the system temporary directory.
+    fn output_root_with_override(
+        override_dir: Option<String>,
+        out_dir: &Path,
+    ) -> (PathBuf, OutputRootSource) {
+        match override_dir.filter(|t| !t.trim().is_empty()) {
+            Some(dir) => {
+                let dir = std::path::absolute(&dir).unwrap_or_else(|_| PathBuf::from(dir));
+                (dir, OutputRootSource::Env)
             }
+            None if !out_dir.exists() =>
+                (Self::fallback_output_root(), OutputRootSource::MissingOutDir),
+            None => Self::output_root(out_dir),
         }
-        false
     }
 
-    fn get_package_edition(table: &toml::Table) -> Option<&str> {
-        table.get("package")
-            .and_then(toml::Value::as_table)
-            .and_then(|pkg_table| pkg_table.get("edition"))
-            .and_then(toml::Value::as_str)
+    /// The output root used when no build directory of the macro crate can be found.
+    fn fallback_output_root() -> PathBuf {
+        std::env::temp_dir().join(CRATE)
     }
 
-    fn get_package_version<'t>(table: &'t toml::Table, name: &str) -> Option<&'t toml::Value> {
-        table.get("dependencies")
-            .and_then(toml::Value::as_table)
-            .and_then(|pkg_table| pkg_table.get(name))
-            .filter(|v| v.is_str())
+    /// Directory of the projects of the macro. The `out_dir` option takes precedence over the
+    /// `CRABTIME_OUT_DIR` environment variable, which takes precedence over `OUT_DIR`.
+    fn project_output_root(options: &MacroOptions) -> (PathBuf, OutputRootSource) {
+        let Some(dir) = &options.out_dir else { return Self::resolve_output_root() };
+        let manifest_dir = inputs::var("CARGO_MANIFEST_DIR").map(PathBuf::from).unwrap_or_default();
+        (manifest_dir.join(dir), OutputRootSource::OutDirOption)
     }
 
-    fn print_lints(lints: &toml::Value) -> String {
-        lints.as_table().map(|t| {
-            t.iter().map(|(k, v)| format!("{k} = {v}")).collect::<Vec<_>>().join("\n")
-        }).unwrap_or_default()
+    /// The `crabtime` directory in the `build` directory of the profile. Cargo places `OUT_DIR` in
+    /// `<build>/<package>-<hash>/out`, so the build directory is found by the layout, not by its
+    /// name, as the target directory can be anywhere with `CARGO_TARGET_DIR`, for example, in
+    /// `/builds/ci/artifacts`. Build wrappers, like cross-rs, can use other layouts, so the
+    /// directory is searched for in order:
+    /// 1. The build directory of the cargo layout.
+    /// 2. The nearest ancestor named `build`.
+    /// 3. The profile directory in the nearest ancestor named `target`.
+    /// 4. The system temporary directory.
+    fn output_root(out_dir: &Path) -> (PathBuf, OutputRootSource) {
+        let build_dir = out_dir.parent().and_then(Path::parent)
+            .filter(|_| out_dir.file_name() == Some(std::ffi::OsStr::new("out")));
+        if let Some(build_dir) = build_dir {
+            return (build_dir.join(CRATE), OutputRootSource::BuildDir)
+        }
+        debug!("OUT_DIR '{}' does not have the cargo layout.", out_dir.display());
+        if let Ok(build_dir) = path::find_parent(out_dir, "build") {
+            return (build_dir.join(CRATE), OutputRootSource::BuildAncestor)
+        }
+        debug!("OUT_DIR '{}' does not have an ancestor named 'build'.", out_dir.display());
+        let target = std::ffi::OsStr::new("target");
+        let profile_dir = out_dir.ancestors()
+            .find(|dir| dir.parent().and_then(Path::file_name) == Some(target));
+        if let Some(profile_dir) = profile_dir {
+            return (profile_dir.join(CRATE), OutputRootSource::TargetProfile)
+        }
+        debug!("OUT_DIR '{}' is not in a 'target' directory.", out_dir.display());
+        (Self::fallback_output_root(), OutputRootSource::TempDir)
     }
 
-    fn fill_from_cargo_toml(&mut self, paths: &CargoConfigPaths) -> Result {
-        use toml::Value;
-        let config_str = fs::read_to_string(&paths.crate_config)?;
-        let workspace_str = paths.workspace_config.as_ref().map(fs::read_to_string).transpose()?;
-        let config = toml::from_str::<Value>(&config_str)?;
-        let workspace_config_opt = workspace_str.map(|t| toml::from_str::<Value>(&t)).transpose()?;
-        let workspace_config_table_opt = workspace_config_opt.as_ref()
-            .and_then(|t| t.get("workspace")).and_then(|v| v.as_table());
-        let dependencies = config
-            .get("build-dependencies")
-            .and_then(|v| v.as_table())
-            .map_or(vec![], |t| t.iter().filter_map(|(k, v)|
-                if !Self::is_workspace_table(v) {
-                    Some(Dependency::new(k.clone(), v.to_string(), None))
-                } else {
-                    workspace_config_table_opt
-                        .and_then(|t| Self::get_package_version(t, k))
-                        .map(|t| Dependency::new(k.clone(), t.to_string(), None))
-                }
-            ).collect());
-        let edition = config
-            .get("package")
-            .and_then(|v| v.as_table())
-            .and_then(|table| table.get("edition"))
-            .and_then(|v| if !Self::is_workspace_table(v) { v.as_str() } else {
-                workspace_config_table_opt.and_then(Self::get_package_edition)
-            })
-            .unwrap_or("2024");
-        let lints = config.get("lints").map(|v| {
-            let table_opt = if Self::is_workspace_table(v) {
-                workspace_config_table_opt
-            } else {
-                v.as_table()
-            };
-            let ws_lints = table_opt
-                .and_then(|t| t.get("lints"))
-                .and_then(|t| t.as_table());
-            let clippy = ws_lints.and_then(|t| t.get("clippy"))
-                .map(Self::print_lints).unwrap_or_default();
-            let rust = ws_lints.and_then(|t| t.get("rust"))
-                .map(Self::print_lints).unwrap_or_default();
-            LintsConfig {clippy, rust}
-        });
-        self.dependencies.extend(dependencies);
-        self.edition = Some(edition.to_string());
-        self.lints = lints.unwrap_or_default();
-        Ok(())
+    fn with_output_dir<T>(&self, f: impl FnOnce(&PathBuf) -> Result<T>) -> Result<T> {
+        remove_other_mode_projects(&self.macro_dir, self.one_shot_output_dir);
+        collect_garbage(&self.macro_dir, max_project_age()?);
+        evict_least_recently_used(&self.output_root, &self.macro_dir, max_cache_size()?);
+        let (output_dir, lock, one_shot) = self.lock_output_dir(PROJECT_LOCK_TIMEOUT)?;
+        if !one_shot {
+            clear_outdated_project(&output_dir, &project_stamp())?;
+        }
+        let out = f(&output_dir);
+        if one_shot {
+            remove_project_dir_if_unused(&output_dir, lock);
+        }
+        out
     }
 
-    fn extract_inline_attributes(&mut self, attributes: Vec<syn::Attribute>) -> Result<String> {
-        let mut other_attributes = Vec::with_capacity(attributes.len());
-        let mut new_dependencies = vec![];
-        for attr in attributes {
-            let tokens = attr.parse_args::<TokenStream>().context("Failed to parse attributes")?;
-            let tokens_str = tokens.to_string().replace(" ", "");
-            let token_range = tokens.clone().into_iter().next()
-                .zip(tokens.clone().into_iter().last())
-                .map(|(first, last)| TokenRange::new(first, last));
-            if attr.path().is_ident("dependency") {
-                let (key, value) = tokens_str.split_once('=').context(||
-                    error!("Incorrect dependency '{tokens_str}'")
-                )?;
-                let key = key.to_string();
-                let value = value.to_string();
-                new_dependencies.push(Dependency::new(key, value, token_range));
-            } else if attr.path().is_ident("edition") {
-                self.edition = Some(tokens_str);
-            } else {
-                other_attributes.push(attr.to_token_stream().to_string());
+    /// Locks the output directory, returning it with the lock and whether it is a one-shot
+    /// project. Cached projects are locked exclusively, so parallel expansions of the same macro,
+    /// like the ones of an IDE and of a terminal build, wait for each other instead of overwriting
+    /// each other's files. If the project stays locked for longer than the timeout, a one-shot
+    /// project is used instead.
+    fn lock_output_dir(&self, timeout: std::time::Duration) -> Result<(PathBuf, File, bool)> {
+        if !self.one_shot_output_dir {
+            if let Some(lock) = lock_project_dir_exclusive(&self.output_dir, timeout)? {
+                return Ok((self.output_dir.clone(), lock, false))
             }
+            print_note!(
+                "The project '{}' has been used by another expansion for more than {} s, so the \
+                macro is built in a one-shot project instead.",
+                self.output_dir.display(), timeout.as_secs()
+            );
         }
-        #[cfg(nightly)]
-        for dependency in &new_dependencies {
-            warning!(dependency.span(),
-                "When using the nightly Rust channel, dependencies should be specified in the \
-                [build-dependencies] section of your Cargo.toml file."
-            ).emit();
-        }
-        self.dependencies.extend(new_dependencies);
-        Ok(other_attributes.join("\n"))
+        let output_dir = if self.one_shot_output_dir {
+            self.output_dir.clone()
+        } else {
+            Self::project_dir(&self.macro_dir, true)
+        };
+        let lock = lock_project_dir(&output_dir)?;
+        Ok((output_dir, lock, true))
     }
 }
 
-fn create_project_skeleton(project_dir: &Path, cfg: CargoConfig, main: &str) -> Result<bool> {
-    let src_dir = project_dir.join("src");
-    let existed = src_dir.exists();
-    if !existed {
-        fs::create_dir_all(&src_dir).context("Failed to create src directory.")?;
-    }
-
-    let cargo_toml = project_dir.join("Cargo.toml");
-    let cargo_toml_content = cfg.print();
-    fs::write(&cargo_toml, cargo_toml_content).context("Failed to write Cargo.toml.")?;
-
-    let main_rs = src_dir.join("main.rs");
-    let mut file = File::create(&main_rs).context("Failed to create main.rs")?;
-    file.write_all(main.as_bytes()).context("Failed to write main.rs")?;
-    Ok(existed)
+/// How the directory containing the projects of all macros was chosen.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputRootSource {
+    /// The `out_dir` option of the macro.
+    OutDirOption,
+    /// The `CRABTIME_OUT_DIR` environment variable.
+    Env,
+    /// The build directory of the cargo layout of `OUT_DIR`.
+    BuildDir,
+    /// The nearest ancestor of `OUT_DIR` named `build`.
+    BuildAncestor,
+    /// The profile directory in the nearest ancestor of `OUT_DIR` named `target`.
+    TargetProfile,
+    /// The system temporary directory, as `OUT_DIR` does not exist.
+    MissingOutDir,
+    /// The system temporary directory, as `OUT_DIR` has an unknown layout.
+    TempDir,
 }
 
-fn get_host_target() -> Result<String> {
-    let output = Command::new("rustc")
-        .arg("-vV")
-        .stdout(std::process::Stdio::piped())
-        .output()
-        .context("Failed to run rustc")?;
-
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    for line in stdout.lines() {
-        if let Some(stripped) = line.strip_prefix("host:") {
-            return Ok(stripped.trim().to_string())
+impl OutputRootSource {
+    /// Description shown in the compilation stats.
+    fn description(self) -> &'static str {
+        match self {
+            Self::OutDirOption => "'out_dir' option",
+            Self::Env => OUT_DIR_ENV,
+            Self::BuildDir => "cargo build directory",
+            Self::BuildAncestor => "'build' ancestor of OUT_DIR",
+            Self::TargetProfile => "'target' profile directory",
+            Self::MissingOutDir => "temporary directory, OUT_DIR is missing",
+            Self::TempDir => "temporary directory, unknown OUT_DIR layout",
         }
     }
-    err!("Could not determine host target from rustc")
 }
 
-fn run_cargo_project(project_dir: &PathBuf) -> Result<String> {
-    // In case the project uses .cargo/config.toml, we need to explicitly revert target to native.
-    let host_target = get_host_target()?;
-    let output = Command::new("cargo")
-        .arg("run")
-        .arg("--target")
-        .arg(&host_target)
-        .current_dir(project_dir)
-        .output()
-        .context("Failed to execute cargo run")?;
+/// Creates the project directory if needed and opens its lock file.
+fn open_project_lock(dir: &Path) -> Result<File> {
+    let lock_path = dir.join(LOCK_FILE);
+    fs::create_dir_all(dir).with_path("create project directory", dir)?;
+    fs::OpenOptions::new().create(true).truncate(false).write(true)
+        .open(&lock_path)
+        .with_path("open the lock file", &lock_path)
+}
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        // TODO: Parse it and map gen code spans to call site spans.
-        eprintln!("{stderr}");
-        #[allow(clippy::panic)]
-        if let Some(index) = stderr.find("thread 'main' panicked") {
-            panic!("{}", &stderr[index..]);
+/// Creates the project directory if needed and takes a shared lock on it, held as long as the
+/// returned file is alive.
+fn lock_project_dir(dir: &Path) -> Result<File> {
+    let lock_path = dir.join(LOCK_FILE);
+    loop {
+        let lock = open_project_lock(dir)?;
+        lock.lock_shared().with_path("lock", &lock_path)?;
+        // The directory could have been removed while we were waiting for the lock.
+        if lock_path.exists() {
+            return Ok(lock)
         }
-        err!("Compilation of the generated code failed.")
-    } else {
-        Ok(String::from_utf8_lossy(&output.stdout).to_string())
     }
 }
 
-// ====================
-// === Output Macro ===
-// ====================
-
-/// Find and expand the `output!` macro in the input `TokenStream`. After this lib stabilizes, this
-/// should be rewritten to standard macro and imported by the generated code.
-fn expand_expand_macro(input: TokenStream) -> TokenStream {
-    let tokens: Vec<TokenTree> = input.into_iter().collect();
-    let mut output = TokenStream::new();
-    let mut i = 0;
-    while i < tokens.len() {
-        if let TokenTree::Ident(ref ident) = tokens[i] {
-            if *ident == "expand" && i + 1 < tokens.len() {
-                if let TokenTree::Punct(ref excl) = tokens[i + 1] {
-                    if excl.as_char() == '!' && i + 2 < tokens.len() {
-                        if let TokenTree::Group(ref group) = tokens[i + 2] {
-                            output.extend(group.stream());
-                            i += 3;
-                            continue;
-                        }
-                    }
-                }
+/// Creates the project directory if needed and takes an exclusive lock on it, held as long as the
+/// returned file is alive. Returns `None` if the lock could not be taken within the timeout.
+fn lock_project_dir_exclusive(dir: &Path, timeout: std::time::Duration) -> Result<Option<File>> {
+    let lock_path = dir.join(LOCK_FILE);
+    let start = std::time::Instant::now();
+    loop {
+        let lock = open_project_lock(dir)?;
+        loop {
+            match lock.try_lock() {
+                Ok(()) => break,
+                Err(fs::TryLockError::WouldBlock) if start.elapsed() < timeout =>
+                    std::thread::sleep(PROJECT_LOCK_POLL_INTERVAL),
+                Err(fs::TryLockError::WouldBlock) => return Ok(None),
+                Err(fs::TryLockError::Error(err)) => return Err(err).with_path("lock", &lock_path),
             }
         }
-        match &tokens[i] {
-            TokenTree::Group(group) => {
-                let new_stream = expand_expand_macro(group.stream());
-                let new_group = TokenTree::Group(
-                    proc_macro2::Group::new(group.delimiter(), new_stream)
-                );
-                output.extend(std::iter::once(new_group));
-            }
-            _ => {
-                output.extend(std::iter::once(tokens[i].clone()));
-            }
+        // The directory could have been removed while we were waiting for the lock.
+        if lock_path.exists() {
+            return Ok(Some(lock))
         }
-        i += 1;
     }
-    output
 }
 
-fn expand_builtin_macro(
-    name: &str,
-    input: TokenStream,
-    f: &impl Fn(TokenStream) -> TokenStream
-) -> TokenStream {
-    let tokens: Vec<TokenTree> = input.into_iter().collect();
-    let mut output = TokenStream::new();
-    let len = tokens.len();
-    let mut i = 0;
+/// Removes the project directory, unless it is locked by another expansion, which is then
+/// responsible for removing it. The lock file is removed last, after the lock is released, as open
+/// files cannot be removed on Windows.
+fn remove_project_dir_if_unused(dir: &Path, lock: File) {
+    if lock.try_lock().is_err() {
+        return
+    }
+    for entry in fs::read_dir(dir).into_iter().flatten().flatten() {
+        let path = entry.path();
+        if entry.file_name() != LOCK_FILE {
+            remove_with_retries(&path, |t| if t.is_dir() { fs::remove_dir_all(t) } else {
+                fs::remove_file(t)
+            });
+        }
+    }
+    drop(lock);
+    remove_with_retries(dir, |t| fs::remove_dir_all(t));
+}
 
-    while i < len {
-        // Check for the pattern: crabtime :: output ! ( group )
-        if i + 5 < len {
-            if let TokenTree::Ident(ref ident) = tokens[i] {
-                if ident == GEN_MOD {
-                    if let TokenTree::Punct(ref colon1) = tokens[i + 1] {
-                        if colon1.as_char() == ':' {
-                            if let TokenTree::Punct(ref colon2) = tokens[i + 2] {
-                                if colon2.as_char() == ':' {
-                                    if let TokenTree::Ident(ref out_ident) = tokens[i + 3] {
-                                        if out_ident == name {
-                                            if let TokenTree::Punct(ref excl) = tokens[i + 4] {
-                                                if excl.as_char() == '!' {
-                                                    if let TokenTree::Group(ref group) = tokens[i + 5] {
-                                                        let inner_rewritten = expand_builtin_macro(name, group.stream(), f);
-                                                        let new_tokens = f(inner_rewritten);
-                                                        output.extend(new_tokens);
-                                                        i += 6;
-                                                        continue;
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
+/// Removes the path, retrying with growing delays, as on Windows, files can stay open for a while
+/// after their processes end, for example, by antivirus scanners. If it still fails, the path is
+/// renamed with the `.trash` suffix, so it does not get in the way of new projects, and it is
+/// removed by a later expansion.
+fn remove_with_retries(path: &Path, remove: impl Fn(&Path) -> std::io::Result<()>) {
+    let mut delay = REMOVE_RETRY_DELAY;
+    for attempt in 0..=REMOVE_RETRIES {
+        match remove(path) {
+            Ok(()) => return,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return,
+            Err(err) if attempt == REMOVE_RETRIES => {
+                debug!("Failed to remove '{}': {err}", path.display());
+            }
+            Err(_) => {
+                std::thread::sleep(delay);
+                delay *= 2;
             }
         }
+    }
+    let Some(name) = path.file_name() else { return };
+    let trash_name = format!("{}.{}{TRASH_SUFFIX}", name.to_string_lossy(), Paths::unique_dir_name());
+    fs::rename(path, path.with_file_name(trash_name)).ok();
+}
 
-        // Recurse into groups or pass through token.
-        match &tokens[i] {
-            TokenTree::Group(group) => {
-                let new_stream = expand_builtin_macro(name, group.stream(), f);
-                // Rebuild group with same delimiter.
-                let new_group = TokenTree::Group(proc_macro2::Group::new(group.delimiter(), new_stream));
-                output.extend(std::iter::once(new_group));
+/// Removes the entries of the macro directory and of its one-shot projects directory which could
+/// not be removed earlier and were renamed with the `.trash` suffix.
+fn remove_trash(macro_dir: &Path) {
+    for dir in [macro_dir.to_path_buf(), macro_dir.join(ONE_SHOT_PROJECTS_DIR)] {
+        for entry in fs::read_dir(&dir).into_iter().flatten().flatten() {
+            if entry.file_name().to_string_lossy().ends_with(TRASH_SUFFIX) {
+                let path = entry.path();
+                let removed =
+                    if path.is_dir() { fs::remove_dir_all(&path) } else { fs::remove_file(&path) };
+                removed.ok();
             }
-            token => output.extend(std::iter::once(token.clone())),
         }
-        i += 1;
     }
-    output
 }
 
-fn expand_output_macro(input: TokenStream) -> TokenStream {
-    let gen_mod = syn::Ident::new(GEN_MOD, Span::call_site());
-    expand_builtin_macro("output", input, &|inner_rewritten| {
-        let content_str = print_tokens(&inner_rewritten);
-        let lit = syn::LitStr::new(&content_str, Span::call_site());
-        quote! {
-            #gen_mod::write_ln!(__output_buffer__, #lit);
+/// Removes the projects left in the macro directory by the other caching mode, e.g. after the
+/// `cache` option was toggled. Projects still used by other expansions are kept.
+fn remove_other_mode_projects(macro_dir: &Path, one_shot: bool) {
+    if one_shot {
+        remove_stale_project_dir(&macro_dir.join(CACHED_PROJECT_DIR));
+    } else {
+        let one_shot_dir = macro_dir.join(ONE_SHOT_PROJECTS_DIR);
+        for entry in fs::read_dir(&one_shot_dir).into_iter().flatten().flatten() {
+            remove_stale_project_dir(&entry.path());
         }
-    })
+        // Fails if any of the projects is still in use.
+        fs::remove_dir(&one_shot_dir).ok();
+    }
 }
 
-fn expand_quote_macro(input: TokenStream) -> TokenStream {
-    expand_builtin_macro("quote", input, &|inner_rewritten| {
-        let content_str = print_tokens(&inner_rewritten);
-        let lit = syn::LitStr::new(&content_str, Span::call_site());
-        quote! { format!(#lit) }
-    })
+/// Number of days after which unused projects are removed, set with `CRABTIME_MAX_PROJECT_AGE`.
+fn max_project_age() -> Result<std::time::Duration> {
+    let days = match inputs::var(MAX_PROJECT_AGE_ENV) {
+        None => DEFAULT_MAX_PROJECT_AGE_DAYS,
+        Some(value) => value.trim().parse::<u64>().context(|| error!(
+            "Invalid value '{value}' of the '{MAX_PROJECT_AGE_ENV}' environment variable. \
+            Expected a number of days."
+        ))?,
+    };
+    Ok(std::time::Duration::from_secs(days * 24 * 60 * 60))
 }
 
-// =============
-// === Print ===
-// =============
-
-#[derive(Debug)]
-struct PrintOutput {
-    output: String,
-    start_token: Option<LineColumn>,
-    end_token: Option<LineColumn>,
+/// Removes the projects abandoned by killed compiler processes, like after Ctrl-C or a canceled
+/// IDE check, and records the use of the macro directory. One-shot projects are abandoned if they
+/// are not locked, as the lock is released when its process ends. The other macro directories of
+/// the same kind, like the `project_<hash>` directories of older inputs, are removed if they were
+/// not used for `max_age`. Failures are ignored, as the garbage is removed by later expansions.
+fn collect_garbage(macro_dir: &Path, max_age: std::time::Duration) {
+    remove_trash(macro_dir);
+    for entry in fs::read_dir(macro_dir.join(ONE_SHOT_PROJECTS_DIR)).into_iter().flatten().flatten() {
+        remove_stale_project_dir(&entry.path());
+    }
+    fs::create_dir_all(macro_dir).and_then(|_| fs::write(macro_dir.join(LAST_USED_FILE), "")).ok();
+    let Some(name) = macro_dir.file_name().and_then(|t| t.to_str()) else { return };
+    let Some((kind, _)) = name.split_once('_') else { return };
+    let Some(parent) = macro_dir.parent() else { return };
+    for entry in fs::read_dir(parent).into_iter().flatten().flatten() {
+        let dir = entry.path();
+        let is_same_kind = entry.file_name().to_str().is_some_and(|t| {
+            t != name && t.split_once('_').is_some_and(|(prefix, hash)| {
+                prefix == kind && hash.chars().all(|c| c.is_ascii_hexdigit())
+            })
+        });
+        let last_used = fs::metadata(dir.join(LAST_USED_FILE)).or_else(|_| entry.metadata())
+            .and_then(|t| t.modified());
+        let is_stale = last_used.ok()
+            .and_then(|t| t.elapsed().ok())
+            .is_some_and(|age| age > max_age);
+        if is_same_kind && is_stale {
+            remove_unused_macro_dir(&dir);
+        }
+    }
 }
 
-/// Prints the token stream as a string ready to be used by the format macro. It is very careful
-/// where spaces are inserted. In particular, spaces are not inserted around `{` and `}` tokens if
-/// they were not present in the original token stream. It is fine-tuned to work in different IDEs,
-/// such as `RustRover`.
-fn print_tokens(tokens: &TokenStream) -> String {
-    // Replaces `{` with `{{` and vice versa.
-    print_tokens_internal(tokens).output
-        .replace("{", "{{")
-        .replace("}", "}}")
-        .replace("{{%%%{{%%%{{", "{{ {")
-        .replace("}}%%%}}%%%}}", "} }}")
-        .replace("{{%%%{{", "{")
-        .replace("}}%%%}}", "}")
+/// Maximum size of the `crabtime` build directory in bytes, set with `CRABTIME_CACHE_MAX_MB`.
+fn max_cache_size() -> Result<u64> {
+    let megabytes = match inputs::var(CACHE_MAX_MB_ENV) {
+        None => DEFAULT_CACHE_MAX_MB,
+        Some(value) => value.trim().parse::<u64>().context(|| error!(
+            "Invalid value '{value}' of the '{CACHE_MAX_MB_ENV}' environment variable. \
+            Expected a number of megabytes."
+        ))?,
+    };
+    Ok(megabytes.saturating_mul(1024 * 1024))
 }
 
-fn print_tokens_internal(tokens: &TokenStream) -> PrintOutput {
-    let token_vec: Vec<TokenTree> = tokens.clone().into_iter().collect();
-    let mut output = String::new();
-    let mut first_token_start = None;
-    let mut prev_token_end: Option<LineColumn> = None;
-    let mut prev_token_was_brace = false;
-    for (i, token) in token_vec.iter().enumerate() {
-        let mut add_space = true;
-        let mut token_start = token.span().start();
-        let mut token_end = token.span().end();
-        let mut is_brace = false;
-        let mut is_keyword = false;
-        let token_str = match token {
-            TokenTree::Group(g) => {
-                let content = print_tokens_internal(&g.stream());
-                let mut content_str = content.output;
-                content_str.pop();
-                let (open, close) = match g.delimiter() {
-                    Delimiter::Brace => {
-                        is_brace = true;
-                        if content_str.starts_with("{") && content_str.ends_with("}") {
-                            ("{%%%", "%%%}")
-                        } else {
-                            ("{", "}")
-                        }
-                    },
-                    Delimiter::Parenthesis => ("(", ")"),
-                    Delimiter::Bracket => ("[", "]"),
-                    _ => ("", ""),
-                };
+/// Size and last use of a macro directory, a directory with the [`LAST_USED_FILE`].
+#[derive(Clone, Debug, PartialEq)]
+struct MacroDirUsage {
+    path: PathBuf,
+    last_used: std::time::SystemTime,
+    size: u64,
+}
 
-                if let Some(content_first_token_start) = content.start_token {
-                    token_start.line = content_first_token_start.line;
-                    if content_first_token_start.column > 0 {
-                        token_start.column = content_first_token_start.column - 1;
-                    }
-                }
-                if let Some(content_end) = content.end_token {
-                    token_end.line = content_end.line;
-                    token_end.column = content_end.column + 1;
-                }
+/// Removes the least recently used macro directories, other than the one being expanded, until
+/// the `crabtime` build directory fits the maximum size. The size is checked at most once per
+/// [`CACHE_SIZE_CHECK_INTERVAL`]. Macro directories with projects in use are not removed.
+fn evict_least_recently_used(output_root: &Path, current_macro_dir: &Path, max_size: u64) {
+    let checked_file = output_root.join(CACHE_SIZE_CHECKED_FILE);
+    let checked_recently = fs::metadata(&checked_file).and_then(|t| t.modified()).ok()
+        .and_then(|t| t.elapsed().ok())
+        .is_some_and(|t| t < CACHE_SIZE_CHECK_INTERVAL);
+    if checked_recently {
+        return
+    }
+    fs::create_dir_all(output_root).and_then(|_| fs::write(&checked_file, "")).ok();
+    let mut macro_dirs = Vec::new();
+    let total_size = scan_cache_dir(output_root, &mut macro_dirs);
+    for dir in select_evictions(macro_dirs, total_size, max_size, current_macro_dir) {
+        debug!("Evicting the least recently used macro directory '{}'.", dir.display());
+        remove_unused_macro_dir(&dir);
+    }
+}
 
-                format!("{open}{content_str}{close}")
-            }
-            TokenTree::Ident(ident) => {
-                let str = ident.to_string();
-                is_keyword = KEYWORDS.contains(&str.as_str());
-                str
-            },
-            TokenTree::Literal(lit) => lit.to_string(),
-            TokenTree::Punct(punct) => {
-                if punct.spacing() == proc_macro2::Spacing::Joint {
-                    add_space = false;
-                }
-                punct.as_char().to_string()
-            },
-        };
-        debug!("{i}: [{token_start:?}-{token_end:?}] [{prev_token_end:?}]: {token}");
+/// Computes the size of the directory, collecting the usage of the macro directories in it.
+fn scan_cache_dir(dir: &Path, macro_dirs: &mut Vec<MacroDirUsage>) -> u64 {
+    let last_used = fs::metadata(dir.join(LAST_USED_FILE)).and_then(|t| t.modified());
+    if let Ok(last_used) = last_used {
+        let size = dir_size(dir);
+        macro_dirs.push(MacroDirUsage { path: dir.to_path_buf(), last_used, size });
+        return size
+    }
+    fs::read_dir(dir).into_iter().flatten().flatten().map(|entry| {
+        match entry.file_type() {
+            Ok(tp) if tp.is_dir() => scan_cache_dir(&entry.path(), macro_dirs),
+            _ => entry.metadata().map_or(0, |t| t.len()),
+        }
+    }).sum()
+}
 
-        // check if the punct has set flags to have no spaces
-        if is_brace || prev_token_was_brace {
-            if let Some(prev_token_end) = prev_token_end {
-                if prev_token_end.line == token_start.line
-                && prev_token_end.column >= token_start.column
-                && output.ends_with(" ") {
-                    output.pop();
-                }
-            }
+/// Total size of the files in the directory. Symbolic links are not followed.
+fn dir_size(dir: &Path) -> u64 {
+    fs::read_dir(dir).into_iter().flatten().flatten().map(|entry| {
+        match entry.file_type() {
+            Ok(tp) if tp.is_dir() => dir_size(&entry.path()),
+            _ => entry.metadata().map_or(0, |t| t.len()),
         }
-        prev_token_was_brace = is_brace;
+    }).sum()
+}
 
-        // Pushing a space before and after keywords is for IntelliJ only.
-        // Their token spans are invalid.
-        if is_keyword { output.push(' '); }
-        output.push_str(&token_str);
-        if add_space {
-            output.push(' ');
+/// Macro directories to remove, least recently used first, until the total size fits the maximum.
+/// The macro directory being expanded is never removed.
+fn select_evictions(
+    mut macro_dirs: Vec<MacroDirUsage>,
+    total_size: u64,
+    max_size: u64,
+    current_macro_dir: &Path,
+) -> Vec<PathBuf> {
+    macro_dirs.sort_by_key(|t| t.last_used);
+    let mut size = total_size;
+    let mut evicted = Vec::new();
+    for dir in macro_dirs {
+        if size <= max_size {
+            break
         }
-        if is_keyword { output.push(' '); }
+        if dir.path != current_macro_dir {
+            size = size.saturating_sub(dir.size);
+            evicted.push(dir.path);
+        }
+    }
+    evicted
+}
 
-        first_token_start.get_or_insert(token_start);
-        prev_token_end = Some(token_end);
+/// Removes the macro directory with its projects, unless any of them is in use.
+fn remove_unused_macro_dir(macro_dir: &Path) {
+    remove_trash(macro_dir);
+    remove_stale_project_dir(&macro_dir.join(CACHED_PROJECT_DIR));
+    let one_shot_dir = macro_dir.join(ONE_SHOT_PROJECTS_DIR);
+    for entry in fs::read_dir(&one_shot_dir).into_iter().flatten().flatten() {
+        remove_stale_project_dir(&entry.path());
     }
-    PrintOutput {
-        output,
-        start_token: first_token_start,
-        end_token: prev_token_end,
+    // Fails if any of the projects is still in use.
+    fs::remove_dir(&one_shot_dir).ok();
+    let remaining = fs::read_dir(macro_dir).into_iter().flatten().flatten()
+        .any(|t| t.file_name() != LAST_USED_FILE);
+    if !remaining {
+        fs::remove_dir_all(macro_dir).ok();
+    }
+    // The target directory shared by the subprojects is not needed after the last one is removed.
+    let Some(parent) = macro_dir.parent() else { return };
+    let subprojects_remain = fs::read_dir(parent).into_iter().flatten().flatten()
+        .any(|t| t.file_name() != SHARED_TARGET_DIR);
+    if !subprojects_remain && parent.join(SHARED_TARGET_DIR).is_dir() {
+        fs::remove_dir_all(parent.join(SHARED_TARGET_DIR)).ok();
     }
 }
 
-// ==================
-// === Eval Macro ===
-// ==================
+/// Removes a project directory left by an earlier expansion, unless it is in use.
+fn remove_stale_project_dir(dir: &Path) {
+    let lock_path = dir.join(LOCK_FILE);
+    if let Ok(lock) = fs::OpenOptions::new().write(true).open(&lock_path) {
+        remove_project_dir_if_unused(dir, lock);
+    }
+}
 
-enum Args {
-    TokenStream { ident: syn::Ident },
-    Pattern { str: TokenStream }
+/// File of a cached project directory naming the Crabtime version and the compiler the project
+/// was generated with.
+const PROJECT_STAMP_FILE: &str = "crabtime-stamp";
+
+/// The stamp of the projects generated by this Crabtime version, expanded by this compiler.
+fn project_stamp() -> String {
+    format!("crabtime {}\n{RUSTC_VERSION}\n", env!("CARGO_PKG_VERSION"))
 }
 
-impl Args {
-    fn pattern(&self) -> TokenStream {
-        match self {
-            Self::TokenStream { ident } => quote! { $($#ident:tt)* },
-            Self::Pattern { str } => str.clone(),
-        }
+/// File of a cached project storing the output of its last run, reused by macros with the
+/// `memoize` option.
+const MEMO_FILE: &str = "crabtime-memo";
+
+/// Header of the memoized output of the project, naming the Crabtime version and the compiler, and
+/// hashing the manifest and the code, so the output is reused only if none of them changed.
+fn memo_header(cfg: &CargoConfig, input_code: &str) -> String {
+    let code = format!("{}\n{}\n{input_code}", cfg.print(), cfg.profile.name());
+    format!("{}{:032x}\n", project_stamp(), inputs::stable_hash(code.as_bytes()))
+}
+
+/// The memoized output of the project, if it was stored with the given header.
+fn read_memoized_output(project_dir: &Path, header: &str) -> Option<String> {
+    let memo = fs::read_to_string(project_dir.join(MEMO_FILE)).ok()?;
+    memo.strip_prefix(header).map(|t| t.to_string())
+}
+
+/// Stores the output of the project run, unless it is not reusable, like the ones of macros using
+/// random numbers. Returns the memoization status shown in the compilation stats.
+fn memoize_output(project_dir: &Path, header: &str, output: &str) -> Result<&'static str> {
+    if output.lines().any(|line| line.trim() == NON_REUSABLE_MARKER) {
+        fs::remove_file(project_dir.join(MEMO_FILE)).ok();
+        return Ok("on, skipped for a non-reusable output")
     }
+    write_if_changed(&project_dir.join(MEMO_FILE), format!("{header}{output}").as_bytes())?;
+    Ok("on, stored")
+}
 
-    fn setup(&self) -> TokenStream {
-        if let Self::TokenStream { ident } = self {
-            quote! {
-                use proc_macro2::TokenStream;
-                let #ident: TokenStream = stringify!($($#ident)*).parse().unwrap();
-            }
-        } else {
-            Default::default()
+/// Removes the files of a cached project generated by another Crabtime version or compiler, so the
+/// project is generated and built from scratch instead of reusing its outdated files, like the
+/// prelude of the old version. The project must be locked exclusively. Returns whether the project
+/// was cleared.
+fn clear_outdated_project(dir: &Path, stamp: &str) -> Result<bool> {
+    let stamp_file = dir.join(PROJECT_STAMP_FILE);
+    if fs::read_to_string(&stamp_file).is_ok_and(|t| t == stamp) {
+        return Ok(false)
+    }
+    for entry in fs::read_dir(dir).with_path("read directory", dir)?.flatten() {
+        let path = entry.path();
+        if entry.file_name() == LOCK_FILE {
+            continue
         }
+        let removed = if path.is_dir() { fs::remove_dir_all(&path) } else { fs::remove_file(&path) };
+        removed.with_path("remove", &path)?;
     }
+    fs::write(&stamp_file, stamp).with_path("write", &stamp_file)?;
+    Ok(true)
 }
 
-fn parse_args(
-    args: &syn::punctuated::Punctuated<syn::FnArg, syn::token::Comma>
-) -> Option<(Args, TokenStream)> {
-    let Some(arg) = args.first() else {
-        return Some((Args::Pattern { str: Default::default() }, TokenStream::new()))
-    };
+/// The resolver cargo uses by default for the edition. Resolver 3 requires edition 2024, so older
+/// editions cannot use it unless it is set explicitly.
+fn default_resolver(edition: &str) -> &'static str {
+    match edition {
+        "2015" => "1",
+        "2018" | "2021" => "2",
+        _ => "3",
+    }
+}
 
-    // First try the specialized parsers, then fallback to our generic type handling.
-    parse_args_for_pattern(arg)
-        .or_else(|| parse_args_for_token_stream(arg))
-        .map(|t| (t, TokenStream::new()))
-        .or_else(|| {
-            let mut is_first = true;
-            let mut pat = quote!{};
-            let mut code = TokenStream::new();
+// ========================
+// === CargoConfigPaths ===
+// ========================
 
-            for arg in args {
-                if !is_first {
-                    pat = quote! {#pat, };
-                }
-                is_first = false;
-                if let syn::FnArg::Typed(pat_type) = arg {
-
-                    if let syn::Pat::Ident(name) = &*pat_type.pat {
-                        let name_str = name.ident.to_string();
-                        let ty = &*pat_type.ty;
-                        code = quote! {
-                            #code
-                            let #name: #ty =
-                        };
-                        if let Some((param_pat, param_code)) = parse_arg_type(&name_str, ty) {
-                            pat = quote! {#pat #param_pat};
-                            code = quote! {#code #param_code};
-                        }
-                        code = quote! {#code;};
-                    }
-                }
-            }
-            pat = quote! {#pat $(,)?};
-            Some((Args::Pattern { str: pat }, code))
-        })
+#[derive(Debug)]
+struct CargoConfigPaths {
+    crate_config: PathBuf,
+    workspace_config: Option<PathBuf>,
 }
 
-/// Returns (pattern, code) for a given type. It supports both vector types and non‑vector types.
-#[inline(always)]
-fn parse_arg_type(pfx: &str, ty: &syn::Type) -> Option<(TokenStream, TokenStream)> {
-    if let syn::Type::Path(type_path) = ty {
-        let last_segment = type_path.path.segments.last()?;
-        if last_segment.ident == "Vec" {
-            if let syn::PathArguments::AngleBracketed(angle_bracketed) = &last_segment.arguments {
-                let generic_arg = angle_bracketed.args.first()?;
-                if let syn::GenericArgument::Type(inner_ty) = generic_arg {
-                    if let Some((inner_pat, inner_code)) = parse_inner_type(pfx, inner_ty) {
-                        let pat = quote! {[$(#inner_pat),*$(,)?]};
-                        let code = quote! { [$(#inner_code),*].into_iter().collect() };
-                        return Some((pat, code));
-                    }
-                }
+fn find_cargo_configs(path: &Path) -> Result<CargoConfigPaths> {
+    let mut current_path = path.to_path_buf();
+    let mut candidates = Vec::new();
+    loop {
+        let candidate = current_path.join("Cargo.toml");
+        if candidate.is_file() { candidates.push(candidate) }
+        if !current_path.pop() { break }
+    }
+    if candidates.is_empty() {
+        return err!("No 'Cargo.toml' files found in parent directories of '{}'.", path.display())
+    }
+    // Virtual manifests, like the ones of nested example directories, do not describe the crate.
+    // If no package manifest is found, the nearest manifest is used.
+    let crate_index = candidates.iter()
+        .position(|candidate| !CargoConfig::is_virtual_manifest(candidate))
+        .unwrap_or_default();
+    let crate_config = &candidates[crate_index];
+    let other_candidates = &candidates[crate_index + 1..];
+
+    // Cargo uses the nearest workspace not excluding the crate, and a crate with its own
+    // `[workspace]` table is a workspace root itself. Workspaces listing the crate in `members` are
+    // preferred, as the other ones can contain it only as a path dependency of a member.
+    let crate_dir = path::parent(crate_config)?;
+    let is_root = CargoConfig::is_workspace(crate_config).unwrap_or_default();
+    let mut listing = None;
+    let mut enclosing = None;
+    for candidate in other_candidates.iter().filter(|_| !is_root) {
+        match CargoConfig::workspace_membership(candidate, crate_dir) {
+            Ok(Some(Membership::Listed)) => {
+                listing = Some(candidate.clone());
+                break
             }
-        } else {
-            return parse_inner_type(pfx, ty);
+            Ok(Some(Membership::Unlisted)) => { enclosing.get_or_insert_with(|| candidate.clone()); }
+            Ok(Some(Membership::Excluded) | None) => {}
+            // A broken manifest outside of the crate should not stop all macros from expanding.
+            Err(err) => print_warning!("Skipping workspace discovery: {}", err.message),
         }
     }
-    None
+    let workspace_config = listing.or(enclosing);
+    let crate_config = crate_config.clone();
+    Ok(CargoConfigPaths { crate_config, workspace_config })
 }
 
-#[inline(always)]
-fn parse_inner_type(pfx: &str, ty: &syn::Type) -> Option<(TokenStream, TokenStream)> {
-    let arg_str = format!("{pfx}_arg");
-    let arg_ident = syn::Ident::new(&arg_str, Span::call_site());
-    let arg = quote! {$#arg_ident};
-    match ty {
-        syn::Type::Reference(ty_ref) => {
-            if let syn::Type::Path(inner_path) = &*ty_ref.elem {
-                if let Some(inner_seg) = inner_path.path.segments.last() {
-                    if inner_seg.ident == "str" {
-                        let pat = quote!{#arg:expr};
-                        let code = quote!{crabtime::stringify_if_needed!{#arg}};
-                        return Some((pat, code));
-                    }
-                }
-            }
-        },
-        syn::Type::Path(inner_type_path) => {
-            if let Some(inner_seg) = inner_type_path.path.segments.last() {
-                let ident_str = inner_seg.ident.to_string();
-                if ident_str == "String" {
-                    let pat = quote!{#arg:expr};
-                    let code = quote!{crabtime::stringify_if_needed!(#arg).to_string()};
-                    return Some((pat, code));
-                } else if matches!(ident_str.as_str(),
-                    "usize" | "u8" | "u16" | "u32" | "u64" | "u128" |
-                    "isize" | "i8" | "i16" | "i32" | "i64" | "i128"
-                ) {
-                    return Some((quote!{#arg:literal}, quote!{#arg}));
-                }
-            }
-        },
-        _ => {}
-    }
-    None
+/// Relation of a crate to a workspace enclosing it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Membership {
+    /// The crate matches a `members` glob of the workspace.
+    Listed,
+    /// The crate is neither listed nor excluded. Cargo includes such crates if they are path
+    /// dependencies of the members.
+    Unlisted,
+    /// The crate is in an `exclude` directory of the workspace and is not listed explicitly.
+    Excluded,
 }
 
-fn parse_args_for_pattern(arg: &syn::FnArg) -> Option<Args> {
-    let syn::FnArg::Typed(pat) = arg else { return None };
-    let syn::Pat::Macro(m) = &*pat.pat else { return None };
-    Some(Args::Pattern {str: m.mac.tokens.clone() })
+// ===================
+// === CargoConfig ===
+// ===================
+
+/// Checks whether the path is absolute on any platform, including Windows drive paths like
+/// `C:/deps`, which are not absolute for the Unix [`Path`].
+fn is_absolute_path(path: &str) -> bool {
+    let bytes = path.as_bytes();
+    let has_drive = bytes.len() > 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':'
+        && bytes[2] == b'/';
+    has_drive || path.starts_with('/') || Path::new(path).is_absolute()
 }
 
-fn parse_args_for_token_stream(arg: &syn::FnArg) -> Option<Args> {
-    let syn::FnArg::Typed(pat) = arg else { return None };
-    let syn::Pat::Ident(pat_ident) = &*pat.pat else { return None };
-    let tp = &pat.ty;
-    let tp_str = quote! { #tp }.to_string();
-    if tp_str != "TokenStream" { return None }
-    let ident = pat_ident.ident.clone();
-    Some(Args::TokenStream { ident })
+#[derive(Debug)]
+struct Dependency {
+    label: String,
+    tokens_str: String,
+    token_range: Option<TokenRange>,
 }
 
-const WRONG_ARGS: &str = "Function should have zero or one argument, one of:
-    - `pattern!(<pattern>): _`, where <pattern> is a `macro_rules!` pattern
-    - `input: TokenStream`
-";
+impl Dependency {
+    fn new(label: String, tokens_str: String, token_range: Option<TokenRange>) -> Self {
+        Self { label, tokens_str, token_range }
+    }
 
-fn prepare_input_code(
-    attributes:&str,
-    body: &str,
-    output_tp: &str,
-    include_token_stream_impl: bool,
-    paths: &Paths
-) -> String {
-    let body_esc: String = body.chars().flat_map(|c| c.escape_default()).collect();
-    let prelude = gen_prelude(include_token_stream_impl, paths);
-    format!("
-        {attributes}
-        {prelude}
+    fn to_config_string(&self) -> String {
+        format!("{} = {}", self.label, self.tokens_str)
+    }
 
-        const SOURCE_CODE: &str = \"{body_esc}\";
+    fn span(&self) -> Span {
+        self.token_range.as_ref().map_or(Span::call_site(), |t| t.span())
+    }
 
-        fn main() {{
-            let mut __output_buffer__ = String::new();
-            let result: {output_tp} = {{
-                {body}
-            }};
-            __output_buffer__.push_str(&{GEN_MOD}::code_from_output(result));
-            println!(\"{{}}\", {GEN_MOD}::prefix_lines_with_output(&__output_buffer__));
-        }}",
-    )
-}
+    /// The dependency specification, like `"1"` or `{ package = "proc-macro2", version = "1" }`.
+    fn spec(&self) -> Option<toml::Value> {
+        let mut table = toml::from_str::<toml::Table>(&format!("spec = {}", self.tokens_str)).ok()?;
+        table.remove("spec")
+    }
 
-fn parse_output(output: &str) -> String {
-    let mut code = String::new();
-    for line in output.split('\n') {
-        let line_trimmed = line.trim();
-        if let Some(stripped) = line_trimmed.strip_prefix(OUTPUT_PREFIX) {
-            code.push_str(stripped);
-            code.push('\n');
-        } else if let Some(stripped) = line_trimmed.strip_prefix(Level::WARNING_PREFIX) {
-            print_warning!("{}", stripped);
-        } else if let Some(stripped) = line_trimmed.strip_prefix(Level::ERROR_PREFIX) {
-            print_error!("{}", stripped);
-        } else if !line_trimmed.is_empty() {
-            println!("{line}");
+    /// Name of the package, which differs from the label if the dependency is renamed.
+    fn package_name(&self) -> String {
+        self.spec()
+            .and_then(|t| t.get("package").and_then(toml::Value::as_str).map(str::to_string))
+            .unwrap_or_else(|| self.label.clone())
+    }
+
+    /// Name the dependency is referred to in code.
+    fn crate_name(&self) -> String {
+        self.label.replace('-', "_")
+    }
+
+    /// Major version of the version requirement, if any. Dependencies without a version
+    /// requirement, like git or path ones, return `None`.
+    fn major_version(&self) -> Option<u64> {
+        let spec = self.spec()?;
+        let version = spec.as_str().or_else(|| spec.get("version").and_then(toml::Value::as_str))?;
+        let requirement = semver::VersionReq::parse(version).ok()?;
+        requirement.comparators.first().map(|t| t.major)
+    }
+
+    /// Name of the alternative registry of the dependency, like `internal` for
+    /// `{ version = "1", registry = "internal" }`.
+    fn registry(&self) -> Option<String> {
+        self.spec()?.get("registry").and_then(toml::Value::as_str).map(str::to_string)
+    }
+
+    /// Parses the `label = spec` tokens of the `#![dependency(...)]` attribute. The spec can be a
+    /// version or an inline table, like `{ version = "1", default-features = false }`. It is
+    /// validated as TOML, so mistakes are reported at the attribute.
+    fn from_attribute_tokens(tokens: TokenStream) -> Result<Self> {
+        let token_range = TokenRange::from_tokens(tokens.clone());
+        let span = SpanMap::span(token_range.as_ref());
+        let tokens = tokens.into_iter().collect::<Vec<_>>();
+        let eq = tokens.iter()
+            .position(|t| matches!(t, TokenTree::Punct(punct) if punct.as_char() == '='))
+            .filter(|eq| *eq > 0 && eq + 1 < tokens.len());
+        let Some(eq) = eq else {
+            return err!(span, "Incorrect dependency '{}'", print_toml_tokens(&tokens))
+        };
+        let label = print_toml_tokens(&tokens[..eq]);
+        let value = Self::normalize_version(&label, &print_toml_tokens(&tokens[eq + 1..]), span)?;
+        if let Err(e) = toml::from_str::<toml::Table>(&format!("spec = {value}")) {
+            return err!(span,
+                "Invalid specification '{value}' of dependency '{label}': {}", e.message().trim()
+            )
+        }
+        Ok(Self::new(label, value, token_range))
+    }
+
+    /// Resolves a relative `path` of the dependency against the base directory, as the generated
+    /// project lives in a different place than the manifest the dependency was written for. Both
+    /// path separators are accepted, and the path is re-serialized as a TOML string, so spaces and
+    /// backslashes are quoted correctly.
+    fn with_absolute_path(mut self, base: &Path) -> Self {
+        let Some(toml::Value::Table(mut spec)) = self.spec() else { return self };
+        let Some(path) = spec.get("path").and_then(toml::Value::as_str) else { return self };
+        let path = path.replace('\\', "/");
+        if is_absolute_path(&path) {
+            return self
+        }
+        let resolved = base.join(path).display().to_string().replace('\\', "/");
+        spec.insert("path".to_string(), toml::Value::String(resolved));
+        self.tokens_str = toml::Value::Table(spec).to_string();
+        self
+    }
+
+    /// Quotes bare number versions (like `serde = 1`) and validates version requirements, so that
+    /// mistakes are reported at the attribute instead of by the nested cargo invocation. Other
+    /// values, like inline tables, are returned unchanged.
+    fn normalize_version(label: &str, value: &str, span: Span) -> Result<String> {
+        let is_bare_number = !value.is_empty()
+            && value.chars().all(|c| c.is_ascii_digit() || c == '.');
+        let requirement = if is_bare_number {
+            value
+        } else if let Some(requirement) = value.strip_prefix('"').and_then(|t| t.strip_suffix('"')) {
+            requirement
+        } else {
+            return Ok(value.to_string())
+        };
+        if let Err(e) = semver::VersionReq::parse(requirement) {
+            return err!(span, "Invalid version requirement '{requirement}' of dependency '{label}': {e}.")
         }
+        Ok(format!("\"{requirement}\""))
     }
-    code
 }
 
-#[derive(Clone, Copy, Debug)]
-struct MacroOptions {
-    pub cache: bool,
-    pub content_base_name: bool,
+/// Cargo profile the generated project is built with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+enum Profile {
+    #[default]
+    Debug,
+    /// Optimized build, for macros doing heavy computations, which it speeds up many times at the
+    /// cost of a longer compilation.
+    Release,
 }
 
-impl Default for MacroOptions {
-    fn default() -> Self {
-        Self {
-            cache: true,
-            content_base_name: false,
+impl Profile {
+    const NAMES: &[&str] = &["debug", "release"];
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "debug" => Some(Self::Debug),
+            "release" => Some(Self::Release),
+            _ => None,
         }
     }
-}
 
-impl syn::parse::Parse for MacroOptions {
-    fn parse(input: syn::parse::ParseStream) -> Result<Self, syn::Error> {
-        let mut options = MacroOptions::default();
-        while !input.is_empty() {
-            let ident: syn::Ident = input.parse()?;
-            let _eq_token: syn::Token![=] = input.parse()?;
-            if ident == "cache" {
-                let bool_lit: syn::LitBool = input.parse()?;
-                options.cache = bool_lit.value;
-            } else if ident == "content_base_name" {
-                let bool_lit: syn::LitBool = input.parse()?;
-                options.content_base_name = bool_lit.value;
-            } else {
-                return Err(syn::Error::new(ident.span(), "unknown attribute"));
-            }
-            if input.peek(syn::Token![,]) {
-                let _comma: syn::Token![,] = input.parse()?;
+    fn name(self) -> &'static str {
+        match self {
+            Self::Debug => "debug",
+            Self::Release => "release",
+        }
+    }
+
+    /// Name of the profile in the `[profile.<name>]` section of Cargo.toml.
+    fn cargo_name(self) -> &'static str {
+        match self {
+            Self::Debug => "dev",
+            Self::Release => "release",
+        }
+    }
+
+    /// Parses profile settings, like `opt_level = 3, debug = false, lto = "thin"`. Underscores in
+    /// the keys are the same as dashes, as dashes cannot be used in Rust identifiers.
+    fn settings_from_attribute_tokens(tokens: TokenStream) -> Result<toml::Table> {
+        let span = SpanMap::span(TokenRange::from_tokens(tokens.clone()).as_ref());
+        let tokens = tokens.into_iter().collect::<Vec<_>>();
+        let mut settings = toml::Table::new();
+        let entries = tokens.split(|t| matches!(t, TokenTree::Punct(p) if p.as_char() == ','));
+        for entry in entries.filter(|t| !t.is_empty()) {
+            let eq = entry.iter()
+                .position(|t| matches!(t, TokenTree::Punct(p) if p.as_char() == '='))
+                .filter(|eq| *eq > 0 && eq + 1 < entry.len());
+            let Some(eq) = eq else {
+                return err!(span, "Incorrect profile setting '{}', expected '<key> = <value>'.",
+                    print_toml_tokens(entry))
+            };
+            let key_tokens = entry[..eq].iter().cloned().collect();
+            let key_span = SpanMap::span(TokenRange::from_tokens(key_tokens).as_ref());
+            let key = print_toml_tokens(&entry[..eq]).replace('_', "-");
+            if !PROFILE_SETTINGS.contains(&key.as_str()) {
+                return err!(key_span, "Unknown profile setting '{key}', expected one of: {}.",
+                    PROFILE_SETTINGS.join(", "))
             }
+            let value_str = print_toml_tokens(&entry[eq + 1..]);
+            let value = toml::from_str::<toml::Table>(&format!("value = {value_str}")).ok()
+                .and_then(|mut t| t.remove("value"));
+            let Some(value) = value else {
+                return err!(key_span, "Incorrect value '{value_str}' of profile setting '{key}'.")
+            };
+            settings.insert(key, value);
         }
-        Ok(options)
+        Ok(settings)
     }
 }
 
-// =====================
-// === Eval Function ===
-// =====================
+#[derive(Debug, Default)]
+struct CargoConfig {
+    /// Name of the generated package, shown in the build output and panic messages. Defaults to
+    /// [`DEFAULT_PACKAGE_NAME`].
+    package_name: Option<String>,
+    edition: Option<String>,
+    /// Edition of the crate using the macro, which the output is checked against. It does not
+    /// affect the generated project.
+    output_edition: Option<String>,
+    resolver: Option<String>,
+    dependencies: Vec<Dependency>,
+    /// The `[patch.<source>]` tables of the crate and workspace manifests, by source.
+    patches: Vec<(String, Vec<Dependency>)>,
+    lints: LintsConfig,
+    /// The workspace lockfile copied to the project, so dependencies resolve to the same versions
+    /// as in the workspace.
+    lockfile: Option<PathBuf>,
+    /// Profile of the build. It does not affect the manifest, as Cargo keeps the artifacts of
+    /// profiles in separate directories.
+    profile: Profile,
+    /// Settings of the profile of the build, like `opt-level`, printed in its `[profile.<name>]`
+    /// section.
+    profile_settings: toml::Table,
+    /// Whether the project is built with the `minimal` option, without dependencies and lints.
+    minimal: bool,
+    /// Cargo config files defining the registries of the dependencies, passed to the nested cargo,
+    /// as they may not be in the parent directories of the project.
+    config_files: Vec<PathBuf>,
+    /// Name of the binary, unique among the projects sharing a target directory, which would
+    /// overwrite each other's binaries otherwise. Defaults to the package name.
+    bin_name: Option<String>,
+}
 
-#[proc_macro_attribute]
-pub fn eval_function(
-    attr: proc_macro::TokenStream,
-    item: proc_macro::TokenStream
-) -> proc_macro::TokenStream {
-    // SAFETY: Used to panic in case of error.
-    #[allow(clippy::unwrap_used)]
-    eval_function_impl(attr, item).unwrap_or_compile_error().into()
+#[derive(Debug, Default)]
+struct LintsConfig {
+    clippy: toml::Table,
+    rust: toml::Table,
 }
 
+impl LintsConfig {
+    /// Parses lint overrides, like `missing_docs = "allow", clippy::all = { level = "warn",
+    /// priority = -1 }`. Lints without a tool prefix are Rust lints.
+    fn from_attribute_tokens(tokens: TokenStream) -> Result<Self> {
+        let span = SpanMap::span(TokenRange::from_tokens(tokens.clone()).as_ref());
+        let tokens = tokens.into_iter().collect::<Vec<_>>();
+        let mut lints = Self::default();
+        let entries = tokens.split(|t| matches!(t, TokenTree::Punct(p) if p.as_char() == ','));
+        for entry in entries.filter(|t| !t.is_empty()) {
+            let eq = entry.iter()
+                .position(|t| matches!(t, TokenTree::Punct(p) if p.as_char() == '='))
+                .filter(|eq| *eq > 0 && eq + 1 < entry.len());
+            let Some(eq) = eq else {
+                return err!(span, "Incorrect lint '{}', expected '<lint> = <level>'.",
+                    print_toml_tokens(entry))
+            };
+            let name = print_toml_tokens(&entry[..eq]);
+            let value_str = print_toml_tokens(&entry[eq + 1..]);
+            let value = toml::from_str::<toml::Table>(&format!("value = {value_str}")).ok()
+                .and_then(|mut t| t.remove("value"))
+                .filter(|t| t.is_str() || t.get("level").is_some_and(toml::Value::is_str));
+            let Some(value) = value else {
+                return err!(span,
+                    "Incorrect level '{value_str}' of lint '{name}', expected a string, like \
+                    \"allow\", or a table, like {{ level = \"allow\", priority = 1 }}."
+                )
+            };
+            let (table, lint) = match name.split_once("::") {
+                None => (&mut lints.rust, name.as_str()),
+                Some(("clippy", lint)) => (&mut lints.clippy, lint),
+                Some(_) => return err!(span,
+                    "Incorrect lint '{name}', expected '<lint>' or 'clippy::<lint>'."
+                ),
+            };
+            table.insert(lint.to_string(), value);
+        }
+        Ok(lints)
+    }
 
-fn eval_function_impl(
-    attr: proc_macro::TokenStream,
-    item: proc_macro::TokenStream
-) -> Result<TokenStream> {
-    let options = syn::parse::<MacroOptions>(attr)?;
-    let start_time = get_current_time();
-    let timer = std::time::Instant::now();
+    /// Applies the overrides, which take precedence over the lints with the same names.
+    fn extend(&mut self, overrides: Self) {
+        self.rust.extend(overrides.rust);
+        self.clippy.extend(overrides.clippy);
+    }
+}
 
-    let input_fn_ast = syn::parse::<syn::ItemFn>(item)?;
-    let name = &input_fn_ast.sig.ident.to_string();
-    let body_ast = &input_fn_ast.block.stmts;
-    let output_tp = &input_fn_ast.sig.output;
-    let input_str = expand_output_macro(expand_quote_macro(quote!{ #(#body_ast)* })).to_string();
-    let paths = Paths::new(options, name, &input_str)?;
+impl CargoConfig {
+    fn package_name(&self) -> &str {
+        self.package_name.as_deref().unwrap_or(DEFAULT_PACKAGE_NAME)
+    }
 
-    let mut cfg = CargoConfig::default();
-    if let Some(path) = &paths.cargo_toml_path {
-        cfg.fill_from_cargo_toml(path)?;
+    /// Edition the output is expected to be compiled with, the edition of the project unless
+    /// `output_edition` is set.
+    fn output_edition(&self) -> &str {
+        self.output_edition.as_deref().or(self.edition.as_deref()).unwrap_or(DEFAULT_EDITION)
     }
-    let attributes = cfg.extract_inline_attributes(input_fn_ast.attrs)?;
-    let include_token_stream_impl = cfg.contains_dependency("proc-macro2");
-    let output_tp_str = match output_tp {
-        syn::ReturnType::Default => "()".to_string(),
-        syn::ReturnType::Type(_, tp) => quote!{#tp}.to_string(),
-    };
-    let input_code = prepare_input_code(
-        &attributes,
-        &input_str,
-        &output_tp_str,
-        include_token_stream_impl,
-        &paths
-    );
-    debug!("INPUT CODE: {input_code}");
-    let mut output_dir_str = String::new();
-    let (output, was_cached) = paths.with_output_dir(|output_dir| {
-        debug!("OUTPUT_DIR: {:?}", output_dir);
-        output_dir_str = output_dir.to_string_lossy().to_string();
-        let was_cached = create_project_skeleton(output_dir, cfg, &input_code)?;
-        let output = run_cargo_project(output_dir)?;
-        Ok((output, was_cached))
-    })?;
-    let output_code = parse_output(&output);
-    let duration = format_duration(timer.elapsed());
-    let options_doc = format!("{options:#?}").replace("\n", "\n/// ");
-    let macro_code = format!("
-        /// # Compilation Stats
-        /// Start: {start_time}
-        /// Duration: {duration}
-        /// Cached: {was_cached}
-        /// Output Dir: {output_dir_str}
-        /// Macro Options: {options_doc}
-        #[cfg(any())]
-        const _: () = ();
-        {output_code}
-    ");
-
-    debug!("BODY: {macro_code}");
-    let out: TokenStream = macro_code.parse()
-        .map_err(|err| error!("{err:?}"))
-        .context("Failed to parse generated code.")?;
-    debug!("OUTPUT: {out} ");
-    Ok(out)
-}
 
-// ================
-// === Function ===
-// ================
+    fn proc_macro2_dependencies(&self) -> impl Iterator<Item = &Dependency> {
+        self.dependencies.iter().filter(|d| d.package_name() == "proc-macro2")
+    }
 
-#[proc_macro_attribute]
-pub fn function(
-    attr: proc_macro::TokenStream,
-    item: proc_macro::TokenStream
-) -> proc_macro::TokenStream {
-    // SAFETY: Used to panic in case of error.
-    #[allow(clippy::unwrap_used)]
-    function_impl(attr, item, false).unwrap_or_compile_error().into()
-}
+    fn legacy_proc_macro2_dependencies(&self) -> impl Iterator<Item = &Dependency> {
+        self.proc_macro2_dependencies().filter(|d| d.major_version() == Some(0))
+    }
 
-#[proc_macro_attribute]
-pub fn statement(
-    attr: proc_macro::TokenStream,
-    item: proc_macro::TokenStream
-) -> proc_macro::TokenStream {
-    // SAFETY: Used to panic in case of error.
-    #[allow(clippy::unwrap_used)]
-    function_impl(attr, item, false).unwrap_or_compile_error().into()
-}
+    /// Name of the first dependency on the given package, which differs from the package name if
+    /// the dependency is renamed.
+    fn dependency_crate(&self, package: &str) -> Option<String> {
+        self.dependencies.iter().find(|d| d.package_name() == package).map(|d| d.crate_name())
+    }
 
-#[proc_macro_attribute]
-pub fn expression(
-    attr: proc_macro::TokenStream,
-    item: proc_macro::TokenStream
-) -> proc_macro::TokenStream {
-    // SAFETY: Used to panic in case of error.
-    #[allow(clippy::unwrap_used)]
-    function_impl(attr, item, true).unwrap_or_compile_error().into()
-}
+    fn prelude_crates(&self, fn_tokens: &TokenStream) -> PreludeCrates {
+        let edition = self.edition.as_deref().unwrap_or(DEFAULT_EDITION);
+        // Optional dependencies are not enabled in the generated project, so they cannot be used.
+        let extern_crates = if edition != "2015" { vec![] } else {
+            self.dependencies.iter()
+                .filter(|d| d.spec().and_then(|t| t.get("optional")?.as_bool()) != Some(true))
+                .map(|d| d.crate_name())
+                .collect()
+        };
+        PreludeCrates {
+            token_stream: self.token_stream_crate(fn_tokens),
+            serde_json: self.dependency_crate("serde_json"),
+            serde: self.dependency_crate("serde"),
+            extern_crates,
+            ident_keywords: ident_keywords(self.output_edition()),
+        }
+    }
 
-fn split_attrs(attrs: Vec<syn::Attribute>) -> (Vec<syn::Attribute>, Vec<syn::Attribute>) {
-    let (outer, inner): (Vec<_>, Vec<_>) = attrs.into_iter().partition(|attr| {
-        matches!(attr.style, syn::AttrStyle::Outer)
-    });
-    (outer, inner)
-}
+    /// Name of the `proc-macro2` crate whose `TokenStream` can be returned from the macro. It is
+    /// resolved from the dependencies, including renamed ones. Dependencies older than 1.0 are not
+    /// supported. If no dependency or more than one is found, the body is scanned for the
+    /// `<crate>::TokenStream` usage, as the crate can be provided in a way we don't understand.
+    fn token_stream_crate(&self, fn_tokens: &TokenStream) -> Option<String> {
+        let has_dependencies = self.proc_macro2_dependencies().next().is_some();
+        let mut crates = self.proc_macro2_dependencies()
+            .filter(|d| d.major_version() != Some(0))
+            .map(|d| d.crate_name())
+            .collect::<Vec<_>>();
+        if crates.len() == 1 {
+            return crates.pop()
+        }
+        if crates.is_empty() && !has_dependencies {
+            crates.push("proc_macro2".to_string());
+        }
+        crates.into_iter().find(|name| uses_token_stream(fn_tokens.clone(), name))
+    }
 
-fn function_impl(
-    attr_in: proc_macro::TokenStream,
-    item: proc_macro::TokenStream,
-    extra_braces: bool,
-) -> Result<TokenStream> {
-    let attr: TokenStream = attr_in.into();
-    let input_fn_ast = syn::parse::<syn::ItemFn>(item)?;
-    let name = &input_fn_ast.sig.ident;
-    let args_ast = &input_fn_ast.sig.inputs;
-    let body_ast = &input_fn_ast.block.stmts;
-    let output_tp = &input_fn_ast.sig.output;
+    fn print(&self) -> String {
+        let package_name = self.package_name();
+        let edition = self.edition.as_ref().map_or(DEFAULT_EDITION, |t| t.as_str());
+        let resolver = self.resolver.as_deref().unwrap_or_else(|| default_resolver(edition));
+        let profile_settings = self.print_profile_settings();
+        let bin = self.print_bin();
+        if self.minimal {
+            return format!("
+                [workspace]
+                [package]
+                name    = \"{package_name}\"
+                version = \"1.0.0\"
+                edition = \"{edition}\"
+
+                {bin}
+
+                {profile_settings}
+            ")
+        }
+        let lints_rust = Self::print_table_entries(&self.lints.rust);
+        let lints_clippy = Self::print_table_entries(&self.lints.clippy);
+        let dependencies = self.dependencies.iter()
+            .map(|t| t.to_config_string())
+            .collect::<Vec<_>>()
+            .join("\n");
+        let patches = self.print_patches();
+        let out = format!("
+            [workspace]
+            [package]
+            name     = \"{package_name}\"
+            version  = \"1.0.0\"
+            edition  = \"{edition}\"
+            resolver = \"{resolver}\"
 
-    let (args, args_code) = parse_args(args_ast).context(|| error!(WRONG_ARGS))?;
-    let args_pattern = args.pattern();
-    let args_setup = args.setup();
-    let body = quote!{ #(#body_ast)* };
-    let input_str = expand_expand_macro(quote!{ #(#body_ast)* });
+            {bin}
 
-    // Check if the expansion engine is Rust Analyzer. If so, we need to generate
-    // a code which looks like a function to enable type hints.
-    let program_name = std::env::current_exe()?
-        .file_name()
-        .map_or_else(|| "unknown".into(), |s| s.to_string_lossy().into_owned());
-    let rust_analyzer_hints = if program_name.contains("rust-analyzer") {
-        quote! {
-            mod __rust_analyzer_hints__ {
-                #[test]
-                #[ignore]
-                fn mytest() {
-                    #body
-                }
+            [dependencies]
+            {dependencies}
+
+            [lints.rust]
+            {lints_rust}
+
+            [lints.clippy]
+            {lints_clippy}
+
+            {profile_settings}
+
+            {patches}
+        ");
+        out
+    }
+
+    /// Prints the `[[bin]]` section naming the binary, or nothing if it is named after the package.
+    fn print_bin(&self) -> String {
+        let Some(bin_name) = &self.bin_name else { return String::new() };
+        format!("[[bin]]\nname = \"{bin_name}\"\npath = \"src/main.rs\"")
+    }
+
+    /// Prints the `[profile.<name>]` section of the build profile, or nothing if there are no
+    /// profile settings.
+    fn print_profile_settings(&self) -> String {
+        if self.profile_settings.is_empty() {
+            return String::new()
+        }
+        let name = self.profile.cargo_name();
+        format!("[profile.{name}]\n{}", Self::print_table_entries(&self.profile_settings))
+    }
+
+    fn print_patches(&self) -> String {
+        self.patches.iter().map(|(source, patches)| {
+            let is_bare = source.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            let key = if is_bare {
+                source.clone()
+            } else {
+                toml::Value::from(source.as_str()).to_string()
+            };
+            let patches = patches.iter().map(|t| t.to_config_string()).collect::<Vec<_>>();
+            format!("[patch.{key}]\n{}", patches.join("\n"))
+        }).collect::<Vec<_>>().join("\n\n")
+    }
+
+    /// Adds the `[patch]` tables of the manifest, overriding the patches of the same crates.
+    /// Relative paths are resolved against the manifest directory, as in dependencies.
+    fn extend_patches(&mut self, manifest: &toml::Value, manifest_dir: &Path) {
+        let Some(sources) = manifest.get("patch").and_then(toml::Value::as_table) else { return };
+        for (source, table) in sources {
+            let Some(table) = table.as_table() else { continue };
+            let index = self.patches.iter().position(|(t, _)| t == source).unwrap_or_else(|| {
+                self.patches.push((source.clone(), vec![]));
+                self.patches.len() - 1
+            });
+            let patches = &mut self.patches[index].1;
+            for (label, spec) in table {
+                let patch = Dependency::new(label.clone(), spec.to_string(), None)
+                    .with_absolute_path(manifest_dir);
+                patches.retain(|t| t.label != *label);
+                patches.push(patch);
             }
         }
-    } else {
-        quote! {}
-    };
+    }
 
-    let attrs_vec = input_fn_ast.attrs;
-    let (outer_attrs_vec, inner_attrs_vec) = split_attrs(attrs_vec);
+    fn is_workspace(path: &Path) -> Result<bool> {
+        Ok(Self::read_manifest(path)?.get("workspace").is_some())
+    }
 
-    let outer_attrs = quote!{ #(#outer_attrs_vec)* };
-    let inner_attrs = quote!{ #(#inner_attrs_vec)* };
-    let mut out = quote! {
-        {
-            #[crabtime::eval_function(#attr)]
-            fn #name() #output_tp {
-                #inner_attrs
-                #args_setup
-                #args_code
-                #input_str
+    /// Relation of the crate in the directory to the workspace of the manifest, or `None` if the
+    /// manifest does not have a `[workspace]` table. Like in cargo, `members` are globs, and
+    /// `exclude` entries are directories, both relative to the manifest directory.
+    fn workspace_membership(path: &Path, crate_dir: &Path) -> Result<Option<Membership>> {
+        let manifest = Self::read_manifest(path)?;
+        let Some(workspace) = manifest.get("workspace") else { return Ok(None) };
+        let workspace_dir = path::parent(path)?;
+        let Ok(relative) = crate_dir.strip_prefix(workspace_dir) else { return Ok(None) };
+        let entries = |key: &str| workspace.get(key).and_then(toml::Value::as_array)
+            .map(|t| t.iter().filter_map(toml::Value::as_str).collect::<Vec<_>>())
+            .unwrap_or_default();
+        let trim = |entry: &str| entry.trim_start_matches("./").to_string();
+        let is_root = relative.as_os_str().is_empty();
+        let listed = is_root || entries("members").into_iter()
+            .any(|member| path::matches_glob(&trim(member), relative));
+        let excluded = entries("exclude").into_iter()
+            .any(|dir| relative.starts_with(trim(dir)));
+        let membership = match (listed, excluded) {
+            (true, _) => Membership::Listed,
+            (false, true) => Membership::Excluded,
+            (false, false) => Membership::Unlisted,
+        };
+        Ok(Some(membership))
+    }
+
+    /// Whether the manifest has a `[workspace]` table but no `[package]` one. Manifests which can't
+    /// be read are not virtual, so their errors are reported when the crate config is read.
+    fn is_virtual_manifest(path: &Path) -> bool {
+        Self::read_manifest(path).is_ok_and(|manifest| {
+            manifest.get("workspace").is_some() && manifest.get("package").is_none()
+        })
+    }
+
+    fn read_manifest(path: &Path) -> Result<toml::Value> {
+        let content = inputs::read_file(path).with_path("read", path)?;
+        Self::parse_manifest(path, &content)
+    }
+
+    /// Parses the manifest, skipping the UTF-8 byte order mark some editors put at the start of
+    /// files. Errors name the file and the line and column of the problem.
+    fn parse_manifest(path: &Path, content: &str) -> Result<toml::Value> {
+        let content = content.strip_prefix('\u{feff}').unwrap_or(content);
+        toml::from_str(content).map_err(|e| {
+            let location = e.span().map(|span| {
+                let before = &content[..span.start.min(content.len())];
+                let line = before.matches('\n').count() + 1;
+                let column = before.rsplit('\n').next().map_or(0, |t| t.chars().count()) + 1;
+                format!(" at line {line}, column {column}")
+            }).unwrap_or_default();
+            let message = e.message().trim_end_matches('.');
+            error!("Failed to parse '{}'{location}: {message}.", path.display())
+        })
+    }
+
+    /// The `[target.<triple or cfg>.build-dependencies]` tables of the manifest applying to the
+    /// host target, which macro projects are built for. Targets whose cfg expressions are not
+    /// supported are skipped with a warning.
+    fn host_build_dependency_tables<'t>(
+        config: &'t toml::Value,
+        host_target: &str,
+    ) -> Vec<&'t toml::Table> {
+        let targets = config.get("target").and_then(|v| v.as_table());
+        targets.into_iter().flatten().filter_map(|(target, table)| {
+            let dependencies = table.get("build-dependencies").and_then(|v| v.as_table())?;
+            let applies = target_applies(target, host_target).unwrap_or_else(|| {
+                print_warning!(
+                    "Ignoring the build-dependencies of target '{target}'. Only target triples \
+                    and the 'windows', 'unix', 'target_family', 'not', 'all', and 'any' cfg \
+                    predicates are supported."
+                );
+                false
+            });
+            applies.then_some(dependencies)
+        }).collect()
+    }
+
+    fn is_workspace_table(value: &toml::Value) -> bool {
+        if let toml::Value::Table(table) = value
+        && let Some(toml::Value::Boolean(true)) = table.get("workspace") {
+            return true;
+        }
+        false
+    }
+
+    fn get_package_edition(table: &toml::Table) -> Option<&str> {
+        table.get("package")
+            .and_then(toml::Value::as_table)
+            .and_then(|pkg_table| pkg_table.get("edition"))
+            .and_then(toml::Value::as_str)
+    }
+
+    /// The `[workspace.dependencies]` entry of a dependency declared with `workspace = true`,
+    /// merged with the crate entry like cargo does. The crate can add `features`, which extend the
+    /// workspace ones, and mark the dependency as `optional`.
+    fn inherit_workspace_dependency(
+        workspace: &toml::Table,
+        name: &str,
+        member: &toml::Value,
+    ) -> Option<toml::Value> {
+        let inherited = workspace.get("dependencies")?.as_table()?.get(name)?;
+        let member = member.as_table()?;
+        let features = member.get("features").and_then(toml::Value::as_array);
+        let optional = member.get("optional");
+        if inherited.is_str() && features.is_none() && optional.is_none() {
+            return Some(inherited.clone())
+        }
+        let mut spec = match inherited {
+            toml::Value::String(version) => {
+                toml::Table::from_iter([("version".to_string(), version.clone().into())])
+            }
+            toml::Value::Table(spec) => spec.clone(),
+            _ => return None,
+        };
+        if let Some(features) = features {
+            let all = spec.entry("features").or_insert_with(|| toml::Value::Array(vec![]));
+            if let Some(all) = all.as_array_mut() {
+                let new = features.iter().filter(|t| !all.contains(t)).cloned().collect::<Vec<_>>();
+                all.extend(new);
             }
         }
-    };
-    if extra_braces {
-        out = quote! {
-            { #out }
+        if let Some(optional) = optional {
+            spec.insert("optional".to_string(), optional.clone());
+        }
+        Some(toml::Value::Table(spec))
+    }
+
+    /// Prints the lints as `key = value` lines. Values are either levels, like `"warn"`, or inline
+    /// tables with priorities, like `{ level = "warn", priority = -1 }`.
+    fn print_table_entries(entries: &toml::Table) -> String {
+        let print_key = |key: &str| {
+            let is_bare = !key.is_empty()
+                && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+            if is_bare { key.to_string() } else { toml::Value::from(key).to_string() }
         };
+        entries.iter().map(|(k, v)| format!("{} = {v}", print_key(k))).collect::<Vec<_>>().join("\n")
     }
-    out = quote! {
-        #rust_analyzer_hints
 
-        #outer_attrs
-        macro_rules! #name {
-            (#args_pattern) => #out;
+    fn fill_from_cargo_toml(&mut self, paths: &CargoConfigPaths) -> Result {
+        let config = Self::read_manifest(&paths.crate_config)?;
+        let crate_dir = path::parent(&paths.crate_config)?;
+        let workspace_config_opt = paths.workspace_config.as_ref().and_then(|path| {
+            Self::read_manifest(path).map_err(|err| print_warning!(
+                "Using the crate configuration only. {}", err.message
+            )).ok()
+        });
+        let workspace_config_table_opt = workspace_config_opt.as_ref()
+            .and_then(|t| t.get("workspace")).and_then(|v| v.as_table());
+        // Paths of workspace dependencies are relative to the workspace manifest.
+        let workspace_dir = paths.workspace_config.as_deref().and_then(Path::parent);
+        let to_dependencies = |table: &toml::Table| table.iter().filter_map(|(k, v)|
+            if !Self::is_workspace_table(v) {
+                let dependency = Dependency::new(k.clone(), v.to_string(), None);
+                Some(dependency.with_absolute_path(crate_dir))
+            } else {
+                let inherited = workspace_config_table_opt
+                    .and_then(|t| Self::inherit_workspace_dependency(t, k, v))?;
+                let dependency = Dependency::new(k.clone(), inherited.to_string(), None);
+                Some(dependency.with_absolute_path(workspace_dir.unwrap_or(crate_dir)))
+            }
+        ).collect::<Vec<_>>();
+        let mut dependencies = config
+            .get("build-dependencies")
+            .and_then(|v| v.as_table())
+            .map_or(vec![], to_dependencies);
+        // Dependencies declared for all targets take precedence, as cargo would merge them.
+        for table in Self::host_build_dependency_tables(&config, HOST_TARGET) {
+            let target_dependencies = to_dependencies(table).into_iter()
+                .filter(|t| !dependencies.iter().any(|d| d.label == t.label))
+                .collect::<Vec<_>>();
+            dependencies.extend(target_dependencies);
         }
-    };
-    debug!("OUT: {out}");
-    Ok(out)
+        let edition = config
+            .get("package")
+            .and_then(|v| v.as_table())
+            .and_then(|table| table.get("edition"))
+            .and_then(|v| if !Self::is_workspace_table(v) { v.as_str() } else {
+                workspace_config_table_opt.and_then(Self::get_package_edition)
+            })
+            .unwrap_or("2024");
+        let lints = config.get("lints").map(|v| {
+            let lints_table = if Self::is_workspace_table(v) {
+                workspace_config_table_opt.and_then(|t| t.get("lints")).and_then(|t| t.as_table())
+            } else {
+                v.as_table()
+            };
+            let tool_lints = |tool: &str| lints_table.and_then(|t| t.get(tool)?.as_table())
+                .cloned().unwrap_or_default();
+            let clippy = tool_lints("clippy");
+            let rust = tool_lints("rust");
+            LintsConfig {clippy, rust}
+        });
+        // Patches of both manifests are applied, the crate ones taking precedence.
+        if let (Some(config), Some(path)) = (&workspace_config_opt, &paths.workspace_config) {
+            self.extend_patches(config, path::parent(path)?);
+        }
+        self.extend_patches(&config, crate_dir);
+        self.dependencies.extend(dependencies);
+        self.edition = Some(edition.to_string());
+        self.lints = lints.unwrap_or_default();
+        Ok(())
+    }
+
+    /// The dependency with the given label or package name, as cargo errors use either of them.
+    fn dependency(&self, name: &str) -> Option<&Dependency> {
+        self.dependencies.iter().find(|t| t.label == name || t.package_name() == name)
+    }
+
+    /// Resolves relative `path` dependencies against the base directory. Already resolved ones are
+    /// left unchanged.
+    fn resolve_dependency_paths(&mut self, base: &Path) {
+        self.dependencies = std::mem::take(&mut self.dependencies).into_iter()
+            .map(|t| t.with_absolute_path(base))
+            .collect();
+    }
+
+    /// Finds the configuration of the alternative registries of the dependencies, as cargo would
+    /// for a build started in `dir`, reporting registries which are not configured.
+    fn resolve_registries(&mut self, dir: &Path) -> Result {
+        for dependency in &self.dependencies {
+            let Some(name) = dependency.registry() else { continue };
+            match registry::find(&name, dir) {
+                Some(registry::RegistrySource::File(path)) =>
+                    if !self.config_files.contains(&path) {
+                        self.config_files.push(path);
+                    },
+                Some(registry::RegistrySource::Inherited) => {}
+                None => return err!(dependency.span(),
+                    "Registry '{name}' of dependency '{}' is not configured. Define it in the \
+                    '[registries.{name}]' section of a '.cargo/config.toml' file of your project \
+                    or set the '{}' environment variable.",
+                    dependency.label, registry::index_env_var(&name)
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies the Cargo configuration attributes and returns the other ones. Inner attributes,
+    /// like `#![feature(...)]` or `#![recursion_limit = "256"]`, apply to the whole generated crate
+    /// and are returned before the outer ones, as rustc rejects inner attributes following outer
+    /// ones. The ranges of the attribute values are recorded in the span map.
+    fn extract_inline_attributes(
+        &mut self,
+        attributes: Vec<syn::Attribute>,
+        spans: &mut SpanMap,
+    ) -> Result<String> {
+        let mut other_attributes = Vec::with_capacity(attributes.len());
+        let mut new_dependencies = vec![];
+        let mut lint_overrides = LintsConfig::default();
+        for attr in attributes {
+            // Configuration attributes take arguments, so `#![name = value]` ones are passed through.
+            if !matches!(attr.meta, syn::Meta::List(_)) {
+                other_attributes.push(attr);
+                continue
+            }
+            let tokens = attr.parse_args::<TokenStream>().context("Failed to parse attributes")?;
+            let tokens_str = tokens.to_string().replace(" ", "");
+            let token_range = TokenRange::from_tokens(tokens.clone());
+            if let Some(name) = attr.path().get_ident() && let Some(range) = &token_range {
+                spans.attributes.push((name.to_string(), range.clone()));
+            }
+            if attr.path().is_ident("dependency") {
+                new_dependencies.push(Dependency::from_attribute_tokens(tokens)?);
+            } else if attr.path().is_ident("package_name") {
+                let Ok(name) = syn::parse2::<syn::LitStr>(tokens) else {
+                    return err!(SpanMap::span(token_range.as_ref()),
+                        "Incorrect package_name '{tokens_str}', expected a string, like \
+                        \"my_macro\"."
+                    )
+                };
+                self.package_name = Some(sanitize_package_name(&name.value()));
+            } else if attr.path().is_ident("edition") {
+                if !EDITIONS.contains(&tokens_str.as_str()) {
+                    return err!(SpanMap::span(token_range.as_ref()),
+                        "Incorrect edition '{tokens_str}', expected one of: {}.",
+                        EDITIONS.join(", ")
+                    )
+                }
+                self.edition = Some(tokens_str);
+            } else if attr.path().is_ident("resolver") {
+                if !RESOLVERS.contains(&tokens_str.as_str()) {
+                    return err!(SpanMap::span(token_range.as_ref()),
+                        "Incorrect resolver '{tokens_str}', expected one of: {}.",
+                        RESOLVERS.join(", ")
+                    )
+                }
+                self.resolver = Some(tokens_str);
+            } else if attr.path().is_ident("output_edition") {
+                if !EDITIONS.contains(&tokens_str.as_str()) {
+                    return err!(SpanMap::span(token_range.as_ref()),
+                        "Incorrect output_edition '{tokens_str}', expected one of: {}.",
+                        EDITIONS.join(", ")
+                    )
+                }
+                self.output_edition = Some(tokens_str);
+            } else if attr.path().is_ident("profile") {
+                self.profile = Profile::from_name(&tokens_str).context(|| error!(
+                    SpanMap::span(token_range.as_ref()),
+                    "Incorrect profile '{tokens_str}', expected one of: {}.",
+                    Profile::NAMES.join(", ")
+                ))?;
+            } else if attr.path().is_ident("profile_settings") {
+                self.profile_settings.extend(Profile::settings_from_attribute_tokens(tokens)?);
+            } else if attr.path().is_ident("clippy_lints") {
+                let inherit = syn::parse2::<syn::LitBool>(tokens).context(||
+                    error!("Incorrect clippy_lints '{tokens_str}', expected 'true' or 'false'.")
+                )?;
+                if !inherit.value {
+                    self.lints.clippy.clear();
+                }
+            } else if attr.path().is_ident("inherit_lints") {
+                let inherit = syn::parse2::<syn::LitBool>(tokens).context(||
+                    error!("Incorrect inherit_lints '{tokens_str}', expected 'true' or 'false'.")
+                )?;
+                if !inherit.value {
+                    self.lints = LintsConfig::default();
+                }
+            } else if attr.path().is_ident("lints") {
+                lint_overrides.extend(LintsConfig::from_attribute_tokens(tokens)?);
+            } else {
+                other_attributes.push(attr);
+            }
+        }
+        // On stable, Cargo.toml can't be discovered, so inline dependencies are the only option.
+        #[cfg(nightly)]
+        for dependency in &new_dependencies {
+            print_warning!(dependency.span(),
+                "When using the nightly Rust channel, dependencies should be specified in the \
+                [build-dependencies] section of your Cargo.toml file."
+            );
+        }
+        self.dependencies.extend(new_dependencies);
+        // Overrides are applied last, so they are kept even if the inherited lints are dropped.
+        self.lints.extend(lint_overrides);
+        other_attributes.sort_by_key(|t| matches!(t.style, syn::AttrStyle::Outer));
+        let other_attributes = other_attributes.iter().map(|t| t.to_token_stream().to_string());
+        Ok(other_attributes.collect::<Vec<_>>().join("\n"))
+    }
 }
 
-fn format_duration(duration: std::time::Duration) -> String {
-    let total_seconds = duration.as_secs();
-    if total_seconds >= 60 {
-        let minutes = total_seconds / 60;
-        let seconds = total_seconds % 60;
-        format!("{minutes}m {seconds}s")
-    } else {
-        let millis = duration.as_millis() % 1000;
-        let fractional = millis as f64 / 1000.0;
-        format!("{:.2} s", total_seconds as f64 + fractional)
+/// Turns the name into a valid package and crate name, replacing the characters which cannot be
+/// used in identifiers with underscores, like `r#type` with `r_type`. Names which cargo rejects,
+/// like keywords, get an underscore suffix.
+fn sanitize_package_name(name: &str) -> String {
+    let mut out = name.chars()
+        .map(|c| if c == '_' || c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+    if out.starts_with(|c: char| c.is_ascii_digit()) {
+        out.insert(0, '_');
+    }
+    if out.chars().all(|c| c == '_') {
+        return DEFAULT_PACKAGE_NAME.to_string()
     }
+    // Cargo rejects keywords of any edition, so the keywords of the latest one are used.
+    let is_keyword = ident_keywords(DEFAULT_EDITION).contains(&out.as_str());
+    if is_keyword || RESERVED_PACKAGE_NAMES.contains(&out.as_str()) {
+        out.push('_');
+    }
+    out
 }
 
-fn get_current_time() -> String {
-    let now = std::time::SystemTime::now();
-    #[allow(clippy::unwrap_used)]
-    let duration_since_epoch = now.duration_since(std::time::UNIX_EPOCH).unwrap();
-    let total_seconds = duration_since_epoch.as_secs();
-    let milliseconds = (duration_since_epoch.as_millis() % 1000) as u32;
-    let hours = (total_seconds / 3600) % 24;
-    let minutes = (total_seconds / 60) % 60;
-    let seconds = total_seconds % 60;
-    format!("{hours:02}:{minutes:02}:{seconds:02} ({milliseconds:03})")
+/// State of the project directory found by an expansion.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ProjectState {
+    New,
+    /// The project existed, but its manifest or code were rewritten.
+    Changed,
+    /// The project existed with the same manifest and code, so no file was written, and cargo
+    /// does not need to check its fingerprints again.
+    Unchanged,
+    /// The output was read from a snapshot, so no project was used.
+    Snapshot,
+}
+
+impl ProjectState {
+    fn existed(self) -> bool {
+        self != Self::New
+    }
+
+    /// Value of the `Cached` line of the compilation stats.
+    fn stats(self) -> &'static str {
+        match self {
+            Self::New => "false",
+            Self::Changed => "true",
+            Self::Unchanged => "true (unchanged)",
+            Self::Snapshot => "snapshot",
+        }
+    }
+}
+
+fn create_project_skeleton(
+    project_dir: &Path,
+    cfg: &CargoConfig,
+    main: &str,
+) -> Result<ProjectState> {
+    let src_dir = project_dir.join("src");
+    // Projects left partially removed, for example, by a failed removal on Windows, are rewritten.
+    let existed = src_dir.is_dir() && project_dir.join("Cargo.toml").is_file();
+    if src_dir.is_file() {
+        fs::remove_file(&src_dir).with_path("remove", &src_dir)?;
+    }
+    fs::create_dir_all(&src_dir).with_path("create directory", &src_dir)?;
+
+    let cargo_toml = project_dir.join("Cargo.toml");
+    let mut changed = write_if_changed(&cargo_toml, cfg.print().as_bytes())?;
+
+    // Cargo prunes the entries of packages the project does not use, and keeps the other ones. As
+    // the pruned file differs from the workspace one, it does not count as a change.
+    if let Some(lockfile) = &cfg.lockfile {
+        let content = inputs::read_file(lockfile).with_path("read", lockfile)?;
+        write_if_changed(&project_dir.join("Cargo.lock"), content.as_bytes())?;
+    }
+
+    changed |= write_if_changed(&src_dir.join("main.rs"), main.as_bytes())?;
+    Ok(match (existed, changed) {
+        (false, _) => ProjectState::New,
+        (true, true) => ProjectState::Changed,
+        (true, false) => ProjectState::Unchanged,
+    })
+}
+
+/// Writes the file, unless it already has the content, so that its modification time, which
+/// cargo and tools like sccache check, is kept. Returns whether the file was written.
+fn write_if_changed(path: &Path, content: &[u8]) -> Result<bool> {
+    if fs::read(path).is_ok_and(|current| current == content) {
+        return Ok(false)
+    }
+    fs::write(path, content).with_path("write", path)?;
+    Ok(true)
+}
+
+/// The cargo and rustc binaries building the generated projects.
+#[derive(Clone, Debug, PartialEq)]
+struct Toolchain {
+    cargo: PathBuf,
+    rustc: PathBuf,
+}
+
+impl Toolchain {
+    /// The binaries of the `CARGO` and `RUSTC` environment variables, so that the nested builds use
+    /// the toolchain of the outer one, even if it is not the one in `PATH`, like under rustup
+    /// proxies or build systems providing their own toolchains.
+    fn from_env() -> Self {
+        Self::new(std::env::var_os(CARGO_ENV), std::env::var_os(RUSTC_ENV))
+    }
+
+    /// The binaries of the given environment variable values, falling back to the bare binary
+    /// names, looked up in `PATH`.
+    fn new(cargo: Option<std::ffi::OsString>, rustc: Option<std::ffi::OsString>) -> Self {
+        let binary = |value: Option<std::ffi::OsString>, name: &str| {
+            value.filter(|t| !t.is_empty()).map_or_else(|| PathBuf::from(name), PathBuf::from)
+        };
+        Self { cargo: binary(cargo, "cargo"), rustc: binary(rustc, "rustc") }
+    }
+}
+
+/// The output of `rustc -vV`, describing the compiler version and the host target.
+fn rustc_version_info(rustc: &Path) -> Result<String> {
+    let output = Command::new(rustc)
+        .arg("-vV")
+        .stdout(std::process::Stdio::piped())
+        .output()
+        .context(|| error!("Failed to run '{}'", rustc.display()))?;
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Target the macro projects are built for. Proc macros are compiled for the host too.
+const HOST_TARGET: &str = env!("CRABTIME_HOST_TARGET");
+
+/// Version of the compiler the macro was built with, which is the one expanding it.
+const RUSTC_VERSION: &str = env!("CRABTIME_RUSTC_VERSION");
+
+/// Checks whether the target of a `[target.<target>]` manifest table, a target triple or a cfg
+/// expression like `cfg(windows)`, applies to the host target. Returns `None` for unsupported
+/// cfg predicates.
+fn target_applies(target: &str, host_target: &str) -> Option<bool> {
+    if !target.starts_with("cfg(") {
+        return Some(target == host_target)
+    }
+    let syn::Meta::List(cfg) = syn::parse_str::<syn::Meta>(target).ok()? else { return None };
+    let predicate = syn::parse2::<syn::Meta>(cfg.tokens).ok()?;
+    cfg_predicate_holds(&predicate, host_target)
+}
+
+fn cfg_predicate_holds(predicate: &syn::Meta, host_target: &str) -> Option<bool> {
+    let windows = host_target.contains("windows");
+    let unix = !windows && !["wasm", "-none", "-uefi"].iter().any(|t| host_target.contains(t));
+    match predicate {
+        syn::Meta::Path(path) if path.is_ident("windows") => Some(windows),
+        syn::Meta::Path(path) if path.is_ident("unix") => Some(unix),
+        syn::Meta::NameValue(name_value) if name_value.path.is_ident("target_family") => {
+            let syn::Expr::Lit(syn::ExprLit { lit: syn::Lit::Str(family), .. }) =
+                &name_value.value else { return None };
+            match family.value().as_str() {
+                "windows" => Some(windows),
+                "unix" => Some(unix),
+                "wasm" => Some(host_target.starts_with("wasm")),
+                _ => None,
+            }
+        }
+        syn::Meta::List(list) => {
+            let args = list.parse_args_with(
+                syn::punctuated::Punctuated::<syn::Meta, syn::Token![,]>::parse_terminated
+            ).ok()?;
+            // All predicates are evaluated, so unsupported ones are reported even if the result
+            // is known without them.
+            let values = args.iter()
+                .map(|t| cfg_predicate_holds(t, host_target))
+                .collect::<Option<Vec<_>>>()?;
+            if list.path.is_ident("not") && values.len() == 1 {
+                Some(!values[0])
+            } else if list.path.is_ident("all") {
+                Some(values.iter().all(|t| *t))
+            } else if list.path.is_ident("any") {
+                Some(values.iter().any(|t| *t))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+fn get_host_target(rustc_version_info: &str, rustc: &Path) -> Result<String> {
+    for line in rustc_version_info.lines() {
+        if let Some(stripped) = line.strip_prefix("host:") {
+            return Ok(stripped.trim().to_string())
+        }
+    }
+    err!("Could not determine host target from the output of '{} -vV'", rustc.display())
+}
+
+fn parse_cargo_flags(flags: &str) -> Result<Vec<String>> {
+    flags.split_whitespace().map(|flag| {
+        if ALLOWED_CARGO_FLAGS.contains(&flag) {
+            Ok(flag.to_string())
+        } else {
+            err!(
+                "Flag '{flag}' provided in the '{CARGO_FLAGS_ENV}' environment variable is not \
+                allowed. Allowed flags are: {}.", ALLOWED_CARGO_FLAGS.join(", ")
+            )
+        }
+    }).collect()
+}
+
+fn cargo_flags_from_env() -> Result<Vec<String>> {
+    inputs::var(CARGO_FLAGS_ENV).map_or_else(|| Ok(vec![]), |flags| parse_cargo_flags(&flags))
+}
+
+/// Creates empty directories the generated program is run in. The program's working directory
+/// and temporary directory are separate from the project, so files it creates cannot break
+/// subsequent builds of the project or accumulate in the cache.
+fn prepare_run_dirs(project_dir: &Path) -> Result {
+    for dir in [RUN_DIR, RUN_TMP_DIR] {
+        let dir = project_dir.join(dir);
+        if dir.exists() {
+            fs::remove_dir_all(&dir).with_path("clean directory", &dir)?;
+        }
+        fs::create_dir_all(&dir).with_path("create directory", &dir)?;
+    }
+    Ok(())
+}
+
+fn project_dir_entries(project_dir: &Path) -> Vec<std::ffi::OsString> {
+    fs::read_dir(project_dir)
+        .map(|entries| entries.filter_map(|entry| entry.ok()).map(|entry| entry.file_name()).collect())
+        .unwrap_or_default()
+}
+
+/// Entries of the project directory created during the run which were not created by cargo.
+fn unexpected_project_entries(
+    before: &[std::ffi::OsString],
+    after: &[std::ffi::OsString]
+) -> Vec<String> {
+    after.iter()
+        .filter(|entry| !before.contains(entry))
+        .filter(|entry| !["Cargo.lock", "target"].iter().any(|name| *entry == name))
+        .map(|entry| entry.to_string_lossy().to_string())
+        .collect()
+}
+
+fn cargo_build_command(
+    cargo: &Path,
+    project_dir: &Path,
+    target_dir: &Path,
+    host_target: &str,
+    profile: Profile,
+    flags: &[String],
+    jobs: Option<usize>
+) -> Command {
+    let tmp_dir = project_dir.join(RUN_TMP_DIR);
+    let mut command = Command::new(cargo);
+    command
+        .arg("build")
+        .arg("--manifest-path")
+        .arg(project_dir.join("Cargo.toml"))
+        .arg("--target")
+        .arg(host_target)
+        .args((profile == Profile::Release).then_some("--release"))
+        // The target directory of the outer build, set with `CARGO_TARGET_DIR` or in a config
+        // file, would be locked by the build expanding the macro.
+        .arg("--target-dir")
+        .arg(target_dir)
+        .args(flags)
+        .current_dir(project_dir.join(RUN_DIR))
+        .env("TMPDIR", &tmp_dir)
+        .env("TMP", &tmp_dir)
+        .env("TEMP", &tmp_dir);
+    // Wrappers set by the outer build, like `clippy-driver` set by `cargo clippy`, would lint or
+    // otherwise process the generated project, failing the expansion on issues in scratch code.
+    for wrapper in WRAPPER_ENVS {
+        command.env_remove(wrapper);
+    }
+    if let Some(jobs) = jobs {
+        command.arg("-j").arg(jobs.to_string());
+    }
+    command
+}
+
+/// Compiles the project with `rustc` directly, skipping cargo, whose manifest parsing, lockfile
+/// handling, and fingerprinting cost hundreds of milliseconds per expansion. Only projects without
+/// dependencies, Rust lints, and profile settings qualify. The binary is cached in the project's
+/// `target` directory, separately for every profile, with a stamp of the code, edition, and
+/// compiler it was built from. Returns the command running the binary, or `None` if the project
+/// needs cargo or does not compile, so cargo reports the errors.
+fn rustc_run_command(
+    rustc: &Path,
+    project_dir: &Path,
+    cfg: &CargoConfig,
+    rustc_version_info: &str,
+    host_target: &str,
+    deadline: Option<&Deadline>,
+) -> Result<Option<Command>> {
+    let needs_cargo = !cfg.dependencies.is_empty()
+        || !cfg.lints.rust.is_empty()
+        || !cfg.profile_settings.is_empty();
+    if needs_cargo {
+        return Ok(None)
+    }
+    let edition = cfg.edition.as_deref().unwrap_or(DEFAULT_EDITION);
+    let main_rs = project_dir.join("src").join("main.rs");
+    let main = fs::read_to_string(&main_rs).with_path("read", &main_rs)?;
+    let bin_dir = project_dir.join("target").join(RUSTC_BIN_DIR).join(cfg.profile.name());
+    let binary =
+        bin_dir.join(format!("{}{}", cfg.package_name(), std::env::consts::EXE_SUFFIX));
+    let stamp_file = bin_dir.join("stamp");
+    let stamp = format!("{:016x}", inputs::hash((&main, edition, rustc_version_info)));
+    let is_fresh = binary.is_file()
+        && fs::read_to_string(&stamp_file).is_ok_and(|t| t == stamp);
+    if !is_fresh {
+        fs::create_dir_all(&bin_dir).with_path("create directory", &bin_dir)?;
+        let mut compile = Command::new(rustc);
+        compile
+            .arg("--edition").arg(edition)
+            .arg("--crate-name").arg(cfg.package_name())
+            .arg("--crate-type").arg("bin")
+            // Unlike cargo, rustc does not read the target from `.cargo/config.toml`.
+            .args((!cfg.minimal).then_some(["--target", host_target]).into_iter().flatten())
+            .args((cfg.profile == Profile::Release).then_some("-Copt-level=3"))
+            .arg("-o").arg(&binary)
+            .arg(&main_rs);
+        let output = wait_until(&mut compile, deadline, |_| {})?;
+        if !output.as_ref().is_ok_and(|t| t.status.success()) {
+            debug!("Compiling with rustc failed, falling back to cargo: {output:?}");
+            return Ok(None)
+        }
+        fs::write(&stamp_file, stamp).with_path("write", &stamp_file)?;
+    }
+    Ok(Some(binary_run_command(project_dir, &binary)))
+}
+
+/// Command running the binary of the project directly, in the same environment for all ways of
+/// building it.
+fn binary_run_command(project_dir: &Path, binary: &Path) -> Command {
+    let tmp_dir = project_dir.join(RUN_TMP_DIR);
+    let mut command = Command::new(binary);
+    command
+        .current_dir(project_dir.join(RUN_DIR))
+        .env("TMPDIR", &tmp_dir)
+        .env("TMP", &tmp_dir)
+        .env("TEMP", &tmp_dir);
+    command
+}
+
+/// File of a project built with cargo, containing the stamp of the manifest, the code, and the
+/// compiler its binary was built from.
+const CARGO_BINARY_STAMP_FILE: &str = "crabtime-binary";
+
+/// Path of the binary `cargo build` builds in the target directory, unless cargo reports another
+/// one.
+fn cargo_binary_path(target_dir: &Path, host_target: &str, cfg: &CargoConfig) -> PathBuf {
+    let name = cfg.bin_name.as_deref().unwrap_or(cfg.package_name());
+    let file_name = format!("{name}{}", std::env::consts::EXE_SUFFIX);
+    target_dir.join(host_target).join(cfg.profile.name()).join(file_name)
+}
+
+/// Stamp of everything the binary built by cargo depends on, except for the source files, whose
+/// modification times are checked instead.
+fn cargo_binary_stamp(
+    project_dir: &Path,
+    cfg: &CargoConfig,
+    rustc_version_info: &str,
+    flags: &[String],
+) -> Result<String> {
+    let main_rs = project_dir.join("src").join("main.rs");
+    let main = fs::read_to_string(&main_rs).with_path("read", &main_rs)?;
+    let lockfile = cfg.lockfile.as_deref().and_then(|t| inputs::read_file(t).ok());
+    let rustflags = ["RUSTFLAGS", "CARGO_ENCODED_RUSTFLAGS"].map(inputs::var);
+    let stamp_inputs = (cfg.print(), main, lockfile, rustc_version_info, flags, rustflags);
+    Ok(format!("{:016x}", inputs::hash((stamp_inputs, &cfg.config_files))))
+}
+
+/// Command running the binary built by cargo in an earlier expansion, skipping the startup and
+/// fingerprinting of cargo. The binary is run only if it was built with the same stamp, and none of
+/// the source files listed in its dep-info file, like the ones of path dependencies, changed since.
+fn cargo_binary_run_command(project_dir: &Path, binary: &Path, stamp: &str) -> Option<Command> {
+    let stamp_file = project_dir.join(CARGO_BINARY_STAMP_FILE);
+    let is_fresh = fs::read_to_string(stamp_file).is_ok_and(|t| t == stamp)
+        && binary_sources_unchanged(binary);
+    is_fresh.then(|| binary_run_command(project_dir, binary))
+}
+
+/// Checks whether all source files of the binary are older than the binary.
+fn binary_sources_unchanged(binary: &Path) -> bool {
+    let modified = |path: &Path| fs::metadata(path).and_then(|t| t.modified()).ok();
+    let Some(built) = modified(binary) else { return false };
+    let Ok(dep_info) = fs::read_to_string(binary.with_extension("d")) else { return false };
+    let sources = dep_info_sources(&dep_info);
+    !sources.is_empty() && sources.iter().all(|t| modified(t).is_some_and(|t| t <= built))
+}
+
+/// Source files listed in a Makefile-style dep-info file, like `bin: src/main.rs src/a\ b.rs`,
+/// where spaces in paths are escaped with backslashes.
+fn dep_info_sources(dep_info: &str) -> Vec<PathBuf> {
+    let mut sources = Vec::new();
+    for line in dep_info.lines() {
+        let Some((_, deps)) = line.split_once(": ") else { continue };
+        let mut source = String::new();
+        let mut chars = deps.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c == '\\' && chars.peek() == Some(&' ') {
+                source.push(' ');
+                chars.next();
+            } else if c == ' ' {
+                if !source.is_empty() {
+                    sources.push(PathBuf::from(std::mem::take(&mut source)));
+                }
+            } else {
+                source.push(c);
+            }
+        }
+        if !source.is_empty() {
+            sources.push(PathBuf::from(source));
+        }
+    }
+    sources
+}
+
+/// Sets the environment of the generated program shared by all ways of running it.
+fn set_run_env(command: &mut Command, build_id: Option<u128>) {
+    if let Some(build_id) = build_id {
+        command.env(BUILD_ID_ENV, build_id.to_string());
+    }
+    command.env(PHASE_CACHE_DIR_ENV, Paths::phase_cache_dir());
+}
+
+/// Quotes the argument for POSIX shells, if needed.
+fn shell_quote(arg: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "_-./=:,+@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(is_safe) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Renders the command as a line that can be pasted into a terminal to reproduce the run. Only the
+/// environment variables set or removed explicitly are included, not the whole inherited
+/// environment. POSIX shell syntax is used, so on Windows, the line has to be run in a POSIX
+/// shell, like Git Bash.
+fn render_command(command: &Command) -> String {
+    let quote = |s: &std::ffi::OsStr| shell_quote(&s.to_string_lossy());
+    let mut removed = Vec::new();
+    let mut set = Vec::new();
+    for (key, value) in command.get_envs() {
+        match value {
+            None => removed.push(format!("-u {}", quote(key))),
+            Some(value) => set.push(format!("{}={}", quote(key), quote(value))),
+        }
+    }
+    let mut parts = Vec::new();
+    if !removed.is_empty() {
+        parts.push("env".to_string());
+        parts.extend(removed);
+    }
+    parts.extend(set);
+    parts.push(quote(command.get_program()));
+    parts.extend(command.get_args().map(quote));
+    let line = parts.join(" ");
+    match command.get_current_dir() {
+        Some(dir) => format!("cd {} && {line}", quote(dir.as_os_str())),
+        None => line,
+    }
+}
+
+/// Runs the nested build, retrying it up to `retries` times if it fails with a transient error,
+/// like a network timeout or a locked registry index. Other failures are returned as they are.
+fn run_with_retries(
+    retries: usize,
+    backoff: std::time::Duration,
+    mut run: impl FnMut() -> Result<std::process::Output>
+) -> Result<std::process::Output> {
+    let mut delay = backoff;
+    for attempt in 1.. {
+        let output = run()?;
+        if output.status.success() || attempt > retries {
+            return Ok(output)
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let Some(class) = process::transient_failure(&stderr) else { return Ok(output) };
+        print_warning!(
+            "The nested cargo failed with a transient {class} error. Retrying in {} (retry \
+            {attempt} of {retries}).", format_duration(delay)
+        );
+        std::thread::sleep(delay);
+        delay *= 2;
+    }
+    unreachable!()
+}
+
+/// Settings of a run of the generated project, other than the project configuration.
+#[derive(Clone, Copy, Debug, Default)]
+struct RunSettings<'t> {
+    /// Name of the macro, used in error messages.
+    name: &'t str,
+    build_id: Option<u128>,
+    jobs: Option<usize>,
+    retries: usize,
+    /// Maximum time of building and running the project, including the retries.
+    timeout: Option<std::time::Duration>,
+    /// Whether to print the stderr of a successful run, attributed to the macro.
+    forward_stderr: bool,
+}
+
+impl RunSettings<'_> {
+    /// Prints the lines of the stderr of a successful run, like logs of the macro, prefixed with the
+    /// macro name. The program is run separately from cargo, so the stderr contains no cargo output.
+    fn forward_stderr(&self, output: &std::process::Output) {
+        if !self.forward_stderr {
+            return
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        for line in stderr.lines().filter(|t| !t.trim().is_empty()) {
+            eprintln!("[crabtime:{}] {line}", self.name);
+        }
+    }
+}
+
+/// The moment when the run of the generated project of a macro is stopped.
+#[derive(Debug)]
+struct Deadline<'t> {
+    name: &'t str,
+    project_dir: &'t Path,
+    start: std::time::Instant,
+    timeout: std::time::Duration,
+}
+
+impl Deadline<'_> {
+    fn instant(&self) -> std::time::Instant {
+        self.start + self.timeout
+    }
+
+    /// The error of a run which did not finish in time. The debug output the macro printed before
+    /// it was stopped was already forwarded, as it often tells where the macro got stuck.
+    fn exceeded(&self) -> Issue {
+        error!(
+            "Macro '{}' did not finish within the timeout of {}, so it was stopped after {}. The \
+            timeout covers both compiling and running the macro. You can reproduce the run with \
+            'cargo run' in '{}', and change the timeout with the 'timeout_secs' option or the \
+            '{TIMEOUT_ENV}' environment variable.",
+            self.name, format_duration(self.timeout), format_duration(self.start.elapsed()),
+            self.project_dir.display()
+        )
+    }
+}
+
+/// Runs the command to completion, passing the lines of its stdout to `on_line` as they are
+/// printed. If the deadline passes first, the command is killed and the error is returned, while
+/// failing to run it at all is left to the caller.
+fn wait_until(
+    command: &mut Command,
+    deadline: Option<&Deadline>,
+    on_line: impl FnMut(&str),
+) -> Result<std::io::Result<std::process::Output>> {
+    let waited = process::ChildGuard::spawn(command)
+        .and_then(|t| t.wait_with_output_until(deadline.map(Deadline::instant), on_line));
+    match waited {
+        Ok(process::Waited::Finished(output)) => Ok(Ok(output)),
+        Ok(process::Waited::TimedOut) => match deadline {
+            Some(deadline) => Err(deadline.exceeded()),
+            None => Ok(Err(std::io::Error::other("The process was stopped."))),
+        },
+        Err(err) => Ok(Err(err)),
+    }
+}
+
+fn run_cargo_project(
+    project_dir: &Path,
+    target_dir: Option<&Path>,
+    cfg: &CargoConfig,
+    settings: &RunSettings,
+) -> Result<String> {
+    let build_id = settings.build_id;
+    let deadline = settings.timeout.map(|timeout| Deadline {
+        name: settings.name, project_dir, start: std::time::Instant::now(), timeout
+    });
+    let deadline = deadline.as_ref();
+    let toolchain = Toolchain::from_env();
+    // In case the project uses .cargo/config.toml, we need to explicitly revert target to native.
+    // Minimal projects skip querying rustc, using the compiler and target of the macro instead.
+    let (rustc_version_info, host_target) = if cfg.minimal {
+        (RUSTC_VERSION.to_string(), HOST_TARGET.to_string())
+    } else {
+        let rustc_version_info = rustc_version_info(&toolchain.rustc)?;
+        let host_target = get_host_target(&rustc_version_info, &toolchain.rustc)?;
+        (rustc_version_info, host_target)
+    };
+    let flags = cargo_flags_from_env()?;
+    prepare_run_dirs(project_dir)?;
+    let entries_before = project_dir_entries(project_dir);
+    let rustc_command = rustc_run_command(
+        &toolchain.rustc, project_dir, cfg, &rustc_version_info, &host_target, deadline
+    )?;
+    let (mut command, binary_stamp) = match rustc_command {
+        Some(command) => (command, None),
+        None => {
+            let target_dir =
+                target_dir.map_or_else(|| project_dir.join("target"), Path::to_path_buf);
+            let binary = cargo_binary_path(&target_dir, &host_target, cfg);
+            let stamp = cargo_binary_stamp(project_dir, cfg, &rustc_version_info, &flags)?;
+            if let Some(mut command) = cargo_binary_run_command(project_dir, &binary, &stamp) {
+                set_run_env(&mut command, build_id);
+                match wait_until(&mut command, deadline, forward_debug_line)? {
+                    Ok(output) if output.status.success() => {
+                        settings.forward_stderr(&output);
+                        return Ok(protocol_output(&output))
+                    }
+                    output => debug!("Running the binary failed, using cargo: {output:?}"),
+                }
+            }
+            // The stamp is written again after a successful run.
+            fs::remove_file(project_dir.join(CARGO_BINARY_STAMP_FILE)).ok();
+            let build = CargoBuild {
+                cargo: &toolchain.cargo,
+                project_dir,
+                target_dir: &target_dir,
+                host_target: &host_target,
+                cfg,
+                flags: &flags,
+            };
+            let binary = build.run(settings, deadline)?;
+            (binary_run_command(project_dir, &binary), Some(stamp))
+        }
+    };
+    set_run_env(&mut command, build_id);
+    let output = wait_until(&mut command, deadline, forward_debug_line)?
+        .context(|| error!("Failed to execute '{}'", command.get_program().display()))?;
+    let unexpected_entries =
+        unexpected_project_entries(&entries_before, &project_dir_entries(project_dir));
+    if !unexpected_entries.is_empty() {
+        print_warning!(
+            "The macro created unexpected files in the project directory '{}': {}. Files should \
+            be created relative to the working directory, which is cleaned before each run.",
+            project_dir.display(), unexpected_entries.join(", ")
+        );
+    }
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let reproduce = format!("Reproduce: {}", render_command(&command));
+        eprintln!("{stderr}");
+        eprintln!("{reproduce}");
+        #[allow(clippy::panic)]
+        if let Some(index) = stderr.find("thread 'main' panicked") {
+            panic!("{}\n{reproduce}", &stderr[index..]);
+        }
+        err!("Macro '{}' failed with {}.\n{reproduce}", settings.name, output.status)
+    } else {
+        if let Some(stamp) = &binary_stamp {
+            let stamp_file = project_dir.join(CARGO_BINARY_STAMP_FILE);
+            fs::write(&stamp_file, stamp).with_path("write", &stamp_file)?;
+        }
+        settings.forward_stderr(&output);
+        Ok(protocol_output(&output))
+    }
+}
+
+/// Cargo flag printing the compiler diagnostics as JSON, with their rendered text colored.
+const CARGO_MESSAGE_FORMAT: &str = "--message-format=json-diagnostic-rendered-ansi";
+
+/// Build of the generated project with cargo. The binary is run separately, so that its stdout
+/// contains only the Stdout Protocol, and the diagnostics of the compiler are reported as
+/// diagnostics of the macro instead of raw cargo output.
+struct CargoBuild<'t> {
+    cargo: &'t Path,
+    project_dir: &'t Path,
+    target_dir: &'t Path,
+    host_target: &'t str,
+    cfg: &'t CargoConfig,
+    flags: &'t [String],
+}
+
+impl CargoBuild<'_> {
+    /// Builds the project, retrying transient failures. Returns the path of the built binary.
+    fn run(&self, settings: &RunSettings, deadline: Option<&Deadline>) -> Result<PathBuf> {
+        let mut command = cargo_build_command(
+            self.cargo, self.project_dir, self.target_dir, self.host_target, self.cfg.profile,
+            self.flags, settings.jobs
+        );
+        for config_file in &self.cfg.config_files {
+            command.arg("--config").arg(config_file);
+        }
+        // The JSON messages are not meant for people, so the flag is not a part of the command
+        // shown for reproducing the build.
+        let reproduce = format!("Reproduce: {}", render_command(&command));
+        command.arg(CARGO_MESSAGE_FORMAT);
+        let mut executable = None;
+        let mut has_errors = false;
+        let output = run_with_retries(settings.retries, RETRY_BACKOFF, || {
+            // Only the messages of the last attempt describe the reported result.
+            executable = None;
+            has_errors = false;
+            let on_line = |line: &str| match process::cargo_message(line) {
+                Some(process::CargoMessage::Diagnostic { level, rendered }) => {
+                    has_errors |= matches!(level, Level::Error);
+                    print(level, None, &rendered);
+                }
+                Some(process::CargoMessage::Executable(path)) => executable = Some(path),
+                None => {}
+            };
+            wait_until(&mut command, deadline, on_line)?
+                .context(|| error!("Failed to execute '{}'", command.get_program().display()))
+        })?;
+        if output.status.success() {
+            let binary = || cargo_binary_path(self.target_dir, self.host_target, self.cfg);
+            return Ok(executable.unwrap_or_else(binary))
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if let Some((name, error)) = process::failed_dependency(&stderr)
+        && let Some(dependency) = self.cfg.dependency(&name) {
+            return err!(dependency.span(),
+                "Failed to fetch dependency '{}'.\n{error}\n{reproduce}", dependency.label
+            )
+        }
+        // Failures without compiler errors, like invalid manifests, are reported by cargo itself.
+        if !has_errors {
+            eprintln!("{stderr}");
+        }
+        err!("Compilation of the generated code failed.\n{reproduce}")
+    }
+}
+
+/// Prints the line of the macro output to the compiler console right away, unless it belongs to
+/// the Stdout Protocol, so progress messages of long-running macros are visible.
+fn forward_debug_line(line: &str) {
+    if !line.trim().is_empty() && !is_protocol_line(line) {
+        println!("{line}");
+    }
+}
+
+/// The lines of the macro output which belong to the Stdout Protocol, in their original order. The
+/// other lines were already forwarded while the macro was running.
+fn protocol_output(output: &std::process::Output) -> String {
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| is_protocol_line(line))
+        .map(|line| format!("{line}\n"))
+        .collect()
+}
+
+/// File of a shared project directory naming the build, the compiler process, and the code which
+/// last used it.
+const PROJECT_OWNER_FILE: &str = "crabtime-owner";
+
+/// Records that the project directory is used by the code with the given fingerprint in the
+/// build. Different code using the same directory in one compiler process would overwrite the
+/// project of the other expansion, so it is reported as an error instead. Builds are detected by
+/// a pause between expansions, so the process is compared too, as otherwise quickly rebuilding
+/// edited code would be reported.
+fn claim_project_dir(dir: &Path, build_id: u128, fingerprint: u64) -> Result {
+    let owner_file = dir.join(PROJECT_OWNER_FILE);
+    let process = format!("{build_id}\t{}", std::process::id());
+    let owner = format!("{process}\t{fingerprint:016x}");
+    let previous = fs::read_to_string(&owner_file).unwrap_or_default();
+    if let Some((previous_process, previous_fingerprint)) = previous.rsplit_once('\t')
+    && previous_process == process
+    && previous_fingerprint != format!("{fingerprint:016x}") {
+        return err!(
+            "Two expansions with different code use the project directory '{}' in one build, so \
+            they would overwrite each other's project. Please report it as a bug. As a workaround, \
+            use the 'content_base_name = true' option.", dir.display()
+        )
+    }
+    fs::create_dir_all(dir).with_path("create directory", dir)?;
+    fs::write(&owner_file, owner).with_path("write", &owner_file)
+}
+
+/// Checks whether the tokens contain the `<crate_name>::TokenStream` path.
+fn uses_token_stream(tokens: TokenStream, crate_name: &str) -> bool {
+    let tokens = tokens.into_iter().collect::<Vec<_>>();
+    tokens.iter().enumerate().any(|(i, token)| match token {
+        TokenTree::Group(group) => uses_token_stream(group.stream(), crate_name),
+        TokenTree::Ident(ident) if *ident == crate_name => matches!(
+            &tokens[i + 1..],
+            [TokenTree::Punct(colon1), TokenTree::Punct(colon2), TokenTree::Ident(tp), ..]
+            if colon1.as_char() == ':' && colon2.as_char() == ':' && *tp == "TokenStream"
+        ),
+        _ => false,
+    })
+}
+
+// ====================
+// === Output Macro ===
+// ====================
+
+/// Find and expand the `output!` macro in the input `TokenStream`. After this lib stabilizes, this
+/// should be rewritten to standard macro and imported by the generated code.
+fn expand_expand_macro(input: TokenStream) -> TokenStream {
+    let tokens: Vec<TokenTree> = input.into_iter().collect();
+    let mut output = TokenStream::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        if let TokenTree::Ident(ref ident) = tokens[i]
+        && *ident == "expand" && i + 1 < tokens.len()
+        && let TokenTree::Punct(ref excl) = tokens[i + 1]
+        && excl.as_char() == '!' && i + 2 < tokens.len()
+        && let TokenTree::Group(ref group) = tokens[i + 2] {
+            output.extend(group.stream());
+            i += 3;
+            continue;
+        }
+        match &tokens[i] {
+            TokenTree::Group(group) => {
+                let new_stream = expand_expand_macro(group.stream());
+                let new_group = TokenTree::Group(
+                    proc_macro2::Group::new(group.delimiter(), new_stream)
+                );
+                output.extend(std::iter::once(new_group));
+            }
+            _ => {
+                output.extend(std::iter::once(tokens[i].clone()));
+            }
+        }
+        i += 1;
+    }
+    output
+}
+
+fn expand_builtin_macro(
+    name: &str,
+    input: TokenStream,
+    f: &impl Fn(TokenStream) -> TokenStream
+) -> TokenStream {
+    let tokens: Vec<TokenTree> = input.into_iter().collect();
+    let mut output = TokenStream::new();
+    let len = tokens.len();
+    let mut i = 0;
+
+    while i < len {
+        // Check for the pattern: crabtime :: output ! ( group )
+        if i + 5 < len
+        && let TokenTree::Ident(ref ident) = tokens[i]
+        && ident == GEN_MOD
+        && let TokenTree::Punct(ref colon1) = tokens[i + 1]
+        && colon1.as_char() == ':'
+        && let TokenTree::Punct(ref colon2) = tokens[i + 2]
+        && colon2.as_char() == ':'
+        && let TokenTree::Ident(ref out_ident) = tokens[i + 3]
+        && out_ident == name
+        && let TokenTree::Punct(ref excl) = tokens[i + 4]
+        && excl.as_char() == '!'
+        && let TokenTree::Group(ref group) = tokens[i + 5] {
+            let inner_rewritten = expand_builtin_macro(name, group.stream(), f);
+            let new_tokens = f(inner_rewritten);
+            output.extend(new_tokens);
+            i += 6;
+            continue;
+        }
+
+        // Recurse into groups or pass through token.
+        match &tokens[i] {
+            TokenTree::Group(group) => {
+                let new_stream = expand_builtin_macro(name, group.stream(), f);
+                // Rebuild group with same delimiter.
+                let new_group = TokenTree::Group(proc_macro2::Group::new(group.delimiter(), new_stream));
+                output.extend(std::iter::once(new_group));
+            }
+            token => output.extend(std::iter::once(token.clone())),
+        }
+        i += 1;
+    }
+    output
+}
+
+fn expand_output_macro(input: TokenStream, ide_compat: IdeCompat) -> TokenStream {
+    let gen_mod = syn::Ident::new(GEN_MOD, Span::call_site());
+    expand_builtin_macro("output", input, &|inner_rewritten| {
+        let content_str = print_tokens(&inner_rewritten, ide_compat.pad_keywords(&inner_rewritten));
+        let lit = syn::LitStr::new(&content_str, Span::call_site());
+        quote! {
+            #gen_mod::write_ln!(__output_buffer__, #lit);
+        }
+    })
+}
+
+fn expand_quote_macro(input: TokenStream, ide_compat: IdeCompat) -> TokenStream {
+    let gen_mod = syn::Ident::new(GEN_MOD, Span::call_site());
+    expand_builtin_macro("quote", input, &|inner_rewritten| {
+        let content_str = print_tokens(&inner_rewritten, ide_compat.pad_keywords(&inner_rewritten));
+        let lit = syn::LitStr::new(&content_str, Span::call_site());
+        quote! { #gen_mod::Snippet::from(format!(#lit)) }
+    })
+}
+
+/// Expands `output_macro_rules!` to a string literal. It has to be done before the function body
+/// is pasted into the `macro_rules!` definition, as otherwise the `$` tokens of the generated rules
+/// would be interpreted as metavariables of the outer macro.
+fn expand_output_macro_rules_macro(input: TokenStream, ide_compat: IdeCompat) -> TokenStream {
+    let gen_mod = syn::Ident::new(GEN_MOD, Span::call_site());
+    expand_builtin_macro("output_macro_rules", input, &|inner_rewritten| {
+        let pad_keywords = ide_compat.pad_keywords(&inner_rewritten);
+        match print_macro_rules_tokens(&inner_rewritten, pad_keywords) {
+            Some(content_str) => {
+                let lit = syn::LitStr::new(&content_str, Span::call_site());
+                quote! {
+                    #gen_mod::write_ln!(__output_buffer__, #lit);
+                }
+            }
+            None => quote! {
+                compile_error!("Expected 'output_macro_rules! { name { rules } }'.");
+            }
+        }
+    })
+}
+
+/// Expands `output_cfg!` to a string literal. The items are passed through a helper
+/// `macro_rules!`, which puts the `cfg` attribute on each of them, so the items don't need to be
+/// split here.
+fn expand_output_cfg_macro(input: TokenStream, ide_compat: IdeCompat) -> TokenStream {
+    let gen_mod = syn::Ident::new(GEN_MOD, Span::call_site());
+    expand_builtin_macro("output_cfg", input, &|inner_rewritten| {
+        let pad_keywords = ide_compat.pad_keywords(&inner_rewritten);
+        match print_output_cfg_tokens(&inner_rewritten, pad_keywords) {
+            Some(content_str) => {
+                let lit = syn::LitStr::new(&content_str, Span::call_site());
+                quote! {
+                    #gen_mod::write_ln!(__output_buffer__, #lit);
+                }
+            }
+            None => quote! {
+                compile_error!("Expected 'output_cfg! { predicate => { items } }'.");
+            }
+        }
+    })
+}
+
+/// Expands `stringify_if_needed!` to a string literal. String literals are passed through, so
+/// their outer quotes are not part of the string. Other tokens are printed compactly, so paths and
+/// generic types don't get the spaces that `stringify!` would insert around `::` and `<`.
+fn expand_stringify_if_needed_macro(input: TokenStream) -> TokenStream {
+    expand_builtin_macro("stringify_if_needed", input, &|inner_rewritten| {
+        let inner = unwrap_invisible_group(inner_rewritten);
+        match syn::parse2::<syn::LitStr>(inner.clone()) {
+            Ok(lit) => quote! { #lit },
+            Err(_) => {
+                let lit = syn::LitStr::new(&print_tokens_compact(&inner), Span::call_site());
+                quote! { #lit }
+            }
+        }
+    })
+}
+
+/// Macro fragments, like `$arg:expr`, are passed to proc macros wrapped in invisible groups.
+fn unwrap_invisible_group(tokens: TokenStream) -> TokenStream {
+    let mut iter = tokens.clone().into_iter();
+    match (iter.next(), iter.next()) {
+        (Some(TokenTree::Group(group)), None) if group.delimiter() == Delimiter::None =>
+            unwrap_invisible_group(group.stream()),
+        _ => tokens,
+    }
+}
+
+// =============
+// === Print ===
+// =============
+
+/// Workarounds for IDEs whose token spans are incorrect. `IntelliJ` / `RustRover` spans don't
+/// tell where spaces were, so spaces are added around keywords to keep them apart from adjacent
+/// tokens. The padding changes the spacing of the generated code, so it is applied only when
+/// needed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum IdeCompat {
+    /// Pad keywords only if the token spans look incorrect.
+    #[default]
+    Auto,
+    /// Always pad keywords.
+    IntelliJ,
+    /// Never pad keywords.
+    None,
+}
+
+impl IdeCompat {
+    fn pad_keywords(self, tokens: &TokenStream) -> bool {
+        match self {
+            Self::Auto => !spans_look_valid(tokens),
+            Self::IntelliJ => true,
+            Self::None => false,
+        }
+    }
+}
+
+/// Checks whether the token spans are non-empty and their starts increase monotonically, which is
+/// not the case for IDEs providing incorrect spans.
+fn spans_look_valid(tokens: &TokenStream) -> bool {
+    let mut prev_start: Option<LineColumn> = None;
+    tokens.clone().into_iter().all(|token| {
+        let start = token.span().start();
+        let end = token.span().end();
+        let increasing = prev_start
+            .is_none_or(|prev| (start.line, start.column) > (prev.line, prev.column));
+        prev_start = Some(start);
+        let group_valid = match &token {
+            TokenTree::Group(group) => spans_look_valid(&group.stream()),
+            _ => true,
+        };
+        start != end && increasing && group_valid
+    })
+}
+
+#[derive(Debug)]
+struct PrintOutput {
+    output: String,
+    start_token: Option<LineColumn>,
+    end_token: Option<LineColumn>,
+}
+
+/// Prints the token stream as a string ready to be used by the format macro. It is very careful
+/// where spaces are inserted. In particular, spaces are not inserted around `{` and `}` tokens if
+/// they were not present in the original token stream. It is fine-tuned to work in different IDEs,
+/// such as `RustRover`, by padding keywords with spaces if `pad_keywords` is set.
+fn print_tokens(tokens: &TokenStream, pad_keywords: bool) -> String {
+    // Replaces `{` with `{{` and vice versa.
+    print_tokens_internal(tokens, pad_keywords).output
+        .replace("{", "{{")
+        .replace("}", "}}")
+        .replace("{{%%%{{%%%{{", "{{ {")
+        .replace("}}%%%}}%%%}}", "} }}")
+        .replace("{{%%%{{", "{")
+        .replace("}}%%%}}", "}")
+}
+
+/// Prints the token stream as a string ready to be used by the format macro, treating all braces
+/// literally, so no interpolation is performed.
+fn print_tokens_literal(tokens: &TokenStream, pad_keywords: bool) -> String {
+    print_tokens_internal(tokens, pad_keywords).output
+        .replace("{%%%", "{")
+        .replace("%%%}", "}")
+        .replace("{", "{{")
+        .replace("}", "}}")
+}
+
+/// Prints the `[#[attr]] name { rules }` input of `output_macro_rules!` as a `macro_rules!`
+/// definition ready to be used by the format macro. Interpolation is supported in the attributes
+/// and the macro name only. Braces in the rules are printed literally.
+fn print_macro_rules_tokens(tokens: &TokenStream, pad_keywords: bool) -> Option<String> {
+    let mut header: Vec<TokenTree> = tokens.clone().into_iter().collect();
+    let Some(TokenTree::Group(rules)) = header.pop() else { return None };
+    if rules.delimiter() != Delimiter::Brace || header.is_empty() { return None }
+    let mut attrs_len = 0;
+    while let [TokenTree::Punct(hash), TokenTree::Group(group), ..] = &header[attrs_len..]
+    && hash.as_char() == '#'
+    && group.delimiter() == Delimiter::Bracket {
+        attrs_len += 2;
+    }
+    let name = header.split_off(attrs_len);
+    let attrs_str = print_tokens(&header.into_iter().collect(), pad_keywords);
+    let name_str = print_tokens(&name.into_iter().collect(), pad_keywords);
+    let rules_str = print_tokens_literal(&rules.stream(), pad_keywords);
+    Some(format!("{attrs_str} macro_rules! {name_str} {{{{ {rules_str} }}}}"))
+}
+
+/// Prints the `predicate => { items }` input of `output_cfg!` as items gated by the predicate,
+/// ready to be used by the format macro. The predicate can be given as tokens or as a string
+/// literal, and it is printed literally. Interpolation is supported in the items only.
+fn print_output_cfg_tokens(tokens: &TokenStream, pad_keywords: bool) -> Option<String> {
+    let mut predicate: Vec<TokenTree> = tokens.clone().into_iter().collect();
+    let Some(TokenTree::Group(items)) = predicate.pop() else { return None };
+    let [.., TokenTree::Punct(eq), TokenTree::Punct(gt)] = &predicate[..] else { return None };
+    if items.delimiter() != Delimiter::Brace
+    || eq.as_char() != '='
+    || eq.spacing() != proc_macro2::Spacing::Joint
+    || gt.as_char() != '>' {
+        return None
+    }
+    predicate.truncate(predicate.len() - 2);
+    let mut predicate: TokenStream = predicate.into_iter().collect();
+    if let Ok(lit) = syn::parse2::<syn::LitStr>(predicate.clone()) {
+        predicate = lit.parse().ok()?;
+    }
+    syn::parse2::<syn::Meta>(predicate.clone()).ok()?;
+    let predicate_str = print_tokens_literal(&predicate, pad_keywords);
+    let items_str = print_tokens(&items.stream(), pad_keywords);
+    Some(format!(
+        "macro_rules! __crabtime_output_cfg {{{{ ($($item:item)*) => {{{{ \
+        $(#[cfg({predicate_str})] $item)* }}}} }}}} __crabtime_output_cfg! {{{{ {items_str} }}}}"
+    ))
+}
+
+/// Prints the token stream the way it was written, with runs of whitespace collapsed to a single
+/// space. If the token spans are not available, for example, in some IDEs, spaces are inserted
+/// between tokens, except around `::` and before `,`, `;`, and `.`.
+fn print_tokens_compact(tokens: &TokenStream) -> String {
+    fn print(tokens: &TokenStream, output: &mut String) {
+        let mut prev: Option<(LineColumn, String, bool)> = None;
+        for token in tokens.clone() {
+            let start = token.span().start();
+            let end = token.span().end();
+            let (token_str, joint) = match &token {
+                TokenTree::Group(group) => {
+                    let mut content = String::new();
+                    print(&group.stream(), &mut content);
+                    let (open, close) = match group.delimiter() {
+                        Delimiter::Brace => ("{", "}"),
+                        Delimiter::Parenthesis => ("(", ")"),
+                        Delimiter::Bracket => ("[", "]"),
+                        Delimiter::None => ("", ""),
+                    };
+                    (format!("{open}{content}{close}"), false)
+                }
+                TokenTree::Punct(punct) =>
+                    (punct.as_char().to_string(), punct.spacing() == proc_macro2::Spacing::Joint),
+                token => (token.to_string(), false),
+            };
+            if let Some((prev_end, prev_str, prev_joint)) = &prev {
+                let spans_valid = start != end
+                    && (start.line, start.column) >= (prev_end.line, prev_end.column);
+                let add_space = if *prev_joint {
+                    false
+                } else if spans_valid {
+                    start != *prev_end
+                } else {
+                    let after_path_sep = prev_str == ":" && output.ends_with("::");
+                    let before_path_sep = token_str == ":" && joint;
+                    !after_path_sep && !before_path_sep && prev_str != "."
+                        && !matches!(token_str.as_str(), "," | ";" | ".")
+                };
+                if add_space {
+                    output.push(' ');
+                }
+            }
+            output.push_str(&token_str);
+            prev = Some((end, token_str, joint));
+        }
+    }
+    let mut output = String::new();
+    print(tokens, &mut output);
+    output
+}
+
+/// Prints the tokens of a TOML value, like `{ version = "1", default-features = false }`. Rust
+/// splits keys like `default-features` into several tokens, so no spaces are added around `-` and
+/// `.`, and string literals are printed as they were written.
+fn print_toml_tokens(tokens: &[TokenTree]) -> String {
+    let mut output = String::new();
+    for token in tokens {
+        match token {
+            TokenTree::Group(group) => {
+                let content = group.stream().into_iter().collect::<Vec<_>>();
+                let content = print_toml_tokens(&content);
+                // TOML does not allow trailing commas in inline tables.
+                let content = content.trim_end_matches(", ");
+                match group.delimiter() {
+                    Delimiter::Brace if content.is_empty() => output.push_str("{}"),
+                    Delimiter::Brace => output.push_str(&format!("{{ {content} }}")),
+                    Delimiter::Bracket => output.push_str(&format!("[{content}]")),
+                    Delimiter::Parenthesis => output.push_str(&format!("({content})")),
+                    Delimiter::None => output.push_str(content),
+                }
+            }
+            TokenTree::Punct(punct) => match punct.as_char() {
+                '=' => output.push_str(" = "),
+                ',' => output.push_str(", "),
+                char => output.push(char),
+            },
+            token => output.push_str(&token.to_string()),
+        }
+    }
+    output
+}
+
+fn print_tokens_internal(tokens: &TokenStream, pad_keywords: bool) -> PrintOutput {
+    let token_vec: Vec<TokenTree> = tokens.clone().into_iter().collect();
+    let mut output = String::new();
+    let mut first_token_start = None;
+    let mut prev_token_end: Option<LineColumn> = None;
+    let mut prev_token_was_brace = false;
+    for (i, token) in token_vec.iter().enumerate() {
+        let mut add_space = true;
+        let mut token_start = token.span().start();
+        let mut token_end = token.span().end();
+        let mut is_brace = false;
+        let mut is_keyword = false;
+        let token_str = match token {
+            TokenTree::Group(g) => {
+                let content = print_tokens_internal(&g.stream(), pad_keywords);
+                let mut content_str = content.output;
+                content_str.pop();
+                let (open, close) = match g.delimiter() {
+                    Delimiter::Brace => {
+                        is_brace = true;
+                        if content_str.starts_with("{") && content_str.ends_with("}") {
+                            ("{%%%", "%%%}")
+                        } else {
+                            ("{", "}")
+                        }
+                    },
+                    Delimiter::Parenthesis => ("(", ")"),
+                    Delimiter::Bracket => ("[", "]"),
+                    _ => ("", ""),
+                };
+
+                if let Some(content_first_token_start) = content.start_token {
+                    token_start.line = content_first_token_start.line;
+                    if content_first_token_start.column > 0 {
+                        token_start.column = content_first_token_start.column - 1;
+                    }
+                }
+                if let Some(content_end) = content.end_token {
+                    token_end.line = content_end.line;
+                    token_end.column = content_end.column + 1;
+                }
+
+                format!("{open}{content_str}{close}")
+            }
+            TokenTree::Ident(ident) => {
+                let str = ident.to_string();
+                is_keyword = pad_keywords && is_padded_keyword(&str);
+                str
+            },
+            TokenTree::Literal(lit) => lit.to_string(),
+            TokenTree::Punct(punct) => {
+                if punct.spacing() == proc_macro2::Spacing::Joint {
+                    add_space = false;
+                }
+                punct.as_char().to_string()
+            },
+        };
+        debug!("{i}: [{token_start:?}-{token_end:?}] [{prev_token_end:?}]: {token}");
+
+        // check if the punct has set flags to have no spaces
+        if (is_brace || prev_token_was_brace)
+        && let Some(prev_token_end) = prev_token_end
+        && prev_token_end.line == token_start.line
+        && prev_token_end.column >= token_start.column
+        && output.ends_with(" ") {
+            output.pop();
+        }
+        prev_token_was_brace = is_brace;
+
+        // Pushing a space before and after keywords is for IntelliJ only.
+        // Their token spans are invalid.
+        if is_keyword { output.push(' '); }
+        output.push_str(&token_str);
+        if add_space {
+            output.push(' ');
+        }
+        if is_keyword { output.push(' '); }
+
+        first_token_start.get_or_insert(token_start);
+        prev_token_end = Some(token_end);
+    }
+    PrintOutput {
+        output,
+        start_token: first_token_start,
+        end_token: prev_token_end,
+    }
+}
+
+// ==================
+// === Eval Macro ===
+// ==================
+
+enum Args {
+    TokenStream { ident: syn::Ident },
+    Pattern { str: TokenStream }
+}
+
+impl Args {
+    fn pattern(&self) -> TokenStream {
+        match self {
+            Self::TokenStream { ident } => quote! { $($#ident:tt)* },
+            Self::Pattern { str } => str.clone(),
+        }
+    }
+
+    fn setup(&self) -> TokenStream {
+        if let Self::TokenStream { ident } = self {
+            quote! {
+                use proc_macro2::TokenStream;
+                let #ident: TokenStream = stringify!($($#ident)*).parse().unwrap();
+            }
+        } else {
+            Default::default()
+        }
+    }
+}
+
+/// Parsed macro arguments: the pattern, the code binding the arguments, and the code binding
+/// their defaults if the macro can be invoked without arguments.
+type ParsedArgs = (Args, TokenStream, Option<TokenStream>);
+
+/// Parses the function arguments, recording their ranges in the span map.
+fn parse_args(
+    args: &syn::punctuated::Punctuated<syn::FnArg, syn::token::Comma>,
+    spans: &mut SpanMap,
+) -> Option<ParsedArgs> {
+    for arg in args {
+        if let syn::FnArg::Typed(pat_type) = arg
+        && let Some(range) = TokenRange::from_tokens(arg.to_token_stream()) {
+            let pat = &pat_type.pat;
+            spans.args.push((quote!{#pat}.to_string(), range));
+        }
+    }
+    let Some(arg) = args.first() else {
+        return Some((Args::Pattern { str: Default::default() }, TokenStream::new(), None))
+    };
+
+    // First try the specialized parsers, then fallback to our generic type handling.
+    parse_args_for_pattern(arg)
+        .or_else(|| parse_args_for_token_stream(arg))
+        .map(|t| (t, TokenStream::new(), None))
+        .or_else(|| {
+            let mut is_first = true;
+            let mut pat = quote!{};
+            let mut code = TokenStream::new();
+            let mut default_code = Some(TokenStream::new());
+
+            for arg in args {
+                if !is_first {
+                    pat = quote! {#pat, };
+                }
+                is_first = false;
+                if let syn::FnArg::Typed(pat_type) = arg
+                && let syn::Pat::Ident(name) = &*pat_type.pat {
+                    let name_str = name.ident.to_string();
+                    let ty = &*pat_type.ty;
+                    code = quote! {
+                        #code
+                        let #name: #ty =
+                    };
+                    if let Some((param_pat, param_code)) = parse_arg_type(&name_str, ty) {
+                        pat = quote! {#pat #param_pat};
+                        code = quote! {#code #param_code};
+                    }
+                    code = quote! {#code;};
+                    default_code = default_code.filter(|_| has_default(pat_type)).map(|t| quote! {
+                        #t
+                        let #name: #ty = Default::default();
+                    });
+                }
+            }
+            pat = quote! {#pat $(,)?};
+            Some((Args::Pattern { str: pat }, code, default_code))
+        })
+}
+
+/// Checks whether the argument can be omitted when the macro is invoked without arguments. It is
+/// true for `Vec` and `Option` arguments, which default to an empty vector and `None`, and for
+/// arguments marked with the `#[default]` attribute, which default to `Default::default()`.
+fn has_default(arg: &syn::PatType) -> bool {
+    let has_default_attr = arg.attrs.iter().any(|attr| attr.path().is_ident("default"));
+    let has_default_type = matches!(&*arg.ty, syn::Type::Path(type_path)
+        if type_path.path.segments.last().is_some_and(|t| t.ident == "Vec" || t.ident == "Option")
+    );
+    has_default_attr || has_default_type
+}
+
+const INTEGER_TYPES: &[&str] = &[
+    "usize", "u8", "u16", "u32", "u64", "u128", "isize", "i8", "i16", "i32", "i64", "i128",
+];
+
+/// Returns (pattern, code) for a given type. It supports both vector types and non‑vector types.
+#[inline(always)]
+fn parse_arg_type(pfx: &str, ty: &syn::Type) -> Option<(TokenStream, TokenStream)> {
+    if let syn::Type::Path(type_path) = ty {
+        let last_segment = type_path.path.segments.last()?;
+        if last_segment.ident == "Option" {
+            if let syn::PathArguments::AngleBracketed(angle_bracketed) = &last_segment.arguments
+            && let Some(syn::GenericArgument::Type(inner_ty)) = angle_bracketed.args.first()
+            && let Some((inner_pat, inner_code)) = parse_inner_type(pfx, inner_ty) {
+                return Some((inner_pat, quote! { Some(#inner_code) }));
+            }
+        } else if last_segment.ident == "Vec" {
+            if let syn::PathArguments::AngleBracketed(angle_bracketed) = &last_segment.arguments {
+                let generic_arg = angle_bracketed.args.first()?;
+                if let syn::GenericArgument::Type(inner_ty) = generic_arg
+                && let Some((inner_pat, inner_code)) = parse_inner_type(pfx, inner_ty) {
+                    let pat = quote! {[$(#inner_pat),*$(,)?]};
+                    let code = quote! { [$(#inner_code),*].into_iter().collect() };
+                    return Some((pat, code));
+                }
+            }
+        } else if last_segment.ident == "Range" || last_segment.ident == "RangeInclusive" {
+            return parse_range_type(pfx, &last_segment.ident, &last_segment.arguments);
+        } else if last_segment.ident == "CodeFn" {
+            return Some(parse_code_fn_type(pfx));
+        } else {
+            return parse_inner_type(pfx, ty);
+        }
+    } else if let syn::Type::Reference(_) = ty {
+        return parse_inner_type(pfx, ty);
+    } else if let syn::Type::BareFn(_) = ty {
+        return Some(parse_code_fn_type(pfx));
+    }
+    None
+}
+
+/// Returns (pattern, code) for `crabtime::CodeFn` and function pointer types. The argument, like
+/// `|field| format!("get_{field}")`, is pasted into the generated program, so it is checked against
+/// the parameter type by the compiler of the generated project.
+fn parse_code_fn_type(pfx: &str) -> (TokenStream, TokenStream) {
+    let arg_ident = syn::Ident::new(&format!("{pfx}_arg"), Span::call_site());
+    (quote! {$#arg_ident:expr}, quote! {$#arg_ident})
+}
+
+/// Returns (pattern, code) for `Range<T>` and `RangeInclusive<T>` of integer types, passed as
+/// `start..end` and `start..=end`. Reversed ranges are reported by the generated program.
+fn parse_range_type(
+    pfx: &str,
+    ident: &syn::Ident,
+    arguments: &syn::PathArguments
+) -> Option<(TokenStream, TokenStream)> {
+    let syn::PathArguments::AngleBracketed(angle_bracketed) = arguments else { return None };
+    let Some(syn::GenericArgument::Type(inner_ty)) = angle_bracketed.args.first() else {
+        return None
+    };
+    let syn::Type::Path(inner_path) = inner_ty else { return None };
+    let inner_ident = &inner_path.path.segments.last()?.ident;
+    if !INTEGER_TYPES.iter().any(|t| inner_ident == t) {
+        return None
+    }
+    let start = syn::Ident::new(&format!("{pfx}_start"), Span::call_site());
+    let end = syn::Ident::new(&format!("{pfx}_end"), Span::call_site());
+    let op = if ident == "Range" { quote!{..} } else { quote!{..=} };
+    let op_str = op.to_string();
+    let pat = quote! {$#start:literal #op $#end:literal};
+    let code = quote! {{
+        let (start, end) = ($#start, $#end);
+        if start > end {
+            println!("{}", crabtime::prefix_lines_with_error(&format!(
+                "Invalid range argument '{}': {start}{}{end}, the start is greater than the end.",
+                #pfx, #op_str
+            )));
+        }
+        start #op end
+    }};
+    Some((pat, code))
+}
+
+#[inline(always)]
+fn parse_inner_type(pfx: &str, ty: &syn::Type) -> Option<(TokenStream, TokenStream)> {
+    let arg_str = format!("{pfx}_arg");
+    let arg_ident = syn::Ident::new(&arg_str, Span::call_site());
+    let arg = quote! {$#arg_ident};
+    match ty {
+        syn::Type::Reference(ty_ref) => {
+            if let syn::Type::Path(inner_path) = &*ty_ref.elem
+            && let Some(inner_seg) = inner_path.path.segments.last()
+            && inner_seg.ident == "str" {
+                let pat = quote!{#arg:expr};
+                let code = quote!{crabtime::stringify_if_needed!{#arg}};
+                return Some((pat, code));
+            }
+        },
+        syn::Type::Path(inner_type_path) => {
+            if let Some(inner_seg) = inner_type_path.path.segments.last() {
+                let ident_str = inner_seg.ident.to_string();
+                if ident_str == "String" {
+                    let pat = quote!{#arg:expr};
+                    let code = quote!{crabtime::stringify_if_needed!(#arg).to_string()};
+                    return Some((pat, code));
+                } else if INTEGER_TYPES.contains(&ident_str.as_str()) {
+                    return Some((quote!{#arg:literal}, quote!{#arg}));
+                }
+            }
+        },
+        _ => {}
+    }
+    None
+}
+
+fn parse_args_for_pattern(arg: &syn::FnArg) -> Option<Args> {
+    let syn::FnArg::Typed(pat) = arg else { return None };
+    let syn::Pat::Macro(m) = &*pat.pat else { return None };
+    Some(Args::Pattern {str: m.mac.tokens.clone() })
+}
+
+fn parse_args_for_token_stream(arg: &syn::FnArg) -> Option<Args> {
+    let syn::FnArg::Typed(pat) = arg else { return None };
+    let syn::Pat::Ident(pat_ident) = &*pat.pat else { return None };
+    let tp = &pat.ty;
+    let tp_str = quote! { #tp }.to_string();
+    if tp_str != "TokenStream" { return None }
+    let ident = pat_ident.ident.clone();
+    Some(Args::TokenStream { ident })
+}
+
+const WRONG_ARGS: &str = "Function should have zero or one argument, one of:
+    - `pattern!(<pattern>): _`, where <pattern> is a `macro_rules!` pattern
+    - `input: TokenStream`
+";
+
+fn prepare_input_code(
+    attributes:&str,
+    body: &str,
+    output_tp: &str,
+    crates: &PreludeCrates,
+    paths: &Paths,
+    passthrough: &[(String, TokenStream)],
+    call_args: &[String],
+) -> String {
+    let body_esc: String = body.chars().flat_map(|c| c.escape_default()).collect();
+    let prelude = gen_prelude(crates, paths, passthrough, call_args, body);
+    format!("
+        {attributes}
+        {prelude}
+
+        const SOURCE_CODE: &str = \"{body_esc}\";
+
+        fn main() {{
+            let mut __output_buffer__ = String::new();
+            let result: {output_tp} = {{
+                {body}
+            }};
+            __output_buffer__.push_str(&{GEN_MOD}::code_from_output(result));
+            println!(\"{{}}\", {GEN_MOD}::prefix_lines_with_output(&__output_buffer__));
+        }}",
+    )
+}
+
+/// Code generated by the macro body.
+#[derive(Debug, Default)]
+struct ParsedOutput {
+    code: String,
+    /// False if the macro body reported that its output must not be reused, for example, because
+    /// it used randomness.
+    reusable: bool,
+    /// Lines of notices, reported at most once per build by [`emit_notices`].
+    notices: Vec<String>,
+}
+
+/// Checks whether the line of the macro output belongs to the Stdout Protocol, rather than being a
+/// debug print.
+fn is_protocol_line(line: &str) -> bool {
+    let line = line.trim();
+    [OUTPUT_PREFIX, Level::WARNING_PREFIX, Level::ERROR_PREFIX, NOTICE_PREFIX]
+        .iter()
+        .any(|prefix| line.starts_with(prefix))
+        || line == NON_REUSABLE_MARKER
+}
+
+fn parse_output(
+    name: &str,
+    output: &str,
+    options: &MacroOptions,
+    spans: &SpanMap,
+) -> Result<ParsedOutput> {
+    let max_output = options.max_output()?;
+    let max_output_warn = options.max_output_warn.unwrap_or(DEFAULT_MAX_OUTPUT_WARN);
+    let mut code = String::new();
+    let mut reusable = true;
+    let mut notices = Vec::new();
+    // We keep counting after the limit is exceeded to report the real size in the error message.
+    let mut size = 0;
+    for line in output.split('\n') {
+        let line_trimmed = line.trim();
+        if let Some(stripped) = line_trimmed.strip_prefix(OUTPUT_PREFIX) {
+            size += stripped.len() + 1;
+            if max_output.is_none_or(|max| size <= max) {
+                code.push_str(stripped);
+                code.push('\n');
+            }
+        } else if let Some(stripped) = line_trimmed.strip_prefix(Level::WARNING_PREFIX) {
+            print_warning!("{}", stripped);
+        } else if let Some(stripped) = line_trimmed.strip_prefix(Level::ERROR_PREFIX) {
+            print_error!("{}", stripped);
+        } else if let Some(stripped) = line_trimmed.strip_prefix(NOTICE_PREFIX) {
+            notices.push(stripped.trim_start().to_string());
+        } else if line_trimmed == NON_REUSABLE_MARKER {
+            reusable = false;
+        } else if !line_trimmed.is_empty() {
+            println!("{line}");
+        }
+    }
+    if let Some(max) = max_output && size > max {
+        return err!(SpanMap::span(spans.option("max_output")),
+            "Macro '{name}' generated {} of code, which exceeds the limit of {}. You can change \
+            the limit with the 'max_output' option or the '{MAX_OUTPUT_ENV}' environment variable.",
+            format_size(size), format_size(max)
+        )
+    }
+    if size > max_output_warn {
+        print_warning!(
+            "Macro '{name}' generated {} of code, which exceeds the warning threshold of {}.",
+            format_size(size), format_size(max_output_warn)
+        );
+    }
+    Ok(ParsedOutput { code, reusable, notices })
+}
+
+#[derive(Clone, Debug)]
+struct MacroOptions {
+    pub cache: bool,
+    pub content_base_name: bool,
+    /// Name of the project directory, replacing the one derived from the call site, so that macros
+    /// can share or isolate a cached project deliberately.
+    pub cache_key: Option<String>,
+    /// Module path of the macro, like `my_crate::my_module`, locating its project directory instead
+    /// of the call site. It enables caching on stable, where the call site is not known.
+    pub module: Option<String>,
+    /// Whether to reuse the output of the last run of the cached project if its code did not
+    /// change, instead of running it again. Only for macros not reading files or env variables.
+    pub memoize: bool,
+    /// Directory of the generated projects, overriding `CRABTIME_OUT_DIR` and the directory derived
+    /// from `OUT_DIR`. Relative paths are resolved against the crate directory.
+    pub out_dir: Option<String>,
+    /// Maximum size of the generated code in bytes. Exceeding it is an error.
+    pub max_output: Option<usize>,
+    /// Size of the generated code in bytes above which a warning is emitted.
+    pub max_output_warn: Option<usize>,
+    /// Whether to add a doc attribute naming the source macro to generated items.
+    pub provenance_doc: bool,
+    /// Whether to add a constant naming the source macro and its call site to generated items.
+    pub provenance_const: bool,
+    /// Maximum number of parallel jobs of the nested cargo invocation.
+    pub jobs: Option<usize>,
+    /// Number of retries of a nested build which failed with a transient error.
+    pub retries: usize,
+    /// Maximum time of building and running the macro in seconds. Exceeding it is an error, and
+    /// `0` disables the limit.
+    pub timeout_secs: Option<u64>,
+    /// Whether to print the stderr of a successful run of the macro, like its logs.
+    pub forward_stderr: bool,
+    /// Workarounds for IDEs providing incorrect token spans.
+    pub ide_compat: IdeCompat,
+    /// Whether to replace expansions identical to one emitted earlier in the build.
+    pub dedup: bool,
+    /// Module re-exported instead of a deduplicated expansion, like `crate::generated`.
+    pub dedup_path: Option<String>,
+    /// Unknown options, collected instead of rejected if the `lenient` marker is present, so that
+    /// wrapper macros can forward their own options to the macro body.
+    pub passthrough: Vec<(String, TokenStream)>,
+    /// Whether to copy the workspace lockfile to the project.
+    pub lockfile: bool,
+    /// Cargo profile of the generated project, overridden by the `#![profile(...)]` attribute.
+    pub profile: Profile,
+    /// Whether to skip the manifest discovery and build a project without dependencies, taking
+    /// every fast path available.
+    pub minimal: bool,
+}
+
+impl MacroOptions {
+    /// The `max_output` option, defaulting to the `CRABTIME_MAX_OUTPUT` environment variable.
+    fn max_output(&self) -> Result<Option<usize>> {
+        if self.max_output.is_some() {
+            return Ok(self.max_output)
+        }
+        let Some(value) = inputs::var(MAX_OUTPUT_ENV) else { return Ok(None) };
+        let max_output = value.trim().parse::<usize>().context(|| error!(
+            "Invalid value '{value}' of the '{MAX_OUTPUT_ENV}' environment variable. Expected \
+            a number of bytes."
+        ))?;
+        Ok(Some(max_output))
+    }
+
+    /// The `jobs` option, defaulting to the `CRABTIME_JOBS` environment variable and then to the
+    /// `CARGO_BUILD_JOBS` one. Values of the latter which are not positive numbers, like `default`,
+    /// are left to the nested cargo, which inherits the variable.
+    fn jobs(&self) -> Result<Option<usize>> {
+        if self.jobs.is_some() {
+            return Ok(self.jobs)
+        }
+        if let Some(value) = inputs::var(JOBS_ENV) {
+            let jobs = value.trim().parse::<usize>().ok().filter(|jobs| *jobs >= 1);
+            let jobs = jobs.context(|| error!(
+                "Invalid value '{value}' of the '{JOBS_ENV}' environment variable. Expected \
+                a number of jobs of at least 1."
+            ))?;
+            return Ok(Some(jobs))
+        }
+        let jobs = inputs::var(CARGO_JOBS_ENV).and_then(|t| t.trim().parse::<usize>().ok());
+        Ok(jobs.filter(|jobs| *jobs >= 1))
+    }
+
+    /// The `timeout_secs` option, defaulting to the `CRABTIME_TIMEOUT_SECS` environment variable.
+    /// A timeout of zero seconds means no timeout.
+    fn timeout(&self) -> Result<Option<std::time::Duration>> {
+        let secs = match self.timeout_secs {
+            Some(secs) => secs,
+            None => {
+                let Some(value) = inputs::var(TIMEOUT_ENV) else { return Ok(None) };
+                value.trim().parse::<u64>().context(|| error!(
+                    "Invalid value '{value}' of the '{TIMEOUT_ENV}' environment variable. Expected \
+                    a number of seconds."
+                ))?
+            }
+        };
+        Ok((secs > 0).then(|| std::time::Duration::from_secs(secs)))
+    }
+}
+
+impl Default for MacroOptions {
+    fn default() -> Self {
+        Self {
+            cache: true,
+            content_base_name: false,
+            cache_key: None,
+            module: None,
+            memoize: false,
+            out_dir: None,
+            max_output: None,
+            max_output_warn: None,
+            provenance_doc: true,
+            provenance_const: false,
+            jobs: None,
+            retries: DEFAULT_RETRIES,
+            timeout_secs: None,
+            forward_stderr: true,
+            ide_compat: IdeCompat::Auto,
+            dedup: false,
+            dedup_path: None,
+            passthrough: vec![],
+            lockfile: true,
+            profile: Profile::Debug,
+            minimal: false,
+        }
+    }
+}
+
+impl syn::parse::Parse for MacroOptions {
+    fn parse(input: syn::parse::ParseStream) -> Result<Self, syn::Error> {
+        Self::parse_with_spans(input, &mut SpanMap::default())
+    }
+}
+
+impl MacroOptions {
+    /// Checks whether the options contain the bare `lenient` marker, which can be placed anywhere
+    /// in the list, e.g. after the options of a wrapper macro.
+    fn has_lenient_marker(tokens: TokenStream) -> bool {
+        let tokens = tokens.into_iter().collect::<Vec<_>>();
+        let is_comma = |token: Option<&TokenTree>|
+            matches!(token, Some(TokenTree::Punct(punct)) if punct.as_char() == ',');
+        tokens.iter().enumerate().any(|(i, token)| {
+            matches!(token, TokenTree::Ident(ident) if ident == "lenient")
+                && (i == 0 || is_comma(tokens.get(i - 1)))
+                && (i + 1 == tokens.len() || is_comma(tokens.get(i + 1)))
+        })
+    }
+
+    /// The boolean option of the given name.
+    fn flag_mut(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "cache" => Some(&mut self.cache),
+            "memoize" => Some(&mut self.memoize),
+            "content_base_name" => Some(&mut self.content_base_name),
+            "provenance_doc" => Some(&mut self.provenance_doc),
+            "provenance_const" => Some(&mut self.provenance_const),
+            "lockfile" => Some(&mut self.lockfile),
+            "minimal" => Some(&mut self.minimal),
+            "dedup" => Some(&mut self.dedup),
+            "forward_stderr" => Some(&mut self.forward_stderr),
+            _ => None,
+        }
+    }
+
+    /// Parses the options, recording the ranges of their values in the span map.
+    fn parse_with_spans(
+        input: syn::parse::ParseStream,
+        spans: &mut SpanMap,
+    ) -> Result<Self, syn::Error> {
+        let mut options = MacroOptions::default();
+        let lenient = Self::has_lenient_marker(input.fork().parse::<TokenStream>()?);
+        while !input.is_empty() {
+            let ident: syn::Ident = input.parse()?;
+            if ident == "lenient" && !input.peek(syn::Token![=]) {
+                if input.peek(syn::Token![,]) {
+                    let _comma: syn::Token![,] = input.parse()?;
+                }
+                continue
+            }
+            let _eq_token: syn::Token![=] = input.parse()?;
+            if let Ok(value) = input.fork().parse::<TokenTree>() {
+                spans.options.push((ident.to_string(), TokenRange::new(value.clone(), value)));
+            }
+            if let Some(flag) = options.flag_mut(&ident.to_string()) {
+                let bool_lit: syn::LitBool = input.parse()?;
+                *flag = bool_lit.value;
+            } else if ident == "out_dir" {
+                options.out_dir = Some(parse_out_dir(input)?);
+            } else if ident == "cache_key" {
+                options.cache_key = Some(parse_cache_key(input)?);
+            } else if ident == "module" {
+                let path = if input.peek(syn::LitStr) {
+                    input.parse::<syn::LitStr>()?.parse::<syn::Path>()?
+                } else {
+                    input.parse::<syn::Path>()?
+                };
+                options.module = Some(parse_module_path(&path)?);
+            } else if ident == "max_output" {
+                let int_lit: syn::LitInt = input.parse()?;
+                options.max_output = Some(int_lit.base10_parse()?);
+            } else if ident == "max_output_warn" {
+                let int_lit: syn::LitInt = input.parse()?;
+                options.max_output_warn = Some(int_lit.base10_parse()?);
+            } else if ident == "jobs" {
+                let int_lit: syn::LitInt = input.parse()?;
+                let jobs = int_lit.base10_parse()?;
+                if jobs == 0 {
+                    return Err(syn::Error::new(int_lit.span(), "the number of jobs must be at least 1"));
+                }
+                options.jobs = Some(jobs);
+            } else if ident == "retries" {
+                let int_lit: syn::LitInt = input.parse()?;
+                options.retries = int_lit.base10_parse()?;
+            } else if ident == "timeout_secs" {
+                let int_lit: syn::LitInt = input.parse()?;
+                options.timeout_secs = Some(int_lit.base10_parse()?);
+            } else if ident == "ide_compat" {
+                let str_lit: syn::LitStr = input.parse()?;
+                options.ide_compat = match str_lit.value().as_str() {
+                    "auto" => IdeCompat::Auto,
+                    "intellij" => IdeCompat::IntelliJ,
+                    "none" => IdeCompat::None,
+                    _ => return Err(syn::Error::new(
+                        str_lit.span(), "expected \"auto\", \"intellij\", or \"none\""
+                    )),
+                };
+            } else if ident == "profile" {
+                let str_lit: syn::LitStr = input.parse()?;
+                options.profile = Profile::from_name(&str_lit.value()).ok_or_else(|| {
+                    let expected = Profile::NAMES.iter().map(|t| format!("\"{t}\""));
+                    let expected = expected.collect::<Vec<_>>().join(" or ");
+                    syn::Error::new(str_lit.span(), format!("expected {expected}"))
+                })?;
+            } else if ident == "dedup_path" {
+                let str_lit: syn::LitStr = input.parse()?;
+                let path = str_lit.parse::<syn::Path>()?;
+                options.dedup_path = Some(quote!{#path}.to_string().replace(" ", ""));
+            } else if lenient {
+                let mut value = TokenStream::new();
+                while !input.is_empty() && !input.peek(syn::Token![,]) {
+                    value.extend([input.parse::<TokenTree>()?]);
+                }
+                if value.is_empty() {
+                    return Err(syn::Error::new(ident.span(), "expected a value"));
+                }
+                options.passthrough.push((ident.to_string(), value));
+            } else {
+                return Err(syn::Error::new(ident.span(), "unknown attribute"));
+            }
+            if input.peek(syn::Token![,]) {
+                let _comma: syn::Token![,] = input.parse()?;
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Value of the `cache_key` option, an identifier or a string literal usable as a directory name.
+fn parse_cache_key(input: syn::parse::ParseStream) -> Result<String, syn::Error> {
+    let (key, span) = if input.peek(syn::LitStr) {
+        let str_lit: syn::LitStr = input.parse()?;
+        (str_lit.value(), str_lit.span())
+    } else {
+        let key_ident: syn::Ident = input.parse()?;
+        (key_ident.to_string(), key_ident.span())
+    };
+    let valid_char = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+    if key.is_empty() || !key.chars().all(valid_char) {
+        return Err(syn::Error::new(span,
+            "the cache key can contain only ASCII letters, digits, '_', and '-'"
+        ));
+    }
+    Ok(key)
+}
+
+/// Value of the `out_dir` option, a non-empty string literal.
+fn parse_out_dir(input: syn::parse::ParseStream) -> Result<String, syn::Error> {
+    let str_lit: syn::LitStr = input.parse()?;
+    if str_lit.value().trim().is_empty() {
+        return Err(syn::Error::new(str_lit.span(), "expected a directory path"));
+    }
+    Ok(str_lit.value())
+}
+
+/// Module path given to the `module` option or attribute, with the segments separated by `::`.
+fn parse_module_path(path: &syn::Path) -> Result<String, syn::Error> {
+    let invalid = path.leading_colon.is_some()
+        || path.segments.iter().any(|t| !t.arguments.is_none());
+    if invalid {
+        return Err(syn::Error::new_spanned(path, "expected a module path, like 'my_crate::gen'"));
+    }
+    Ok(path.segments.iter().map(|t| t.ident.to_string()).collect::<Vec<_>>().join("::"))
+}
+
+/// Removes the `#[module(...)]` attribute of the macro function, returning its module path.
+fn extract_module_attr(attrs: &mut Vec<syn::Attribute>) -> Result<Option<syn::Path>> {
+    let Some(index) = attrs.iter().position(|attr| attr.path().is_ident(MODULE_ATTR)) else {
+        return Ok(None)
+    };
+    let path = attrs.remove(index).parse_args::<syn::Path>()?;
+    parse_module_path(&path)?;
+    Ok(Some(path))
+}
+
+// =====================
+// === Eval Function ===
+// =====================
+
+#[proc_macro_attribute]
+pub fn eval_function(
+    attr: proc_macro::TokenStream,
+    item: proc_macro::TokenStream
+) -> proc_macro::TokenStream {
+    // SAFETY: Used to panic in case of error.
+    #[allow(clippy::unwrap_used)]
+    eval_function_impl(attr, item).unwrap_or_compile_error().into()
+}
+
+
+/// The content identifying the project of the expansion. The arguments are a part of the generated
+/// code, even if the body does not use them, and so are the attributes, like the dependencies.
+fn project_input(input_str: &str, call_args: &[String], attrs: &[syn::Attribute]) -> String {
+    format!("{input_str}{call_args:?}{}", quote! { #(#attrs)* })
+}
+
+fn eval_function_impl(
+    attr: proc_macro::TokenStream,
+    item: proc_macro::TokenStream
+) -> Result<TokenStream> {
+    inputs::begin();
+    let mut spans = SpanMap::default();
+    let options = syn::parse::Parser::parse(
+        |input: syn::parse::ParseStream| MacroOptions::parse_with_spans(input, &mut spans),
+        attr
+    )?;
+    let start_time = get_current_time();
+    let timer = std::time::Instant::now();
+
+    let mut input_fn_ast = syn::parse::<syn::ItemFn>(item)?;
+    let call_args = extract_call_args(&mut input_fn_ast.attrs);
+    let name = &input_fn_ast.sig.ident.to_string();
+    let body_ast = &input_fn_ast.block.stmts;
+    let output_tp = &input_fn_ast.sig.output;
+    let ide_compat = options.ide_compat;
+    let body = expand_output_macro_rules_macro(quote!{ #(#body_ast)* }, ide_compat);
+    let body = expand_stringify_if_needed_macro(body);
+    let body = expand_output_cfg_macro(body, ide_compat);
+    let input_str = expand_output_macro(expand_quote_macro(body, ide_compat), ide_compat)
+        .to_string();
+    let project_input = project_input(&input_str, &call_args, &input_fn_ast.attrs);
+    let paths = Paths::new(&options, name, &project_input)?;
+    let build_id = begin_build_tracking();
+
+    let minimal = options.minimal;
+    let mut cfg = CargoConfig {
+        package_name: Some(sanitize_package_name(name)),
+        profile: options.profile,
+        minimal,
+        ..CargoConfig::default()
+    };
+    if let Some(path) = &paths.cargo_toml_path {
+        cfg.fill_from_cargo_toml(path)?;
+    }
+    if options.lockfile && !minimal {
+        cfg.lockfile = paths.lockfile();
+    }
+    // Subprojects share a target directory, so their binaries need distinct names.
+    if let Some(subproject) = paths.subproject() {
+        cfg.bin_name = Some(format!("{}_{subproject}", cfg.package_name()));
+    }
+    let fn_tokens = quote! { #output_tp #(#body_ast)* };
+    let attributes = cfg.extract_inline_attributes(input_fn_ast.attrs, &mut spans)?;
+    if let Some(dependency) = cfg.dependencies.first().filter(|_| minimal) {
+        return err!(dependency.span(),
+            "Macros with the 'minimal' option cannot have dependencies. Remove the option to use \
+            '{}'.", dependency.label
+        )
+    }
+    cfg.resolve_dependency_paths(paths.manifest_dir());
+    cfg.resolve_registries(paths.manifest_dir())?;
+    for dependency in cfg.legacy_proc_macro2_dependencies() {
+        print_warning!(dependency.span(),
+            "Dependency '{}' uses 'proc-macro2' older than 1.0, so returning its 'TokenStream' from \
+            the macro is not supported.", dependency.label
+        );
+    }
+    let crates = cfg.prelude_crates(&fn_tokens);
+    let output_tp_str = match output_tp {
+        syn::ReturnType::Default => "()".to_string(),
+        syn::ReturnType::Type(_, tp) => quote!{#tp}.to_string(),
+    };
+    let input_code = prepare_input_code(
+        &attributes,
+        &input_str,
+        &output_tp_str,
+        &crates,
+        &paths,
+        &options.passthrough,
+        &call_args,
+    );
+    debug!("INPUT CODE: {input_code}");
+    let expansion = Expansion {
+        name,
+        options: &options,
+        spans: &spans,
+        paths: &paths,
+        cfg: &cfg,
+        input_str: &input_str,
+        input_code: &input_code,
+        fingerprint: inputs::hash((cfg.print(), cfg.profile, &input_code)),
+        run: RunSettings {
+            name,
+            build_id,
+            jobs: options.jobs()?,
+            retries: options.retries,
+            timeout: options.timeout()?,
+            forward_stderr: options.forward_stderr,
+        },
+        start_time,
+        timer,
+        setup_duration: format_duration(timer.elapsed()),
+    };
+
+    let acquired = expansion.acquire_output(&project_input)?;
+    let parsed_output = parse_output(name, &acquired.output, &options, &spans)?;
+    emit_notices(name, &parsed_output.notices, build_id);
+    let cache_verification = expansion.verify_cache(acquired.project_state, &parsed_output)?;
+    let reusable = parsed_output.reusable;
+    let output = expansion.process_output(parsed_output.code)?;
+    end_build_tracking(build_id, name, timer.elapsed(), !acquired.project_state.existed());
+    let inputs = expansion.write_inputs();
+    let stats = expansion.print_stats(&acquired, &output, reusable, cache_verification, &inputs)?;
+    let output_tokens = output.tokens;
+    let out = quote! { #stats #output_tokens };
+    // Make sure that the whole output points to the macro call site, so that IDEs and error
+    // messages refer to the call site instead of a random location.
+    #[cfg(nightly)]
+    let out = respan(out, Span::call_site());
+    debug!("OUTPUT: {out} ");
+    Ok(out)
+}
+
+// =================
+// === Expansion ===
+// =================
+
+/// State of a macro expansion shared by the steps of [`eval_function_impl`].
+struct Expansion<'t> {
+    name: &'t str,
+    options: &'t MacroOptions,
+    spans: &'t SpanMap,
+    paths: &'t Paths,
+    cfg: &'t CargoConfig,
+    /// The macro body, with the Crabtime macros expanded.
+    input_str: &'t str,
+    /// Code of the generated project.
+    input_code: &'t str,
+    /// Hash of the project configuration and code, identifying the build owning the project.
+    fingerprint: u64,
+    run: RunSettings<'t>,
+    start_time: String,
+    timer: std::time::Instant,
+    setup_duration: String,
+}
+
+/// Output of the generated program, with the descriptions of how it was acquired shown in the
+/// compilation stats.
+struct AcquiredOutput {
+    output: String,
+    project_state: ProjectState,
+    /// Directory of the project, empty if the output was read from a snapshot.
+    output_dir: String,
+    memoization: &'static str,
+    snapshot: String,
+}
+
+/// The generated code after dedup and provenance.
+struct ProcessedOutput {
+    tokens: TokenStream,
+    /// Size of the code after dedup, in bytes.
+    size: usize,
+    /// Description of the dedup shown in the compilation stats.
+    dedup: String,
+}
+
+impl Expansion<'_> {
+    /// Reads the output from a snapshot or the memoized output if available, or builds and runs
+    /// the project otherwise.
+    fn acquire_output(&self, project_input: &str) -> Result<AcquiredOutput> {
+        let paths = self.paths;
+        let target_dir = paths.shared_target_dir();
+        let memo_header = self.options.memoize.then(|| memo_header(self.cfg, self.input_code));
+        let snapshot_mode = snapshot_mode();
+        let snapshot_hash = Paths::input_hash(project_input);
+        let mut output_dir_str = String::new();
+        let (output, project_state, memoization) =
+            match snapshot_mode.read(self.name, &snapshot_hash)? {
+                Some(output) => (output, ProjectState::Snapshot, "off"),
+                None => paths.with_output_dir(|output_dir| {
+                    debug!("OUTPUT_DIR: {:?}", output_dir);
+                    output_dir_str = output_dir.to_string_lossy().to_string();
+                    if !paths.one_shot_output_dir && let Some(build_id) = self.run.build_id {
+                        claim_project_dir(output_dir, build_id, self.fingerprint)?;
+                    }
+                    let project_state =
+                        create_project_skeleton(output_dir, self.cfg, self.input_code)?;
+                    let memo_header = memo_header.as_deref();
+                    if let Some(header) = memo_header
+                    && let Some(output) = read_memoized_output(output_dir, header) {
+                        return Ok((output, project_state, "on, reused"))
+                    }
+                    let output =
+                        run_cargo_project(output_dir, target_dir.as_deref(), self.cfg, &self.run)?;
+                    let memoization = match memo_header {
+                        None => "off",
+                        Some(_) if paths.one_shot_output_dir =>
+                            "on, skipped for a one-shot project",
+                        Some(header) => memoize_output(output_dir, header, &output)?,
+                    };
+                    Ok((output, project_state, memoization))
+                })?,
+            };
+        let snapshot = match project_state {
+            ProjectState::Snapshot => "read".to_string(),
+            _ => snapshot_mode.write(self.name, &snapshot_hash, &output)?,
+        };
+        let output_dir = output_dir_str;
+        Ok(AcquiredOutput { output, project_state, output_dir, memoization, snapshot })
+    }
+
+    /// Verifies the output of a cached project against a fresh build if `CRABTIME_VERIFY_CACHE`
+    /// is set. Returns the result description shown in the compilation stats.
+    fn verify_cache(
+        &self,
+        project_state: ProjectState,
+        parsed_output: &ParsedOutput,
+    ) -> Result<&'static str> {
+        if !verify_cache_enabled() {
+            Ok("off")
+        } else if project_state == ProjectState::Snapshot {
+            Ok("on, skipped for a snapshot")
+        } else if !project_state.existed() {
+            Ok("on, not needed for a fresh build")
+        } else {
+            let fresh = VerifyCache { cfg: self.cfg, input_code: self.input_code, run: self.run };
+            fresh.verify(parsed_output, self.options, self.spans)
+        }
+    }
+
+    /// Dedups the generated code, parses it, and adds the provenance of the items. Identifiers
+    /// which are keywords in the output edition are reported.
+    fn process_output(&self, code: String) -> Result<ProcessedOutput> {
+        let name = self.name;
+        let options = self.options;
+        let (code, dedup) = if options.dedup {
+            let dedup_path = options.dedup_path.as_deref();
+            dedup_output(name, self.input_str, code, self.run.build_id, dedup_path)?
+        } else {
+            (code, "off".to_string())
+        };
+        debug!("BODY: {code}");
+        let mut tokens: TokenStream = code.parse()
+            .map_err(|err| error!("{err:?}"))
+            .context("Failed to parse generated code.")?;
+        if options.provenance_doc {
+            tokens = add_provenance_docs(name, tokens);
+        }
+        if options.provenance_const {
+            tokens = add_provenance_const(name, tokens);
+        }
+        if let Some(edition) = &self.cfg.output_edition {
+            for ident in edition_keyword_identifiers(edition, &tokens) {
+                print_warning!(SpanMap::span(self.spans.attribute("output_edition")),
+                    "Identifier '{ident}' generated by macro '{name}' is a keyword in edition \
+                    {edition}, the output edition. Use the raw identifier 'r#{ident}' instead."
+                );
+            }
+        }
+        Ok(ProcessedOutput { tokens, size: code.len(), dedup })
+    }
+
+    /// Finishes recording the inputs of the expansion and writes them to the metadata file.
+    fn write_inputs(&self) -> inputs::Inputs {
+        let inputs = inputs::finish(self.fingerprint);
+        let path = Paths::metadata_file(self.name, self.input_str);
+        if let Err(err) = inputs.write(self.name, &path) {
+            print_warning!("Failed to write the expansion metadata: {}", err.message_with_cause());
+        }
+        inputs
+    }
+
+    /// The compilation stats, as doc comments of an item which is compiled out, so that they are
+    /// visible in `cargo expand` and IDE previews.
+    fn print_stats(
+        &self,
+        acquired: &AcquiredOutput,
+        output: &ProcessedOutput,
+        reusable: bool,
+        cache_verification: &str,
+        inputs: &inputs::Inputs,
+    ) -> Result<TokenStream> {
+        let paths = self.paths;
+        let start_time = &self.start_time;
+        let duration = format_duration(self.timer.elapsed());
+        let setup_duration = &self.setup_duration;
+        let cached = acquired.project_state.stats();
+        let project_kind = paths.project_kind();
+        let subproject = match (paths.subproject(), paths.shared_target_dir()) {
+            (Some(subproject), Some(target_dir)) =>
+                format!("{subproject}, sharing target '{}'", target_dir.display()),
+            _ => "none".to_string(),
+        };
+        let output_size = format_size(output.size);
+        let jobs = self.run.jobs.map_or_else(|| "default".to_string(), |t| t.to_string());
+        let profile = self.cfg.profile.name();
+        let memoization = acquired.memoization;
+        let dedup = &output.dedup;
+        let snapshot = &acquired.snapshot;
+        let inputs_stats = format!("{} files, {} env vars", inputs.files.len(), inputs.env.len());
+        let output_dir = &acquired.output_dir;
+        let output_root = paths.output_root_stats();
+        let options_doc = format!("{:#?}", self.options).replace("\n", "\n/// ");
+        let stats_code = format!("
+            /// # Compilation Stats
+            /// Start: {start_time}
+            /// Duration: {duration}
+            /// Setup: {setup_duration}
+            /// Cached: {cached}
+            /// Project: {project_kind}
+            /// Subproject: {subproject}
+            /// Output Size: {output_size}
+            /// Reusable Output: {reusable}
+            /// Jobs: {jobs}
+            /// Profile: {profile}
+            /// Memoization: {memoization}
+            /// Cache Verification: {cache_verification}
+            /// Dedup: {dedup}
+            /// Snapshot: {snapshot}
+            /// Inputs: {inputs_stats}
+            /// Output Dir: {output_dir}
+            /// Output Root: {output_root}
+            /// Macro Options: {options_doc}
+            #[cfg(any())]
+            const _: () = ();
+        ");
+        debug!("STATS: {stats_code}");
+        stats_code.parse()
+            .map_err(|err| error!("{err:?}"))
+            .context("Failed to parse compilation stats.")
+    }
+}
+
+// =============
+// === Dedup ===
+// =============
+
+/// Replaces the output with a marker if the same macro generated the same code from the same input
+/// at another call site earlier in the build, so identical items are not defined twice. The items
+/// are re-exported from `dedup_path` if given. The first call site is recorded in a file shared by
+/// all expansions. Returns the output and its description shown in the compilation stats.
+///
+/// The location of the call site is not available, as the expansion is invoked by the generated
+/// `macro_rules!`, so call sites are identified by the crate being compiled and the occurrence of
+/// the expansion in the compilation. Expansions follow the source order, and a crate can be
+/// compiled several times in a build, e.g. as a library and as its tests, so the first call site
+/// keeps emitting the code in every compilation.
+fn dedup_output(
+    name: &str,
+    input_str: &str,
+    output_code: String,
+    build_id: Option<u128>,
+    dedup_path: Option<&str>,
+) -> Result<(String, String)> {
+    let Some(names) = item_names(&output_code) else {
+        return Ok((output_code, "on, skipped for an output which is not a list of items".into()))
+    };
+    let Some(build_id) = build_id else {
+        return Ok((output_code, "on, skipped as the build is not tracked".into()))
+    };
+    // The IDE expands call sites in any order, so the IDE view shows all items instead.
+    if is_rust_analyzer()? {
+        return Ok((output_code, "on, skipped in Rust Analyzer".into()))
+    }
+    // The key is stored in the dedup file, so it uses a hash which does not change between Rust
+    // releases. The lengths keep the parts apart.
+    let key_input = format!("{}:{name}{}:{input_str}{output_code}", name.len(), input_str.len());
+    let key = inputs::stable_hash(key_input.as_bytes()) as u64;
+    let krate = Paths::crate_name().unwrap_or_default();
+    let call_site = format!("occurrence {} in crate '{krate}'", dedup::occurrence(key));
+    let path = Paths::dedup_file();
+    let Some(first) = dedup::claim(&path, build_id, key, &call_site)? else {
+        return Ok((output_code, "on, first expansion".into()))
+    };
+    let doc = format!(
+        " Deduplicated: macro '{name}' generated the same code earlier in this build, at its \
+        {first}."
+    );
+    let reexport = match dedup_path {
+        Some(path) if !names.is_empty() => format!("pub use {path}::{{{}}};", names.join(", ")),
+        _ => String::new(),
+    };
+    let code = format!("#[doc = {doc:?}] #[cfg(any())] const _: () = (); {reexport}");
+    Ok((code, format!("on, duplicate of {first}")))
+}
+
+/// Reports the notices of the macro as warnings, each at most once per build, no matter how many
+/// times and in how many crates the macro is expanded. Notices are claimed in the dedup file, like
+/// deduplicated outputs. If the build is not tracked or the file can't be updated, they are
+/// reported on every expansion.
+fn emit_notices(name: &str, notices: &[String], build_id: Option<u128>) {
+    for notice in notices {
+        let is_first = build_id.is_none_or(|build_id| {
+            claim_notice(&Paths::dedup_file(), build_id, name, notice)
+                .unwrap_or_else(|err| {
+                    debug!("Failed to claim the notice: {}", err.message_with_cause());
+                    true
+                })
+        });
+        if is_first {
+            print_warning!("{notice}");
+        }
+    }
+}
+
+/// Claims the notice of the macro in the build. Returns whether it was not reported yet. Unlike
+/// deduplicated outputs, which a crate emits in every compilation, notices are claimed per
+/// expansion, so re-compiling the crate in the same build, e.g. as its tests, does not repeat them.
+fn claim_notice(path: &Path, build_id: u128, name: &str, notice: &str) -> Result<bool> {
+    let key = inputs::hash(("notice", name, notice));
+    let krate = Paths::crate_name().unwrap_or_default();
+    let call_site = format!(
+        "occurrence {} in crate '{krate}' in process {}", dedup::occurrence(key), std::process::id()
+    );
+    Ok(dedup::claim(path, build_id, key, &call_site)?.is_none())
+}
+
+/// Names of the items defined by the code, or `None` if the code is not a list of items. Items
+/// which can't be re-exported with `pub use`, like implementations and macros, are skipped.
+fn item_names(code: &str) -> Option<Vec<String>> {
+    let file = syn::parse_str::<syn::File>(code).ok()?;
+    let names = file.items.iter().filter_map(|item| match item {
+        syn::Item::Const(t) => Some(&t.ident),
+        syn::Item::Enum(t) => Some(&t.ident),
+        syn::Item::Fn(t) => Some(&t.sig.ident),
+        syn::Item::Mod(t) => Some(&t.ident),
+        syn::Item::Static(t) => Some(&t.ident),
+        syn::Item::Struct(t) => Some(&t.ident),
+        syn::Item::Trait(t) => Some(&t.ident),
+        syn::Item::TraitAlias(t) => Some(&t.ident),
+        syn::Item::Type(t) => Some(&t.ident),
+        syn::Item::Union(t) => Some(&t.ident),
+        _ => None,
+    });
+    Some(names.map(|t| t.to_string()).filter(|t| t != "_").collect())
+}
+
+// ====================
+// === Verify Cache ===
+// ====================
+
+fn verify_cache_enabled() -> bool {
+    inputs::var(VERIFY_CACHE_ENV).is_some_and(|t| t == "1" || t == "true")
+}
+
+/// Audit of the output of a cached project, enabled with `CRABTIME_VERIFY_CACHE`. The project is
+/// built again from scratch in a one-shot directory, and a warning is emitted if the outputs
+/// differ, which means that the macro is nondeterministic or that the cache key misses an input.
+struct VerifyCache<'t> {
+    cfg: &'t CargoConfig,
+    input_code: &'t str,
+    run: RunSettings<'t>,
+}
+
+impl VerifyCache<'_> {
+    /// Returns the result description shown in the compilation stats.
+    fn verify(
+        &self,
+        cached: &ParsedOutput,
+        options: &MacroOptions,
+        spans: &SpanMap,
+    ) -> Result<&'static str> {
+        if !cached.reusable {
+            return Ok("on, skipped for a non-reusable output")
+        }
+        let dir = Paths::verify_cache_dir();
+        let lock = lock_project_dir(&dir)?;
+        let output = create_project_skeleton(&dir, self.cfg, self.input_code)
+            .and_then(|_| run_cargo_project(&dir, None, self.cfg, &self.run));
+        remove_project_dir_if_unused(&dir, lock);
+        let fresh = parse_output(self.run.name, &output?, options, spans)?;
+        match diff::unified_diff(&cached.code, &fresh.code, VERIFY_CACHE_DIFF_LINES) {
+            None => Ok("on, matched a fresh build"),
+            Some(diff) => {
+                print_warning!(
+                    "{VERIFY_CACHE_ENV}: The output of macro '{}' built in the cached project \
+                    differs from the output of a fresh build. The macro is nondeterministic, or its \
+                    cache key does not cover all of its inputs.\n{diff}", self.run.name
+                );
+                Ok("on, DIFFERED from a fresh build")
+            }
+        }
+    }
+}
+
+/// What the expansion does with the snapshots of its output. Builds on docs.rs, and the ones with
+/// `CARGO_NET_OFFLINE` set, have no network access, so they read the snapshots if available.
+fn snapshot_mode() -> snapshot::Mode {
+    let crate_dir = inputs::var("CARGO_MANIFEST_DIR").map(PathBuf::from).unwrap_or_default();
+    let offline = inputs::var("CARGO_NET_OFFLINE").is_some_and(|t| t == "true")
+        || inputs::var("DOCS_RS").is_some();
+    snapshot::Mode::new(
+        &crate_dir, inputs::var(snapshot::DIR_ENV), inputs::var(snapshot::USE_ENV), offline
+    )
+}
+
+/// Records the expansion start in the shared stats file and returns the id of the current build.
+/// If this is the first expansion of a new build, the summary of the previous build is reported.
+/// Build tracking is best-effort, so failures are ignored instead of failing the expansion.
+fn begin_build_tracking() -> Option<u128> {
+    let started = summary::begin_expansion(&Paths::build_stats_file(), summary::now());
+    match started {
+        Ok((build_id, summary)) => {
+            if let Some(summary) = summary {
+                print_note!("{summary}");
+            }
+            Some(build_id)
+        }
+        Err(err) => {
+            debug!("Failed to record build stats: {}", err.message_with_cause());
+            None
+        }
+    }
+}
+
+/// Records the stats of the finished expansion in the shared stats file.
+fn end_build_tracking(build_id: Option<u128>, name: &str, duration: std::time::Duration, rebuilt: bool) {
+    let Some(build_id) = build_id else { return };
+    let name = name.to_string();
+    let expansion = summary::Expansion { name, duration, rebuilt };
+    let result =
+        summary::end_expansion(&Paths::build_stats_file(), build_id, expansion, summary::now());
+    if let Err(err) = result {
+        debug!("Failed to record build stats: {}", err.message_with_cause());
+    }
+}
+
+// ======================
+// === Output Edition ===
+// ======================
+
+/// Identifiers of the output which are keywords in the given edition, like `gen` in edition 2024.
+/// Keywords used as such, like `dyn` in `Box<dyn Trait>`, are skipped by looking at the
+/// neighbouring tokens, so only identifiers in obvious positions are reported: names of items,
+/// variables, fields, and path segments.
+fn edition_keyword_identifiers(edition: &str, tokens: &TokenStream) -> Vec<String> {
+    fn collect(edition: &str, tokens: &TokenStream, out: &mut Vec<String>) {
+        let tokens = tokens.clone().into_iter().collect::<Vec<_>>();
+        let is_punct = |i: Option<usize>, c: char| matches!(i.and_then(|i| tokens.get(i)),
+            Some(TokenTree::Punct(p)) if p.as_char() == c);
+        for (i, token) in tokens.iter().enumerate() {
+            match token {
+                TokenTree::Group(group) => collect(edition, &group.stream(), out),
+                TokenTree::Ident(ident) => {
+                    let ident = ident.to_string();
+                    let reserved = EDITION_KEYWORDS.iter()
+                        .any(|(keyword, since)| *keyword == ident && *since <= edition);
+                    if !reserved { continue }
+                    let prev = i.checked_sub(1);
+                    let after_definition = matches!(prev.and_then(|i| tokens.get(i)),
+                        Some(TokenTree::Ident(prev)) if [
+                            "fn", "struct", "enum", "union", "trait", "type", "mod", "const",
+                            "static", "let", "mut", "ref"
+                        ].iter().any(|t| prev == t));
+                    let after_dot = is_punct(prev, '.') && ident != "await";
+                    let around_colon = is_punct(prev, ':') || is_punct(Some(i + 1), ':');
+                    if (after_definition || after_dot || around_colon) && !out.contains(&ident) {
+                        out.push(ident);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    let mut out = Vec::new();
+    collect(edition, tokens, &mut out);
+    out
+}
+
+// ==================
+// === Provenance ===
+// ==================
+
+/// Adds a doc attribute naming the macro which generated each top-level item, so that IDE hovers
+/// identify the source macro. Outputs which are not lists of items (e.g. expressions) are returned
+/// unchanged.
+fn add_provenance_docs(macro_name: &str, tokens: TokenStream) -> TokenStream {
+    let Ok(mut file) = syn::parse2::<syn::File>(tokens.clone()) else { return tokens };
+    let location = call_site_location().map(|t| format!(" at `{t}`")).unwrap_or_default();
+    let doc = format!("Generated by crabtime macro `{macro_name}`{location}.");
+    for item in &mut file.items {
+        if let Some(attrs) = item_attrs_mut(item) {
+            let has_docs = attrs.iter().any(|attr| attr.path().is_ident("doc"));
+            // An empty line separates the note from the docs provided by the macro.
+            let doc = if has_docs { format!("\n{doc}") } else { doc.clone() };
+            attrs.push(syn::parse_quote! { #[doc = #doc] });
+        }
+    }
+    file.into_token_stream()
+}
+
+/// Prepends a constant naming the macro and its call site, so that the source of an expansion is
+/// visible in `cargo expand` and IDE previews, even for items which cannot be documented. A
+/// comment would serve this purpose better, but comments are not preserved in token streams.
+/// Outputs which are not lists of items (e.g. expressions) are returned unchanged.
+fn add_provenance_const(macro_name: &str, tokens: TokenStream) -> TokenStream {
+    let Ok(file) = syn::parse2::<syn::File>(tokens.clone()) else { return tokens };
+    if file.items.is_empty() {
+        return tokens
+    }
+    let value = match call_site_location() {
+        Some(location) => format!("{macro_name} @ {location}"),
+        None => macro_name.to_string(),
+    };
+    // The value contains no newlines, so it is separated from the tokens unambiguously.
+    let hash = inputs::stable_hash(format!("{value}\n{tokens}").as_bytes()) as u64;
+    let ident = quote::format_ident!("__CRABTIME_PROVENANCE_{hash:016X}");
+    quote! {
+        #[allow(unused)]
+        const #ident: &str = #value;
+        #tokens
+    }
+}
+
+/// Attributes of items which can be documented. Macro invocations and `extern` blocks are skipped,
+/// as rustdoc does not document them and their docs would trigger the `unused_doc_comments` lint.
+fn item_attrs_mut(item: &mut syn::Item) -> Option<&mut Vec<syn::Attribute>> {
+    match item {
+        syn::Item::Const(t) => Some(&mut t.attrs),
+        syn::Item::Enum(t) => Some(&mut t.attrs),
+        syn::Item::ExternCrate(t) => Some(&mut t.attrs),
+        syn::Item::Fn(t) => Some(&mut t.attrs),
+        syn::Item::Impl(t) => Some(&mut t.attrs),
+        syn::Item::Macro(t) if t.ident.is_some() => Some(&mut t.attrs),
+        syn::Item::Mod(t) => Some(&mut t.attrs),
+        syn::Item::Static(t) => Some(&mut t.attrs),
+        syn::Item::Struct(t) => Some(&mut t.attrs),
+        syn::Item::Trait(t) => Some(&mut t.attrs),
+        syn::Item::TraitAlias(t) => Some(&mut t.attrs),
+        syn::Item::Type(t) => Some(&mut t.attrs),
+        syn::Item::Union(t) => Some(&mut t.attrs),
+        syn::Item::Use(t) => Some(&mut t.attrs),
+        _ => None,
+    }
+}
+
+/// The `file:line` location of the macro call site, or `None` if it is not a file on disk.
+fn call_site_location() -> Option<String> {
+    let span = Span::call_site();
+    let file = span.local_file()?;
+    Some(format!("{}:{}", file.display(), span.start().line))
+}
+
+/// Sets the span of all tokens, including the tokens nested in groups.
+fn respan(tokens: TokenStream, span: Span) -> TokenStream {
+    tokens.into_iter().map(|token| match token {
+        TokenTree::Group(group) => {
+            let stream = respan(group.stream(), span);
+            let mut new_group = proc_macro2::Group::new(group.delimiter(), stream);
+            new_group.set_span(span);
+            TokenTree::Group(new_group)
+        }
+        mut token => {
+            token.set_span(span);
+            token
+        }
+    }).collect()
+}
+
+// ====================
+// === Export Check ===
+// ====================
+
+/// Configuration of the crate defining the macro. Discovery is best-effort, as it is only used to
+/// describe the setup of downstream crates.
+fn defining_crate_config() -> Option<CargoConfig> {
+    let manifest_dir = inputs::var("CARGO_MANIFEST_DIR")?;
+    let paths = find_cargo_configs(Path::new(&manifest_dir)).ok()?;
+    let mut cfg = CargoConfig::default();
+    cfg.fill_from_cargo_toml(&paths).ok()?;
+    Some(cfg)
+}
+
+/// Checks whether the tokens contain the identifier, also nested in groups.
+fn uses_ident(tokens: TokenStream, name: &str) -> bool {
+    tokens.into_iter().any(|token| match token {
+        TokenTree::Group(group) => uses_ident(group.stream(), name),
+        TokenTree::Ident(ident) => ident == name,
+        _ => false,
+    })
+}
+
+/// Setup required by crates invoking an exported macro. The macro is evaluated in the invoking
+/// crate, so it needs the `crabtime` dependency, and the nested build uses the invoking crate's
+/// configuration. In particular, on nightly, build-dependencies are discovered in the invoking
+/// crate's workspace, and on stable, they are not discovered at all.
+fn downstream_requirements(
+    inner_attrs: &[syn::Attribute],
+    body: &TokenStream,
+    defining_config: Option<&CargoConfig>,
+) -> Vec<String> {
+    let mut requirements = vec![format!(
+        "`crabtime = \"{}\"` in `[dependencies]`.", env!("CARGO_PKG_VERSION")
+    )];
+    let inline_dependencies = inner_attrs.iter()
+        .filter(|attr| attr.path().is_ident("dependency"))
+        .filter_map(|attr| attr.parse_args::<TokenStream>().ok())
+        .filter_map(|tokens| Dependency::from_attribute_tokens(tokens).ok())
+        .collect::<Vec<_>>();
+    if !inline_dependencies.is_empty() {
+        let list = inline_dependencies.iter()
+            .map(|dependency| format!("`{}`", dependency.to_config_string()))
+            .collect::<Vec<_>>();
+        requirements.push(format!(
+            "Registry access for the inline dependencies {}, fetched by the nested build.",
+            list.join(", ")
+        ));
+    }
+    let discovered_dependencies = defining_config.map_or(&[][..], |t| &t.dependencies);
+    for dependency in discovered_dependencies {
+        let is_inline = inline_dependencies.iter().any(|t| t.label == dependency.label);
+        if !is_inline && uses_ident(body.clone(), &dependency.crate_name()) {
+            let config = dependency.to_config_string();
+            requirements.push(format!(
+                "`{config}` in `[build-dependencies]`, as build-dependencies are discovered in the \
+                invoking crate on nightly. On stable, they are not discovered, so the macro works \
+                only if it declares `#![dependency({config})]` instead."
+            ));
+        }
+    }
+    requirements
+}
+
+/// Doc attributes listing the downstream requirements of an exported macro, shown in the rustdoc
+/// of the crate defining it.
+fn downstream_requirements_docs(requirements: &[String]) -> TokenStream {
+    let mut lines = vec![
+        String::new(),
+        " # Downstream Requirements".to_string(),
+        " This macro is evaluated by Crabtime in the crate invoking it, which needs:".to_string(),
+    ];
+    lines.extend(requirements.iter().map(|requirement| format!(" - {requirement}")));
+    quote! { #(#[doc = #lines])* }
+}
+
+// ================
+// === Function ===
+// ================
+
+#[proc_macro_attribute]
+pub fn function(
+    attr: proc_macro::TokenStream,
+    item: proc_macro::TokenStream
+) -> proc_macro::TokenStream {
+    // SAFETY: Used to panic in case of error.
+    #[allow(clippy::unwrap_used)]
+    function_impl(attr, item, false).unwrap_or_compile_error().into()
+}
+
+#[proc_macro_attribute]
+pub fn statement(
+    attr: proc_macro::TokenStream,
+    item: proc_macro::TokenStream
+) -> proc_macro::TokenStream {
+    // SAFETY: Used to panic in case of error.
+    #[allow(clippy::unwrap_used)]
+    function_impl(attr, item, false).unwrap_or_compile_error().into()
+}
+
+#[proc_macro_attribute]
+pub fn expression(
+    attr: proc_macro::TokenStream,
+    item: proc_macro::TokenStream
+) -> proc_macro::TokenStream {
+    // SAFETY: Used to panic in case of error.
+    #[allow(clippy::unwrap_used)]
+    function_impl(attr, item, true).unwrap_or_compile_error().into()
+}
+
+/// Splits attributes into the ones applied to the generated `macro_rules!` definition and the ones
+/// applied to the evaluated function. Inner `macro_export` and doc attributes, also wrapped in
+/// `cfg_attr`, are applied to the definition as well, as they have no effect on the function.
+/// Turns the `macro_rules!` pattern into a transcriber reproducing the matched input, like
+/// `$name:ident, $($xs:expr),*` into `$name, $($xs),*`. Repetitions without metavariables, like
+/// `$(,)?`, cannot be transcribed, so they are skipped.
+fn pattern_transcriber(pattern: &TokenStream) -> TokenStream {
+    fn transcribe(tokens: &[TokenTree]) -> (TokenStream, bool) {
+        let is_punct = |token: Option<&TokenTree>, chars: &str|
+            matches!(token, Some(TokenTree::Punct(p)) if chars.contains(p.as_char()));
+        let mut out = TokenStream::new();
+        let mut has_vars = false;
+        let mut i = 0;
+        while i < tokens.len() {
+            let token = &tokens[i];
+            match (token, tokens.get(i + 1)) {
+                (TokenTree::Punct(dollar), Some(TokenTree::Ident(var)))
+                if dollar.as_char() == '$' && is_punct(tokens.get(i + 2), ":") => {
+                    out.extend([token.clone(), TokenTree::Ident(var.clone())]);
+                    has_vars = true;
+                    i += 4;
+                }
+                (TokenTree::Punct(dollar), Some(TokenTree::Group(group)))
+                if dollar.as_char() == '$' && group.delimiter() == Delimiter::Parenthesis => {
+                    let inner = group.stream().into_iter().collect::<Vec<_>>();
+                    let (inner, inner_has_vars) = transcribe(&inner);
+                    let op = (i + 2..tokens.len()).take(3)
+                        .find(|j| is_punct(tokens.get(*j), "*+?"))
+                        .unwrap_or(tokens.len());
+                    if inner_has_vars {
+                        let mut group = proc_macro2::Group::new(Delimiter::Parenthesis, inner);
+                        group.set_span(dollar.span());
+                        out.extend([token.clone(), TokenTree::Group(group)]);
+                        out.extend(tokens[i + 2..(op + 1).min(tokens.len())].iter().cloned());
+                        has_vars = true;
+                    }
+                    i = op + 1;
+                }
+                (TokenTree::Group(group), _) => {
+                    let inner = group.stream().into_iter().collect::<Vec<_>>();
+                    let (inner, inner_has_vars) = transcribe(&inner);
+                    let mut new_group = proc_macro2::Group::new(group.delimiter(), inner);
+                    new_group.set_span(group.span());
+                    out.extend([TokenTree::Group(new_group)]);
+                    has_vars |= inner_has_vars;
+                    i += 1;
+                }
+                _ => {
+                    out.extend([token.clone()]);
+                    i += 1;
+                }
+            }
+        }
+        (out, has_vars)
+    }
+    transcribe(&pattern.clone().into_iter().collect::<Vec<_>>()).0
+}
+
+/// Removes the attribute passing the input of the macro call and returns its top-level
+/// comma-separated arguments, printed as they are printed by the compiler.
+fn extract_call_args(attrs: &mut Vec<syn::Attribute>) -> Vec<String> {
+    let Some(index) = attrs.iter().position(|attr| attr.path().is_ident(CALL_ARGS_ATTR)) else {
+        return vec![]
+    };
+    let attr = attrs.remove(index);
+    let tokens = attr.parse_args::<TokenStream>().unwrap_or_default();
+    let tokens = tokens.into_iter().collect::<Vec<_>>();
+    tokens.split(|t| matches!(t, TokenTree::Punct(p) if p.as_char() == ','))
+        .filter(|arg| !arg.is_empty())
+        .map(|arg| arg.iter().cloned().collect::<TokenStream>().to_string())
+        .collect()
+}
+
+fn split_attrs(attrs: Vec<syn::Attribute>) -> (Vec<syn::Attribute>, Vec<syn::Attribute>) {
+    let (mut outer, inner): (Vec<_>, Vec<_>) = attrs.into_iter().partition(|attr| {
+        matches!(attr.style, syn::AttrStyle::Outer) || is_macro_rules_meta(&attr.meta)
+    });
+    for attr in &mut outer {
+        attr.style = syn::AttrStyle::Outer;
+    }
+    (outer, inner)
+}
+
+fn is_macro_rules_meta(meta: &syn::Meta) -> bool {
+    meta_has_path(meta, "macro_export") || meta_has_path(meta, "doc")
+}
+
+/// Checks whether the meta is the given attribute, also wrapped in `cfg_attr`.
+fn meta_has_path(meta: &syn::Meta, name: &str) -> bool {
+    let path = meta.path();
+    if path.is_ident(name) {
+        return true
+    }
+    if path.is_ident("cfg_attr")
+    && let syn::Meta::List(list) = meta
+    && let Ok(metas) = list.parse_args_with(
+        syn::punctuated::Punctuated::<syn::Meta, syn::Token![,]>::parse_terminated
+    ) {
+        // The first meta is the configuration predicate.
+        return metas.iter().skip(1).any(|meta| meta_has_path(meta, name))
+    }
+    false
+}
+
+fn function_impl(
+    attr_in: proc_macro::TokenStream,
+    item: proc_macro::TokenStream,
+    extra_braces: bool,
+) -> Result<TokenStream> {
+    let mut attr: TokenStream = attr_in.into();
+    let mut input_fn_ast = syn::parse::<syn::ItemFn>(item)?;
+    let module_path = extract_module_attr(&mut input_fn_ast.attrs)?;
+    let name = &input_fn_ast.sig.ident;
+    let args_ast = &input_fn_ast.sig.inputs;
+    let body_ast = &input_fn_ast.block.stmts;
+    let output_tp = &input_fn_ast.sig.output;
+
+    let mut spans = SpanMap::default();
+    let options = syn::parse::Parser::parse2(
+        |input: syn::parse::ParseStream| MacroOptions::parse_with_spans(input, &mut spans),
+        attr.clone()
+    )?;
+    if let Some(module_path) = module_path {
+        if options.module.is_some() {
+            let span = syn::spanned::Spanned::span(&module_path);
+            return err!(span, "The module is already provided by the 'module' option.")
+        }
+        let comma = (!attr.is_empty() && !attr.to_string().trim_end().ends_with(','))
+            .then(|| quote!{,});
+        attr = quote!{ #attr #comma module = #module_path };
+    }
+    let (args, args_code, args_default_code) =
+        parse_args(args_ast, &mut spans).context(|| error!(spans.args_span(), WRONG_ARGS))?;
+    let args_pattern = args.pattern();
+    let args_setup = args.setup();
+    let args_transcriber = pattern_transcriber(&args_pattern);
+    let call_args_attr = syn::Ident::new(CALL_ARGS_ATTR, Span::call_site());
+    let body = quote!{ #(#body_ast)* };
+    let ide_compat = options.ide_compat;
+    let input_str = expand_expand_macro(
+        expand_output_macro_rules_macro(quote!{ #(#body_ast)* }, ide_compat)
+    );
+
+    // Check if the expansion engine is Rust Analyzer. If so, we need to generate
+    // a code which looks like a function to enable type hints.
+    let rust_analyzer_hints = if is_rust_analyzer()? {
+        quote! {
+            mod __rust_analyzer_hints__ {
+                #[test]
+                #[ignore]
+                fn mytest() {
+                    #body
+                }
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    let attrs_vec = input_fn_ast.attrs;
+    let (outer_attrs_vec, inner_attrs_vec) = split_attrs(attrs_vec);
+    let exported = outer_attrs_vec.iter().any(|attr| meta_has_path(&attr.meta, "macro_export"));
+    let requirements_docs = if exported {
+        let defining_config = defining_crate_config();
+        let requirements =
+            downstream_requirements(&inner_attrs_vec, &body, defining_config.as_ref());
+        if inputs::var(CHECK_EXPORT_ENV).is_some_and(|t| t == "1" || t == "true") {
+            print_warning!(
+                "{CHECK_EXPORT_ENV}: Macro '{name}' is exported. Crates invoking it need:\n- {}",
+                requirements.join("\n- ")
+            );
+        }
+        downstream_requirements_docs(&requirements)
+    } else {
+        quote! {}
+    };
+
+    let outer_attrs = quote!{ #(#outer_attrs_vec)* #requirements_docs };
+    let inner_attrs = quote!{ #(#inner_attrs_vec)* };
+    let expansion = |args_code: &TokenStream, call_args: &TokenStream| {
+        let out = quote! {
+            {
+                #[crabtime::eval_function(#attr)]
+                fn #name() #output_tp {
+                    #![#call_args_attr(#call_args)]
+                    #inner_attrs
+                    #args_setup
+                    #args_code
+                    #input_str
+                }
+            }
+        };
+        if extra_braces { quote! {{ #out }} } else { out }
+    };
+    let out = expansion(&args_code, &args_transcriber);
+    // If all arguments have defaults, the macro can also be invoked without arguments. The
+    // populated pattern never matches an empty input, so the arms are not ambiguous.
+    let default_arm = args_default_code.map(|default_code| {
+        let out = expansion(&default_code, &TokenStream::new());
+        quote! { () => #out; }
+    });
+    let out = quote! {
+        #rust_analyzer_hints
+
+        #outer_attrs
+        macro_rules! #name {
+            #default_arm
+            (#args_pattern) => #out;
+        }
+    };
+    debug!("OUT: {out}");
+    Ok(out)
+}
+
+fn is_rust_analyzer() -> Result<bool> {
+    let program_name = std::env::current_exe()?
+        .file_name()
+        .map_or_else(|| "unknown".into(), |s| s.to_string_lossy().into_owned());
+    Ok(program_name.contains("rust-analyzer"))
+}
+
+fn format_duration(duration: std::time::Duration) -> String {
+    let total_seconds = duration.as_secs();
+    if total_seconds >= 60 {
+        let minutes = total_seconds / 60;
+        let seconds = total_seconds % 60;
+        format!("{minutes}m {seconds}s")
+    } else {
+        let millis = duration.as_millis() % 1000;
+        let fractional = millis as f64 / 1000.0;
+        format!("{:.2} s", total_seconds as f64 + fractional)
+    }
+}
+
+fn format_size(bytes: usize) -> String {
+    const UNITS: &[&str] = &["KB", "MB", "GB"];
+    if bytes < 1024 {
+        return format!("{bytes} B")
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = UNITS[0];
+    for next_unit in &UNITS[1..] {
+        if size < 1024.0 { break }
+        size /= 1024.0;
+        unit = next_unit;
+    }
+    format!("{size:.2} {unit}")
+}
+
+fn get_current_time() -> String {
+    let now = std::time::SystemTime::now();
+    #[allow(clippy::unwrap_used)]
+    let duration_since_epoch = now.duration_since(std::time::UNIX_EPOCH).unwrap();
+    let total_seconds = duration_since_epoch.as_secs();
+    let milliseconds = (duration_since_epoch.as_millis() % 1000) as u32;
+    let hours = (total_seconds / 3600) % 24;
+    let minutes = (total_seconds / 60) % 60;
+    let seconds = total_seconds % 60;
+    format!("{hours:02}:{minutes:02}:{seconds:02} ({milliseconds:03})")
+}
+
+// =============
+// === Tests ===
+// =============
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    #[test]
+    fn args_specific_dir_names() {
+        let input1 = "gen_positions ([\"X\", \"Y\"])";
+        let input2 = "gen_positions ([\"X\", \"Y\", \"Z\"])";
+        assert_eq!(Paths::args_dir_name(input1), Paths::args_dir_name(input1));
+        assert_ne!(Paths::args_dir_name(input1), Paths::args_dir_name(input2));
+        assert!(Paths::args_dir_name(input1).starts_with("args_"));
+        let pinned = "project_4ed63e19bcdc95bb861c2f7a2fe11dff";
+        assert_eq!(Paths::project_name_from_input("fn main() {}"), pinned);
+    }
+
+    #[test]
+    fn call_site_edits_keep_other_builds() {
+        let macro_dir = TempDir::new("call_sites");
+        let cfg = CargoConfig::default();
+        let main = |value: usize| format!("fn main() {{ println!(\"[OUTPUT] {value}\"); }}");
+        // Every call site is built in the directory of its arguments, like in `Paths::new`.
+        let project_dir = |value| macro_dir.join(Paths::args_dir_name(&main(value)));
+        let expand = |value| {
+            create_project_skeleton(&project_dir(value), &cfg, &main(value))
+                .and_then(|_| {
+                    run_cargo_project(&project_dir(value), None, &cfg, &RunSettings::default())
+                })
+                .ok()
+        };
+        let build = |value| {
+            let bin_dir = project_dir(value).join("target").join(RUSTC_BIN_DIR).join("debug");
+            let stamp = fs::read_to_string(bin_dir.join("stamp")).ok()?;
+            let binary_name = format!("{}{}", cfg.package_name(), std::env::consts::EXE_SUFFIX);
+            let modified = fs::metadata(bin_dir.join(binary_name)).and_then(|t| t.modified()).ok()?;
+            let main_rs = fs::read_to_string(project_dir(value).join("src/main.rs")).ok()?;
+            Some((stamp, modified, main_rs))
+        };
+        let first = expand(1);
+        let second = expand(2);
+        let second_build = build(2);
+        // The arguments of the first call site are edited.
+        let edited = expand(3);
+        assert_eq!(first.as_deref(), Some("[OUTPUT] 1\n"));
+        assert_eq!(second.as_deref(), Some("[OUTPUT] 2\n"));
+        assert_eq!(edited.as_deref(), Some("[OUTPUT] 3\n"));
+        assert!(second_build.is_some());
+        assert_eq!(build(2), second_build);
+    }
+
+    #[test]
+    fn provenance_docs_added_to_items() {
+        let tokens = quote! {
+            /// Docs.
+            struct A;
+            impl A {}
+            extern "C" {}
+            macro_rules! m { () => {} }
+            m!();
+        };
+        let out = add_provenance_docs("gen", tokens).to_string();
+        let doc = "Generated by crabtime macro `gen`.";
+        assert!(out.contains(&format!("# [doc = r\" Docs.\"] # [doc = \"\\n{doc}\"] struct A")));
+        assert!(out.contains(&format!("# [doc = \"{doc}\"] impl A {{ }} extern \"C\"")));
+        assert!(out.contains(&format!("# [doc = \"{doc}\"] macro_rules ! m")));
+        assert!(out.ends_with("m ! () ;"));
+    }
+
+    #[test]
+    fn provenance_docs_skip_expressions() {
+        let tokens = quote! { 1 + 2 };
+        assert_eq!(add_provenance_docs("gen", tokens).to_string(), "1 + 2");
+    }
+
+    #[test]
+    fn provenance_const_added_to_items() {
+        let out = add_provenance_const("gen", quote! { struct A; }).to_string();
+        assert!(out.starts_with("# [allow (unused)] const __CRABTIME_PROVENANCE_"));
+        assert!(out.ends_with(": & str = \"gen\" ; struct A ;"));
+        assert_eq!(add_provenance_const("gen", quote! { 1 + 2 }).to_string(), "1 + 2");
+        assert_eq!(add_provenance_const("gen", quote! {}).to_string(), "");
+    }
+
+    #[test]
+    fn code_builder() {
+        let mut fields = code::Code::new();
+        fields.item("pub x: f32,").item(format_args!("pub {}: f32,", "y"));
+        let mut out = code::Code::new();
+        out.item("#[derive(Debug)]").block("pub struct Point", |b| { b.append(&fields); });
+        out.item("");
+        out.block("impl Point", |b| {
+            b.block("pub fn sum(&self) -> f32", |b| { b.item("self.x\n    + self.y"); });
+        });
+        assert_eq!(out.to_string(), [
+            "#[derive(Debug)]",
+            "pub struct Point {",
+            "    pub x: f32,",
+            "    pub y: f32,",
+            "}",
+            "",
+            "impl Point {",
+            "    pub fn sum(&self) -> f32 {",
+            "        self.x",
+            "            + self.y",
+            "    }",
+            "}",
+        ].join("\n"));
+        let mut manual = code::Code::new();
+        manual.indent().item("a").dedent().dedent().item("b");
+        assert_eq!(manual.to_string(), "    a\nb");
+        assert!(code::Code::new().is_empty());
+        assert_eq!(code::Code::new().block("", |_| {}).to_string(), "{\n}");
+    }
+
+    #[test]
+    fn snippets() {
+        use snippet::Snippet;
+        let a = Snippet::from("struct A;");
+        let b = Snippet::from("struct B;".to_string());
+        let mut sum = a.clone() + &b + " struct C;";
+        assert_eq!(sum, "struct A;struct B; struct C;");
+        sum += Snippet::from("struct D;");
+        assert!(sum.ends_with("struct D;"));
+        let collected = [a, b].into_iter().collect::<Snippet>();
+        assert_eq!(format!("mod m {{ {collected} }}"), "mod m { struct A;\nstruct B; }");
+        assert_eq!(String::from(collected), "struct A;\nstruct B;");
+        assert_eq!(Vec::<Snippet>::new().into_iter().collect::<Snippet>(), Snippet::new());
+    }
+
+    #[test]
+    fn manifests_with_bom_and_errors() {
+        let path = Path::new("crate/Cargo.toml");
+        let bom = CargoConfig::parse_manifest(path, "\u{feff}[package]\nedition = \"2021\"\n").ok();
+        let edition = bom.as_ref().and_then(|t| t.get("package")).and_then(|t| t.get("edition"));
+        assert_eq!(edition.and_then(|t| t.as_str()), Some("2021"));
+        let malformed = CargoConfig::parse_manifest(path, "\u{feff}[package]\nedition = 2021x\n");
+        let message = malformed.err().map(|e| e.message).unwrap_or_default();
+        assert!(message.starts_with("Failed to parse 'crate/Cargo.toml' at line 2, column 15: "));
+    }
+
+    #[test]
+    fn malformed_workspace_manifest_ignored() {
+        let dir = TempDir::new("manifest");
+        let crate_config = dir.join("crate").join("Cargo.toml");
+        let workspace_config = dir.join("Cargo.toml");
+        let written = fs::create_dir_all(dir.join("crate"))
+            .and_then(|_| fs::write(&crate_config, "\u{feff}[package]\nedition = \"2018\""))
+            .and_then(|_| fs::write(&workspace_config, "[workspace\nmembers = []"))
+            .ok();
+        let paths = CargoConfigPaths { crate_config, workspace_config: Some(workspace_config) };
+        let mut cfg = CargoConfig::default();
+        let filled = cfg.fill_from_cargo_toml(&paths).ok();
+        let discovered = find_cargo_configs(&dir.join("crate").join("src")).ok();
+        assert_eq!(written, Some(()));
+        assert_eq!(filled, Some(()));
+        assert_eq!(cfg.edition.as_deref(), Some("2018"));
+        assert_eq!(discovered.map(|t| t.workspace_config), Some(None));
+    }
+
+    #[test]
+    fn virtual_manifests_skipped_for_crate_config() {
+        let fixture = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../tests/nightly/fixtures/virtual-workspace");
+        let member = fixture.join("member");
+        let configs = find_cargo_configs(&member.join("examples")).ok();
+        let mut cfg = CargoConfig::default();
+        let filled = configs.as_ref().map(|t| cfg.fill_from_cargo_toml(t).is_ok());
+        assert_eq!(configs.as_ref().map(|t| &t.crate_config), Some(&member.join("Cargo.toml")));
+        assert_eq!(configs.and_then(|t| t.workspace_config), Some(fixture.join("Cargo.toml")));
+        assert_eq!(filled, Some(true));
+        assert_eq!(cfg.edition.as_deref(), Some("2024"));
+        assert!(cfg.dependencies.iter().any(|t| t.label == "semver"));
+    }
+
+    #[test]
+    fn nearest_workspace_listing_crate_used() {
+        let dir = TempDir::new("nested_workspace");
+        let outer = "[workspace]\nmembers = [\"crates/*\"]\nexclude = [\"./nested\", \"tools\"]";
+        let files = [
+            ("Cargo.toml", outer),
+            ("crates/app/Cargo.toml", "[package]"),
+            ("nested/Cargo.toml", "[workspace]\nmembers = [\"libs/**\"]"),
+            ("nested/libs/deep/member/Cargo.toml", "[package]"),
+            ("nested/root/Cargo.toml", "[package]\n[workspace]"),
+            ("tools/gen/Cargo.toml", "[package]"),
+        ];
+        let written = files.iter().try_for_each(|(file, content)| {
+            fs::create_dir_all(dir.join(file).parent().unwrap_or(&dir))?;
+            fs::write(dir.join(file), content)
+        });
+        let workspace = |crate_dir: &str| find_cargo_configs(&dir.join(crate_dir).join("src")).ok()
+            .map(|t| t.workspace_config);
+        let app = workspace("crates/app");
+        let nested_member = workspace("nested/libs/deep/member");
+        let nested_root = workspace("nested/root");
+        let excluded = workspace("tools/gen");
+        assert_eq!(written.ok(), Some(()));
+        assert_eq!(app, Some(Some(dir.join("Cargo.toml"))));
+        assert_eq!(nested_member, Some(Some(dir.join("nested/Cargo.toml"))));
+        assert_eq!(nested_root, Some(None));
+        assert_eq!(excluded, Some(None));
+    }
+
+    #[test]
+    fn workspace_member_globs() {
+        let matches = |glob: &str, path: &str| path::matches_glob(glob, Path::new(path));
+        assert!(matches("crates/*", "crates/app"));
+        assert!(!matches("crates/*", "crates/app/nested"));
+        assert!(matches("crates/**", "crates/app/nested"));
+        assert!(matches("**/member", "nested/libs/member"));
+        assert!(matches("crate-?", "crate-a"));
+        assert!(matches("lib-*-sys", "lib-zstd-sys"));
+        assert!(!matches("lib-*-sys", "lib-zstd"));
+        assert!(matches("./tools/gen", "tools/gen"));
+    }
+
+    #[test]
+    fn build_dependency_tables_preserved() {
+        let dir = TempDir::new("dep_tables");
+        let crate_config = dir.join("Cargo.toml");
+        let manifest = "[build-dependencies]\n\
+            syn = { version = \"2\", features = [\"full\", \"extra-traits\"] }\n\
+            [build-dependencies.quote]\n\
+            version = \"1\"\n\
+            features = [\"proc-macro\"]\n\
+            default-features = false\n\
+            optional = true";
+        let written = fs::create_dir_all(&dir)
+            .and_then(|_| fs::write(&crate_config, manifest))
+            .ok();
+        let paths = CargoConfigPaths { crate_config, workspace_config: None };
+        let mut cfg = CargoConfig::default();
+        let filled = cfg.fill_from_cargo_toml(&paths).ok();
+        assert_eq!(written, Some(()));
+        assert_eq!(filled, Some(()));
+        let dependencies = cfg.dependencies.iter()
+            .map(|t| t.to_config_string())
+            .collect::<Vec<_>>();
+        assert_eq!(dependencies, [
+            "quote = { default-features = false, features = [\"proc-macro\"], optional = true, \
+            version = \"1\" }",
+            "syn = { features = [\"full\", \"extra-traits\"], version = \"2\" }",
+        ]);
+        let config = toml::from_str::<toml::Table>(&dependencies.join("\n")).ok();
+        let syn_features = config.as_ref()
+            .and_then(|t| t.get("syn")).and_then(|t| t.get("features")).cloned();
+        let expected_features = vec!["full".into(), "extra-traits".into()];
+        assert_eq!(syn_features, Some(toml::Value::Array(expected_features)));
+    }
+
+    #[test]
+    fn target_specific_build_dependencies() {
+        let linux = "x86_64-unknown-linux-gnu";
+        let windows = "x86_64-pc-windows-msvc";
+        let wasm = "wasm32-unknown-unknown";
+        let applies = |target: &str| [linux, windows, wasm].map(|t| target_applies(target, t));
+        assert_eq!(applies(linux), [Some(true), Some(false), Some(false)]);
+        assert_eq!(applies("cfg(windows)"), [Some(false), Some(true), Some(false)]);
+        assert_eq!(applies("cfg(unix)"), [Some(true), Some(false), Some(false)]);
+        let wasm_family = "cfg(target_family = \"wasm\")";
+        assert_eq!(applies(wasm_family), [Some(false), Some(false), Some(true)]);
+        assert_eq!(applies("cfg(not(any(windows, unix)))"), [Some(false), Some(false), Some(true)]);
+        assert_eq!(applies("cfg(all(unix, not(windows)))"), [Some(true), Some(false), Some(false)]);
+        assert_eq!(applies("cfg(all())"), [Some(true), Some(true), Some(true)]);
+        assert_eq!(applies("cfg(target_os = \"linux\")"), [None, None, None]);
+        assert_eq!(applies("cfg(any(windows, feature = \"a\"))"), [None, None, None]);
+        assert_eq!(applies("cfg(not(windows, unix))"), [None, None, None]);
+
+        let dir = TempDir::new("target_deps");
+        let crate_config = dir.join("Cargo.toml");
+        let manifest = format!("[build-dependencies]\n\
+            shared = \"1\"\n\
+            [target.'cfg(any(windows, unix))'.build-dependencies]\n\
+            shared = \"2\"\n\
+            desktop = {{ version = \"1\", features = [\"a\"] }}\n\
+            [target.'cfg(not(any(windows, unix)))'.build-dependencies]\n\
+            embedded = \"1\"\n\
+            [target.{HOST_TARGET}.build-dependencies]\n\
+            host = \"1\"\n\
+            [target.{HOST_TARGET}.dependencies]\n\
+            regular = \"1\"");
+        let written = fs::create_dir_all(&dir)
+            .and_then(|_| fs::write(&crate_config, manifest))
+            .ok();
+        let paths = CargoConfigPaths { crate_config, workspace_config: None };
+        let mut cfg = CargoConfig::default();
+        let filled = cfg.fill_from_cargo_toml(&paths).ok();
+        assert_eq!(written, Some(()));
+        assert_eq!(filled, Some(()));
+        let mut dependencies = cfg.dependencies.iter()
+            .map(|t| t.to_config_string())
+            .collect::<Vec<_>>();
+        dependencies.sort();
+        assert_eq!(dependencies, [
+            "desktop = { features = [\"a\"], version = \"1\" }",
+            "host = \"1\"",
+            "shared = \"1\"",
+        ]);
+    }
+
+    #[test]
+    fn relative_path_dependencies_resolved() {
+        let dir = TempDir::new("path_deps");
+        let crate_config = dir.join("my crate").join("Cargo.toml");
+        let manifest = "[build-dependencies]\nhelpers = { path = \"../helpers\", version = \"1\" }";
+        let written = fs::create_dir_all(dir.join("my crate"))
+            .and_then(|_| fs::write(&crate_config, manifest))
+            .ok();
+        let paths = CargoConfigPaths { crate_config, workspace_config: None };
+        let mut cfg = CargoConfig::default();
+        let filled = cfg.fill_from_cargo_toml(&paths).ok();
+        assert_eq!(written, Some(()));
+        assert_eq!(filled, Some(()));
+        let crate_dir = dir.join("my crate").display().to_string().replace('\\', "/");
+        let path_of = |dependency: &Dependency| dependency.spec()
+            .and_then(|t| t.get("path").and_then(toml::Value::as_str).map(str::to_string));
+        assert_eq!(path_of(&cfg.dependencies[0]), Some(format!("{crate_dir}/../helpers")));
+        assert_eq!(cfg.dependencies[0].major_version(), Some(1));
+
+        let base = Path::new("/work/my crate");
+        let dependency = |spec: &str| Dependency::new("dep".to_string(), spec.to_string(), None);
+        let windows = dependency(r#"{ path = '..\sibling dir\helpers' }"#).with_absolute_path(base);
+        assert_eq!(path_of(&windows).as_deref(), Some("/work/my crate/../sibling dir/helpers"));
+        for absolute in [r#"{ path = "/deps/a" }"#, r#"{ path = 'C:\deps\a' }"#, r#""1""#] {
+            let resolved = dependency(absolute).with_absolute_path(base);
+            assert_eq!(resolved.tokens_str, absolute);
+        }
+        let config = toml::from_str::<toml::Table>(&windows.to_config_string()).ok();
+        assert!(config.is_some());
+    }
+
+    #[test]
+    fn git_dependencies_preserved() {
+        let dir = TempDir::new("git_deps");
+        let crate_config = dir.join("Cargo.toml");
+        let manifest = "[build-dependencies]\n\
+            a = { git = \"https://github.com/me/a\", rev = \"0a1b2c\" }\n\
+            b = { git = \"https://github.com/me/b c\", tag = \"v1.0\", package = \"b-core\" }";
+        let written = fs::create_dir_all(&dir)
+            .and_then(|_| fs::write(&crate_config, manifest))
+            .ok();
+        let paths = CargoConfigPaths { crate_config, workspace_config: None };
+        let mut cfg = CargoConfig::default();
+        let filled = cfg.fill_from_cargo_toml(&paths).ok();
+        assert_eq!(written, Some(()));
+        assert_eq!(filled, Some(()));
+        let tokens = quote! {
+            my_parser = { git = "https://github.com/me/my-parser", branch = "main" }
+        };
+        cfg.dependencies.extend(Dependency::from_attribute_tokens(tokens).ok());
+        let specs = cfg.dependencies.iter().map(Dependency::to_config_string).collect::<Vec<_>>();
+        assert_eq!(specs, [
+            "a = { git = \"https://github.com/me/a\", rev = \"0a1b2c\" }",
+            "b = { git = \"https://github.com/me/b c\", package = \"b-core\", tag = \"v1.0\" }",
+            "my_parser = { git = \"https://github.com/me/my-parser\", branch = \"main\" }",
+        ]);
+        assert_eq!(cfg.dependency("b-core").map(|t| t.label.as_str()), Some("b"));
+        assert!(cfg.dependency("my_parser").is_some());
+    }
+
+    #[test]
+    fn patches_propagated() {
+        let dir = TempDir::new("patch");
+        let crate_config = dir.join("my_crate").join("Cargo.toml");
+        let workspace_config = dir.join("Cargo.toml");
+        let crate_manifest = "[build-dependencies]\nserde = \"1\"\n\
+            [patch.crates-io]\nquote = { path = \"../vendor/quote\" }";
+        let workspace_manifest = "[workspace]\nmembers = [\"my_crate\"]\n\
+            [patch.crates-io]\nserde = { path = \"vendor/serde\" }\nquote = \"1\"\n\
+            [patch.\"https://github.com/me/a\"]\na = { git = \"https://github.com/me/a-fork\" }";
+        let written = fs::create_dir_all(dir.join("my_crate"))
+            .and_then(|_| fs::write(&crate_config, crate_manifest))
+            .and_then(|_| fs::write(&workspace_config, workspace_manifest))
+            .ok();
+        let paths = CargoConfigPaths { crate_config, workspace_config: Some(workspace_config) };
+        let mut cfg = CargoConfig::default();
+        let filled = cfg.fill_from_cargo_toml(&paths).ok();
+        assert_eq!(written, Some(()));
+        assert_eq!(filled, Some(()));
+        let dir = dir.display().to_string().replace('\\', "/");
+        assert_eq!(cfg.print_patches(), format!("\
+            [patch.crates-io]\n\
+            serde = {{ path = \"{dir}/vendor/serde\" }}\n\
+            quote = {{ path = \"{dir}/my_crate/../vendor/quote\" }}\n\n\
+            [patch.\"https://github.com/me/a\"]\n\
+            a = {{ git = \"https://github.com/me/a-fork\" }}"
+        ));
+        let manifest = toml::from_str::<toml::Table>(&cfg.print()).ok();
+        let patch = manifest.as_ref()
+            .and_then(|t| t.get("patch"))
+            .and_then(|t| t.get("crates-io"))
+            .and_then(|t| t.get("serde"))
+            .and_then(|t| t.get("path"))
+            .and_then(toml::Value::as_str);
+        assert_eq!(patch, Some(format!("{dir}/vendor/serde").as_str()));
+    }
+
+    #[test]
+    fn workspace_dependencies_inherited() {
+        let dir = TempDir::new("inherit");
+        let crate_config = dir.join("my_crate").join("Cargo.toml");
+        let workspace_config = dir.join("Cargo.toml");
+        let crate_manifest = "[build-dependencies]\n\
+            anyhow = { workspace = true }\n\
+            tokio = { workspace = true, features = [\"fs\", \"rt\"], optional = true }\n\
+            serde = { workspace = true, features = [\"derive\"] }\n\
+            helpers = { workspace = true }\n\
+            pm2 = { workspace = true }\n\
+            missing = { workspace = true }";
+        let workspace_manifest = "[workspace]\nmembers = [\"my_crate\"]\n\
+            [workspace.dependencies]\n\
+            anyhow = \"1\"\n\
+            tokio = { version = \"1\", features = [\"full\", \"fs\"], default-features = false }\n\
+            serde = \"1\"\n\
+            helpers = { path = \"helpers\" }\n\
+            pm2 = { package = \"proc-macro2\", version = \"1\" }";
+        let written = fs::create_dir_all(dir.join("my_crate"))
+            .and_then(|_| fs::write(&crate_config, crate_manifest))
+            .and_then(|_| fs::write(&workspace_config, workspace_manifest))
+            .ok();
+        let paths = CargoConfigPaths { crate_config, workspace_config: Some(workspace_config) };
+        let mut cfg = CargoConfig::default();
+        let filled = cfg.fill_from_cargo_toml(&paths).ok();
+        assert_eq!(written, Some(()));
+        assert_eq!(filled, Some(()));
+        let dir = dir.display().to_string().replace('\\', "/");
+        let dependencies = cfg.dependencies.iter()
+            .map(|t| t.to_config_string())
+            .collect::<Vec<_>>();
+        assert_eq!(dependencies, [
+            "anyhow = \"1\"".to_string(),
+            format!("helpers = {{ path = \"{dir}/helpers\" }}"),
+            "pm2 = { package = \"proc-macro2\", version = \"1\" }".to_string(),
+            "serde = { features = [\"derive\"], version = \"1\" }".to_string(),
+            "tokio = { default-features = false, features = [\"full\", \"fs\", \"rt\"], \
+            optional = true, version = \"1\" }".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn workspace_lints_inherited() {
+        let dir = TempDir::new("lints");
+        let inheriting_config = dir.join("inheriting").join("Cargo.toml");
+        let own_config = dir.join("own").join("Cargo.toml");
+        let workspace_config = dir.join("Cargo.toml");
+        let workspace_manifest = "[workspace]\nmembers = [\"inheriting\", \"own\"]\n\
+            [workspace.lints.rust]\n\
+            unsafe_code = { level = \"deny\", priority = 1 }\n\
+            unused = \"warn\"\n\
+            unexpected_cfgs = { level = \"warn\", check-cfg = ['cfg(crabtime_test)'] }\n\
+            [workspace.lints.clippy]\n\
+            all = { level = \"warn\", priority = -1 }\n\
+            needless_return = \"allow\"";
+        let written = fs::create_dir_all(dir.join("inheriting"))
+            .and_then(|_| fs::create_dir_all(dir.join("own")))
+            .and_then(|_| fs::write(&workspace_config, workspace_manifest))
+            .and_then(|_| fs::write(&inheriting_config, "[lints]\nworkspace = true"))
+            .and_then(|_| fs::write(&own_config, "[lints.clippy]\n\
+                pedantic = { level = \"warn\", priority = -2 }"))
+            .ok();
+        let manifest_of = |crate_config: &Path| {
+            let crate_config = crate_config.to_path_buf();
+            let workspace_config = Some(workspace_config.clone());
+            let paths = CargoConfigPaths { crate_config, workspace_config };
+            let mut cfg = CargoConfig::default();
+            cfg.fill_from_cargo_toml(&paths).ok()?;
+            cfg.print().parse::<toml::Table>().ok()?.remove("lints")
+        };
+        let inherited = manifest_of(&inheriting_config);
+        let own = manifest_of(&own_config);
+        assert_eq!(written, Some(()));
+        let expected_inherited = "[clippy]\n\
+            all = { level = \"warn\", priority = -1 }\n\
+            needless_return = \"allow\"\n\
+            [rust]\n\
+            unexpected_cfgs = { level = \"warn\", check-cfg = [\"cfg(crabtime_test)\"] }\n\
+            unsafe_code = { level = \"deny\", priority = 1 }\n\
+            unused = \"warn\"";
+        assert_eq!(inherited, toml::from_str(expected_inherited).ok());
+        let expected_own = "[clippy]\npedantic = { level = \"warn\", priority = -2 }\n[rust]";
+        assert_eq!(own, toml::from_str(expected_own).ok());
+        let quoted_key = toml::from_str("\"odd.lint\" = \"warn\"").ok();
+        let printed = quoted_key.as_ref().map(CargoConfig::print_table_entries);
+        assert_eq!(printed.as_deref(), Some("\"odd.lint\" = \"warn\""));
+    }
+
+    #[test]
+    fn ident_keywords_per_edition() {
+        let keywords_2015 = ident_keywords("2015");
+        assert!(keywords_2015.contains(&"static"));
+        assert!(!keywords_2015.contains(&"async"));
+        assert!(ident_keywords("2018").contains(&"async"));
+        assert!(!ident_keywords("2021").contains(&"gen"));
+        assert!(ident_keywords("2024").contains(&"gen"));
+        let mut cfg = CargoConfig { edition: Some("2021".to_string()), ..CargoConfig::default() };
+        assert_eq!(cfg.output_edition(), "2021");
+        cfg.output_edition = Some("2024".to_string());
+        assert_eq!(cfg.output_edition(), "2024");
+    }
+
+    #[test]
+    fn edition_keyword_identifiers_reported() {
+        let output = quote! {
+            pub fn gen(x: Box<dyn Fn()>) -> usize { let try = 1; try }
+            pub async fn run(f: impl std::future::Future) { f.await; }
+            pub struct S { dyn: usize, r#gen: usize }
+            pub mod async_mod { pub use crate::gen; }
+        };
+        assert_eq!(edition_keyword_identifiers("2015", &output), Vec::<String>::new());
+        assert_eq!(edition_keyword_identifiers("2021", &output), ["try", "dyn"]);
+        assert_eq!(edition_keyword_identifiers("2024", &output), ["gen", "try", "dyn"]);
+        let mut cfg = CargoConfig::default();
+        let attrs: Vec<syn::Attribute> = vec![syn::parse_quote! { #![output_edition(2018)] }];
+        assert!(cfg.extract_inline_attributes(attrs, &mut SpanMap::default()).is_ok());
+        assert_eq!(cfg.output_edition.as_deref(), Some("2018"));
+        assert_eq!(cfg.edition, None);
+        let attrs: Vec<syn::Attribute> = vec![syn::parse_quote! { #![output_edition(2019)] }];
+        assert!(cfg.extract_inline_attributes(attrs, &mut SpanMap::default()).is_err());
+    }
+
+    #[test]
+    fn edition_attribute_validated() {
+        let resolver = |attrs: Vec<syn::Attribute>| {
+            let mut cfg = CargoConfig::default();
+            cfg.extract_inline_attributes(attrs, &mut SpanMap::default()).ok()?;
+            let manifest = cfg.print().parse::<toml::Table>().ok()?;
+            let package = manifest.get("package")?;
+            Some((package.get("edition")?.as_str()?.to_string(),
+                package.get("resolver")?.as_str()?.to_string()))
+        };
+        let with_edition = |edition: &str| resolver(syn::parse::Parser::parse_str(
+            syn::Attribute::parse_inner, &format!("#![edition({edition})]")
+        ).ok()?);
+        let pair =
+            |edition: &str, resolver: &str| Some((edition.to_string(), resolver.to_string()));
+        assert_eq!(with_edition("2015"), pair("2015", "1"));
+        assert_eq!(with_edition("2018"), pair("2018", "2"));
+        assert_eq!(with_edition("2021"), pair("2021", "2"));
+        assert_eq!(with_edition("2024"), pair("2024", "3"));
+        assert_eq!(resolver(vec![]), pair("2024", "3"));
+        let explicit_resolver = resolver(vec![
+            syn::parse_quote! { #![edition(2021)] },
+            syn::parse_quote! { #![resolver(1)] },
+        ]);
+        assert_eq!(explicit_resolver, pair("2021", "1"));
+        assert_eq!(with_edition("2019"), None);
+        assert_eq!(with_edition("edition2021"), None);
+        let mut cfg = CargoConfig::default();
+        let attrs = vec![syn::parse_quote! { #![edition(2019)] }];
+        let error = cfg.extract_inline_attributes(attrs, &mut SpanMap::default()).err();
+        assert_eq!(error.map(|t| t.message),
+            Some("Incorrect edition '2019', expected one of: 2015, 2018, 2021, 2024.".to_string()));
+    }
+
+    #[test]
+    fn case_conversions() {
+        // Input, snake, shouty snake, kebab, camel, Pascal, Title. Expectations match `heck`.
+        let table = [
+            ["CamelCase", "camel_case", "CAMEL_CASE", "camel-case", "camelCase", "CamelCase", "Camel Case"],
+            ["snake_case", "snake_case", "SNAKE_CASE", "snake-case", "snakeCase", "SnakeCase", "Snake Case"],
+            ["XMLHttpRequest", "xml_http_request", "XML_HTTP_REQUEST", "xml-http-request", "xmlHttpRequest", "XmlHttpRequest", "Xml Http Request"],
+            ["kebab-case word", "kebab_case_word", "KEBAB_CASE_WORD", "kebab-case-word", "kebabCaseWord", "KebabCaseWord", "Kebab Case Word"],
+            ["ABC", "abc", "ABC", "abc", "abc", "Abc", "Abc"],
+            ["field2Name", "field2_name", "FIELD2_NAME", "field2-name", "field2Name", "Field2Name", "Field2 Name"],
+            ["__leading__trailing__", "leading_trailing", "LEADING_TRAILING", "leading-trailing", "leadingTrailing", "LeadingTrailing", "Leading Trailing"],
+            ["", "", "", "", "", "", ""],
+        ];
+        for [input, snake, shouty, kebab, camel, pascal, title] in table {
+            assert_eq!(case::to_snake_case(input), snake);
+            assert_eq!(case::to_shouty_snake_case(input), shouty);
+            assert_eq!(case::to_kebab_case(input), kebab);
+            assert_eq!(case::to_camel_case(input), camel);
+            assert_eq!(case::to_pascal_case(input), pascal);
+            assert_eq!(case::to_title_case(input), title);
+        }
+    }
+
+    #[test]
+    fn literal_escaping() {
+        assert_eq!(literal::escape_str("a\"b\\c\nd"), r#""a\"b\\c\nd""#);
+        let escaped = r#""\r\0\u{7f}\u{202e}zażółć 🦀""#;
+        assert_eq!(literal::escape_str("\r\0\u{7f}\u{202e}zażółć 🦀"), escaped);
+        assert_eq!(literal::escape_bytes(b"a\"\\\n\xff\x00"), r#"b"a\"\\\n\xff\x00""#);
+        assert_eq!(literal::const_str("NAME", "x"), r#"pub const NAME: &str = "x";"#);
+        assert_eq!(literal::static_bytes("DATA", b"x"), r#"pub static DATA: &[u8] = b"x";"#);
+    }
+
+    #[test]
+    fn inline_table_dependencies() {
+        let mut cfg = CargoConfig::default();
+        let attrs: Vec<syn::Attribute> = vec![
+            syn::parse_quote! { #![dependency(anyhow = 1)] },
+            syn::parse_quote! {
+                #![dependency(serde = {
+                    version = "1", features = ["derive", "rc"], default-features = false,
+                })]
+            },
+            syn::parse_quote! {
+                #![dependency(my-tables = {
+                    version = "0.2", optional = true, package = "tables",
+                    metadata = { docs = { all-features = true } },
+                })]
+            },
+            syn::parse_quote! { #![dependency(spaced = { git = "https://a.b/c d", tag = "v 1" })] },
+            syn::parse_quote! { #![dependency(pm2 = { package = "proc-macro2", version = "1" })] },
+        ];
+        assert!(cfg.extract_inline_attributes(attrs, &mut SpanMap::default()).is_ok());
+        let specs = cfg.dependencies.iter().map(Dependency::to_config_string).collect::<Vec<_>>();
+        assert_eq!(specs, [
+            "anyhow = \"1\"",
+            "serde = { version = \"1\", features = [\"derive\", \"rc\"], default-features = false }",
+            "my-tables = { version = \"0.2\", optional = true, package = \"tables\", \
+            metadata = { docs = { all-features = true } } }",
+            "spaced = { git = \"https://a.b/c d\", tag = \"v 1\" }",
+            "pm2 = { package = \"proc-macro2\", version = \"1\" }",
+        ]);
+        assert_eq!(cfg.dependencies[2].package_name(), "tables");
+        assert_eq!(cfg.dependencies[2].crate_name(), "my_tables");
+        assert_eq!(cfg.dependencies[4].package_name(), "proc-macro2");
+        assert_eq!(cfg.token_stream_crate(&TokenStream::new()).as_deref(), Some("pm2"));
+
+        let manifest = toml::from_str::<toml::Table>(&cfg.print()).ok();
+        let dependencies = manifest.as_ref()
+            .and_then(|t| t.get("dependencies"))
+            .and_then(toml::Value::as_table);
+        let serde = dependencies.and_then(|t| t.get("serde"));
+        assert_eq!(dependencies.map(toml::map::Map::len), Some(5));
+        assert_eq!(
+            serde.and_then(|t| t.get("default-features")).and_then(toml::Value::as_bool),
+            Some(false)
+        );
+        assert_eq!(
+            serde.and_then(|t| t.get("features")).and_then(toml::Value::as_array).map(Vec::len),
+            Some(2)
+        );
+
+        let invalid: Vec<syn::Attribute> = vec![
+            syn::parse_quote! { #![dependency(serde = { version = "1", features = derive })] },
+        ];
+        let error = cfg.extract_inline_attributes(invalid, &mut SpanMap::default())
+            .err().map(|e| e.message);
+        assert!(error.is_some_and(|t| t.starts_with(
+            "Invalid specification '{ version = \"1\", features = derive }' of dependency 'serde'"
+        )));
+        let missing: Vec<syn::Attribute> = vec![syn::parse_quote! { #![dependency(serde)] }];
+        assert!(cfg.extract_inline_attributes(missing, &mut SpanMap::default()).is_err());
+    }
+
+    #[test]
+    fn dependency_version_shorthands() {
+        let normalize = |value| Dependency::normalize_version("serde", value, Span::call_site()).ok();
+        assert_eq!(normalize("1"), Some("\"1\"".to_string()));
+        assert_eq!(normalize("1.0"), Some("\"1.0\"".to_string()));
+        assert_eq!(normalize("\"^1.2\""), Some("\"^1.2\"".to_string()));
+        assert_eq!(normalize("{version=\"1\"}"), Some("{version=\"1\"}".to_string()));
+        assert_eq!(normalize("\"garbage\""), None);
+        assert_eq!(normalize("1.2.3.4"), None);
+    }
+
+    fn config_with_dependencies(dependencies: &[(&str, &str)]) -> CargoConfig {
+        let dependencies = dependencies.iter()
+            .map(|(label, spec)| Dependency::new(label.to_string(), spec.to_string(), None))
+            .collect();
+        CargoConfig { dependencies, ..Default::default() }
+    }
+
+    #[test]
+    fn token_stream_crate_detection() {
+        let unused = quote! { fn f() -> usize { 1 } };
+        let used = quote! { let t: proc_macro2::TokenStream = quote!{}; };
+        let crate_of = |deps: &[(&str, &str)], tokens: &TokenStream| {
+            config_with_dependencies(deps).token_stream_crate(tokens)
+        };
+        let plain = [("proc-macro2", "\"1\"")];
+        assert_eq!(crate_of(&plain, &unused).as_deref(), Some("proc_macro2"));
+
+        // Renamed packages are resolved with the `package` key.
+        let renamed = [("pm2", "{package=\"proc-macro2\",version=\"1.0\"}")];
+        assert_eq!(crate_of(&renamed, &unused).as_deref(), Some("pm2"));
+
+        // Legacy versions are not supported, even if used.
+        let old_major = [("proc-macro2", "\"0.4\"")];
+        assert_eq!(crate_of(&old_major, &used), None);
+        let old_major_cfg = config_with_dependencies(&old_major);
+        assert_eq!(old_major_cfg.legacy_proc_macro2_dependencies().count(), 1);
+        assert_eq!(config_with_dependencies(&plain).legacy_proc_macro2_dependencies().count(), 0);
+
+        // Without a known dependency, the body decides.
+        assert_eq!(crate_of(&[], &used).as_deref(), Some("proc_macro2"));
+        assert_eq!(crate_of(&[], &unused), None);
+        assert_eq!(crate_of(&[("serde", "\"1\"")], &used).as_deref(), Some("proc_macro2"));
+
+        // With multiple candidates, the body decides too.
+        let ambiguous = [("proc-macro2", "\"1\""), ("pm2", "{package=\"proc-macro2\",version=\"1\"}")];
+        let used_renamed = quote! { fn f() -> pm2::TokenStream { todo!() } };
+        assert_eq!(crate_of(&ambiguous, &used_renamed).as_deref(), Some("pm2"));
+        assert_eq!(crate_of(&ambiguous, &unused), None);
+    }
+
+    #[test]
+    fn json_prelude_follows_dependencies() {
+        let crates_of = |deps: &[(&str, &str)]| {
+            config_with_dependencies(deps).prelude_crates(&TokenStream::new())
+        };
+        let no_json = crates_of(&[("serde", "\"1\"")]);
+        assert_eq!((no_json.serde_json, no_json.serde.as_deref()), (None, Some("serde")));
+
+        let renamed = crates_of(&[("json", "{package=\"serde_json\",version=\"1\"}")]);
+        assert_eq!(renamed.serde_json.as_deref(), Some("json"));
+        let value_only = gen_prelude_for_json("json", None);
+        assert!(value_only.contains("impl CodeFromOutput for json::Value"));
+        assert!(!value_only.contains("fn from_json_str"));
+        assert!(!value_only.contains("macro_rules! __crabtime_prelude_phase_cache"));
+
+        let with_serde = gen_prelude_for_json("serde_json", Some("serde"));
+        assert!(with_serde.contains("fn from_json_str<T: serde::de::DeserializeOwned>"));
+        assert!(with_serde.contains("fn to_json_string<T: serde::Serialize + ?Sized>"));
+        assert!(with_serde.contains("macro_rules! __crabtime_prelude_phase_cache"));
+    }
+
+    #[test]
+    fn macro_rules_attrs_hoisted() {
+        let item: syn::ItemFn = syn::parse_quote! {
+            #[cfg_attr(feature = "macros", macro_export)]
+            fn gen() {
+                #![cfg_attr(all(feature = "macros", unix), allow(unused), macro_export)]
+                #![doc = "Docs."]
+                #![cfg_attr(feature = "macros", allow(unused))]
+                #![dependency(serde = "1")]
+            }
+        };
+        let (outer, inner) = split_attrs(item.attrs);
+        let print = |attrs: Vec<syn::Attribute>| attrs.iter()
+            .map(|attr| attr.to_token_stream().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(print(outer), [
+            "# [cfg_attr (feature = \"macros\" , macro_export)]",
+            "# [cfg_attr (all (feature = \"macros\" , unix) , allow (unused) , macro_export)]",
+            "# [doc = \"Docs.\"]",
+        ]);
+        assert_eq!(print(inner), [
+            "# ! [cfg_attr (feature = \"macros\" , allow (unused))]",
+            "# ! [dependency (serde = \"1\")]",
+        ]);
+    }
+
+    #[test]
+    fn downstream_requirements_listed() {
+        let item: syn::ItemFn = syn::parse_quote! {
+            fn gen() {
+                #![dependency(anyhow = "1.0")]
+                #![dependency(serde = { version = "1", features = ["derive"] })]
+                let value: serde_json::Value = itertools::repeat_n(1, 2).collect();
+            }
+        };
+        let (_, inner) = split_attrs(item.attrs);
+        let body = item.block.to_token_stream();
+        let dependency = |label: &str, value: &str|
+            Dependency::new(label.to_string(), value.to_string(), None);
+        let defining_config = CargoConfig {
+            dependencies: vec![
+                dependency("serde", "\"1\""),
+                dependency("serde_json", "\"1\""),
+                dependency("regex", "\"1\""),
+            ],
+            ..Default::default()
+        };
+        let requirements = downstream_requirements(&inner, &body, Some(&defining_config));
+        assert_eq!(requirements.len(), 3);
+        assert_eq!(requirements[0], format!(
+            "`crabtime = \"{}\"` in `[dependencies]`.", env!("CARGO_PKG_VERSION")
+        ));
+        assert_eq!(requirements[1],
+            "Registry access for the inline dependencies `anyhow = \"1.0\"`, \
+            `serde = { version = \"1\", features = [\"derive\"] }`, fetched by the nested build."
+        );
+        assert!(requirements[2].starts_with("`serde_json = \"1\"` in `[build-dependencies]`"));
+
+        let docs = downstream_requirements_docs(&requirements).to_string();
+        assert!(docs.contains("# Downstream Requirements"));
+        assert!(docs.contains("anyhow = \\\"1.0\\\""));
+        assert_eq!(downstream_requirements(&[], &quote! {}, None).len(), 1);
+    }
+
+    #[test]
+    fn keyword_padding_modes() {
+        let tokens = "pub struct {{name}}for { x: usize } impl {{name}}for {}"
+            .parse::<TokenStream>().unwrap_or_default();
+        let intellij = print_tokens(&tokens, IdeCompat::IntelliJ.pad_keywords(&tokens));
+        let none = print_tokens(&tokens, IdeCompat::None.pad_keywords(&tokens));
+        let auto = print_tokens(&tokens, IdeCompat::Auto.pad_keywords(&tokens));
+        assert_eq!(intellij, " pub   struct  {name} for  {{x : usize}}  impl  {name} for  {{}} ");
+        assert_eq!(none, "pub struct {name}for {{x : usize}} impl {name}for {{}} ");
+        assert_eq!(auto, none);
+
+        // Tokens created by `quote!` share the call site span, as tokens provided by IntelliJ do.
+        let quoted = quote! { pub struct {{name}}for {} };
+        assert_eq!(
+            print_tokens(&quoted, IdeCompat::Auto.pad_keywords(&quoted)),
+            print_tokens(&quoted, IdeCompat::IntelliJ.pad_keywords(&quoted))
+        );
+
+        let options = syn::parse_str::<MacroOptions>("ide_compat = \"none\"").ok();
+        assert_eq!(options.map(|t| t.ide_compat), Some(IdeCompat::None));
+        assert!(syn::parse_str::<MacroOptions>("ide_compat = \"vim\"").is_err());
+    }
+
+    #[test]
+    fn range_argument_patterns() {
+        let pattern = |ty: syn::Type| parse_arg_type("dims", &ty).map(|t| t.0.to_string());
+        assert_eq!(pattern(syn::parse_quote!(std::ops::Range<usize>)).as_deref(),
+            Some("$ dims_start : literal .. $ dims_end : literal"));
+        assert_eq!(pattern(syn::parse_quote!(RangeInclusive<i8>)).as_deref(),
+            Some("$ dims_start : literal ..= $ dims_end : literal"));
+        assert_eq!(pattern(syn::parse_quote!(Range<f32>)), None);
+        assert_eq!(pattern(syn::parse_quote!(Range)), None);
+    }
+
+    #[test]
+    fn unconfigured_registries_reported() {
+        let mut cfg = CargoConfig::default();
+        let attrs: Vec<syn::Attribute> = vec![syn::parse_quote! {
+            #![dependency(corp-utils = { version = "1", registry = "crabtime-test-missing" })]
+        }];
+        assert!(cfg.extract_inline_attributes(attrs, &mut SpanMap::default()).is_ok());
+        assert_eq!(cfg.dependencies[0].registry().as_deref(), Some("crabtime-test-missing"));
+        let message = cfg.resolve_registries(&std::env::temp_dir()).err().map(|t| t.message);
+        assert_eq!(message.as_deref(), Some(
+            "Registry 'crabtime-test-missing' of dependency 'corp-utils' is not configured. Define \
+            it in the '[registries.crabtime-test-missing]' section of a '.cargo/config.toml' file \
+            of your project or set the 'CARGO_REGISTRIES_CRABTIME_TEST_MISSING_INDEX' environment \
+            variable."
+        ));
+        assert!(cfg.config_files.is_empty());
+    }
+
+    #[test]
+    fn minimal_manifest() {
+        let options = syn::parse_str::<MacroOptions>("minimal = true").ok();
+        assert_eq!(options.map(|t| t.minimal), Some(true));
+        let cfg = CargoConfig {
+            edition: Some("2021".to_string()),
+            minimal: true,
+            ..CargoConfig::default()
+        };
+        let manifest = toml::from_str::<toml::Table>(&cfg.print()).ok();
+        let keys = manifest.as_ref().map(|t| t.keys().cloned().collect::<Vec<_>>());
+        assert_eq!(keys, Some(vec!["package".to_string(), "workspace".to_string()]));
+        let edition = manifest.as_ref()
+            .and_then(|t| t.get("package")).and_then(|t| t.get("edition"))
+            .and_then(toml::Value::as_str);
+        assert_eq!(edition, Some("2021"));
+        assert!(RUSTC_VERSION.starts_with("rustc "));
+    }
+
+    #[test]
+    fn code_fn_argument_patterns() {
+        let parse = |ty: syn::Type| parse_arg_type("f", &ty)
+            .map(|(pat, code)| (pat.to_string(), code.to_string()));
+        let expected = Some(("$ f_arg : expr".to_string(), "$ f_arg".to_string()));
+        assert_eq!(parse(syn::parse_quote!(crabtime::CodeFn)), expected);
+        assert_eq!(parse(syn::parse_quote!(fn(&str) -> String)), expected);
+        let item = syn::parse_str::<syn::ItemFn>("fn gen(name: String, f: crabtime::CodeFn) {}");
+        let parsed = item.ok()
+            .and_then(|item| parse_args(&item.sig.inputs, &mut SpanMap::default()))
+            .map(|(args, code, _)| (args.pattern().to_string(), code.to_string()));
+        assert_eq!(parsed, Some((
+            "$ name_arg : expr , $ f_arg : expr $ (,) ?".to_string(),
+            "let name : String = crabtime :: stringify_if_needed ! ($ name_arg) . to_string () ; \
+            let f : crabtime :: CodeFn = $ f_arg ;".to_string(),
+        )));
+    }
+
+    #[test]
+    fn stringify_if_needed_expansion() {
+        let expand = |arg: &str| {
+            let input = format!("crabtime::stringify_if_needed!{{{arg}}}");
+            input.parse::<TokenStream>().ok().map(|t| expand_stringify_if_needed_macro(t).to_string())
+        };
+        assert_eq!(expand("std::collections::HashMap").as_deref(), Some("\"std::collections::HashMap\""));
+        assert_eq!(expand("\"my name\"").as_deref(), Some("\"my name\""));
+        assert_eq!(expand("r#\"raw\"#").as_deref(), Some("r#\"raw\"#"));
+        assert_eq!(expand("Name").as_deref(), Some("\"Name\""));
+        assert_eq!(expand("Vec<Option<u8>>").as_deref(), Some("\"Vec<Option<u8>>\""));
+        assert_eq!(expand("a  +   b").as_deref(), Some("\"a + b\""));
+        // Tokens without source locations, as in some IDEs.
+        assert_eq!(print_tokens_compact(&quote! { std::collections::HashMap<K, V> }),
+            "std::collections::HashMap < K, V >");
+        assert_eq!(print_tokens_compact(&quote! { a + b.len() }), "a + b.len ()");
+    }
+
+    #[test]
+    fn cargo_flags_reach_command() {
+        let flags = parse_cargo_flags(" --locked  --offline ").ok();
+        assert_eq!(flags, Some(vec!["--locked".to_string(), "--offline".to_string()]));
+        let flags = flags.unwrap_or_default();
+        let project_dir = Path::new("project");
+        let target_dir = project_dir.join("target");
+        let command = cargo_build_command(
+            Path::new("cargo"), project_dir, &target_dir, "host", Profile::Debug, &flags, None
+        );
+        let args = command.get_args().map(|t| t.to_string_lossy()).collect::<Vec<_>>();
+        let manifest = Path::new("project").join("Cargo.toml");
+        let manifest = manifest.to_string_lossy();
+        let target_dir = target_dir.to_string_lossy();
+        assert_eq!(args, [
+            "build", "--manifest-path", &manifest, "--target", "host", "--target-dir", &target_dir,
+            "--locked", "--offline"
+        ]);
+        assert_eq!(command.get_current_dir(), Some(Path::new("project").join(RUN_DIR).as_path()));
+    }
+
+    #[test]
+    fn span_map_records_ranges() {
+        let item = syn::parse_str::<syn::ItemFn>(
+            "fn gen(name: String, sizes: Vec<usize>) {\n    \
+                #![edition(2021)]\n    \
+                #![allow(unused, dead_code)]\n\
+            }"
+        );
+        #[allow(clippy::unwrap_used)]
+        let item = item.unwrap();
+        let position = |range: Option<&TokenRange>| range.map(|range| {
+            let span = range.span();
+            ((span.start().line, span.start().column), (span.end().line, span.end().column))
+        });
+        let mut spans = SpanMap::default();
+        assert!(parse_args(&item.sig.inputs, &mut spans).is_some());
+        let (_, inner) = split_attrs(item.attrs);
+        assert!(CargoConfig::default().extract_inline_attributes(inner, &mut spans).is_ok());
+        let options = syn::parse::Parser::parse_str(
+            |input: syn::parse::ParseStream| MacroOptions::parse_with_spans(input, &mut spans),
+            "cache = false, max_output = 1024"
+        );
+        assert!(options.is_ok());
+
+        let arg_names = spans.args.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>();
+        assert_eq!(arg_names, ["name", "sizes"]);
+        assert_eq!(position(spans.args.first().map(|t| &t.1)), Some(((1, 7), (1, 19))));
+        assert_eq!(position(spans.args.last().map(|t| &t.1)), Some(((1, 21), (1, 38))));
+        let args_span = spans.args_span();
+        assert_eq!((args_span.start().column, args_span.end().column), (7, 38));
+        assert_eq!(position(spans.attribute("edition")), Some(((2, 15), (2, 19))));
+        assert_eq!(position(spans.attribute("allow")), Some(((3, 13), (3, 30))));
+        assert_eq!(position(spans.attribute("resolver")), None);
+        assert_eq!(position(spans.option("cache")), Some(((1, 8), (1, 13))));
+        assert_eq!(position(spans.option("max_output")), Some(((1, 28), (1, 32))));
+    }
+
+    #[test]
+    fn notices_reported_once_per_build() {
+        let output = "[NOTICE] Option 'legacy' is deprecated.\n[OUTPUT] struct A;";
+        let parsed = parse_output("gen", output, &MacroOptions::default(), &SpanMap::default());
+        let parsed = parsed.ok().map(|t| (t.code, t.notices));
+        let notice = "Option 'legacy' is deprecated.".to_string();
+        assert_eq!(parsed, Some((" struct A;\n".to_string(), vec![notice.clone()])));
+
+        let path = std::env::temp_dir()
+            .join(format!("crabtime_notice_test_{}", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        // Two invocations of the same macro in one build, and one in the next build.
+        let claim = |build_id, name| claim_notice(&path, build_id, name, &notice).ok();
+        let claims = [claim(1, "gen"), claim(1, "gen"), claim(1, "other"), claim(2, "gen")];
+        std::fs::remove_file(&path).ok();
+        assert_eq!(claims, [Some(true), Some(false), Some(true), Some(true)]);
+    }
+
+    #[test]
+    fn dedup_item_names() {
+        let code = "pub struct A; impl A {} pub enum B {} const _: () = (); pub fn c() {} \
+            macro_rules! m { () => {} } pub mod d {} pub use d::*;";
+        assert_eq!(item_names(code), Some(vec!["A".into(), "B".into(), "c".into(), "d".into()]));
+        assert_eq!(item_names("1 + 2"), None);
+        let options = syn::parse_str::<MacroOptions>("dedup = true, dedup_path = \"crate :: gen\"");
+        assert_eq!(options.ok().and_then(|t| t.dedup_path), Some("crate::gen".to_string()));
+        assert!(syn::parse_str::<MacroOptions>("dedup_path = \"crate::\"").is_err());
+    }
+
+    #[test]
+    fn resolver_attribute() {
+        let mut cfg = CargoConfig::default();
+        let attrs: Vec<syn::Attribute> = vec![
+            syn::parse_quote! { #![resolver(2)] },
+            syn::parse_quote! { #![edition(2021)] },
+        ];
+        let mut spans = SpanMap::default();
+        assert_eq!(cfg.extract_inline_attributes(attrs, &mut spans).ok().as_deref(), Some(""));
+        assert!(cfg.print().contains("edition  = \"2021\""));
+        assert!(cfg.print().contains("resolver = \"2\""));
+        let attrs: Vec<syn::Attribute> = vec![syn::parse_quote! { #![resolver(4)] }];
+        let error = cfg.extract_inline_attributes(attrs, &mut spans).err().map(|e| e.message);
+        assert_eq!(error.as_deref(), Some("Incorrect resolver '4', expected one of: 1, 2, 3."));
+    }
+
+    #[test]
+    fn wrappers_removed_from_command() {
+        let project_dir = Path::new("project");
+        let target_dir = project_dir.join("target");
+        let command = cargo_build_command(
+            Path::new("cargo"), project_dir, &target_dir, "host", Profile::Debug, &[], None
+        );
+        let removed = command.get_envs()
+            .filter(|(_, value)| value.is_none())
+            .map(|(key, _)| key.to_string_lossy())
+            .collect::<Vec<_>>();
+        assert_eq!(removed, ["RUSTC_WORKSPACE_WRAPPER", "RUSTC_WRAPPER"]);
+    }
+
+    #[test]
+    fn lint_overrides() {
+        let lints_of = |attrs: Vec<syn::Attribute>| {
+            let mut cfg = CargoConfig::default();
+            cfg.lints.rust.insert("missing_docs".to_string(), "deny".into());
+            cfg.lints.rust.insert("unsafe_code".to_string(), "deny".into());
+            let pedantic = toml::from_str("level = \"deny\"\npriority = -1").ok()?;
+            cfg.lints.clippy.insert("pedantic".to_string(), toml::Value::Table(pedantic));
+            cfg.extract_inline_attributes(attrs, &mut SpanMap::default()).ok()?;
+            cfg.print().parse::<toml::Table>().ok()?.remove("lints")
+        };
+        let downgraded = lints_of(vec![syn::parse_quote! {
+            #![lints(missing_docs = "allow", clippy::pedantic = { level = "allow", priority = -1 })]
+        }]);
+        let expected = "[rust]\nmissing_docs = \"allow\"\nunsafe_code = \"deny\"\n\
+            [clippy]\npedantic = { level = \"allow\", priority = -1 }";
+        assert_eq!(downgraded, toml::from_str(expected).ok());
+        // Overrides are kept regardless of the order of the attributes.
+        let not_inherited = lints_of(vec![
+            syn::parse_quote! { #![lints(clippy::todo = "warn")] },
+            syn::parse_quote! { #![inherit_lints(false)] },
+        ]);
+        assert_eq!(not_inherited, toml::from_str("[rust]\n[clippy]\ntodo = \"warn\"").ok());
+        let error_of = |attr: syn::Attribute| {
+            let mut cfg = CargoConfig::default();
+            let result = cfg.extract_inline_attributes(vec![attr], &mut SpanMap::default());
+            result.err().map(|e| e.message)
+        };
+        let unknown_tool = error_of(syn::parse_quote! { #![lints(rustdoc::broken = "allow")] });
+        assert_eq!(unknown_tool.as_deref(),
+            Some("Incorrect lint 'rustdoc::broken', expected '<lint>' or 'clippy::<lint>'."));
+        assert!(error_of(syn::parse_quote! { #![lints(unused = 1)] }).is_some());
+        assert!(error_of(syn::parse_quote! { #![lints(unused)] }).is_some());
+        assert!(error_of(syn::parse_quote! { #![inherit_lints(no)] }).is_some());
+    }
+
+    #[test]
+    fn package_name_sanitized() {
+        assert_eq!(sanitize_package_name("gen_positions"), "gen_positions");
+        assert_eq!(sanitize_package_name("r#type"), "r_type");
+        assert_eq!(sanitize_package_name("gen-positions 2"), "gen_positions_2");
+        assert_eq!(sanitize_package_name("3d"), "_3d");
+        assert_eq!(sanitize_package_name("fn"), "fn_");
+        assert_eq!(sanitize_package_name("static"), "static_");
+        assert_eq!(sanitize_package_name("test"), "test_");
+        assert_eq!(sanitize_package_name("ünïcode"), "_n_code");
+        assert_eq!(sanitize_package_name("--"), DEFAULT_PACKAGE_NAME);
+        let mut cfg = CargoConfig::default();
+        assert!(cfg.print().contains("name     = \"eval_project\""));
+        let attr: syn::Attribute = syn::parse_quote! { #![package_name("gen-positions")] };
+        assert!(cfg.extract_inline_attributes(vec![attr], &mut SpanMap::default()).is_ok());
+        assert!(cfg.print().contains("name     = \"gen_positions\""));
+        let invalid: syn::Attribute = syn::parse_quote! { #![package_name(gen_positions)] };
+        let error = cfg.extract_inline_attributes(vec![invalid], &mut SpanMap::default())
+            .err().map(|e| e.message);
+        assert_eq!(error.as_deref(), Some(
+            "Incorrect package_name 'gen_positions', expected a string, like \"my_macro\"."
+        ));
+    }
+
+    #[test]
+    fn crate_attributes_come_first() {
+        let attrs: Vec<syn::Attribute> = vec![
+            syn::parse_quote! { #[allow(unused)] },
+            syn::parse_quote! { #![feature(iter_intersperse)] },
+            syn::parse_quote! { #![edition(2021)] },
+            syn::parse_quote! { #![recursion_limit = "256"] },
+        ];
+        let mut cfg = CargoConfig::default();
+        let other = cfg.extract_inline_attributes(attrs, &mut SpanMap::default()).ok();
+        let expected = "# ! [feature (iter_intersperse)]\n# ! [recursion_limit = \"256\"]\n\
+            # [allow (unused)]";
+        assert_eq!(other.as_deref(), Some(expected));
+        assert_eq!(cfg.edition.as_deref(), Some("2021"));
+    }
+
+    #[test]
+    fn clippy_lints_opt_out() {
+        let mut cfg = CargoConfig::default();
+        cfg.lints.clippy.insert("needless_range_loop".to_string(), "deny".into());
+        let keep: syn::Attribute = syn::parse_quote! { #![clippy_lints(true)] };
+        assert!(cfg.extract_inline_attributes(vec![keep], &mut SpanMap::default()).is_ok());
+        assert!(cfg.print().contains("needless_range_loop"));
+        let drop: syn::Attribute = syn::parse_quote! { #![clippy_lints(false)] };
+        assert!(cfg.extract_inline_attributes(vec![drop], &mut SpanMap::default()).is_ok());
+        assert!(!cfg.print().contains("needless_range_loop"));
+        let invalid: syn::Attribute = syn::parse_quote! { #![clippy_lints(no)] };
+        assert!(cfg.extract_inline_attributes(vec![invalid], &mut SpanMap::default()).is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn command_rendering() {
+        assert_eq!(shell_quote("target/debug/build"), "target/debug/build");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("my project"), "'my project'");
+        assert_eq!(shell_quote("it's $HOME"), "'it'\\''s $HOME'");
+        let project_dir = Path::new("/tmp/my project/it's");
+        let target = "x86_64-unknown-linux-gnu";
+        let target_dir = project_dir.join("target");
+        let mut command = cargo_build_command(
+            Path::new("cargo"), project_dir, &target_dir, target, Profile::Debug, &[], Some(2)
+        );
+        command.env(BUILD_ID_ENV, "1");
+        assert_eq!(render_command(&command), format!(
+            "cd '/tmp/my project/it'\\''s/{RUN_DIR}' && env -u RUSTC_WORKSPACE_WRAPPER \
+            -u RUSTC_WRAPPER {BUILD_ID_ENV}=1 TEMP='/tmp/my project/it'\\''s/{RUN_TMP_DIR}' \
+            TMP='/tmp/my project/it'\\''s/{RUN_TMP_DIR}' \
+            TMPDIR='/tmp/my project/it'\\''s/{RUN_TMP_DIR}' cargo build --manifest-path \
+            '/tmp/my project/it'\\''s/Cargo.toml' --target x86_64-unknown-linux-gnu \
+            --target-dir '/tmp/my project/it'\\''s/target' -j 2"
+        ));
+    }
+
+    #[test]
+    fn jobs_reach_command() {
+        let project_dir = Path::new("project");
+        let target_dir = project_dir.join("target");
+        let command = cargo_build_command(
+            Path::new("cargo"), project_dir, &target_dir, "host", Profile::Debug, &[], Some(2)
+        );
+        let args = command.get_args().map(|t| t.to_string_lossy()).collect::<Vec<_>>();
+        assert_eq!(args[args.len() - 2..], ["-j", "2"]);
+
+        let options = syn::parse_str::<MacroOptions>("cache = false, jobs = 3").ok();
+        assert_eq!(options.and_then(|t| t.jobs().ok()), Some(Some(3)));
+        assert!(syn::parse_str::<MacroOptions>("jobs = 0").is_err());
+        assert!(syn::parse_str::<MacroOptions>("jobs = \"2\"").is_err());
+    }
+
+    #[test]
+    fn cache_key_option() {
+        let key = |options: &str| syn::parse_str::<MacroOptions>(options).ok().map(|t| t.cache_key);
+        assert_eq!(key("cache_key = my_key"), Some(Some("my_key".into())));
+        assert_eq!(key("cache_key = \"my-key\", cache = false"), Some(Some("my-key".into())));
+        assert_eq!(key("cache = true"), Some(None));
+        assert_eq!(key("cache_key = \"../escape\""), None);
+        assert_eq!(key("cache_key = \"\""), None);
+        assert_eq!(key("cache_key = 1"), None);
+    }
+
+    #[test]
+    fn output_root_falls_back_when_out_dir_is_missing() {
+        let bogus_out_dir = std::env::temp_dir()
+            .join(format!("crabtime_missing_{}/debug/build/crabtime-0a1b/out", std::process::id()));
+        assert_eq!(Paths::output_root_with_override(None, &bogus_out_dir),
+            (std::env::temp_dir().join(CRATE), OutputRootSource::MissingOutDir));
+        let out_dir = Path::new(OUT_DIR);
+        assert_eq!(Paths::output_root_with_override(None, out_dir), Paths::output_root(out_dir));
+    }
+
+    #[test]
+    fn module_option_and_attribute() {
+        let module = |options: &str| syn::parse_str::<MacroOptions>(options).ok().map(|t| t.module);
+        assert_eq!(module("module = my_crate::gen"), Some(Some("my_crate::gen".into())));
+        assert_eq!(module("module = \"my_crate :: gen\", cache = false"),
+            Some(Some("my_crate::gen".into())));
+        assert_eq!(module("module = ::my_crate"), None);
+        assert_eq!(module("module = gen::<T>"), None);
+
+        let item = |attrs: &str| syn::parse_str::<syn::ItemFn>(&format!("{attrs} fn gen() {{}}"));
+        let extracted = item("#[doc = \"Docs.\"] #[module(my_crate::gen)]").ok().map(|mut item| {
+            let path = extract_module_attr(&mut item.attrs).ok().flatten();
+            (path.map(|t| quote!{#t}.to_string()), item.attrs.len())
+        });
+        assert_eq!(extracted, Some((Some("my_crate :: gen".into()), 1)));
+        let invalid = item("#[module(::my_crate)]").ok()
+            .map(|mut item| extract_module_attr(&mut item.attrs).is_err());
+        assert_eq!(invalid, Some(true));
+    }
+
+    #[test]
+    fn lenient_options_passed_through() {
+        let options = syn::parse_str::<MacroOptions>("jobs = 2, lenient, flavor = fancy, x = a::b");
+        let options = options.ok();
+        let passthrough = options.as_ref().map(|t| {
+            t.passthrough.iter().map(|(k, v)| (k.as_str(), v.to_string())).collect::<Vec<_>>()
+        });
+        assert_eq!(options.as_ref().and_then(|t| t.jobs), Some(2));
+        assert_eq!(passthrough, Some(vec![("flavor", "fancy".into()), ("x", "a :: b".into())]));
+        assert!(syn::parse_str::<MacroOptions>("flavor = fancy, lenient").is_ok());
+        assert!(syn::parse_str::<MacroOptions>("flavor = fancy").is_err());
+        assert!(syn::parse_str::<MacroOptions>("lenient = true, flavor = fancy").is_err());
+        assert!(syn::parse_str::<MacroOptions>("lenient, flavor =").is_err());
+        // Known options are still validated.
+        assert!(syn::parse_str::<MacroOptions>("lenient, jobs = 0").is_err());
+    }
+
+    // Retries print warnings, which use the nightly diagnostics API, available only in macros.
+    #[cfg(all(unix, not(nightly)))]
+    #[test]
+    fn transient_failures_are_retried() {
+        let counter = std::env::temp_dir()
+            .join(format!("crabtime_retry_test_{}", std::process::id()));
+        fs::remove_file(&counter).ok();
+        // Stands for a nested cargo whose first run fails to download a dependency.
+        let script = format!(
+            "if [ -e '{0}' ]; then echo done; else touch '{0}'; \
+            echo 'error: failed to download from `https://static.crates.io`' >&2; exit 101; fi",
+            counter.display()
+        );
+        let mut runs = 0;
+        let output = run_with_retries(1, std::time::Duration::ZERO, || {
+            runs += 1;
+            wait_until(Command::new("sh").arg("-c").arg(&script), None, |_| {})?
+                .context("Failed to run the script")
+        }).ok();
+        fs::remove_file(&counter).ok();
+        assert_eq!(runs, 2);
+        assert_eq!(output.map(|t| t.stdout), Some(b"done\n".to_vec()));
+
+        let mut runs = 0;
+        let output = run_with_retries(3, std::time::Duration::ZERO, || {
+            runs += 1;
+            wait_until(Command::new("sh").arg("-c").arg(
+                "echo 'error: could not compile `project`' >&2; exit 101"
+            ), None, |_| {})?.context("Failed to run the script")
+        }).ok();
+        assert_eq!(runs, 1);
+        assert_eq!(output.map(|t| t.status.success()), Some(false));
+
+        let options = syn::parse_str::<MacroOptions>("retries = 0").ok();
+        assert_eq!(options.map(|t| t.retries), Some(0));
+        assert_eq!(MacroOptions::default().retries, DEFAULT_RETRIES);
+    }
+
+    #[test]
+    fn skeleton_errors_contain_path() {
+        // A file in place of the project directory makes creating the `src` directory fail.
+        let project_dir = std::env::temp_dir()
+            .join(format!("crabtime_skeleton_error_test_{}", std::process::id()));
+        let written = fs::write(&project_dir, "").ok();
+        let result = create_project_skeleton(&project_dir, &CargoConfig::default(), "");
+        fs::remove_file(&project_dir).ok();
+        assert_eq!(written, Some(()));
+        let message = result.err().map(|e| e.message).unwrap_or_default();
+        let expected = format!("Failed to create directory '{}'", project_dir.join("src").display());
+        assert!(message.starts_with(&expected), "{message}");
+    }
+
+    #[test]
+    fn unchanged_projects_not_rewritten() {
+        let project_dir = TempDir::new("unchanged");
+        let files = [project_dir.join("Cargo.toml"), project_dir.join("src/main.rs")];
+        let old_time = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1 << 30);
+        let cfg = CargoConfig::default();
+        let expand = |main: &str| create_project_skeleton(&project_dir, &cfg, main).ok();
+        // Marks the files as old, so that any write is visible in their modification times.
+        let age_files = || files.iter().all(|t| {
+            File::options().write(true).open(t).and_then(|f| f.set_modified(old_time)).is_ok()
+        });
+        let old_files = || files.iter()
+            .map(|t| fs::metadata(t).and_then(|m| m.modified()).is_ok_and(|t| t == old_time))
+            .collect::<Vec<_>>();
+        let first = expand("fn main() {}");
+        let aged = age_files();
+        let second = expand("fn main() {}");
+        let kept = old_files();
+        let third = expand("fn main() { }");
+        let rewritten = old_files();
+        assert_eq!(first, Some(ProjectState::New));
+        assert!(aged);
+        assert_eq!(second, Some(ProjectState::Unchanged));
+        assert_eq!(kept, [true, true]);
+        assert_eq!(third, Some(ProjectState::Changed));
+        assert_eq!(rewritten, [true, false]);
+    }
+
+    #[test]
+    fn memoized_outputs_reused_for_same_code() {
+        let project_dir = TempDir::new("memo");
+        let options = syn::parse_str::<MacroOptions>("memoize = true").ok();
+        assert_eq!(options.map(|t| t.memoize), Some(true));
+        let cfg = CargoConfig::default();
+        let header = memo_header(&cfg, "fn main() {}");
+        let output = "[OUTPUT] struct A;\n";
+        let created = fs::create_dir_all(&project_dir).ok();
+        let stored = memoize_output(&project_dir, &header, output).ok();
+        let reused = read_memoized_output(&project_dir, &header);
+        let other_code = read_memoized_output(&project_dir, &memo_header(&cfg, "fn main() { }"));
+        let release = CargoConfig { profile: Profile::Release, ..CargoConfig::default() };
+        let other_cfg = read_memoized_output(&project_dir, &memo_header(&release, "fn main() {}"));
+        let non_reusable = format!("{NON_REUSABLE_MARKER}\n{output}");
+        let skipped = memoize_output(&project_dir, &header, &non_reusable).ok();
+        let after_skipped = read_memoized_output(&project_dir, &header);
+        assert_eq!(created, Some(()));
+        assert!(header.starts_with(&project_stamp()));
+        assert_eq!(stored, Some("on, stored"));
+        assert_eq!(reused.as_deref(), Some(output));
+        assert_eq!((other_code, other_cfg), (None, None));
+        assert_eq!(skipped, Some("on, skipped for a non-reusable output"));
+        assert_eq!(after_skipped, None);
+    }
+
+    #[test]
+    fn unexpected_project_entries_ignore_cargo_files() {
+        let before = ["Cargo.toml", "src", "run", "tmp"].map(std::ffi::OsString::from);
+        let after = ["Cargo.toml", "Cargo.lock", "build.rs", "src", "run", "target", "tmp"]
+            .map(std::ffi::OsString::from);
+        assert_eq!(unexpected_project_entries(&before, &after), ["build.rs"]);
+    }
+
+    #[test]
+    fn dependency_free_projects_skip_cargo() {
+        let dir = TempDir::new("rustc");
+        let main = "fn main() { println!(\"[OUTPUT] 1\"); }";
+        let run = |name: &str, cfg: &CargoConfig| {
+            let project_dir = dir.join(name);
+            let output = create_project_skeleton(&project_dir, cfg, main)
+                .and_then(|_| run_cargo_project(&project_dir, None, cfg, &RunSettings::default()))
+                .ok();
+            let built_with_rustc = project_dir.join("target").join(RUSTC_BIN_DIR).exists();
+            let built_with_cargo = project_dir.join("Cargo.lock").exists();
+            (output, built_with_rustc, built_with_cargo)
+        };
+        let plain = run("plain", &CargoConfig::default());
+        let mut with_lints = CargoConfig::default();
+        with_lints.lints.rust.insert("unsafe_code".to_string(), "forbid".into());
+        let linted = run("linted", &with_lints);
+        assert_eq!(plain, (Some("[OUTPUT] 1\n".to_string()), true, false));
+        assert_eq!(linted, (Some("[OUTPUT] 1\n".to_string()), false, true));
+    }
+
+    #[test]
+    fn cargo_projects_built_and_run_separately() {
+        let project_dir = TempDir::new("cargo_build");
+        // Rust lints make the project built with cargo instead of rustc.
+        let mut cfg = CargoConfig::default();
+        cfg.lints.rust.insert("unsafe_code".to_string(), "forbid".into());
+        let settings = RunSettings { name: "gen_values", ..RunSettings::default() };
+        let run = |main: &str| create_project_skeleton(&project_dir, &cfg, main)
+            .and_then(|_| run_cargo_project(&project_dir, None, &cfg, &settings));
+        let built = run("fn main() { let unused = 1; println!(\"[OUTPUT] 1\"); }").ok();
+        let broken = run("fn main() { println!(\"{}\", missing); }").err().map(|t| t.message);
+        let failed = run("fn main() { std::process::exit(3); }").err().map(|t| t.message);
+        assert_eq!(built.as_deref(), Some("[OUTPUT] 1\n"));
+        assert!(broken.as_ref().is_some_and(|t| t.contains("Compilation of the generated code")));
+        assert!(broken.as_ref().is_some_and(|t| t.contains(" build --manifest-path ")));
+        assert!(broken.is_some_and(|t| !t.contains(CARGO_MESSAGE_FORMAT)));
+        assert!(failed.is_some_and(|t| t.starts_with("Macro 'gen_values' failed with exit")));
+    }
+
+    #[test]
+    fn toolchain_from_env_values() {
+        let toolchain = Toolchain::new(None, Some("".into()));
+        assert_eq!(toolchain, Toolchain { cargo: "cargo".into(), rustc: "rustc".into() });
+        let toolchain = Toolchain::new(Some("/opt/rust/cargo".into()), Some("/opt/rust/rustc".into()));
+        assert_eq!(toolchain.cargo, Path::new("/opt/rust/cargo"));
+        assert_eq!(toolchain.rustc, Path::new("/opt/rust/rustc"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn toolchain_wrappers_used() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TempDir::new("toolchain");
+        let project_dir = dir.join("project");
+        let target_dir = project_dir.join("target");
+        let log = dir.join("invocations");
+        let toolchain = Toolchain::from_env();
+        let wrapper = |name: &str, binary: &Path| {
+            let path = dir.join(name);
+            let script = format!(
+                "#!/bin/sh\necho {name} >> '{}'\nexec '{}' \"$@\"\n", log.display(), binary.display()
+            );
+            fs::write(&path, script).ok()?;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).ok()?;
+            Some(path)
+        };
+        let main = "fn main() { println!(\"[OUTPUT] 1\"); }";
+        let cfg = CargoConfig::default();
+        let prepared = create_project_skeleton(&project_dir, &cfg, main)
+            .and_then(|_| prepare_run_dirs(&project_dir))
+            .ok();
+        let cargo = wrapper("cargo-wrapper", &toolchain.cargo).unwrap_or_default();
+        let rustc = wrapper("rustc-wrapper", &toolchain.rustc).unwrap_or_default();
+        let build = |cargo: &Path| CargoBuild {
+            cargo, project_dir: &project_dir, target_dir: &target_dir, host_target: HOST_TARGET,
+            cfg: &cfg, flags: &[],
+        }.run(&RunSettings::default(), None);
+        let binary = build(&cargo).ok().is_some_and(|t| t.is_file());
+        let missing_cargo = dir.join("missing-cargo");
+        let error = build(&missing_cargo).err().map(|t| t.message_with_cause());
+        let rustc_command =
+            rustc_run_command(&rustc, &project_dir, &cfg, RUSTC_VERSION, HOST_TARGET, None).ok();
+        let invocations = fs::read_to_string(&log).ok();
+        assert_eq!(prepared, Some(()));
+        assert!(binary);
+        assert!(error.is_some_and(|t| t.contains(&missing_cargo.display().to_string())));
+        assert!(rustc_command.is_some_and(|t| t.is_some()));
+        assert_eq!(invocations.as_deref(), Some("cargo-wrapper\nrustc-wrapper\n"));
+    }
+
+    #[test]
+    fn hanging_macros_stopped_at_timeout() {
+        let project_dir = TempDir::new("timeout");
+        let main = "fn main() { println!(\"[OUTPUT] 1\"); loop { std::thread::yield_now(); } }";
+        let cfg = CargoConfig::default();
+        let settings = RunSettings {
+            name: "gen_hanging",
+            timeout: Some(std::time::Duration::from_secs(10)),
+            ..RunSettings::default()
+        };
+        let start = std::time::Instant::now();
+        let error = create_project_skeleton(&project_dir, &cfg, main)
+            .and_then(|_| run_cargo_project(&project_dir, None, &cfg, &settings))
+            .err()
+            .map(|t| t.message);
+        let elapsed = start.elapsed();
+        assert!(elapsed < std::time::Duration::from_secs(30));
+        assert!(error.as_ref().is_some_and(|t| t.contains("Macro 'gen_hanging' did not finish")));
+        assert!(error.is_some_and(|t| t.contains(&project_dir.display().to_string())));
+
+        let options = syn::parse_str::<MacroOptions>("timeout_secs = 30").ok();
+        let timeout = options.and_then(|t| t.timeout().ok()).flatten();
+        assert_eq!(timeout, Some(std::time::Duration::from_secs(30)));
+        let disabled = syn::parse_str::<MacroOptions>("timeout_secs = 0").ok();
+        assert_eq!(disabled.and_then(|t| t.timeout().ok()), Some(None));
+    }
+
+    #[test]
+    fn streamed_output_keeps_protocol_lines_in_order() {
+        let project_dir = TempDir::new("stream");
+        let main = "fn main() {
+            let pause = || std::thread::sleep(std::time::Duration::from_millis(200));
+            println!(\"processing\");
+            pause();
+            println!(\"[OUTPUT] const A: u8 = 1;\");
+            pause();
+            println!(\"[WARNING] slow input\");
+            println!(\"processed 10 rows\");
+            pause();
+            println!(\"[OUTPUT] const B: u8 = 2;\");
+        }";
+        let cfg = CargoConfig::default();
+        let output = create_project_skeleton(&project_dir, &cfg, main)
+            .and_then(|_| run_cargo_project(&project_dir, None, &cfg, &RunSettings::default()))
+            .ok();
+        let expected =
+            "[OUTPUT] const A: u8 = 1;\n[WARNING] slow input\n[OUTPUT] const B: u8 = 2;\n";
+        assert_eq!(output.as_deref(), Some(expected));
+    }
+
+    #[test]
+    fn stderr_forwarding_can_be_disabled() {
+        assert!(MacroOptions::default().forward_stderr);
+        let options = syn::parse_str::<MacroOptions>("forward_stderr = false").ok();
+        assert_eq!(options.map(|t| t.forward_stderr), Some(false));
+        assert!(syn::parse_str::<MacroOptions>("forward_stderr = \"no\"").is_err());
+    }
+
+    #[test]
+    fn protocol_lines_recognized() {
+        assert!(is_protocol_line("  [OUTPUT] struct A;"));
+        assert!(is_protocol_line("[WARNING] careful"));
+        assert!(is_protocol_line(NON_REUSABLE_MARKER));
+        assert!(!is_protocol_line("processed 10 rows"));
+    }
+
+    #[test]
+    fn subprojects_share_target_dir() {
+        let dir = TempDir::new("subprojects");
+        let target_dir = dir.join(SHARED_TARGET_DIR);
+        let run = |subproject: &str, value: usize| {
+            let project_dir = dir.join(subproject);
+            let mut cfg = CargoConfig {
+                package_name: Some("gen".to_string()),
+                bin_name: Some(format!("gen_{subproject}")),
+                ..CargoConfig::default()
+            };
+            // Lints make the project built with cargo instead of rustc.
+            cfg.lints.rust.insert("unsafe_code".to_string(), "forbid".into());
+            let main = format!("fn main() {{ println!(\"[OUTPUT] {value}\"); }}");
+            create_project_skeleton(&project_dir, &cfg, &main)
+                .and_then(|_| {
+                    run_cargo_project(
+                        &project_dir, Some(&target_dir), &cfg, &RunSettings::default()
+                    )
+                })
+                .ok()
+        };
+        let first = run("args_a", 1);
+        let second = run("args_b", 2);
+        let first_again = run("args_a", 1);
+        let own_targets = ["args_a", "args_b"].map(|t| dir.join(t).join("target").exists());
+        let binaries = fs::read_dir(target_dir.join(HOST_TARGET).join("debug")).into_iter()
+            .flatten()
+            .flatten()
+            .filter(|t| t.file_name().to_string_lossy().starts_with("gen_args_"))
+            .filter(|t| t.path().extension().is_none_or(|ext| ext == "exe"))
+            .count();
+        assert_eq!(first.as_deref(), Some("[OUTPUT] 1\n"));
+        assert_eq!(second.as_deref(), Some("[OUTPUT] 2\n"));
+        assert_eq!(first_again.as_deref(), Some("[OUTPUT] 1\n"));
+        assert_eq!(own_targets, [false, false]);
+        assert_eq!(binaries, 2);
+    }
+
+    #[test]
+    fn dep_info_sources_parsed() {
+        let dep_info = "/t/gen: /p/src/main.rs /p/my\\ dep/lib.rs\n\n/p/src/main.rs:\n";
+        let sources = dep_info_sources(dep_info);
+        assert_eq!(sources, [PathBuf::from("/p/src/main.rs"), PathBuf::from("/p/my dep/lib.rs")]);
+    }
+
+    #[test]
+    fn unchanged_binaries_run_without_cargo() {
+        let project_dir = TempDir::new("binary");
+        let mut cfg = CargoConfig::default();
+        // Lints make the project built with cargo instead of rustc.
+        cfg.lints.rust.insert("unsafe_code".to_string(), "forbid".into());
+        let main = |value: usize| format!("fn main() {{ println!(\"[OUTPUT] {value}\"); }}");
+        let run = || run_cargo_project(&project_dir, None, &cfg, &RunSettings::default()).ok();
+        let expand = |value| {
+            create_project_skeleton(&project_dir, &cfg, &main(value)).ok().and_then(|_| run())
+        };
+        let first = expand(1);
+        let stamped = project_dir.join(CARGO_BINARY_STAMP_FILE).is_file();
+        // Cargo fails on a broken manifest, so the output of this run comes from the binary.
+        let broken = fs::write(project_dir.join("Cargo.toml"), "[broken").ok();
+        let direct = run();
+        // Sources newer than the binary make it outdated, so cargo is run and fails.
+        let future = std::time::SystemTime::now() + std::time::Duration::from_secs(60);
+        let touched = File::options().write(true).open(project_dir.join("src/main.rs"))
+            .and_then(|t| t.set_modified(future))
+            .ok();
+        let outdated = run();
+        let rebuilt = expand(2);
+        assert_eq!(first.as_deref(), Some("[OUTPUT] 1\n"));
+        assert!(stamped);
+        assert_eq!((broken, touched), (Some(()), Some(())));
+        assert_eq!(direct.as_deref(), Some("[OUTPUT] 1\n"));
+        assert_eq!(outdated, None);
+        assert_eq!(rebuilt.as_deref(), Some("[OUTPUT] 2\n"));
+    }
+
+    #[test]
+    fn release_profile() {
+        let options = syn::parse_str::<MacroOptions>("profile = \"release\"").ok();
+        assert_eq!(options.map(|t| t.profile), Some(Profile::Release));
+        assert!(syn::parse_str::<MacroOptions>("profile = \"fast\"").is_err());
+        let mut cfg = CargoConfig::default();
+        let attr: syn::Attribute = syn::parse_quote! { #![profile(release)] };
+        assert!(cfg.extract_inline_attributes(vec![attr], &mut SpanMap::default()).is_ok());
+        assert_eq!(cfg.profile, Profile::Release);
+        let invalid: syn::Attribute = syn::parse_quote! { #![profile(fast)] };
+        let error = cfg.extract_inline_attributes(vec![invalid], &mut SpanMap::default());
+        let error = error.err().map(|e| e.message);
+        let expected = "Incorrect profile 'fast', expected one of: debug, release.";
+        assert_eq!(error.as_deref(), Some(expected));
+        let project_dir = Path::new("project");
+        let target_dir = project_dir.join("target");
+        let command = cargo_build_command(
+            Path::new("cargo"), project_dir, &target_dir, "host", Profile::Release, &[], None
+        );
+        assert!(command.get_args().any(|t| t == "--release"));
+
+        // Toggling the profile keeps the binaries of both.
+        let project_dir = TempDir::new("profile");
+        let main = "fn main() { println!(\"[OUTPUT] {}\", cfg!(debug_assertions)); }";
+        let run = |cfg: &CargoConfig| create_project_skeleton(&project_dir, cfg, main)
+            .and_then(|_| run_cargo_project(&project_dir, None, cfg, &RunSettings::default()))
+            .ok();
+        let release = run(&cfg);
+        let debug = run(&CargoConfig::default());
+        let bin_dir = project_dir.join("target").join(RUSTC_BIN_DIR);
+        let bin_dirs = (bin_dir.join("debug").is_dir(), bin_dir.join("release").is_dir());
+        assert_eq!(release.as_deref(), Some("[OUTPUT] false\n"));
+        assert_eq!(debug.as_deref(), Some("[OUTPUT] true\n"));
+        assert_eq!(bin_dirs, (true, true));
+    }
+
+    #[test]
+    fn profile_settings() {
+        let settings_of = |attrs: Vec<syn::Attribute>| {
+            let mut cfg = CargoConfig::default();
+            cfg.extract_inline_attributes(attrs, &mut SpanMap::default()).ok()?;
+            cfg.print().parse::<toml::Table>().ok()?.remove("profile")
+        };
+        let dev = settings_of(vec![syn::parse_quote! {
+            #![profile_settings(opt_level = 3, debug = false, lto = "thin")]
+        }]);
+        let expected = "[dev]\nopt-level = 3\ndebug = false\nlto = \"thin\"";
+        assert_eq!(dev, toml::from_str(expected).ok());
+        let release = settings_of(vec![
+            syn::parse_quote! { #![profile_settings(codegen_units = 1)] },
+            syn::parse_quote! { #![profile(release)] },
+        ]);
+        assert_eq!(release, toml::from_str("[release]\ncodegen-units = 1").ok());
+        assert_eq!(settings_of(vec![]), None);
+        // Settings are part of the manifest, so changing them changes the cache fingerprint.
+        let mut cfg = CargoConfig::default();
+        let plain = cfg.print();
+        cfg.profile_settings.insert("opt-level".to_string(), 3.into());
+        assert_ne!(plain, cfg.print());
+        let error_of = |attr: syn::Attribute| {
+            let mut cfg = CargoConfig::default();
+            let result = cfg.extract_inline_attributes(vec![attr], &mut SpanMap::default());
+            result.err().map(|e| e.message)
+        };
+        let unknown = error_of(syn::parse_quote! { #![profile_settings(fast = true)] });
+        assert_eq!(unknown, Some(format!(
+            "Unknown profile setting 'fast', expected one of: {}.", PROFILE_SETTINGS.join(", ")
+        )));
+        assert!(error_of(syn::parse_quote! { #![profile_settings(opt_level = fast)] }).is_some());
+        assert!(error_of(syn::parse_quote! { #![profile_settings(debug)] }).is_some());
+    }
+
+    #[test]
+    fn same_named_macros_of_crates_use_distinct_dirs() {
+        // Crates `a` and `b` of one workspace both define `gen_ids!` and call it in `src/lib.rs`.
+        let root = Path::new("workspace/target/debug/build/crabtime");
+        let dir = |crate_name: Option<&str>| {
+            Paths::crate_output_root(root, crate_name.map(str::to_string))
+                .join("src/lib")
+                .join("gen_ids")
+        };
+        assert_eq!(dir(Some("a")), root.join("crates/a/src/lib/gen_ids"));
+        assert_eq!(dir(Some("b")), root.join("crates/b/src/lib/gen_ids"));
+        assert_eq!(dir(None), root.join("src/lib/gen_ids"));
+    }
+
+    #[test]
+    fn build_scripts_of_packages_use_distinct_dirs() {
+        let name = |crate_name: Option<&str>, package: Option<&str>| Paths::qualified_crate_name(
+            crate_name.map(str::to_string), package.map(str::to_string)
+        );
+        assert_eq!(name(Some("build_script_build"), Some("my-app")),
+            Some("my_app_build_script_build".to_string()));
+        assert_eq!(name(Some("my_app"), Some("my-app")), Some("my_app".to_string()));
+        assert_eq!(name(Some("build_script_build"), None), Some("build_script_build".to_string()));
+        assert_eq!(name(None, Some("my-app")), None);
+    }
+
+    #[test]
+    fn call_sites_relative_to_workspace() {
+        // Cargo reports the call site of a `#[cfg(test)]` macro relative to the workspace, and Rust
+        // Analyzer as an absolute path. Both have to resolve to the same project dir.
+        let dir = TempDir::new("call_site");
+        let member = dir.join("member");
+        let written = fs::create_dir_all(member.join("src")).ok()
+            .and_then(|_| fs::write(dir.join("Cargo.toml"), "[workspace]\n").ok())
+            .and_then(|_| fs::write(member.join("Cargo.toml"), "[package]\n").ok());
+        let absolute = Paths::relative_call_site(&member.join("src/lib.rs"), Some(&member));
+        let root_crate = Paths::relative_call_site(&dir.join("src/lib.rs"), Some(&dir));
+        assert_eq!(written, Some(()));
+        assert_eq!(absolute, Path::new("member/src/lib"));
+        assert_eq!(Paths::relative_call_site(Path::new("member/src/lib.rs"), None), absolute);
+        assert_eq!(root_crate, Path::new("src/lib"));
+    }
+
+    #[test]
+    fn call_sites_of_doctests() {
+        let file = |call_site: &str| Paths::doctest_source_file(call_site);
+        assert_eq!(file("src/lib.rs - my_mod::Item (line 42)"), Some(PathBuf::from("src/lib.rs")));
+        assert_eq!(file("src/lib.rs - (line 3)"), Some(PathBuf::from("src/lib.rs")));
+        assert_eq!(file("/ws/my crate/src/gen.rs - gen (line 7)"),
+            Some(PathBuf::from("/ws/my crate/src/gen.rs")));
+        assert_eq!(file("src/lib.rs"), None);
+        assert_eq!(file("src/notes (line x)"), None);
+        assert_eq!(file("src/(line 3)"), None);
+    }
+
+    #[test]
+    fn call_sites_of_other_target_kinds() {
+        let dir = TempDir::new("target_kinds");
+        let member = dir.join("member");
+        let files = ["Cargo.toml", "member/Cargo.toml", "member/tests/gen.rs",
+            "member/examples/demo.rs", "member/benches/bench.rs", "member/src/gen_items.rs"];
+        let written = files.iter().try_for_each(|file| {
+            fs::create_dir_all(dir.join(file).parent().unwrap_or(&dir))?;
+            fs::write(dir.join(file), "")
+        });
+        // Relative to the workspace root, like cargo reports them, or to the crate directory, like
+        // the files of doctests.
+        let resolve = |call_site: &str| {
+            Paths::resolve_call_site(Path::new(call_site), Some(&member))
+        };
+        let call_sites =
+            ["member/tests/gen.rs", "examples/demo.rs", "member/benches/bench.rs", "src/gen_items.rs"];
+        let resolved = call_sites.map(resolve);
+        let configs = find_cargo_configs(&resolved[0].with_extension("")).ok()
+            .map(|t| t.crate_config);
+        let missing = resolve("tests/missing.rs");
+        assert_eq!(written.ok(), Some(()));
+        assert_eq!(resolved, [member.join("tests/gen.rs"), member.join("examples/demo.rs"),
+            member.join("benches/bench.rs"), member.join("src/gen_items.rs")]);
+        assert_eq!(configs, Some(member.join("Cargo.toml")));
+        assert!(missing.is_absolute() && missing.ends_with("tests/missing.rs"));
+    }
+
+    #[test]
+    fn call_sites_of_path_dependencies() {
+        // A crate outside of the built workspace, used as a path dependency, is reported with
+        // absolute paths, and its files are resolved against its own workspace.
+        let dir = TempDir::new("path_dependency");
+        let app = dir.join("app");
+        let dependency = dir.join("libs").join("shapes");
+        let written = fs::create_dir_all(app.join("src")).ok()
+            .and_then(|_| fs::create_dir_all(dependency.join("src/gen")).ok())
+            .and_then(|_| fs::write(app.join("Cargo.toml"), "[workspace]\n[package]\n").ok())
+            .and_then(|_| fs::write(dependency.join("Cargo.toml"), "[package]\n").ok());
+        let call_site = dependency.join("src/gen/mod.rs");
+        let relative = Paths::relative_call_site(&call_site, Some(&dependency));
+        let configs = find_cargo_configs(&call_site).ok()
+            .map(|t| (t.crate_config, t.workspace_config));
+        // Without manifests, the crate directory is used, and unknown files stay relative.
+        let orphan = dir.join("orphan");
+        let without_manifest = Paths::relative_call_site(&orphan.join("src/lib.rs"), Some(&orphan));
+        let unknown = Paths::relative_call_site(&orphan.join("src/lib.rs"), None);
+        assert_eq!(written, Some(()));
+        assert_eq!(relative, Path::new("src/gen/mod"));
+        assert_eq!(configs, Some((dependency.join("Cargo.toml"), None)));
+        assert_eq!(without_manifest, Path::new("src/lib"));
+        assert!(unknown.is_relative() && unknown.ends_with("orphan/src/lib"));
+    }
+
+    #[test]
+    fn project_dir_collisions_reported() {
+        let dir = TempDir::new("owner");
+        let first = claim_project_dir(&dir, 1, 10).ok();
+        let same_code = claim_project_dir(&dir, 1, 10).ok();
+        let other_code = claim_project_dir(&dir, 1, 11).err().map(|e| e.message);
+        let next_build = claim_project_dir(&dir, 2, 11).ok();
+        // A quick rebuild of edited code runs in another compiler process within the same build.
+        let owner = format!("2\t{}\t{:016x}", std::process::id() + 1, 12);
+        fs::write(dir.join(PROJECT_OWNER_FILE), owner).ok();
+        let next_process = claim_project_dir(&dir, 2, 11).ok();
+        assert_eq!((first, same_code, next_build), (Some(()), Some(()), Some(())));
+        assert_eq!(next_process, Some(()));
+        assert!(other_code.is_some_and(|t| t.contains("Two expansions with different code")));
+    }
+
+    #[test]
+    fn outdated_project_cleared() {
+        let dir = TempDir::new("stamp");
+        let stamp = project_stamp();
+        let outdated = stamp.replace(env!("CARGO_PKG_VERSION"), "0.0.0");
+        let result = lock_project_dir(&dir).and_then(|_lock| {
+            let written = fs::create_dir_all(dir.join("src"))
+                .and_then(|_| fs::write(dir.join("src/main.rs"), "mod old_prelude {}"))
+                .and_then(|_| fs::write(dir.join(PROJECT_STAMP_FILE), &outdated))
+                .ok();
+            let cleared = clear_outdated_project(&dir, &stamp)?;
+            let files = (dir.join("src").exists(), dir.join(LOCK_FILE).exists());
+            let current = fs::read_to_string(dir.join(PROJECT_STAMP_FILE)).ok();
+            let up_to_date = clear_outdated_project(&dir, &stamp)?;
+            Ok((written, cleared, files, current, up_to_date))
+        });
+        let expected = (Some(()), true, (false, true), Some(stamp), false);
+        assert_eq!(result.ok(), Some(expected));
+    }
+
+    fn cached_test_paths(macro_dir: &Path) -> Paths {
+        Paths {
+            workspace: macro_dir.to_path_buf(),
+            output_root: macro_dir.to_path_buf(),
+            output_root_source: OutputRootSource::OutDirOption,
+            macro_dir: macro_dir.to_path_buf(),
+            output_dir: Paths::project_dir(macro_dir, false),
+            #[cfg(nightly)]
+            call_site_file: macro_dir.to_path_buf(),
+            #[cfg(nightly)]
+            crate_config: macro_dir.to_path_buf(),
+            one_shot_output_dir: false,
+            args_specific_output_dir: false,
+            cargo_toml_path: None,
+        }
+    }
+
+    #[test]
+    fn cached_project_locked_exclusively() {
+        use std::io::Write;
+        let macro_dir = TempDir::new("exclusive_lock");
+        let paths = cached_test_paths(&macro_dir);
+        // Two expansions of the same project, each writing its code in two steps.
+        let in_use = std::sync::atomic::AtomicBool::new(false);
+        let results = std::thread::scope(|scope| {
+            let threads = (0..2).map(|i| {
+                let (paths, in_use) = (&paths, &in_use);
+                scope.spawn(move || paths.with_output_dir(|dir| {
+                    let overlapped = in_use.swap(true, std::sync::atomic::Ordering::SeqCst);
+                    let main = dir.join("main.rs");
+                    fs::write(&main, format!("{i}")).context("Failed to write the file.")?;
+                    std::thread::sleep(std::time::Duration::from_millis(100));
+                    let mut file = fs::OpenOptions::new().append(true).open(&main)
+                        .context("Failed to open the file.")?;
+                    file.write_all(b"!").context("Failed to write the file.")?;
+                    let content = fs::read_to_string(&main).context("Failed to read the file.")?;
+                    in_use.store(false, std::sync::atomic::Ordering::SeqCst);
+                    Ok((dir.clone(), overlapped, content == format!("{i}!")))
+                }).ok())
+            }).collect::<Vec<_>>();
+            threads.into_iter().map(|t| t.join().ok().flatten()).collect::<Vec<_>>()
+        });
+        let project = Some((paths.output_dir.clone(), false, true));
+        assert_eq!(results, [project.clone(), project]);
+    }
+
+    #[test]
+    fn locked_cached_project_falls_back_to_one_shot() {
+        let macro_dir = TempDir::new("lock_timeout");
+        let paths = cached_test_paths(&macro_dir);
+        let timeout = std::time::Duration::from_millis(100);
+        let fallback = lock_project_dir_exclusive(&paths.output_dir, timeout).ok().flatten()
+            .and_then(|_lock| paths.lock_output_dir(timeout).ok())
+            .map(|(dir, _, one_shot)| {
+                (dir.starts_with(macro_dir.join(ONE_SHOT_PROJECTS_DIR)), one_shot)
+            });
+        assert_eq!(fallback, Some((true, true)));
+    }
+
+    #[test]
+    fn locked_project_dir_not_removed() {
+        let dir = TempDir::new("lock");
+        let first = lock_project_dir(&dir);
+        let second = lock_project_dir(&dir);
+        assert!(first.is_ok() && second.is_ok());
+        if let (Ok(first), Ok(second)) = (first, second) {
+            remove_project_dir_if_unused(&dir, first);
+            assert!(dir.exists());
+            remove_project_dir_if_unused(&dir, second);
+            assert!(!dir.exists());
+        }
+    }
+
+    #[test]
+    fn failed_removals_retried_and_trashed() {
+        let dir = TempDir::new("trash");
+        let macro_dir = dir.join("project_0");
+        let project = |name: &str| {
+            let project = macro_dir.join(ONE_SHOT_PROJECTS_DIR).join(name);
+            fs::create_dir_all(project.join("src")).ok().map(|_| project)
+        };
+        // A file kept open by another process, like an antivirus scanner, for a few attempts.
+        let attempts = std::cell::Cell::new(0);
+        let retried = project("retried").map(|project| {
+            remove_with_retries(&project, |t| {
+                attempts.set(attempts.get() + 1);
+                if attempts.get() < 3 { Err(std::io::ErrorKind::PermissionDenied.into()) }
+                else { fs::remove_dir_all(t) }
+            });
+            project.exists()
+        });
+        // A file kept open until the next expansion.
+        let trashed = project("trashed").map(|project| {
+            remove_with_retries(&project, |_| Err(std::io::ErrorKind::PermissionDenied.into()));
+            let trash = fs::read_dir(macro_dir.join(ONE_SHOT_PROJECTS_DIR)).into_iter().flatten()
+                .flatten()
+                .filter(|t| t.file_name().to_string_lossy().starts_with("trashed."))
+                .count();
+            collect_garbage(&macro_dir, std::time::Duration::MAX);
+            let remaining = fs::read_dir(macro_dir.join(ONE_SHOT_PROJECTS_DIR)).into_iter()
+                .flatten()
+                .count();
+            (project.exists(), trash, remaining)
+        });
+        // A partially removed project is not reported as cached, and its skeleton is rewritten.
+        let partial = macro_dir.join("partial");
+        let rewritten = fs::create_dir_all(&partial)
+            .and_then(|_| fs::write(partial.join("src"), ""))
+            .ok()
+            .and_then(|_| create_project_skeleton(&partial, &CargoConfig::default(), "").ok())
+            .map(|existed| (existed, partial.join("src/main.rs").is_file()));
+        assert_eq!((retried, attempts.get()), (Some(false), 3));
+        assert_eq!(trashed, Some((false, 1, 0)));
+        assert_eq!(rewritten, Some((ProjectState::New, true)));
+    }
+
+    #[test]
+    fn abandoned_and_stale_projects_collected() {
+        let dir = TempDir::new("garbage");
+        let hour = std::time::Duration::from_secs(60 * 60);
+        let create_project = |macro_dir: &Path, one_shot: bool| {
+            let project_dir = Paths::project_dir(macro_dir, one_shot);
+            fs::create_dir_all(&project_dir).ok()?;
+            fs::write(project_dir.join(LOCK_FILE), "").ok()?;
+            fs::write(macro_dir.join(LAST_USED_FILE), "").ok()?;
+            Some(project_dir)
+        };
+        let set_last_used = |macro_dir: &Path, age: std::time::Duration| {
+            let time = std::time::SystemTime::now() - age;
+            File::options().write(true).open(macro_dir.join(LAST_USED_FILE))
+                .and_then(|t| t.set_modified(time))
+                .ok()
+        };
+        let current = dir.join(format!("project_{:032x}", 1));
+        let stale = dir.join(format!("project_{:032x}", 2));
+        let recent = dir.join(format!("project_{:032x}", 3));
+        let stale_in_use = dir.join(format!("project_{:032x}", 4));
+        let other_kind = dir.join("phase_cache");
+        let setup = (|| {
+            let abandoned = create_project(&current, true)?;
+            let running = create_project(&current, true)?;
+            let running_lock = lock_project_dir(&running).ok()?;
+            create_project(&stale, false)?;
+            create_project(&stale, true)?;
+            set_last_used(&stale, 48 * hour)?;
+            create_project(&recent, false)?;
+            let in_use = create_project(&stale_in_use, false)?;
+            let in_use_lock = lock_project_dir(&in_use).ok()?;
+            set_last_used(&stale_in_use, 48 * hour)?;
+            fs::create_dir_all(&other_kind).ok()?;
+            Some((abandoned, running, running_lock, in_use_lock))
+        })();
+        let result = setup.map(|(abandoned, running, _running_lock, _in_use_lock)| {
+            collect_garbage(&current, 24 * hour);
+            [&abandoned, &running, &stale, &recent, &stale_in_use, &other_kind].map(|t| t.exists())
+        });
+        assert_eq!(result, Some([false, true, false, true, true, true]));
+    }
+
+    #[test]
+    fn least_recently_used_dirs_selected_for_eviction() {
+        let minute = std::time::Duration::from_secs(60);
+        let now = std::time::SystemTime::now();
+        let usage = |name: &str, minutes_ago: u32, size: u64| MacroDirUsage {
+            path: PathBuf::from(name), last_used: now - minute * minutes_ago, size
+        };
+        let dirs = vec![
+            usage("recent", 1, 30), usage("oldest", 30, 10), usage("current", 40, 50),
+            usage("old", 20, 20),
+        ];
+        let select = |total: u64, max: u64| {
+            select_evictions(dirs.clone(), total, max, Path::new("current"))
+        };
+        assert_eq!(select(110, 200), Vec::<PathBuf>::new());
+        assert_eq!(select(110, 100), [PathBuf::from("oldest")]);
+        assert_eq!(select(110, 85), [PathBuf::from("oldest"), PathBuf::from("old")]);
+        // The current directory is kept even if the size cannot fit the maximum without it.
+        assert_eq!(select(110, 0), ["oldest", "old", "recent"].map(PathBuf::from));
+    }
+
+    #[test]
+    fn cache_size_capped() {
+        let dir = TempDir::new("cache_size");
+        let create_macro_dir = |name: &str, hours_ago: u64| {
+            let macro_dir = dir.join(name);
+            let project_dir = Paths::project_dir(&macro_dir, false);
+            fs::create_dir_all(&project_dir).ok()?;
+            fs::write(project_dir.join(LOCK_FILE), "").ok()?;
+            fs::write(project_dir.join("data"), vec![0; 1000]).ok()?;
+            let last_used = std::time::SystemTime::now()
+                - std::time::Duration::from_secs(hours_ago * 60 * 60);
+            fs::write(macro_dir.join(LAST_USED_FILE), "").ok()?;
+            File::options().write(true).open(macro_dir.join(LAST_USED_FILE))
+                .and_then(|t| t.set_modified(last_used))
+                .ok()?;
+            Some(project_dir)
+        };
+        let setup = (|| {
+            let locked = create_macro_dir("locked", 3)?;
+            let lock = lock_project_dir(&locked).ok()?;
+            create_macro_dir("old", 2)?;
+            create_macro_dir("recent", 1)?;
+            create_macro_dir("current", 4)?;
+            Some(lock)
+        })();
+        let result = setup.map(|_lock| {
+            evict_least_recently_used(&dir, &dir.join("current"), 2500);
+            let evicted = ["locked", "old", "recent", "current"].map(|t| dir.join(t).exists());
+            // The size is not checked again right away.
+            evict_least_recently_used(&dir, &dir.join("current"), 0);
+            (evicted, dir.join("recent").exists())
+        });
+        assert_eq!(result, Some(([true, false, true, true], true)));
+    }
+
+    #[test]
+    fn switching_cache_mode_cleans_other_mode() {
+        let dir = TempDir::new("cache_mode");
+        let create_project = |macro_dir: &Path, one_shot: bool| {
+            let project_dir = Paths::project_dir(macro_dir, one_shot);
+            fs::create_dir_all(&project_dir).ok()?;
+            fs::write(project_dir.join(LOCK_FILE), "").ok()?;
+            fs::write(project_dir.join("Cargo.toml"), "").ok()?;
+            Some(project_dir)
+        };
+        let transitions = [(false, false), (false, true), (true, false), (true, true)];
+        let results = transitions.map(|(was_one_shot, one_shot)| {
+            let macro_dir = dir.join(format!("macro_{was_one_shot}_{one_shot}"));
+            let old_project = create_project(&macro_dir, was_one_shot)?;
+            remove_other_mode_projects(&macro_dir, one_shot);
+            let new_project = Paths::project_dir(&macro_dir, one_shot);
+            Some((old_project.exists(), new_project != old_project))
+        });
+        // One-shot projects locked by running expansions are kept.
+        let macro_dir = dir.join("macro_locked");
+        let locked_project = create_project(&macro_dir, true)
+            .and_then(|project| Some((lock_project_dir(&project).ok()?, project)))
+            .map(|(_lock, project)| {
+                remove_other_mode_projects(&macro_dir, false);
+                project.exists()
+            });
+        assert_eq!(results, [
+            Some((true, false)),
+            Some((false, true)),
+            Some((false, true)),
+            Some((true, true)),
+        ]);
+        assert_eq!(locked_project, Some(true));
+        assert!(Paths::project_dir(&dir, false).ends_with(CACHED_PROJECT_DIR));
+        assert!(Paths::project_dir(&dir, true).parent()
+            .is_some_and(|t| t.ends_with(ONE_SHOT_PROJECTS_DIR)));
+    }
+
+    #[test]
+    fn external_target_dir_workspace() {
+        let dir = TempDir::new("target_dir");
+        let workspace = dir.join("workspace");
+        let member = workspace.join("member");
+        let shared = dir.join("shared");
+        let written = fs::create_dir_all(&member)
+            .and_then(|_| fs::write(workspace.join("Cargo.toml"), "[workspace]"))
+            .and_then(|_| fs::write(member.join("Cargo.toml"), "[package]"))
+            .ok();
+        let output_dir = |target: &Path| target.join("debug/build/crabtime/project_0");
+        let workspace_dir = |output_dir: &Path, target_dir: Option<&Path>| {
+            Paths::workspace_dir(output_dir, target_dir, Some(&member)).ok()
+        };
+        // The `target` directory of the workspace.
+        let local = workspace_dir(&output_dir(&workspace.join("target")), None);
+        // A shared directory named `target`, which is not a parent of the crate.
+        let named_target = workspace_dir(&output_dir(&shared.join("target")), None);
+        // A shared directory set with `CARGO_TARGET_DIR`.
+        let target_dir = shared.join("ssd");
+        let from_env = workspace_dir(&output_dir(&target_dir), Some(&target_dir));
+        let without_manifest =
+            Paths::workspace_dir(&output_dir(&workspace.join("target")), None, None).ok();
+        let unknown = Paths::workspace_dir(&output_dir(&target_dir), None, None).ok();
+        assert_eq!(written, Some(()));
+        assert_eq!(local.as_ref(), Some(&workspace));
+        assert_eq!(named_target.as_ref(), Some(&workspace));
+        assert_eq!(from_env.as_ref(), Some(&workspace));
+        assert_eq!(without_manifest.as_ref(), Some(&workspace));
+        assert_eq!(unknown, None);
+    }
+
+    #[test]
+    fn output_root_from_out_dir_layout() {
+        let root = |out_dir: &str| Some(Paths::output_root(Path::new(out_dir)).0);
+        let expected = |build_dir: &str| Some(Path::new(build_dir).join(CRATE));
+        assert_eq!(root("ws/target/debug/build/crabtime-internal-0a1b/out"),
+            expected("ws/target/debug/build"));
+        // Directories set with `CARGO_TARGET_DIR` can have any names, including `build`.
+        assert_eq!(root("/builds/ci/artifacts/debug/build/crabtime-internal-0a1b/out"),
+            expected("/builds/ci/artifacts/debug/build"));
+        assert_eq!(root("/build/release/build/crabtime-internal-0a1b/out"),
+            expected("/build/release/build"));
+        assert_eq!(root("/cache/debug/build/crabtime/nested"), expected("/cache/debug/build"));
+        assert_eq!(root("/cache/debug/crabtime/nested"), Some(Paths::fallback_output_root()));
+    }
+
+    #[test]
+    fn output_root_fallback_chain() {
+        let source = |out_dir: &str| Paths::output_root(Path::new(out_dir));
+        assert_eq!(source("/t/debug/build/crabtime-internal-0a1b/out").1, OutputRootSource::BuildDir);
+        assert_eq!(source("/t/debug/build/crabtime/nested"),
+            (Path::new("/t/debug/build").join(CRATE), OutputRootSource::BuildAncestor));
+        // Layouts of build wrappers, like cross-rs, without a `build` directory.
+        assert_eq!(source("/ws/target/x86_64-unknown-linux-gnu/crabtime-0a1b/output"),
+            (Path::new("/ws/target/x86_64-unknown-linux-gnu").join(CRATE),
+                OutputRootSource::TargetProfile));
+        assert_eq!(source("/opt/artifacts/crabtime-0a1b/output"),
+            (Paths::fallback_output_root(), OutputRootSource::TempDir));
+    }
+
+    #[test]
+    fn output_root_overridden() {
+        let sandbox_dir = TempDir::new("sandbox");
+        let unknown_layout = Path::new("/nix/store/0a1b-crabtime-out");
+        let root = |value: Option<&Path>| {
+            let value = value.map(|t| t.to_string_lossy().to_string());
+            Some(Paths::output_root_with_override(value, unknown_layout).0)
+        };
+        assert_eq!(root(Some(&sandbox_dir)), Some(sandbox_dir.to_path_buf()));
+        assert_eq!(root(None), Some(Paths::fallback_output_root()));
+        assert_eq!(root(Some(Path::new(" "))), Some(Paths::fallback_output_root()));
+        let relative = root(Some(Path::new("crabtime_out")));
+        assert!(relative.is_some_and(|t| t.is_absolute() && t.ends_with("crabtime_out")));
+    }
+
+    #[test]
+    fn cargo_flags_reject_not_allowed() {
+        assert!(parse_cargo_flags("--locked --manifest-path Cargo.toml").is_err());
+        assert!(parse_cargo_flags("--config build.rustflags=[]").is_err());
+        assert!(parse_cargo_flags("").is_ok_and(|t| t.is_empty()));
+    }
+
+    #[test]
+    fn prelude_helpers_included_when_used() {
+        let has_addons = |body: TokenStream| {
+            gen_prelude_helpers(&body.to_string()).contains("pub fn sum_combinations")
+        };
+        assert!(!has_addons(quote!{ crabtime::output! { const X: usize = 1; } }));
+        assert!(has_addons(quote!{ let sums = crabtime::sum_combinations(3); }));
+        assert!(has_addons(quote!{ use crabtime::ident; }));
+        assert!(has_addons(quote!{ include!("helpers.rs"); }));
+        assert!(gen_prelude_helpers("").is_empty());
+        let case_only = gen_prelude_helpers("crabtime :: to_snake_case (\"Foo\")");
+        assert_eq!(case_only, PRELUDE_CASE);
+    }
+
+    #[test]
+    fn call_args_transcribed_and_split() {
+        let transcribe = |pattern: TokenStream| pattern_transcriber(&pattern).to_string();
+        assert_eq!(transcribe(quote!{ $($input:tt)* }), quote!{ $($input)* }.to_string());
+        assert_eq!(
+            transcribe(quote!{ $name:ident, [$($xs:expr),+] $(,)? }),
+            quote!{ $name, [$($xs),+] }.to_string()
+        );
+        assert_eq!(
+            transcribe(quote!{ $($k:ident => $($v:literal)|*);* }),
+            quote!{ $($k => $($v)|*);* }.to_string()
+        );
+        let call_args = |args: TokenStream| {
+            let item = quote!{ fn f() { #![__crabtime_args(#args)] } };
+            let mut attrs = syn::parse2::<syn::ItemFn>(item).map(|t| t.attrs).ok()?;
+            let call_args = extract_call_args(&mut attrs);
+            Some((call_args, attrs.len()))
+        };
+        assert_eq!(call_args(quote!{}), Some((vec![], 0)));
+        let args = [quote!{ (1, (2, 3)) }, quote!{ [4, [5]] }, quote!{ {6, 7} }, quote!{ x }];
+        assert_eq!(
+            call_args(quote!{ #(#args),*, }),
+            Some((args.iter().map(|t| t.to_string()).collect(), 0))
+        );
+    }
+
+    // Paths on nightly depend on the call site, which is available only within a macro.
+    #[cfg(not(nightly))]
+    mod stable_paths {
+        use super::*;
+
+        /// Paths of the expansion of the macro with the given options, like `cache_key = shared`.
+        fn paths(options: &str, macro_name: &str, input: &str) -> Option<Paths> {
+            let options = syn::parse_str::<MacroOptions>(options).ok()?;
+            Paths::new(&options, macro_name, input).ok()
+        }
+
+        #[test]
+        fn same_cache_key_shares_project() {
+            let project = |options: &str, input: &str| {
+                paths(options, "gen", input).map(|t| (t.output_dir, t.one_shot_output_dir))
+            };
+            let first = project("cache_key = shared", "fn main() {}");
+            let second = project("cache_key = \"shared\"", "fn main() {}");
+            let other_key = project("cache_key = other", "fn main() {}");
+            let other_input = project("cache_key = shared", "fn main() { }");
+            let one_shot = project("cache_key = shared, cache = false", "fn main() {}");
+            assert!(first.as_ref().is_some_and(|(dir, one_shot)| {
+                let key_dir = dir.parent().and_then(Path::parent);
+                !one_shot && dir.ends_with(CACHED_PROJECT_DIR)
+                    && key_dir.is_some_and(|t| t.ends_with("key_shared"))
+            }));
+            assert_eq!(first, second);
+            assert_ne!(first, other_key);
+            assert_ne!(first, other_input);
+            assert!(one_shot.is_some_and(|(_, one_shot)| one_shot));
+        }
+
+        #[test]
+        fn module_projects_separate_per_macro() {
+            let project = |options: &str, macro_name: &str| {
+                let paths = paths(options, macro_name, "fn main() {}");
+                paths.map(|t| (t.output_dir, t.one_shot_output_dir))
+            };
+            let first = project("module = my_crate::gen", "gen_a");
+            let second = project("module = my_crate::gen", "gen_a");
+            let other_macro = project("module = my_crate::gen", "gen_b");
+            let other_module = project("module = my_crate::other", "gen_a");
+            assert!(first.as_ref().is_some_and(|(dir, one_shot)| {
+                let macro_dir = dir.parent().and_then(Path::parent);
+                !one_shot && macro_dir.is_some_and(|t| t.ends_with("my_crate/gen/gen_a"))
+            }));
+            assert_eq!(first, second);
+            assert_ne!(first, other_macro);
+            assert_ne!(first, other_module);
+        }
+
+        #[test]
+        fn cached_subprojects_named_after_arguments() {
+            let first = paths("cache_key = shared", "gen", "fn main() {}");
+            let second = paths("cache_key = shared", "gen", "fn main() { }");
+            let subprojects = [&first, &second].map(|t| t.as_ref().and_then(Paths::subproject));
+            assert!(subprojects[0].as_ref().is_some_and(|t| t.starts_with("args_")));
+            assert_ne!(subprojects[0], subprojects[1]);
+            let target_dirs =
+                [&first, &second].map(|t| t.as_ref().and_then(Paths::shared_target_dir));
+            let key_dir = target_dirs[0].as_ref().and_then(|t| t.parent());
+            assert!(key_dir.is_some_and(|t| t.ends_with("key_shared")));
+            assert_eq!(target_dirs[0], target_dirs[1]);
+            let one_shot = paths("cache_key = shared, cache = false", "gen", "fn main() {}");
+            assert_eq!(one_shot.and_then(|t| t.shared_target_dir()), None);
+            assert_eq!(paths("", "gen", "fn main() {}").and_then(|t| t.subproject()), None);
+        }
+
+        #[test]
+        fn one_shot_dirs_are_unique() {
+            let input = format!("one_shot_dirs_are_unique_{}", std::process::id());
+            let results = std::thread::scope(|scope| {
+                let input = &input;
+                let threads = (0..16).map(|i| scope.spawn(move || {
+                    let options = MacroOptions { cache: false, ..MacroOptions::default() };
+                    let paths = Paths::new(&options, "gen", input).ok()?;
+                    paths.with_output_dir(|dir| {
+                        let file = dir.join("main.rs");
+                        fs::write(&file, i.to_string()).context("Failed to write the file.")?;
+                        std::thread::sleep(std::time::Duration::from_millis(50));
+                        let content =
+                            fs::read_to_string(&file).context("Failed to read the file.")?;
+                        Ok((dir.clone(), content == i.to_string()))
+                    }).ok()
+                })).collect::<Vec<_>>();
+                threads.into_iter().map(|t| t.join().ok().flatten()).collect::<Vec<_>>()
+            });
+            let dirs = results.iter().flatten().filter(|(_, valid)| *valid).map(|(dir, _)| dir)
+                .collect::<std::collections::HashSet<_>>();
+            assert_eq!(dirs.len(), 16);
+            assert!(dirs.iter().all(|dir| !dir.exists()));
+        }
+
+        #[test]
+        fn stable_projects_cached_by_content() {
+            let dir = TempDir::new("stable_cache");
+            let options = format!("out_dir = {:?}", dir.to_string_lossy());
+            let first = paths(&options, "gen", "fn main() {}");
+            let second = paths(&options, "gen", "fn main() {}");
+            let changed = paths(&options, "gen", "fn main() { let _ = 1; }");
+            let one_shot = paths(&format!("{options}, cache = false"), "gen", "fn main() {}");
+            let cfg = CargoConfig::default();
+            let expand = |paths: &Paths| paths.with_output_dir(|dir| {
+                Ok((dir.clone(), create_project_skeleton(dir, &cfg, "fn main() {}")?))
+            }).ok();
+            let expansions = first.as_ref().and_then(expand).zip(second.as_ref().and_then(expand));
+            let output_dir = |paths: &Option<Paths>| paths.as_ref().map(|t| t.output_dir.clone());
+            assert_eq!(first.as_ref().map(|t| t.one_shot_output_dir), Some(false));
+            assert!(output_dir(&first).is_some_and(|t| t.ends_with(CACHED_PROJECT_DIR)));
+            assert_eq!(output_dir(&first), output_dir(&second));
+            assert_ne!(output_dir(&first), output_dir(&changed));
+            assert_eq!(one_shot.map(|t| t.one_shot_output_dir), Some(true));
+            // The second identical expansion reuses the project, with its compiled dependencies.
+            let states = expansions.filter(|(first, second)| first.0 == second.0)
+                .map(|(first, second)| (first.1, second.1));
+            assert_eq!(states, Some((ProjectState::New, ProjectState::Unchanged)));
+        }
+
+        #[test]
+        fn projects_created_in_out_dir_option() {
+            let dir = TempDir::new("out_dir");
+            let options = format!("out_dir = {:?}, cache_key = sandboxed", dir.to_string_lossy());
+            let paths = paths(&options, "gen", "fn main() {}");
+            let project_dir = paths.as_ref().and_then(|paths| {
+                paths.with_output_dir(|dir| Ok(dir.is_dir().then(|| dir.clone()))).ok().flatten()
+            });
+            assert!(project_dir.is_some_and(|t| t.starts_with(&dir)));
+            assert!(syn::parse_str::<MacroOptions>("out_dir = \"\"").is_err());
+        }
+    }
 }
@@ -0,0 +1,61 @@
+// Literal escaping available in the generated prelude. This file is embedded verbatim in the
+// prelude of every generated project, so it must not depend on anything but `std` and must not
+// use inner attributes or inner doc comments. Unlike `{:?}`, which follows the `Debug` rules, the
+// escaping follows the Rust literal syntax: all characters are kept except the ones a literal
+// cannot contain or the compiler rejects, like lone carriage returns and bidirectional overrides.
+
+/// Whether the character has to be escaped in a string literal. Control characters are escaped for
+/// readability, and bidirectional formatting characters are rejected by the compiler.
+fn must_escape_char(c: char) -> bool {
+    c.is_control() || matches!(c, '\u{200E}' | '\u{200F}' | '\u{202A}'..='\u{202E}'
+        | '\u{2066}'..='\u{2069}')
+}
+
+/// Returns the string as a Rust string literal, including the quotes.
+pub fn escape_str(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\0' => out.push_str("\\0"),
+            c if must_escape_char(c) => out.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Returns the bytes as a Rust byte string literal, including the `b` prefix and the quotes.
+pub fn escape_bytes(value: &[u8]) -> String {
+    let mut out = String::with_capacity(value.len() + 3);
+    out.push_str("b\"");
+    for &byte in value {
+        match byte {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            b' '..=b'~' => out.push(byte as char),
+            _ => out.push_str(&format!("\\x{byte:02x}")),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Returns a `pub const {name}: &str` item with the given value.
+pub fn const_str(name: &str, value: &str) -> String {
+    format!("pub const {name}: &str = {};", escape_str(value))
+}
+
+/// Returns a `pub static {name}: &[u8]` item with the given value.
+pub fn static_bytes(name: &str, value: &[u8]) -> String {
+    format!("pub static {name}: &[u8] = {};", escape_bytes(value))
+}
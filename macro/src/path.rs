@@ -1,4 +1,5 @@
 use crate::error::*;
+use std::path::Component;
 use std::path::Path;
 
 pub fn parent(path: &Path) -> Result<&Path> {
@@ -13,4 +14,38 @@ pub fn find_parent<'t>(path: &'t Path, dir_name: &str) -> Result<&'t Path> {
             "Path '{}' does not have parent '{dir_name}' directory.",
             path.display()
         ))
-}
\ No newline at end of file
+}
+/// Whether the relative path matches the glob, like the `members` of a cargo workspace. The glob
+/// supports `*` and `?` within a component, and `**` matching any number of components.
+pub fn matches_glob(glob: &str, path: &Path) -> bool {
+    let glob = glob.split(['/', '\\']).filter(|t| !t.is_empty() && *t != ".").collect::<Vec<_>>();
+    let components = path.components()
+        .filter_map(|t| match t {
+            Component::Normal(name) => Some(name.to_string_lossy().to_string()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    matches_components(&glob, &components)
+}
+
+fn matches_components(glob: &[&str], components: &[String]) -> bool {
+    match glob.split_first() {
+        None => components.is_empty(),
+        Some((&"**", rest)) =>
+            (0..=components.len()).any(|i| matches_components(rest, &components[i..])),
+        Some((pattern, rest)) => components.split_first().is_some_and(|(name, tail)| {
+            let pattern = pattern.chars().collect::<Vec<_>>();
+            let name = name.chars().collect::<Vec<_>>();
+            matches_wildcards(&pattern, &name) && matches_components(rest, tail)
+        }),
+    }
+}
+
+fn matches_wildcards(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|i| matches_wildcards(rest, &name[i..])),
+        Some(('?', rest)) => !name.is_empty() && matches_wildcards(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && matches_wildcards(rest, &name[1..]),
+    }
+}
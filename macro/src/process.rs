@@ -0,0 +1,493 @@
+use crate::error::Level;
+use crate::json;
+use std::io;
+use std::io::BufRead;
+use std::io::Read;
+use std::process::Child;
+use std::process::Command;
+use std::path::PathBuf;
+use std::process::Output;
+use std::process::Stdio;
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Interval of checking whether a child process waited for with a deadline finished.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+// ==================
+// === ChildGuard ===
+// ==================
+
+/// A running nested build, killed with all its subprocesses when dropped before it finishes, e.g.
+/// when the expansion unwinds. Otherwise, the nested cargo would keep running in the background
+/// and hold the build directory locks, stalling the next build.
+///
+/// On Unix, the child is started in its own process group, so the whole group can be killed, and
+/// a watchdog process kills the group if the current process dies, e.g. when the host build is
+/// interrupted with Ctrl-C. The child being in its own group, it does not receive the Ctrl-C
+/// itself. On other platforms, only the child process is killed, and only when it is dropped.
+pub(crate) struct ChildGuard {
+    child: Option<Child>,
+    watchdog: Option<Child>,
+}
+
+impl ChildGuard {
+    /// Spawns the command with piped stdout and stderr, like [`Command::output`] does.
+    pub fn spawn(command: &mut Command) -> io::Result<Self> {
+        command.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(command, 0);
+        let child = command.spawn()?;
+        let watchdog = spawn_watchdog(&child);
+        Ok(Self { child: Some(child), watchdog })
+    }
+
+    /// Waits for the child like [`Child::wait_with_output`], but passes every line of its stdout
+    /// to `on_line` as soon as it is printed, and kills the child with all its subprocesses if it
+    /// is still running when the deadline passes. The lines are passed in order, on the current
+    /// thread, so they can be reported as compiler diagnostics.
+    pub fn wait_with_output_until(
+        mut self,
+        deadline: Option<Instant>,
+        mut on_line: impl FnMut(&str),
+    ) -> io::Result<Waited> {
+        let Some(child) = self.child.as_mut() else {
+            return Err(io::Error::other("The child process was already awaited."))
+        };
+        // The pipes are read in the background, as the child would block on a full pipe.
+        let (sender, receiver) = mpsc::channel();
+        let stdout_reader = read_lines_in_background(child.stdout.take(), sender);
+        let stderr_reader = read_in_background(child.stderr.take());
+        let mut stdout = Vec::new();
+        let mut handle_line = |line: Vec<u8>| {
+            let text = String::from_utf8_lossy(&line);
+            on_line(text.trim_end_matches(['\n', '\r']));
+            stdout.extend(line);
+        };
+        let timed_out = loop {
+            match receiver.recv_timeout(POLL_INTERVAL) {
+                Ok(line) => {
+                    handle_line(line);
+                    receiver.try_iter().for_each(&mut handle_line);
+                }
+                // The child closed its stdout, but it may still be running.
+                Err(mpsc::RecvTimeoutError::Disconnected) => std::thread::sleep(POLL_INTERVAL),
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+            }
+            let Some(child) = self.child.as_mut() else { break false };
+            if child.try_wait()?.is_some() {
+                break false
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                break true
+            }
+        };
+        let Some(mut child) = self.child.take() else {
+            return Err(io::Error::other("The child process was already awaited."))
+        };
+        if timed_out {
+            kill_process_group(&mut child);
+        }
+        let status = child.wait()?;
+        // Killing the process group closes the pipes, so the readers finish in both cases.
+        stdout_reader.join().ok();
+        receiver.try_iter().for_each(&mut handle_line);
+        let stderr = stderr_reader.join().unwrap_or_default();
+        if timed_out {
+            Ok(Waited::TimedOut)
+        } else {
+            Ok(Waited::Finished(Output { status, stdout, stderr }))
+        }
+    }
+}
+
+/// The result of waiting for a child process with a deadline.
+#[derive(Debug)]
+pub(crate) enum Waited {
+    Finished(Output),
+    /// The child was killed when the deadline passed. Its stdout was passed on line by line.
+    TimedOut,
+}
+
+fn read_in_background(pipe: Option<impl Read + Send + 'static>) -> JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut buffer = Vec::new();
+        if let Some(mut pipe) = pipe {
+            pipe.read_to_end(&mut buffer).ok();
+        }
+        buffer
+    })
+}
+
+/// Sends the lines of the pipe, including their line endings, until it is closed.
+fn read_lines_in_background(
+    pipe: Option<impl Read + Send + 'static>,
+    sender: mpsc::Sender<Vec<u8>>,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let Some(pipe) = pipe else { return };
+        let mut pipe = io::BufReader::new(pipe);
+        loop {
+            let mut line = Vec::new();
+            match pipe.read_until(b'\n', &mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => if sender.send(line).is_err() { break },
+            }
+        }
+    })
+}
+
+impl Drop for ChildGuard {
+    fn drop(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            kill_process_group(&mut child);
+            child.wait().ok();
+        }
+        if let Some(mut watchdog) = self.watchdog.take() {
+            watchdog.kill().ok();
+            watchdog.wait().ok();
+        }
+    }
+}
+
+/// Starts a process in the child's process group, which kills the group after the current process
+/// dies. It is a separate process, as no code of the current process runs when it is killed by a
+/// signal. Failing to start it is not an error, as it only cleans up after interrupted builds.
+#[cfg(unix)]
+fn spawn_watchdog(child: &Child) -> Option<Child> {
+    let group = child.id();
+    let script = format!(
+        "while kill -0 {} 2>/dev/null; do sleep 1; done; kill -KILL -- -{group} 2>/dev/null",
+        std::process::id()
+    );
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(script)
+        .stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null());
+    std::os::unix::process::CommandExt::process_group(&mut command, group.try_into().ok()?);
+    command.spawn().ok()
+}
+
+#[cfg(not(unix))]
+fn spawn_watchdog(_child: &Child) -> Option<Child> {
+    None
+}
+
+#[cfg(unix)]
+fn kill_process_group(child: &mut Child) {
+    Command::new("kill").arg("-KILL").arg("--").arg(format!("-{}", child.id()))
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .ok();
+    child.kill().ok();
+}
+
+#[cfg(not(unix))]
+fn kill_process_group(child: &mut Child) {
+    child.kill().ok();
+}
+
+// ==========================
+// === Transient Failures ===
+// ==========================
+
+/// Error text signatures of nested build failures which are likely to pass when retried, with
+/// their class names. The text is matched in lowercase.
+const TRANSIENT_SIGNATURES: &[(&str, &str)] = &[
+    ("spurious network error", "network"),
+    ("failed to download", "network"),
+    ("failed to get successful http response", "network"),
+    ("timeout was reached", "network"),
+    ("operation timed out", "network"),
+    ("connection timed out", "network"),
+    ("connection reset", "network"),
+    ("couldn't resolve host", "network"),
+    ("could not resolve host", "network"),
+    ("ssl connect error", "network"),
+    ("failed to acquire package cache lock", "index lock"),
+    ("index.lock", "index lock"),
+];
+
+/// Error text signatures which mean that the build itself failed, or that the built program ran
+/// and failed, so retrying would fail the same way, whatever else the text contains.
+const PERMANENT_SIGNATURES: &[&str] = &[
+    "could not compile",
+    "error[e",
+    "panicked at",
+];
+
+/// The class of a transient nested build failure, like `network` or `index lock`, based on the
+/// error output of cargo, or `None` if the failure is not known to be transient.
+pub(crate) fn transient_failure(stderr: &str) -> Option<&'static str> {
+    let stderr = stderr.to_lowercase();
+    if PERMANENT_SIGNATURES.iter().any(|signature| stderr.contains(signature)) {
+        return None
+    }
+    TRANSIENT_SIGNATURES.iter()
+        .find(|(signature, _)| stderr.contains(signature))
+        .map(|(_, class)| *class)
+}
+
+// ======================
+// === Fetch Failures ===
+// ======================
+
+/// Prefixes and suffixes around the dependency name in cargo errors about dependencies which could
+/// not be fetched, like git repositories which do not exist or branches which were removed.
+const FETCH_FAILURE_PATTERNS: &[(&str, &str)] = &[
+    ("failed to get `", "` as a dependency"),
+    ("failed to load source for dependency `", "`"),
+];
+
+/// The name of the dependency which could not be fetched and the cargo error about it, starting at
+/// its first line, or `None` if the failure is not a fetch one.
+pub(crate) fn failed_dependency(stderr: &str) -> Option<(String, String)> {
+    FETCH_FAILURE_PATTERNS.iter().find_map(|(prefix, suffix)| {
+        let start = stderr.find(prefix)?;
+        let name_start = start + prefix.len();
+        let name_len = stderr[name_start..].find(suffix)?;
+        let name = stderr[name_start..name_start + name_len].to_string();
+        let line_start = stderr[..start].rfind('\n').map_or(0, |t| t + 1);
+        Some((name, stderr[line_start..].trim().to_string()))
+    })
+}
+
+// ======================
+// === Cargo Messages ===
+// ======================
+
+/// A message printed by cargo with `--message-format=json`, one JSON object per line.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum CargoMessage {
+    /// A compiler diagnostic, with its text rendered as the compiler prints it.
+    Diagnostic { level: Level, rendered: String },
+    /// A built binary.
+    Executable(PathBuf),
+}
+
+/// The message of the line of cargo output, or `None` for other messages, like the ones of built
+/// libraries, and for the summaries of the compiler, like `aborting due to 1 previous error`,
+/// which repeat the other diagnostics.
+pub(crate) fn cargo_message(line: &str) -> Option<CargoMessage> {
+    let value = json::parse(line)?;
+    match value.get("reason")?.as_str()? {
+        "compiler-message" => {
+            let message = value.get("message")?;
+            let text = message.get("message")?.as_str()?;
+            let is_summary = text.starts_with("aborting due to")
+                || text.ends_with(" emitted") && text.contains(" warning");
+            let level = match message.get("level")?.as_str()? {
+                _ if is_summary => return None,
+                "failure-note" => return None,
+                "warning" => Level::Warning,
+                level if level.starts_with("error") => Level::Error,
+                _ => Level::Note,
+            };
+            let rendered = message.get("rendered")?.as_str()?.trim_end().to_string();
+            Some(CargoMessage::Diagnostic { level, rendered })
+        }
+        "compiler-artifact" =>
+            value.get("executable")?.as_str().map(|t| CargoMessage::Executable(t.into())),
+        _ => None,
+    }
+}
+
+// =============
+// === Tests ===
+// =============
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn is_running(pid: u32) -> bool {
+        Command::new("kill").arg("-0").arg(pid.to_string())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|t| t.success())
+    }
+
+    fn wait_until(timeout: Duration, f: impl Fn() -> bool) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            if f() {
+                return true
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        false
+    }
+
+    #[test]
+    fn dropped_guard_kills_the_process_group() {
+        let pid_file = std::env::temp_dir()
+            .join(format!("crabtime_child_guard_test_{}", std::process::id()));
+        std::fs::remove_file(&pid_file).ok();
+        // The shell stands for cargo, and the background sleep for its rustc subprocess.
+        let script = format!("sleep 30 & echo $! > '{}'; wait", pid_file.display());
+        let guard = ChildGuard::spawn(Command::new("sh").arg("-c").arg(script)).ok();
+        let pid = guard.as_ref().and_then(|t| t.child.as_ref()).map(Child::id);
+        let sub_pid = wait_until(Duration::from_secs(10), || pid_file.exists())
+            .then(|| std::fs::read_to_string(&pid_file).ok())
+            .flatten()
+            .and_then(|t| t.trim().parse::<u32>().ok());
+        std::fs::remove_file(&pid_file).ok();
+        let started = pid.is_some_and(is_running) && sub_pid.is_some_and(is_running);
+        drop(guard);
+        assert!(started);
+        assert!(pid.is_some_and(|pid| !is_running(pid)));
+        // The subprocess is reaped by its new parent, which can take a moment.
+        let stopped = wait_until(Duration::from_secs(10), || sub_pid.is_some_and(|pid| {
+            let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).unwrap_or_default();
+            !is_running(pid) || stat.split_whitespace().nth(2) == Some("Z")
+        }));
+        assert!(stopped);
+    }
+
+    #[test]
+    fn child_killed_at_deadline() {
+        let start = Instant::now();
+        let command = "echo partial; sleep 30; echo never";
+        let mut lines = Vec::new();
+        let waited = ChildGuard::spawn(Command::new("sh").arg("-c").arg(command))
+            .and_then(|t| t.wait_with_output_until(
+                Some(start + Duration::from_millis(300)), |line| lines.push(line.to_string())
+            ))
+            .ok();
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(matches!(waited, Some(Waited::TimedOut)));
+        assert_eq!(lines, ["partial"]);
+    }
+
+    #[test]
+    fn child_finished_before_deadline() {
+        let deadline = Instant::now() + Duration::from_secs(30);
+        let waited = ChildGuard::spawn(Command::new("sh").arg("-c").arg("echo out; echo err >&2"))
+            .and_then(|t| t.wait_with_output_until(Some(deadline), |_| {}))
+            .ok();
+        let output = match waited {
+            Some(Waited::Finished(output)) => Some(output),
+            _ => None,
+        };
+        assert!(output.as_ref().is_some_and(|t| t.status.success()));
+        assert_eq!(output.as_ref().map(|t| t.stdout.as_slice()), Some(b"out\n".as_slice()));
+        assert_eq!(output.as_ref().map(|t| t.stderr.as_slice()), Some(b"err\n".as_slice()));
+    }
+
+    #[test]
+    fn stdout_lines_passed_as_printed() {
+        let script = "echo 'step 1'; sleep 0.3; echo '[OUTPUT] const A: u8 = 1;'; sleep 0.3; \
+            echo 'step 2'; sleep 0.3; echo '[OUTPUT] const B: u8 = 2;'";
+        let mut lines = Vec::new();
+        let output = ChildGuard::spawn(Command::new("sh").arg("-c").arg(script))
+            .and_then(|t| t.wait_with_output_until(None, |line| {
+                lines.push((line.to_string(), Instant::now()));
+            }))
+            .ok();
+        let finished = Instant::now();
+        let stdout = match output {
+            Some(Waited::Finished(t)) => Some(String::from_utf8_lossy(&t.stdout).to_string()),
+            _ => None,
+        };
+        let texts = lines.iter().map(|t| t.0.as_str()).collect::<Vec<_>>();
+        let expected =
+            ["step 1", "[OUTPUT] const A: u8 = 1;", "step 2", "[OUTPUT] const B: u8 = 2;"];
+        assert_eq!(texts, expected);
+        assert_eq!(stdout, Some(expected.map(|t| format!("{t}\n")).concat()));
+        // The first line is received while the script still sleeps, not when it exits.
+        assert!(lines.first().is_some_and(|t| finished - t.1 >= Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn transient_failures_are_classified() {
+        let download = "\
+            error: failed to download from `https://static.crates.io/crates/syn/2.0.87/download`\n\n\
+            Caused by:\n  [28] Timeout was reached (Operation timed out after 30000 milliseconds)";
+        let index = "\
+            error: failed to get `serde` as a dependency of package `project v1.0.0`\n\n\
+            Caused by:\n  failed to create locked file '/cargo/registry/index/.git/index.lock': \
+            File exists; class=Os (2); code=Locked (-14)";
+        let cache_lock = "error: failed to acquire package cache lock";
+        let resolve = "warning: spurious network error (3 tries remaining): [6] Couldn't resolve \
+            host name (Could not resolve host: index.crates.io)\nerror: failed to query replaced \
+            source registry `crates-io`";
+        assert_eq!(transient_failure(download), Some("network"));
+        assert_eq!(transient_failure(index), Some("index lock"));
+        assert_eq!(transient_failure(cache_lock), Some("index lock"));
+        assert_eq!(transient_failure(resolve), Some("network"));
+    }
+
+    #[test]
+    fn permanent_failures_are_not_retried() {
+        let compile = "\
+            error[E0425]: cannot find value `x` in this scope\n --> src/main.rs:3:5\n\n\
+            error: could not compile `project` (bin \"project\") due to 1 previous error";
+        let panic = "\
+            thread 'main' panicked at src/main.rs:10:5:\nfailed to download the schema: \
+            connection timed out";
+        let version = "\
+            error: failed to select a version for the requirement `serde = \"^99\"`\n\
+            candidate versions found which didn't match: 1.0.215";
+        assert_eq!(transient_failure(compile), None);
+        assert_eq!(transient_failure(panic), None);
+        assert_eq!(transient_failure(version), None);
+        assert_eq!(transient_failure(""), None);
+    }
+
+    #[test]
+    fn fetch_failures_name_the_dependency() {
+        let git = "\
+            Updating git repository `https://github.com/me/my-parser`\n\
+            error: failed to get `my_parser` as a dependency of package `eval_project v1.0.0`\n\n\
+            Caused by:\n  failed to load source for dependency `my_parser`\n\n\
+            Caused by:\n  revspec 'origin/gone' not found";
+        let failure = failed_dependency(git);
+        assert_eq!(failure.as_ref().map(|t| t.0.as_str()), Some("my_parser"));
+        assert!(failure.is_some_and(|t| t.1.starts_with("error: failed to get `my_parser`")));
+        let source = "error: failed to load source for dependency `helpers`";
+        assert_eq!(failed_dependency(source).map(|t| t.0), Some("helpers".to_string()));
+        assert_eq!(failed_dependency("error: could not compile `project`"), None);
+    }
+
+    #[test]
+    fn cargo_messages_parsed() {
+        let warning = r#"{"reason":"compiler-message","package_id":"gen 1.0.0","message":{
+            "message":"unused variable: `x`","level":"warning","spans":[],
+            "rendered":"\u001b[33mwarning\u001b[0m: unused variable: `x`\n"}}"#;
+        let error = r#"{"reason":"compiler-message","message":{"message":"cannot find value",
+            "level":"error","rendered":"error[E0425]: cannot find value\n"}}"#;
+        let summary = r#"{"reason":"compiler-message","message":{"message":"1 warning emitted",
+            "level":"warning","rendered":"warning: 1 warning emitted\n\n"}}"#;
+        let aborting = r#"{"reason":"compiler-message","message":{
+            "message":"aborting due to 1 previous error","level":"error","rendered":""}}"#;
+        let binary = r#"{"reason":"compiler-artifact","executable":"/target/debug/gen"}"#;
+        let library = r#"{"reason":"compiler-artifact","executable":null}"#;
+        let finished = r#"{"reason":"build-finished","success":true}"#;
+        let warning = cargo_message(&warning.replace('\n', ""));
+        assert!(matches!(&warning, Some(CargoMessage::Diagnostic { level: Level::Warning, rendered })
+            if rendered == "\u{1b}[33mwarning\u{1b}[0m: unused variable: `x`"));
+        let error = cargo_message(&error.replace('\n', ""));
+        assert!(matches!(error, Some(CargoMessage::Diagnostic { level: Level::Error, .. })));
+        assert_eq!(cargo_message(&summary.replace('\n', "")), None);
+        assert_eq!(cargo_message(&aborting.replace('\n', "")), None);
+        assert_eq!(cargo_message(binary), Some(CargoMessage::Executable("/target/debug/gen".into())));
+        assert_eq!(cargo_message(library), None);
+        assert_eq!(cargo_message(finished), None);
+        assert_eq!(cargo_message("   Compiling gen v1.0.0"), None);
+    }
+
+    #[test]
+    fn finished_child_output() {
+        let output = ChildGuard::spawn(Command::new("sh").arg("-c").arg("echo out; echo err >&2"))
+            .and_then(|t| t.wait_with_output_until(None, |_| {}))
+            .ok();
+        let output = match output {
+            Some(Waited::Finished(t)) => Some(t),
+            _ => None,
+        };
+        assert_eq!(output.as_ref().map(|t| t.stdout.as_slice()), Some(b"out\n".as_slice()));
+        assert_eq!(output.as_ref().map(|t| t.stderr.as_slice()), Some(b"err\n".as_slice()));
+    }
+}
@@ -0,0 +1,102 @@
+use crate::inputs;
+use std::path::Path;
+use std::path::PathBuf;
+
+// ==================
+// === Registries ===
+// ==================
+
+/// Where an alternative registry used by a dependency is configured.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum RegistrySource {
+    /// Configured by environment variables or by the Cargo home config, which the nested cargo
+    /// reads too.
+    Inherited,
+    /// Configured by a `.cargo/config.toml` file, which does not apply to the generated project if
+    /// it is not in one of its parent directories, so it has to be passed to the nested cargo.
+    File(PathBuf),
+}
+
+/// Environment variable setting the index of the registry, like `CARGO_REGISTRIES_MY_CORP_INDEX`.
+pub(crate) fn index_env_var(name: &str) -> String {
+    format!("CARGO_REGISTRIES_{}_INDEX", name.to_uppercase().replace('-', "_"))
+}
+
+/// Finds the configuration of the registry the way cargo does for a build started in `dir`:
+/// the environment, then the `.cargo/config.toml` files of `dir` and its parents, closest first,
+/// and the config of the Cargo home. Returns `None` if the registry is not configured.
+pub(crate) fn find(name: &str, dir: &Path) -> Option<RegistrySource> {
+    if inputs::var(&index_env_var(name)).is_some() {
+        return Some(RegistrySource::Inherited)
+    }
+    let cargo_home = cargo_home();
+    let is_home_config = |path: &Path| cargo_home.as_ref().is_some_and(|t| path.starts_with(t));
+    let dir_configs = dir.ancestors()
+        .filter_map(|dir| config_file(&dir.join(".cargo")))
+        .filter(|path| !is_home_config(path))
+        .map(|path| (path.clone(), RegistrySource::File(path)));
+    let home_config = cargo_home.as_deref().and_then(config_file)
+        .map(|path| (path, RegistrySource::Inherited));
+    dir_configs.chain(home_config)
+        .find(|(path, _)| defines_registry(path, name))
+        .map(|(_, source)| source)
+}
+
+/// The config file of the `.cargo` directory. Cargo reads `config` if `config.toml` is missing.
+fn config_file(dir: &Path) -> Option<PathBuf> {
+    ["config.toml", "config"].into_iter().map(|name| dir.join(name)).find(|path| path.is_file())
+}
+
+fn cargo_home() -> Option<PathBuf> {
+    inputs::var("CARGO_HOME").map(PathBuf::from)
+        .or_else(|| std::env::home_dir().map(|home| home.join(".cargo")))
+}
+
+fn defines_registry(path: &Path, name: &str) -> bool {
+    let Ok(content) = inputs::read_file(path) else { return false };
+    let Ok(config) = toml::from_str::<toml::Table>(&content) else { return false };
+    config.get("registries")
+        .and_then(|t| t.get(name))
+        .and_then(|t| t.get("index"))
+        .is_some()
+}
+
+// =============
+// === Tests ===
+// =============
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    #[test]
+    fn registries_found_in_parent_configs() {
+        let dir = TempDir::new("registry");
+        let crate_dir = dir.join("my_crate");
+        let workspace_config = dir.join(".cargo").join("config.toml");
+        let crate_config = crate_dir.join(".cargo").join("config");
+        let written = std::fs::create_dir_all(crate_dir.join(".cargo"))
+            .and_then(|_| std::fs::create_dir_all(dir.join(".cargo")))
+            .and_then(|_| std::fs::write(&workspace_config, "\
+                [registries.crabtime-test-corp]\nindex = \"sparse+https://corp.example/index/\"\n\
+                [registries.crabtime-test-shared]\nindex = \"sparse+https://a.example/index/\"\n\
+                [registries.crabtime-test-tokens]\ntoken = \"secret\"\n"
+            ))
+            .and_then(|_| std::fs::write(&crate_config, "\
+                [registries.crabtime-test-shared]\nindex = \"sparse+https://b.example/index/\"\n"
+            ))
+            .ok();
+        let find_in_crate = |name: &str| find(name, &crate_dir.join("src"));
+        let corp = find_in_crate("crabtime-test-corp");
+        let shared = find_in_crate("crabtime-test-shared");
+        let tokens_only = find_in_crate("crabtime-test-tokens");
+        let missing = find_in_crate("crabtime-test-missing");
+        assert_eq!(written, Some(()));
+        assert_eq!(corp, Some(RegistrySource::File(workspace_config)));
+        assert_eq!(shared, Some(RegistrySource::File(crate_config)));
+        assert_eq!(tokens_only, None);
+        assert_eq!(missing, None);
+        assert_eq!(index_env_var("my-corp"), "CARGO_REGISTRIES_MY_CORP_INDEX");
+    }
+}
@@ -0,0 +1,181 @@
+use crate::error::*;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::fs::TryLockError;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+// ===================
+// === Shared File ===
+// ===================
+
+/// Maximum time to wait for the lock of a shared file. The files are updated by many compiler
+/// processes in parallel, and a process holding the lock for long, e.g. when it is suspended,
+/// should not stall all expansions.
+pub(crate) const LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+const LOCK_RETRY_DELAY: Duration = Duration::from_millis(5);
+
+/// Serializes writes to the side files of the current process, as threads share them.
+static SIDE_FILE_WRITES: Mutex<()> = Mutex::new(());
+
+/// Opens the shared file and locks it, waiting at most `timeout`. The lock is advisory, taken with
+/// `flock` on Unix and `LockFileEx` on Windows, and released when the file is closed. Returns
+/// `None` if the lock was not acquired in time, in which case records should be written with
+/// [`append_to_side_file`] instead. Records of side files are merged into the locked file.
+pub(crate) fn lock(path: &Path, what: &str, timeout: Duration) -> Result<Option<File>> {
+    let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false)
+        .open(path)
+        .with_path(&format!("open the {what}"), path)?;
+    let start = Instant::now();
+    loop {
+        match file.try_lock() {
+            Ok(()) => break,
+            Err(TryLockError::WouldBlock) if start.elapsed() < timeout =>
+                std::thread::sleep(LOCK_RETRY_DELAY),
+            Err(TryLockError::WouldBlock) => return Ok(None),
+            Err(TryLockError::Error(err)) => {
+                return Err(err).with_path(&format!("lock the {what}"), path)
+            }
+        }
+    }
+    merge_side_files(&mut file, path, what)?;
+    Ok(Some(file))
+}
+
+/// Reads the whole content of the locked file.
+pub(crate) fn read(file: &mut File, path: &Path, what: &str) -> Result<String> {
+    let mut content = String::new();
+    file.seek(SeekFrom::Start(0)).with_path(&format!("seek the {what}"), path)?;
+    file.read_to_string(&mut content).with_path(&format!("read the {what}"), path)?;
+    Ok(content)
+}
+
+/// Appends the lines to the locked file.
+pub(crate) fn append(file: &mut File, path: &Path, what: &str, lines: &str) -> Result {
+    file.seek(SeekFrom::End(0)).with_path(&format!("seek the {what}"), path)?;
+    file.write_all(lines.as_bytes()).with_path(&format!("write the {what}"), path)
+}
+
+/// Replaces the content of the locked file.
+pub(crate) fn replace(file: &mut File, path: &Path, what: &str, lines: &str) -> Result {
+    file.set_len(0).with_path(&format!("reset the {what}"), path)?;
+    append(file, path, what, lines)
+}
+
+// ==================
+// === Side Files ===
+// ==================
+
+/// Prefix of the names of side files of the shared file, followed by the process id.
+fn side_file_prefix(path: &Path) -> String {
+    let name = path.file_name().map(|t| t.to_string_lossy().to_string()).unwrap_or_default();
+    format!("{name}.side-")
+}
+
+fn side_file(path: &Path) -> PathBuf {
+    path.with_file_name(format!("{}{}", side_file_prefix(path), std::process::id()))
+}
+
+/// Appends the lines to the side file of the current process, used when the shared file stays
+/// locked for too long. The side file is locked too, so it is never merged while being written.
+pub(crate) fn append_to_side_file(path: &Path, what: &str, lines: &str) -> Result {
+    let _guard = SIDE_FILE_WRITES.lock().unwrap_or_else(|t| t.into_inner());
+    let side_path = side_file(path);
+    loop {
+        let mut file = OpenOptions::new().append(true).create(true).open(&side_path)
+            .with_path(&format!("open the {what} side file"), &side_path)?;
+        file.lock().with_path(&format!("lock the {what} side file"), &side_path)?;
+        // The file could be merged and removed between opening and locking it.
+        if side_path.exists() {
+            return file.write_all(lines.as_bytes())
+                .with_path(&format!("write the {what} side file"), &side_path)
+        }
+    }
+}
+
+/// Moves the records of side files which are not being written to the end of the locked file.
+fn merge_side_files(file: &mut File, path: &Path, what: &str) -> Result {
+    let Some(dir) = path.parent() else { return Ok(()) };
+    let prefix = side_file_prefix(path);
+    let Ok(entries) = std::fs::read_dir(dir) else { return Ok(()) };
+    for entry in entries.flatten() {
+        if !entry.file_name().to_string_lossy().starts_with(&prefix) {
+            continue
+        }
+        let side_path = entry.path();
+        let Ok(mut side) = OpenOptions::new().read(true).open(&side_path) else { continue };
+        if side.try_lock().is_err() {
+            continue
+        }
+        let mut lines = String::new();
+        side.read_to_string(&mut lines)
+            .with_path(&format!("read the {what} side file"), &side_path)?;
+        append(file, path, what, &lines)?;
+        std::fs::remove_file(&side_path)
+            .with_path(&format!("remove the {what} side file"), &side_path)?;
+    }
+    Ok(())
+}
+
+// =============
+// === Tests ===
+// =============
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    #[test]
+    fn concurrent_records_are_not_torn() {
+        const THREADS: usize = 8;
+        const RECORDS: usize = 500;
+        let dir = TempDir::new("shared_file");
+        let path = dir.join("records");
+        let created = std::fs::create_dir_all(&dir).ok();
+        let written = std::thread::scope(|scope| {
+            let threads = (0..THREADS).map(|thread| {
+                let path = &path;
+                scope.spawn(move || (0..RECORDS).map(|index| {
+                    // A long payload makes torn writes visible. Half of the threads never wait
+                    // for the lock, so their records often go to the side file.
+                    let line = format!("{thread}\t{index}\t{}\n", "x".repeat(100 + index % 50));
+                    let timeout = if thread % 2 == 0 { LOCK_TIMEOUT } else { Duration::ZERO };
+                    match lock(path, "test file", timeout)? {
+                        Some(mut file) => append(&mut file, path, "test file", &line),
+                        None => append_to_side_file(path, "test file", &line),
+                    }
+                }).filter(|t| t.is_ok()).count())
+            }).collect::<Vec<_>>();
+            threads.into_iter().map(|t| t.join().unwrap_or_default()).sum::<usize>()
+        });
+        let content = lock(&path, "test file", LOCK_TIMEOUT).ok().flatten()
+            .and_then(|mut file| read(&mut file, &path, "test file").ok());
+        let side_files = std::fs::read_dir(&dir).map(|t| t.count()).ok();
+        assert_eq!(created, Some(()));
+        assert_eq!(written, THREADS * RECORDS);
+        assert_eq!(side_files, Some(1));
+        let mut records = content.unwrap_or_default().lines().map(|line| {
+            let fields = line.split('\t').collect::<Vec<_>>();
+            let thread = fields[0].parse::<usize>().ok();
+            let index = fields.get(1).and_then(|t| t.parse::<usize>().ok());
+            let intact = index.is_some_and(|index| {
+                fields.len() == 3 && fields[2] == "x".repeat(100 + index % 50)
+            });
+            intact.then_some((thread, index))
+        }).collect::<Option<Vec<_>>>().unwrap_or_default();
+        records.sort();
+        let expected = (0..THREADS)
+            .flat_map(|thread| (0..RECORDS).map(move |index| (Some(thread), Some(index))))
+            .collect::<Vec<_>>();
+        assert_eq!(records, expected);
+    }
+}
@@ -0,0 +1,159 @@
+use crate::error::*;
+use std::path::Path;
+use std::path::PathBuf;
+
+// =================
+// === Snapshots ===
+// =================
+
+/// Environment variable setting the directory of the snapshots, relative to the crate directory.
+/// If set, the outputs of the generated programs are written to it.
+pub(crate) const DIR_ENV: &str = "CRABTIME_SNAPSHOT_DIR";
+/// Environment variable making all expansions read their outputs from the snapshots, failing if a
+/// snapshot is missing.
+pub(crate) const USE_ENV: &str = "CRABTIME_USE_SNAPSHOTS";
+/// Directory of the snapshots read when `CRABTIME_SNAPSHOT_DIR` is not set, relative to the crate
+/// directory.
+pub(crate) const DEFAULT_DIR: &str = "crabtime-snapshots";
+const EXTENSION: &str = "out";
+
+/// What an expansion does with the snapshots of its output.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Mode {
+    Off,
+    /// The output of the generated program is written to the directory.
+    Write(PathBuf),
+    /// The output is read from the directory instead of building the program. If the snapshot is
+    /// not required, like in offline builds, the program is built when the macro has no snapshot.
+    Read { dir: PathBuf, required: bool },
+}
+
+impl Mode {
+    /// The mode for the given values of `CRABTIME_SNAPSHOT_DIR` and `CRABTIME_USE_SNAPSHOTS`, and
+    /// whether the build is offline, like with `CARGO_NET_OFFLINE` or on docs.rs.
+    pub(crate) fn new(
+        crate_dir: &Path,
+        dir: Option<String>,
+        use_snapshots: Option<String>,
+        offline: bool,
+    ) -> Self {
+        let dir = dir.filter(|t| !t.trim().is_empty());
+        let required = use_snapshots.is_some_and(|t| t == "1" || t == "true");
+        let read_dir = || crate_dir.join(dir.as_deref().unwrap_or(DEFAULT_DIR));
+        if required || offline {
+            Self::Read { dir: read_dir(), required }
+        } else if let Some(dir) = &dir {
+            Self::Write(crate_dir.join(dir))
+        } else {
+            Self::Off
+        }
+    }
+
+    /// The snapshot of the expansion, if it should be used instead of building the program.
+    pub(crate) fn read(&self, macro_name: &str, input_hash: &str) -> Result<Option<String>> {
+        match self {
+            Self::Read { dir, required } => read(dir, macro_name, input_hash, *required),
+            _ => Ok(None),
+        }
+    }
+
+    /// Writes the snapshot of the output, if enabled. Returns the description shown in the
+    /// compilation stats.
+    pub(crate) fn write(&self, macro_name: &str, input_hash: &str, output: &str) -> Result<String> {
+        match self {
+            Self::Write(dir) => {
+                write(dir, macro_name, input_hash, output)?;
+                Ok(format!("written to '{}'", dir.display()))
+            }
+            Self::Read { .. } => Ok("not found, built".to_string()),
+            Self::Off => Ok("off".to_string()),
+        }
+    }
+}
+
+/// The snapshot file of the expansion of the macro with the input of the given hash.
+pub(crate) fn file(dir: &Path, macro_name: &str, input_hash: &str) -> PathBuf {
+    dir.join(format!("{macro_name}-{input_hash}.{EXTENSION}"))
+}
+
+pub(crate) fn write(dir: &Path, macro_name: &str, input_hash: &str, output: &str) -> Result {
+    let path = file(dir, macro_name, input_hash);
+    std::fs::create_dir_all(dir).with_path("create the snapshot directory", dir)?;
+    std::fs::write(&path, output).with_path("write the snapshot", &path)
+}
+
+/// Reads the snapshot of the expansion. If the macro has snapshots of other inputs only, its input
+/// changed since they were written, which is an error, as the snapshots must be regenerated.
+pub(crate) fn read(
+    dir: &Path,
+    macro_name: &str,
+    input_hash: &str,
+    required: bool,
+) -> Result<Option<String>> {
+    let path = file(dir, macro_name, input_hash);
+    if path.is_file() {
+        return std::fs::read_to_string(&path).with_path("read the snapshot", &path).map(Some)
+    }
+    let prefix = format!("{macro_name}-");
+    let has_other_inputs = std::fs::read_dir(dir).into_iter().flatten().flatten().any(|entry| {
+        let name = entry.file_name().to_string_lossy().to_string();
+        name.starts_with(&prefix) && name.ends_with(&format!(".{EXTENSION}"))
+    });
+    if has_other_inputs {
+        return err!(
+            "The snapshots of macro '{macro_name}' in '{}' do not match its input, which changed \
+            since they were written. Regenerate them by building with '{DIR_ENV}' set, without \
+            '{USE_ENV}'.", dir.display()
+        )
+    }
+    if required {
+        return err!(
+            "Macro '{macro_name}' has no snapshot in '{}', while '{USE_ENV}' is set. Generate the \
+            snapshots by building with '{DIR_ENV}' set, without '{USE_ENV}'.", dir.display()
+        )
+    }
+    Ok(None)
+}
+
+// =============
+// === Tests ===
+// =============
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    #[test]
+    fn mode_from_env() {
+        let crate_dir = Path::new("/ws/my_crate");
+        let mode = |dir: Option<&str>, use_snapshots: Option<&str>, offline: bool| Mode::new(
+            crate_dir, dir.map(str::to_string), use_snapshots.map(str::to_string), offline
+        );
+        let snapshots = crate_dir.join("snapshots");
+        assert_eq!(mode(None, None, false), Mode::Off);
+        assert_eq!(mode(Some(" "), Some("0"), false), Mode::Off);
+        assert_eq!(mode(Some("snapshots"), None, false), Mode::Write(snapshots.clone()));
+        assert_eq!(mode(Some("snapshots"), Some("1"), false),
+            Mode::Read { dir: snapshots.clone(), required: true });
+        assert_eq!(mode(Some("snapshots"), None, true), Mode::Read { dir: snapshots, required: false });
+        assert_eq!(mode(None, Some("true"), false),
+            Mode::Read { dir: crate_dir.join(DEFAULT_DIR), required: true });
+        assert_eq!(mode(Some("/abs"), None, false), Mode::Write(PathBuf::from("/abs")));
+    }
+
+    #[test]
+    fn snapshots_written_and_read() {
+        let dir = TempDir::new("snapshot");
+        let written = write(&dir, "gen_ids", "0a1b", "[OUTPUT] const A: u8 = 1;").ok();
+        let matching = read(&dir, "gen_ids", "0a1b", true).ok();
+        let outdated = read(&dir, "gen_ids", "2c3d", false).err().map(|t| t.message);
+        let missing_optional = read(&dir, "gen_names", "0a1b", false).ok();
+        let missing_required = read(&dir, "gen_names", "0a1b", true).is_err();
+        assert_eq!(written, Some(()));
+        assert_eq!(matching, Some(Some("[OUTPUT] const A: u8 = 1;".to_string())));
+        assert!(outdated.is_some_and(|t| t.contains("do not match its input")));
+        assert_eq!(missing_optional, Some(None));
+        assert!(missing_required);
+    }
+}
@@ -0,0 +1,90 @@
+// Generated code returned by `crabtime::quote!`, available in the generated prelude. This file is
+// embedded verbatim in the prelude of every generated project, so it must not depend on anything
+// but `std` and must not use inner attributes or inner doc comments. Its `CodeFromOutput`
+// implementation is defined in the prelude, as the trait is not available when this file is tested.
+
+/// Generated code returned by `crabtime::quote!`. Snippets can be concatenated with `+`, collected
+/// from an iterator, and interpolated in `crabtime::output!` and `crabtime::quote!`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Snippet(String);
+
+impl Snippet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::ops::Deref for Snippet {
+    type Target = str;
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for Snippet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<String> for Snippet {
+    fn from(code: String) -> Self {
+        Self(code)
+    }
+}
+
+impl From<&str> for Snippet {
+    fn from(code: &str) -> Self {
+        Self(code.to_string())
+    }
+}
+
+impl From<Snippet> for String {
+    fn from(snippet: Snippet) -> Self {
+        snippet.0
+    }
+}
+
+impl PartialEq<str> for Snippet {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<&str> for Snippet {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == *other
+    }
+}
+
+impl<T: AsRef<str>> std::ops::Add<T> for Snippet {
+    type Output = Snippet;
+    fn add(mut self, rhs: T) -> Snippet {
+        self += rhs;
+        self
+    }
+}
+
+impl<T: AsRef<str>> std::ops::AddAssign<T> for Snippet {
+    fn add_assign(&mut self, rhs: T) {
+        self.0.push_str(rhs.as_ref());
+    }
+}
+
+impl AsRef<str> for Snippet {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Concatenates the snippets, separating them with new lines.
+impl std::iter::FromIterator<Snippet> for Snippet {
+    fn from_iter<I: IntoIterator<Item = Snippet>>(iter: I) -> Self {
+        let snippets = iter.into_iter().map(|t| t.0).collect::<Vec<_>>();
+        Self(snippets.join("\n"))
+    }
+}
@@ -0,0 +1,268 @@
+use crate::error::*;
+use crate::format_duration;
+use crate::shared_file;
+use crate::shared_file::LOCK_TIMEOUT;
+use std::path::Path;
+use std::time::Duration;
+
+// =============
+// === Build ===
+// =============
+
+/// Proc macros have no end-of-build hook, so the build summary is collected cooperatively. Every
+/// expansion appends its record to a shared stats file, and the first expansion of a new build
+/// reports the summary of the previous one. Expansions separated by a pause longer than this gap
+/// are considered to belong to different builds.
+pub(crate) const BUILD_GAP: Duration = Duration::from_secs(30);
+
+/// Current time in milliseconds since the Unix epoch.
+pub(crate) fn now() -> u128 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+/// Build id of an expansion started at `now`. It is the start time of the first expansion of the
+/// build, in milliseconds since the Unix epoch.
+pub(crate) fn build_id(last: Option<&Record>, now: u128) -> u128 {
+    match last {
+        Some(last) if now.saturating_sub(last.time) <= BUILD_GAP.as_millis() => last.build_id,
+        _ => now,
+    }
+}
+
+// ==============
+// === Record ===
+// ==============
+
+/// A line of the stats file, recording the start or the end of an expansion.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Record {
+    pub build_id: u128,
+    /// Time of the event, in milliseconds since the Unix epoch.
+    pub time: u128,
+    /// Stats of the expansion, `None` if the record marks the start of an expansion.
+    pub expansion: Option<Expansion>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Expansion {
+    pub name: String,
+    pub duration: Duration,
+    pub rebuilt: bool,
+}
+
+impl Record {
+    fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.split('\t');
+        let build_id = fields.next()?.parse().ok()?;
+        let time = fields.next()?.parse().ok()?;
+        let expansion = match fields.next() {
+            None => None,
+            Some(duration) => {
+                let duration = Duration::from_millis(duration.parse().ok()?);
+                let rebuilt = fields.next()? == "1";
+                let name = fields.next()?.to_string();
+                Some(Expansion { name, duration, rebuilt })
+            }
+        };
+        Some(Self { build_id, time, expansion })
+    }
+
+    fn to_line(&self) -> String {
+        let prefix = format!("{}\t{}", self.build_id, self.time);
+        match &self.expansion {
+            None => format!("{prefix}\n"),
+            Some(expansion) => {
+                let duration = expansion.duration.as_millis();
+                let rebuilt = u8::from(expansion.rebuilt);
+                format!("{prefix}\t{duration}\t{rebuilt}\t{}\n", expansion.name)
+            }
+        }
+    }
+}
+
+/// Parses the stats file content, skipping malformed lines, e.g. ones written by older versions.
+pub(crate) fn parse_records(content: &str) -> Vec<Record> {
+    content.lines().filter_map(Record::parse).collect()
+}
+
+// ===============
+// === Summary ===
+// ===============
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Summary {
+    pub expansions: usize,
+    pub rebuilt: usize,
+    pub total: Duration,
+    pub slowest: Option<(String, Duration)>,
+}
+
+impl Summary {
+    /// Summary of all expansions of the given build, or `None` if the build has no finished
+    /// expansions.
+    pub fn new(records: &[Record], build_id: u128) -> Option<Self> {
+        let expansions = records.iter()
+            .filter(|r| r.build_id == build_id)
+            .filter_map(|r| r.expansion.as_ref())
+            .collect::<Vec<_>>();
+        if expansions.is_empty() {
+            return None
+        }
+        let rebuilt = expansions.iter().filter(|t| t.rebuilt).count();
+        let total = expansions.iter().map(|t| t.duration).sum();
+        let slowest = expansions.iter().max_by_key(|t| t.duration).map(|t| (t.name.clone(), t.duration));
+        Some(Self { expansions: expansions.len(), rebuilt, total, slowest })
+    }
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let plural = if self.expansions == 1 { "" } else { "s" };
+        write!(f, "crabtime: {} expansion{plural}, {} rebuilt, total {}",
+            self.expansions, self.rebuilt, format_duration(self.total))?;
+        if let Some((name, duration)) = &self.slowest {
+            write!(f, ", slowest: {name} {}", format_duration(*duration))?;
+        }
+        Ok(())
+    }
+}
+
+// ================
+// === Recorder ===
+// ================
+
+const WHAT: &str = "build stats file";
+
+/// Records the start of an expansion and returns the id of the current build. If the expansion
+/// starts a new build, the file is reset and the summary of the previous build is returned too.
+/// The file is locked for the whole update, as macros are expanded by many compiler processes in
+/// parallel.
+pub(crate) fn begin_expansion(path: &Path, now: u128) -> Result<(u128, Option<Summary>)> {
+    let Some(mut file) = shared_file::lock(path, WHAT, LOCK_TIMEOUT)? else {
+        // The build id is still derived from the file, but the previous build is left for the
+        // expansion holding the lock to report.
+        let records = parse_records(&std::fs::read_to_string(path).unwrap_or_default());
+        let build_id = build_id(records.last(), now);
+        let record = Record { build_id, time: now, expansion: None };
+        shared_file::append_to_side_file(path, WHAT, &record.to_line())?;
+        return Ok((build_id, None))
+    };
+    let records = parse_records(&shared_file::read(&mut file, path, WHAT)?);
+    let last = records.last();
+    let build_id = build_id(last, now);
+    let record = Record { build_id, time: now, expansion: None };
+    match last {
+        Some(last) if last.build_id != build_id => {
+            shared_file::replace(&mut file, path, WHAT, &record.to_line())?;
+            Ok((build_id, Summary::new(&records, last.build_id)))
+        }
+        _ => {
+            shared_file::append(&mut file, path, WHAT, &record.to_line())?;
+            Ok((build_id, None))
+        }
+    }
+}
+
+/// Records the stats of a finished expansion of the given build.
+pub(crate) fn end_expansion(path: &Path, build_id: u128, expansion: Expansion, now: u128) -> Result {
+    let line = Record { build_id, time: now, expansion: Some(expansion) }.to_line();
+    match shared_file::lock(path, WHAT, LOCK_TIMEOUT)? {
+        Some(mut file) => shared_file::append(&mut file, path, WHAT, &line),
+        None => shared_file::append_to_side_file(path, WHAT, &line),
+    }
+}
+
+// =============
+// === Tests ===
+// =============
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GAP: u128 = BUILD_GAP.as_millis();
+
+    fn expansion(name: &str, millis: u64, rebuilt: bool) -> Expansion {
+        Expansion { name: name.to_string(), duration: Duration::from_millis(millis), rebuilt }
+    }
+
+    fn record(build_id: u128, time: u128, millis: u64, rebuilt: bool, name: &str) -> Record {
+        Record { build_id, time, expansion: Some(expansion(name, millis, rebuilt)) }
+    }
+
+    #[test]
+    fn build_id_detection() {
+        let last = record(1000, 5000, 10, false, "a");
+        assert_eq!(build_id(None, 7000), 7000);
+        assert_eq!(build_id(Some(&last), 5000 + GAP), 1000);
+        assert_eq!(build_id(Some(&last), 5001 + GAP), 5001 + GAP);
+        // A clock moved backwards must not start a new build.
+        assert_eq!(build_id(Some(&last), 4000), 1000);
+    }
+
+    #[test]
+    fn records_roundtrip_and_skip_malformed_lines() {
+        let records = [
+            Record { build_id: 1, time: 1, expansion: None },
+            record(1, 2, 300, true, "gen_a"),
+            record(1, 4, 50, false, "gen_b"),
+        ];
+        let lines = records.iter().map(Record::to_line).collect::<String>();
+        let content = format!("garbage\n1\t2\t3\n{lines}");
+        assert_eq!(parse_records(&content), records);
+    }
+
+    #[test]
+    fn summary_aggregation() {
+        let records = [
+            record(1, 10, 500, false, "gen_old"),
+            Record { build_id: 2, time: 11, expansion: None },
+            record(2, 20, 1900, true, "gen_router"),
+            record(2, 30, 300, false, "gen_a"),
+            record(2, 40, 2000, true, "gen_b"),
+        ];
+        let summary = Summary::new(&records, 2);
+        assert_eq!(summary, Some(Summary {
+            expansions: 3,
+            rebuilt: 2,
+            total: Duration::from_millis(4200),
+            slowest: Some(("gen_b".to_string(), Duration::from_millis(2000))),
+        }));
+        assert_eq!(
+            summary.map(|s| s.to_string()).as_deref(),
+            Some("crabtime: 3 expansions, 2 rebuilt, total 4.20 s, slowest: gen_b 2.00 s")
+        );
+        assert_eq!(Summary::new(&records, 3), None);
+    }
+
+    #[test]
+    fn stats_file_reports_previous_build() {
+        let path = std::env::temp_dir()
+            .join(format!("crabtime_build_stats_test_{}", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let first = begin_expansion(&path, 1000).ok();
+        // Parallel expansions started before the first one finished share its build id.
+        let second = begin_expansion(&path, 1500).ok();
+        let first_end = end_expansion(&path, 1000, expansion("gen_a", 100, true), 1100).ok();
+        let second_end = end_expansion(&path, 1000, expansion("gen_b", 300, false), 1800).ok();
+        let third = begin_expansion(&path, 1801 + GAP).ok();
+        let content = std::fs::read_to_string(&path).ok();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(first, Some((1000, None)));
+        assert_eq!(second, Some((1000, None)));
+        assert_eq!(first_end, Some(()));
+        assert_eq!(second_end, Some(()));
+        assert_eq!(third, Some((1801 + GAP, Some(Summary {
+            expansions: 2,
+            rebuilt: 1,
+            total: Duration::from_millis(400),
+            slowest: Some(("gen_b".to_string(), Duration::from_millis(300))),
+        }))));
+        // The previous build is removed, so the file does not grow indefinitely.
+        let expected = Record { build_id: 1801 + GAP, time: 1801 + GAP, expansion: None };
+        assert_eq!(content.map(|t| parse_records(&t)), Some(vec![expected]));
+    }
+}
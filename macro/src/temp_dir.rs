@@ -0,0 +1,49 @@
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+// ===============
+// === TempDir ===
+// ===============
+
+/// Path in the system temporary directory used by a test, removed with its content on drop, so
+/// that it is cleaned up even if an assertion fails. The directory itself is not created, as some
+/// tests check how missing directories are handled.
+#[derive(Debug)]
+pub(crate) struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// The name is made unique with the process id and a counter, so that parallel test runs and
+    /// tests of the same run never share a directory.
+    pub fn new(name: &str) -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let index = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let dir_name = format!("crabtime_{name}_test_{}_{index}", std::process::id());
+        let path = std::env::temp_dir().join(dir_name);
+        // Leftovers of a killed run of a process with the same id.
+        std::fs::remove_dir_all(&path).ok();
+        Self { path }
+    }
+}
+
+impl std::ops::Deref for TempDir {
+    type Target = Path;
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.path).ok();
+    }
+}
@@ -0,0 +1,417 @@
+//! Builds small consumer crates exercising every documented input and output mode of Crabtime
+//! macros, so that regressions in argument parsing or code printing fail with the mode named.
+//!
+//! Every mode is a separate crate depending on the local `crabtime` crate, written to a temporary
+//! directory and built with `cargo build`. The crates are built in parallel by a bounded pool of
+//! workers, each with its own cargo workspace whose target directory is reused across the crates
+//! of the worker. By default, the
+//! toolchain running the tests is used. Set `CRABTIME_EXAMPLES_TOOLCHAINS` to a comma-separated
+//! list of rustup toolchains, like `stable,nightly`, to build every mode with each of them.
+
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+
+const TOOLCHAINS_ENV: &str = "CRABTIME_EXAMPLES_TOOLCHAINS";
+const MAX_WORKERS: usize = 4;
+/// Number of the last lines of the build output shown for a failed mode.
+const FAILURE_OUTPUT_LINES: usize = 40;
+
+// ================
+// === Examples ===
+// ================
+
+/// A consumer crate exercising one mode. Its code is checked by compilation, with constant
+/// assertions where the generated values matter.
+struct Example {
+    mode: &'static str,
+    /// The `[build-dependencies]` of the crate. They are inherited by the macros on nightly only,
+    /// so examples having them are not built with other toolchains.
+    build_dependencies: &'static str,
+    code: &'static str,
+}
+
+const EXAMPLES: &[Example] = &[
+    Example {
+        mode: "output! with String and Vec arguments",
+        build_dependencies: "",
+        code: r#"
+            #[crabtime::function]
+            fn gen_positions(name: String, components: Vec<String>) {
+                for dim in 1 ..= components.len() {
+                    let cons = components[0..dim].join(",");
+                    crabtime::output! {
+                        pub enum {{name}}{{dim}} {
+                            {{cons}}
+                        }
+                    }
+                }
+            }
+            gen_positions!(Position, ["X", "Y", "Z"]);
+
+            fn main() {
+                let _ = (Position1::X, Position3::Z);
+            }
+        "#,
+    },
+    Example {
+        mode: "numeric Vec arguments with a String return",
+        build_dependencies: "",
+        code: r#"
+            #[crabtime::function]
+            fn gen_sum(values: Vec<usize>) -> String {
+                format!("pub const SUM: usize = {};", values.iter().sum::<usize>())
+            }
+            gen_sum!([1, 2, 3]);
+            const _: () = assert!(SUM == 6);
+
+            fn main() {}
+        "#,
+    },
+    Example {
+        mode: "pattern! arguments",
+        build_dependencies: "",
+        code: r#"
+            #[crabtime::function]
+            fn gen_positions(pattern!($name:ident, $components:tt): _) {
+                let components = expand!($components);
+                let name = stringify!($name);
+                for dim in 1 ..= components.len() {
+                    let cons = components[0..dim].join(",");
+                    crabtime::output! {
+                        pub enum {{name}}{{dim}} {
+                            {{cons}}
+                        }
+                    }
+                }
+            }
+            gen_positions!(Color, ["R", "G", "B"]);
+
+            fn main() {
+                let _ = (Color1::R, Color3::B);
+            }
+        "#,
+    },
+    Example {
+        mode: "TokenStream output",
+        build_dependencies: "",
+        code: r#"
+            #[crabtime::function]
+            fn gen_consts() -> proc_macro2::TokenStream {
+                #![dependency(proc-macro2 = "1")]
+                #![dependency(quote = "1")]
+                #![dependency(syn = "2")]
+                use quote::quote;
+                let defs = ["A", "B"].iter().enumerate().map(|(index, name)| {
+                    let ident = syn::Ident::new(name, proc_macro2::Span::call_site());
+                    quote! { pub const #ident: usize = #index; }
+                });
+                quote! { #(#defs)* }
+            }
+            gen_consts!();
+            const _: () = assert!(A == 0 && B == 1);
+
+            fn main() {}
+        "#,
+    },
+    Example {
+        mode: "quote! snippets",
+        build_dependencies: "",
+        code: r#"
+            #[crabtime::function]
+            fn gen_consts(names: Vec<String>) -> crabtime::Snippet {
+                names.iter().enumerate().map(|(index, name)| crabtime::quote! {
+                    pub const {{name}}: usize = {{index}};
+                }).collect()
+            }
+            gen_consts!(["A", "B"]);
+            const _: () = assert!(A == 0 && B == 1);
+
+            fn main() {}
+        "#,
+    },
+    Example {
+        mode: "output_str!",
+        build_dependencies: "",
+        code: r#"
+            #[crabtime::function]
+            fn gen_positions(components: Vec<String>) {
+                for dim in 1 ..= components.len() {
+                    let cons = components[0..dim].join(",");
+                    crabtime::output_str!("pub enum Position{dim} {{ {cons} }}")
+                }
+            }
+            gen_positions!(["X", "Y"]);
+
+            fn main() {
+                let _ = Position2::Y;
+            }
+        "#,
+    },
+    Example {
+        mode: "eval!",
+        build_dependencies: "",
+        code: r#"
+            const MY_NUM: usize = crabtime::eval! {
+                (1 ..= 6).product::<usize>() / 10
+            };
+            const _: () = assert!(MY_NUM == 72);
+
+            fn main() {}
+        "#,
+    },
+    Example {
+        mode: "expression macros",
+        build_dependencies: "",
+        code: r#"
+            #[crabtime::expression]
+            fn gen_len(items: Vec<String>) -> String {
+                format!("{}usize", items.len())
+            }
+
+            fn main() {
+                assert_eq!(gen_len!(["a", "b"]), 2);
+            }
+        "#,
+    },
+    Example {
+        mode: "inherited build-dependencies with features",
+        build_dependencies: r#"syn = { version = "2", features = ["full", "extra-traits"] }"#,
+        code: r#"
+            // Parsing functions requires the `full` feature and debug printing requires the
+            // `extra-traits` feature of `syn`.
+            #[crabtime::function]
+            fn gen_fn_name(code: String) -> String {
+                let name = match syn::parse_str::<syn::ItemFn>(&code) {
+                    Ok(item) if !format!("{item:?}").is_empty() => item.sig.ident.to_string(),
+                    _ => String::new(),
+                };
+                format!("pub const FN_NAME: &str = \"{name}\";")
+            }
+            gen_fn_name!("fn answer() -> usize { 42 }");
+            const _: () = assert!(FN_NAME.len() == 6);
+
+            fn main() {}
+        "#,
+    },
+];
+
+// ==============
+// === Matrix ===
+// ==============
+
+/// A mode built with a toolchain, `None` for the one running the tests.
+struct Job<'t> {
+    example: &'t Example,
+    toolchain: Option<String>,
+}
+
+impl Job<'_> {
+    fn is_supported(&self) -> bool {
+        let nightly = match &self.toolchain {
+            Some(toolchain) => toolchain.starts_with("nightly"),
+            None => cfg!(nightly),
+        };
+        self.example.build_dependencies.is_empty() || nightly
+    }
+
+    fn label(&self) -> String {
+        match &self.toolchain {
+            Some(toolchain) => format!("{} ({toolchain})", self.example.mode),
+            None => self.example.mode.to_string(),
+        }
+    }
+
+    fn crate_name(&self, index: usize) -> String {
+        let toolchain = self.toolchain.as_deref().unwrap_or("default")
+            .replace(|c: char| !c.is_alphanumeric(), "_");
+        format!("crabtime_example_{index}_{toolchain}")
+    }
+}
+
+fn toolchains() -> Vec<Option<String>> {
+    let toolchains = std::env::var(TOOLCHAINS_ENV).unwrap_or_default();
+    let toolchains = toolchains.split(',')
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .map(|t| Some(t.to_string()))
+        .collect::<Vec<_>>();
+    if toolchains.is_empty() { vec![None] } else { toolchains }
+}
+
+// ================
+// === Building ===
+// ================
+
+struct Workspace {
+    /// Directory of the crabtime workspace.
+    repo: PathBuf,
+    /// Directory the cargo workspaces of the workers are written to.
+    root: PathBuf,
+    /// Directory the target directories of the workers are set to with `CARGO_TARGET_DIR`, outside
+    /// of their workspaces. If not set, the workspaces use their own `target` directories.
+    target_dir: Option<PathBuf>,
+}
+
+impl Workspace {
+    /// Writes the crate to the cargo workspace of the worker, creating the workspace if needed.
+    /// Crabtime resolves the call sites against the workspace containing the `target` directory,
+    /// so the crates are its members instead of using a shared target directory.
+    fn write_crate(&self, worker_dir: &Path, name: &str, example: &Example) -> std::io::Result<()> {
+        let manifest = worker_dir.join("Cargo.toml");
+        if !manifest.exists() {
+            std::fs::create_dir_all(worker_dir)?;
+            let members = "[workspace]\nresolver = \"3\"\nmembers = [\"crabtime_example_*\"]\n";
+            std::fs::write(&manifest, members)?;
+            // The lockfile of the workspace keeps the dependency versions tested and lets the
+            // crates build without updating the registry index.
+            std::fs::copy(self.repo.join("Cargo.lock"), worker_dir.join("Cargo.lock"))?;
+        }
+        let crabtime = self.repo.join("lib").display().to_string().replace('\\', "/");
+        let crate_manifest = format!(
+            "[package]\nname = \"{name}\"\nversion = \"0.0.0\"\nedition = \"2024\"\n\n\
+            [dependencies]\ncrabtime = {{ path = \"{crabtime}\" }}\n\n\
+            [build-dependencies]\n{}\n",
+            example.build_dependencies
+        );
+        let dir = worker_dir.join(name);
+        std::fs::create_dir_all(dir.join("src"))?;
+        std::fs::write(dir.join("Cargo.toml"), crate_manifest)?;
+        std::fs::write(dir.join("src").join("main.rs"), example.code)
+    }
+
+    /// Builds the crate of the job, returning the build output if it fails.
+    fn build(&self, job: &Job, index: usize, worker: usize) -> Result<(), String> {
+        let name = job.crate_name(index);
+        let worker_dir = self.root.join(format!("worker_{worker}"));
+        self.write_crate(&worker_dir, &name, job.example)
+            .map_err(|err| format!("Failed to write the crate: {err}"))?;
+        let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+        let mut command = match &job.toolchain {
+            // The rustup proxy selects the toolchain, not the cargo running the tests.
+            Some(toolchain) => {
+                let mut command = Command::new("cargo");
+                command.arg(format!("+{toolchain}"));
+                command
+            }
+            None => Command::new(cargo),
+        };
+        command
+            .arg("build")
+            .arg("--manifest-path")
+            .arg(worker_dir.join("Cargo.toml"))
+            .arg("--package")
+            .arg(&name)
+            // The dependencies of the examples are the ones of this workspace, so they are already
+            // fetched. The variable is inherited by the nested builds of the macros too.
+            .env("CARGO_NET_OFFLINE", "true");
+        match &self.target_dir {
+            Some(dir) => command.env("CARGO_TARGET_DIR", dir.join(format!("worker_{worker}"))),
+            None => command.env_remove("CARGO_TARGET_DIR"),
+        };
+        if job.toolchain.is_some() {
+            command.env_remove("RUSTUP_TOOLCHAIN").env_remove("RUSTC").env_remove("CARGO");
+        }
+        let output = command.output().map_err(|err| format!("Failed to run cargo: {err}"))?;
+        if output.status.success() {
+            return Ok(())
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let lines = stderr.lines().collect::<Vec<_>>();
+        let tail = &lines[lines.len().saturating_sub(FAILURE_OUTPUT_LINES)..];
+        Err(tail.join("\n"))
+    }
+}
+
+/// Builds the jobs with a bounded pool of workers, returning the result and the build time of
+/// every job, in the order of the jobs.
+fn build_all(workspace: &Workspace, jobs: &[Job]) -> Vec<(Result<(), String>, Duration)> {
+    let workers = std::thread::available_parallelism()
+        .map_or(1, usize::from)
+        .clamp(1, MAX_WORKERS)
+        .min(jobs.len());
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::new());
+    std::thread::scope(|scope| {
+        for worker in 0..workers {
+            let (next, results) = (&next, &results);
+            scope.spawn(move || loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(job) = jobs.get(index) else { break };
+                let start = Instant::now();
+                let result = workspace.build(job, index, worker);
+                let mut results = results.lock().unwrap_or_else(|t| t.into_inner());
+                results.push((index, result, start.elapsed()));
+            });
+        }
+    });
+    let mut results = results.into_inner().unwrap_or_else(|t| t.into_inner());
+    results.sort_by_key(|(index, _, _)| *index);
+    results.into_iter().map(|(_, result, duration)| (result, duration)).collect()
+}
+
+// ============
+// === Test ===
+// ============
+
+/// Builds the jobs, asserting that all of them succeed. The crates of failed jobs are kept.
+fn assert_all_build(workspace: &Workspace, jobs: &[Job]) {
+    let results = build_all(workspace, jobs);
+    let failures = jobs.iter().zip(&results)
+        .filter_map(|(job, (result, duration))| result.as_ref().err().map(|output| {
+            let seconds = duration.as_secs_f32();
+            format!("=== {} failed after {seconds:.1} s ===\n{output}", job.label())
+        }))
+        .collect::<Vec<_>>();
+    if failures.is_empty() {
+        std::fs::remove_dir_all(&workspace.root).ok();
+        if let Some(target_dir) = &workspace.target_dir {
+            std::fs::remove_dir_all(target_dir).ok();
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "{} of {} modes failed to build. The crates are kept in '{}'.\n\n{}",
+        failures.len(), jobs.len(), workspace.root.display(), failures.join("\n\n")
+    );
+}
+
+#[test]
+fn documented_modes_build() {
+    let repo = Path::new(env!("CARGO_MANIFEST_DIR")).join("..");
+    let root = std::env::temp_dir().join(format!("crabtime_examples_{}", std::process::id()));
+    let workspace = Workspace { repo, root, target_dir: None };
+    let toolchains = toolchains();
+    let jobs = EXAMPLES.iter()
+        .flat_map(|example| toolchains.iter().map(move |toolchain| {
+            Job { example, toolchain: toolchain.clone() }
+        }))
+        .filter(Job::is_supported)
+        .collect::<Vec<_>>();
+    assert_all_build(&workspace, &jobs);
+}
+
+#[test]
+fn external_target_dir_builds() {
+    let repo = Path::new(env!("CARGO_MANIFEST_DIR")).join("..");
+    let dir = std::env::temp_dir().join(format!("crabtime_target_dir_{}", std::process::id()));
+    // Neither the workspace nor the target directory have the conventional names.
+    let workspace = Workspace {
+        repo,
+        root: dir.join("sources"),
+        target_dir: Some(dir.join("artifacts")),
+    };
+    // Macros with dependencies are built by a nested cargo, the others by rustc.
+    let modes = ["output! with String and Vec arguments", "TokenStream output"];
+    let jobs = EXAMPLES.iter()
+        .filter(|example| modes.contains(&example.mode))
+        .map(|example| Job { example, toolchain: None })
+        .collect::<Vec<_>>();
+    assert_all_build(&workspace, &jobs);
+    std::fs::remove_dir_all(&dir).ok();
+}
@@ -0,0 +1,17 @@
+#![allow(clippy::unwrap_used)]
+
+// === Build Script Call Site ===
+
+// Macros expanded in the build script are configured by the package manifest, and their projects
+// are kept in the crabtime build directory, like the ones of the crate.
+const CELLS: usize = crabtime::eval! {
+    (1..=4).map(|t| t * t).sum::<usize>()
+};
+
+fn main() {
+    assert_eq!(CELLS, 30);
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    let code = format!("pub const CELLS: usize = {CELLS};\n");
+    std::fs::write(std::path::Path::new(&out_dir).join("cells.rs"), code).unwrap();
+    println!("cargo:rerun-if-changed=build.rs");
+}
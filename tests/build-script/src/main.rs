@@ -0,0 +1,15 @@
+// === Build Script Test ===
+
+include!(concat!(env!("OUT_DIR"), "/cells.rs"));
+
+fn main() {
+    println!("{CELLS}");
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn value_generated_by_build_script() {
+        assert_eq!(super::CELLS, 30);
+    }
+}
@@ -0,0 +1,46 @@
+// === Conditional Macro Export Test ===
+
+/// Generates the `EXPORTED` constant.
+#[crabtime::function]
+#[cfg_attr(feature = "macros", macro_export)]
+fn gen_exported() {
+    crabtime::output! {
+        pub const EXPORTED: usize = 1;
+    }
+}
+
+#[crabtime::function]
+fn gen_exported_inner() {
+    //! Generates the `EXPORTED_INNER` constant.
+    #![cfg_attr(feature = "macros", macro_export)]
+    crabtime::output! {
+        pub const EXPORTED_INNER: usize = 2;
+    }
+}
+
+// The macros are usable within the crate regardless of the feature.
+gen_exported!();
+gen_exported_inner!();
+
+// === Conditional Output Test ===
+
+pub struct Gated;
+
+#[crabtime::function]
+fn gen_gated_impls() {
+    crabtime::output_cfg! {
+        feature = "macros" => {
+            impl Gated {
+                pub const MACROS: bool = true;
+            }
+        }
+    }
+    crabtime::output_cfg! {
+        "not(feature = \"macros\")" => {
+            impl Gated {
+                pub const MACROS: bool = false;
+            }
+        }
+    }
+}
+gen_gated_impls!();
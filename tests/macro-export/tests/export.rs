@@ -0,0 +1,18 @@
+// Run with `--no-default-features` to test the crate without exported macros.
+
+#[cfg(feature = "macros")]
+mod exported {
+    crabtime_test_macro_export::gen_exported!();
+    crabtime_test_macro_export::gen_exported_inner!();
+
+    #[test]
+    fn macros_exported_with_feature() {
+        assert_eq!(EXPORTED + EXPORTED_INNER, 3);
+    }
+}
+
+#[test]
+fn macros_usable_within_crate() {
+    assert_eq!(crabtime_test_macro_export::EXPORTED, 1);
+    assert_eq!(crabtime_test_macro_export::EXPORTED_INNER, 2);
+}
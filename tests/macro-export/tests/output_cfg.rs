@@ -0,0 +1,6 @@
+// Run with `--no-default-features` to test the disabled branch.
+
+#[test]
+fn impls_follow_features() {
+    assert_eq!(crabtime_test_macro_export::Gated::MACROS, cfg!(feature = "macros"));
+}
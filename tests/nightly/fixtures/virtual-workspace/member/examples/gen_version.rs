@@ -0,0 +1,9 @@
+// The nearest manifest is virtual, so the build dependencies come from the member manifest.
+#[crabtime::function]
+fn gen_version_major(version: String) {
+    let major = semver::Version::parse(&version).map_or(0, |t| t.major);
+    crabtime::output! {
+        pub const MAJOR: u64 = {{major}};
+    }
+}
+gen_version_major!("2.5.1");
@@ -0,0 +1 @@
+//! Member crate of a virtual workspace, whose build dependencies are used by the macros in it.
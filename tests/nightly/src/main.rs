@@ -14,6 +14,45 @@ fn gen_positions(components: Vec<String>) {
 }
 gen_positions!(["X", "Y", "Z", "W"]);
 
+// Call site with different arguments. It gets its own build, so editing its arguments does not
+// invalidate the build of the call site above.
+mod colors {
+    gen_positions!(["R", "G", "B"]);
+}
+
 fn main() {
     let _p1 = Position2::X;
 }
+
+// === Crate-Level Attributes ===
+
+#[cfg(all(test, nightly))]
+mod tests {
+    #[test]
+    fn unstable_library_feature() {
+        // The outer attribute must not precede the crate-level ones in the generated crate.
+        #[crabtime::expression]
+        #[allow(unused)]
+        fn gen_interspersed() -> String {
+            #![feature(iter_intersperse)]
+            #![recursion_limit = "256"]
+            let joined: String = ["X", "Y", "Z"].into_iter().intersperse(",").collect();
+            format!("{joined:?}")
+        }
+        assert_eq!(gen_interspersed!(), "X,Y,Z");
+    }
+}
+
+// === Virtual Workspace Manifests ===
+
+#[cfg(all(test, nightly))]
+#[path = "../fixtures/virtual-workspace/member/examples/gen_version.rs"]
+mod virtual_workspace;
+
+#[cfg(all(test, nightly))]
+mod virtual_workspace_tests {
+    #[test]
+    fn build_dependencies_of_member_crate() {
+        assert_eq!(super::virtual_workspace::MAJOR, 2);
+    }
+}
@@ -0,0 +1,21 @@
+// === Integration Test Call Site ===
+
+// Macros called from integration tests, which cargo compiles from the `tests` directory of the
+// crate, find the crate manifest like the ones called from `src`.
+#[crabtime::function]
+fn gen_axes(components: Vec<String>) {
+    let variants = components.join(",");
+    crabtime::output! {
+        #[derive(Debug, PartialEq)]
+        enum Axis {
+            {{variants}}
+        }
+    }
+}
+gen_axes!(["X", "Y", "Z"]);
+
+#[test]
+fn macro_expanded_in_integration_test() {
+    assert_ne!(Axis::X, Axis::Z);
+    assert_eq!(format!("{:?}", Axis::Y), "Y");
+}
@@ -0,0 +1,31 @@
+// === Same-Named Macros In One File ===
+
+// Both macros are called from this file, so they share the macro directory, but their bodies are
+// hashed into separate argument-specific subprojects, which are cached independently.
+mod first {
+    #[crabtime::function]
+    fn gen_twin() {
+        crabtime::output! {
+            pub const VALUE: usize = 1;
+            pub const NAME: &str = "first";
+        }
+    }
+    gen_twin!();
+}
+
+mod second {
+    #[crabtime::function]
+    fn gen_twin() {
+        crabtime::output! {
+            pub const VALUE: usize = 2;
+            pub const NAME: &str = "second";
+        }
+    }
+    gen_twin!();
+}
+
+#[test]
+fn same_named_macros_expanded_independently() {
+    assert_eq!((first::VALUE, first::NAME), (1, "first"));
+    assert_eq!((second::VALUE, second::NAME), (2, "second"));
+}